@@ -0,0 +1,421 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec;
+use crate::exchange::ADMIN_EXCHANGE;
+use crate::metrics::{Metrics, MetricsSnapshot, PeerMetrics};
+use crate::node::{self, NodeMap, NodeState};
+use crate::secret;
+use crate::topology::Topology;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+// admin operations
+const MEMBERS: u8 = 0;
+const RING: u8 = 1;
+const STATS: u8 = 2;
+const REMOVE: u8 = 3;
+const GOSSIP: u8 = 4;
+const PEERS: u8 = 5;
+const REMOVE_NODE: u8 = 6;
+
+// reply status
+const OK: u8 = 0;
+const FAILED: u8 = 1;
+
+// ring epoch and token owners
+type Ring = (u64, BTreeMap<u64, u32>);
+
+/// Member as reported by admin queries. Sensitive metadata values are
+/// redacted before leaving the node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminMember {
+    pub address: SocketAddr,
+    pub id: u32,
+    pub incarnation: u64,
+    pub metadata: BTreeMap<String, String>,
+    pub roles: Vec<String>,
+    pub state: NodeState,
+    pub version: u64,
+}
+
+/// Client for the admin queries served by members which enabled them
+/// with Swarm::enable_admin, as used by `swarmctl`.
+pub struct AdminClient {
+    address: SocketAddr,
+    timeout: Duration,
+}
+
+impl AdminClient {
+    pub fn new(address: SocketAddr) -> AdminClient {
+        AdminClient {
+            address,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Lists every member known to the node.
+    pub fn members(&self) -> Result<Vec<AdminMember>, Box<dyn Error>> {
+        let mut stream = self.request(MEMBERS, |_| Ok(()))?;
+        let count = stream.read_u32::<BigEndian>()?;
+        let mut members = Vec::new();
+        for _ in 0..count {
+            let id = stream.read_u32::<BigEndian>()?;
+            let address = node::read_string(&mut stream)?.parse()?;
+            let incarnation = stream.read_u64::<BigEndian>()?;
+            let version = stream.read_u64::<BigEndian>()?;
+            let state = match stream.read_u8()? {
+                0 => NodeState::Alive,
+                1 => NodeState::Suspect,
+                _ => NodeState::Dead,
+            };
+
+            let mut metadata = BTreeMap::new();
+            for _ in 0..stream.read_u16::<BigEndian>()? {
+                let key = node::read_string(&mut stream)?;
+                let value = node::read_string(&mut stream)?;
+                metadata.insert(key, value);
+            }
+
+            let mut roles = Vec::new();
+            for _ in 0..stream.read_u8()? {
+                roles.push(node::read_string(&mut stream)?);
+            }
+
+            members.push(AdminMember { address, id, incarnation, metadata,
+                roles, state, version });
+        }
+
+        Ok(members)
+    }
+
+    /// Returns the ring epoch and token assignments of the node, if its
+    /// topology maintains a ring.
+    pub fn ring(&self) -> Result<Option<Ring>, Box<dyn Error>> {
+        let mut stream = self.request(RING, |_| Ok(()))?;
+        if stream.read_u8()? == 0 {
+            return Ok(None);
+        }
+
+        let epoch = stream.read_u64::<BigEndian>()?;
+        let mut tokens = BTreeMap::new();
+        for _ in 0..stream.read_u32::<BigEndian>()? {
+            let token = stream.read_u64::<BigEndian>()?;
+            let id = stream.read_u32::<BigEndian>()?;
+            tokens.insert(token, id);
+        }
+
+        Ok(Some((epoch, tokens)))
+    }
+
+    /// Returns the gossip counters of the node.
+    pub fn stats(&self) -> Result<MetricsSnapshot, Box<dyn Error>> {
+        let mut stream = self.request(STATS, |_| Ok(()))?;
+        let mut values = [0u64; 10];
+        for value in values.iter_mut() {
+            *value = stream.read_u64::<BigEndian>()?;
+        }
+
+        Ok(MetricsSnapshot {
+            bytes_received: values[0],
+            bytes_sent: values[1],
+            connection_errors: values[2],
+            member_count: values[3] as usize,
+            replies_failed: values[4],
+            replies_succeeded: values[5],
+            rounds_attempted: values[6],
+            rounds_deferred: values[7],
+            rounds_failed: values[8],
+            rounds_succeeded: values[9],
+        })
+    }
+
+    /// Returns the counters of gossip rounds the node started with each
+    /// peer.
+    pub fn peers(&self) -> Result<Vec<PeerMetrics>, Box<dyn Error>> {
+        let mut stream = self.request(PEERS, |_| Ok(()))?;
+        let mut peers = Vec::new();
+        for _ in 0..stream.read_u32::<BigEndian>()? {
+            let address = node::read_string(&mut stream)?.parse()?;
+            peers.push(PeerMetrics {
+                address,
+                bytes_received: stream.read_u64::<BigEndian>()?,
+                bytes_sent: stream.read_u64::<BigEndian>()?,
+                last_latency: Duration::from_micros(
+                    stream.read_u64::<BigEndian>()?),
+                rounds_failed: stream.read_u64::<BigEndian>()?,
+                rounds_succeeded: stream.read_u64::<BigEndian>()?,
+            });
+        }
+
+        Ok(peers)
+    }
+
+    /// Marks member `id` dead on the node, so it stops gossiping with
+    /// the member until it restarts with a new incarnation.
+    pub fn remove(&self, id: u32) -> Result<(), Box<dyn Error>> {
+        self.request(REMOVE, |stream| stream.write_u32::<BigEndian>(id)
+            .map_err(|e| e.into())).map(|_| ())
+    }
+
+    /// Removes member `id` cluster-wide through the node for `ttl`,
+    /// see Topology::remove_node. Unlike remove, the member stays
+    /// removed on every member even after restarting.
+    pub fn remove_node(&self, id: u32, ttl: Duration)
+            -> Result<(), Box<dyn Error>> {
+        self.request(REMOVE_NODE, |stream| {
+            stream.write_u32::<BigEndian>(id)?;
+            stream.write_u64::<BigEndian>(
+                u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))?;
+            Ok(())
+        }).map(|_| ())
+    }
+
+    /// Starts a gossip round on the node without waiting for its
+    /// gossip interval.
+    pub fn gossip(&self) -> Result<(), Box<dyn Error>> {
+        self.request(GOSSIP, |_| Ok(())).map(|_| ())
+    }
+
+    /// Sends `operation` and returns the stream once the reply status
+    /// was read.
+    fn request<F>(&self, operation: u8, write_args: F)
+            -> Result<TcpStream, Box<dyn Error>>
+            where F: FnOnce(&mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut stream =
+            TcpStream::connect_timeout(&self.address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut buf = Vec::new();
+        codec::write_header(&mut buf, rand::random::<u64>(), ADMIN_EXCHANGE)?;
+        buf.write_u8(operation)?;
+        write_args(&mut buf)?;
+        stream.write_all(&buf)?;
+
+        match stream.read_u8()? {
+            OK => Ok(stream),
+            _ => Err(node::read_string(&mut stream)?.into()),
+        }
+    }
+}
+
+/// Wakes the gossiper for a round ahead of its gossip interval.
+pub struct RoundTrigger {
+    condvar: Condvar,
+    triggered: Mutex<bool>,
+}
+
+impl RoundTrigger {
+    pub fn new() -> RoundTrigger {
+        RoundTrigger {
+            condvar: Condvar::new(),
+            triggered: Mutex::new(false),
+        }
+    }
+
+    pub fn trigger(&self) {
+        *self.triggered.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits up to `timeout` for a trigger, returning true if one
+    /// arrived.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let triggered = self.triggered.lock().unwrap();
+        let (mut triggered, _) = self.condvar.wait_timeout_while(
+            triggered, timeout, |triggered| !*triggered).unwrap();
+        std::mem::replace(&mut *triggered, false)
+    }
+}
+
+/// Answers an admin query from `stream` for the local node `id`.
+pub fn serve<T: Topology, S: Read + Write>(stream: &mut S, id: u32,
+        nodes: &NodeMap, topology: &T, metrics: &Metrics,
+        trigger: &RoundTrigger) -> Result<(), Box<dyn Error>> {
+    let operation = stream.read_u8()?;
+    let mut buf = vec!(OK);
+    match operation {
+        MEMBERS => {
+            let nodes = nodes.nodes();
+            buf.write_u32::<BigEndian>(nodes.len() as u32)?;
+            for node in nodes.iter() {
+                buf.write_u32::<BigEndian>(node.get_id())?;
+                node::write_string(&node.get_address().to_string(),
+                    &mut buf)?;
+                buf.write_u64::<BigEndian>(node.get_incarnation())?;
+                buf.write_u64::<BigEndian>(node.get_version())?;
+                buf.write_u8(match node.state() {
+                    NodeState::Alive => 0,
+                    NodeState::Suspect => 1,
+                    NodeState::Dead => 2,
+                })?;
+
+                let metadata: Vec<_> = node.metadata().collect();
+                buf.write_u16::<BigEndian>(metadata.len() as u16)?;
+                for (key, value) in metadata {
+                    node::write_string(key, &mut buf)?;
+                    node::write_string(secret::redact(key, value), &mut buf)?;
+                }
+
+                let roles: Vec<_> = node.roles().collect();
+                buf.write_u8(roles.len() as u8)?;
+                for role in roles {
+                    node::write_string(role, &mut buf)?;
+                }
+            }
+        },
+        RING => match (topology.ring_epoch(), topology.ring_tokens()) {
+            (Some(epoch), Some(tokens)) => {
+                buf.write_u8(1)?;
+                buf.write_u64::<BigEndian>(epoch)?;
+                buf.write_u32::<BigEndian>(tokens.len() as u32)?;
+                for (token, id) in tokens.iter() {
+                    buf.write_u64::<BigEndian>(*token)?;
+                    buf.write_u32::<BigEndian>(*id)?;
+                }
+            },
+            _ => buf.write_u8(0)?,
+        },
+        STATS => {
+            let snapshot = metrics.snapshot(nodes.len());
+            for value in [snapshot.bytes_received, snapshot.bytes_sent,
+                    snapshot.connection_errors, snapshot.member_count as u64,
+                    snapshot.replies_failed, snapshot.replies_succeeded,
+                    snapshot.rounds_attempted, snapshot.rounds_deferred,
+                    snapshot.rounds_failed, snapshot.rounds_succeeded] {
+                buf.write_u64::<BigEndian>(value)?;
+            }
+        },
+        PEERS => {
+            let peers = metrics.peer_snapshot();
+            buf.write_u32::<BigEndian>(peers.len() as u32)?;
+            for peer in peers.iter() {
+                node::write_string(&peer.address.to_string(), &mut buf)?;
+                for value in [peer.bytes_received, peer.bytes_sent,
+                        peer.last_latency.as_micros() as u64,
+                        peer.rounds_failed, peer.rounds_succeeded] {
+                    buf.write_u64::<BigEndian>(value)?;
+                }
+            }
+        },
+        REMOVE => {
+            let remove_id = stream.read_u32::<BigEndian>()?;
+            let result = match remove_id == id {
+                true => Err("cannot remove the local node"),
+                false => match nodes.update(remove_id,
+                        |node| node.set_state(NodeState::Dead)) {
+                    true => Ok(()),
+                    false => Err("unknown node"),
+                },
+            };
+
+            if let Err(e) = result {
+                buf = vec!(FAILED);
+                node::write_string(e, &mut buf)?;
+            } else {
+                info!("removed node by admin request [id={}]", remove_id);
+            }
+        },
+        REMOVE_NODE => {
+            let remove_id = stream.read_u32::<BigEndian>()?;
+            let ttl = Duration::from_millis(stream.read_u64::<BigEndian>()?);
+            if let Err(e) = topology.remove_node(remove_id, ttl) {
+                buf = vec!(FAILED);
+                node::write_string(&e.to_string(), &mut buf)?;
+            } else {
+                info!("removed node cluster-wide by admin request [id={}]",
+                    remove_id);
+            }
+        },
+        GOSSIP => {
+            debug!("triggering gossip round by admin request");
+            trigger.trigger();
+        },
+        _ => {
+            buf = vec!(FAILED);
+            node::write_string(&format!("unknown admin operation [operation={}]",
+                operation), &mut buf)?;
+        },
+    }
+
+    stream.write_all(&buf)?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::prelude::{AdminClient, Dht, DhtBuilder, NodeState, Swarm};
+
+    use std::time::Duration;
+
+    #[test]
+    fn admin_queries() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Dht>> = Vec::new();
+        for i in 0..2 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, DhtBuilder::new(vec!(i as u64)));
+            if i == 0 {
+                swarm.enable_admin();
+            }
+
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        let seed_address = swarms[0].local_addr().expect("local addr");
+        let peer_address = swarms[1].local_addr().expect("local addr");
+        let client = AdminClient::new(seed_address);
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            client.ring().ok().flatten()
+                .map(|(_, tokens)| tokens.len() == 2).unwrap_or(false)
+                && client.peers().map(|peers| peers.len() == 1)
+                    .unwrap_or(false)), "ring convergence");
+        let members = client.members().expect("members");
+        assert_eq!(members.len(), 2);
+        assert!(members.iter().all(|member| member.state == NodeState::Alive));
+
+        let (_, tokens) = client.ring().expect("ring").expect("tokens");
+        assert_eq!(tokens.into_iter().collect::<Vec<_>>(),
+            vec!((0, 0), (1, 1)));
+        assert!(client.stats().expect("stats").rounds_succeeded > 0);
+        let peers = client.peers().expect("peers");
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].address, peer_address);
+        assert!(peers[0].rounds_succeeded > 0 && peers[0].bytes_sent > 0);
+        client.gossip().expect("gossip");
+
+        // removed members are dead -> the local node cannot be removed
+        client.remove(1).expect("remove");
+        assert_eq!(client.members().expect("members")[1].state,
+            NodeState::Dead);
+        assert!(client.remove(0).is_err());
+
+        // removed nodes are dropped and refused while they gossip
+        client.remove_node(1, Duration::from_secs(60)).expect("remove node");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            client.members().map(|members| members.len() == 1)
+                .unwrap_or(false)), "node removal");
+        assert!(client.remove_node(0, Duration::from_secs(60)).is_err());
+
+        // members answer only once admin is enabled
+        let client = AdminClient::new(peer_address);
+        assert!(client.members().is_err());
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_BASE_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30000;
+const DEFAULT_MAX_RETRIES: u32 = 8;
+
+type Handler = Box<dyn Fn(SocketAddr, u32) + Send + Sync>;
+
+struct Failures {
+    count: u32,
+    until: Instant,
+}
+
+/// Backoff for gossip targets which refuse connections, such as a seed
+/// which is down. Every consecutive failure doubles the time before
+/// the target is contacted again, up to `max_backoff`, with jitter so
+/// members do not retry in lockstep. Targets which fail `max_retries`
+/// times in a row are reported unreachable once, then retried at the
+/// maximum backoff until they answer.
+pub struct ConnectBackoff {
+    failures: Mutex<HashMap<SocketAddr, Failures>>,
+    handler: RwLock<Option<Handler>>,
+    limits: RwLock<(Duration, Duration, u32)>,
+}
+
+impl ConnectBackoff {
+    pub fn new() -> ConnectBackoff {
+        ConnectBackoff {
+            failures: Mutex::new(HashMap::new()),
+            handler: RwLock::new(None),
+            limits: RwLock::new((Duration::from_millis(DEFAULT_BASE_BACKOFF_MS),
+                Duration::from_millis(DEFAULT_MAX_BACKOFF_MS),
+                DEFAULT_MAX_RETRIES)),
+        }
+    }
+
+    pub fn set_limits(&self, base_backoff: Duration, max_backoff: Duration,
+            max_retries: u32) {
+        *self.limits.write().unwrap() =
+            (base_backoff, max_backoff, max_retries);
+    }
+
+    pub fn set_handler(&self, handler: Handler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Records a failed connection to `address`, returning the number
+    /// of consecutive failures.
+    pub fn failure(&self, address: SocketAddr, now: Instant) -> u32 {
+        let (base_backoff, max_backoff, max_retries) =
+            *self.limits.read().unwrap();
+
+        let count = {
+            let mut failures = self.failures.lock().unwrap();
+            let failures = failures.entry(address)
+                .or_insert(Failures { count: 0, until: now });
+            let exponent = std::cmp::min(failures.count, 16);
+            let backoff = std::cmp::min(base_backoff * 2u32.pow(exponent),
+                max_backoff);
+
+            // equal jitter -> wait between half and all of the backoff
+            let half = backoff / 2;
+            let jitter = rand::thread_rng()
+                .gen_range(0, half.as_millis() as u64 + 1);
+            failures.count += 1;
+            failures.until = now + half + Duration::from_millis(jitter);
+            debug!("backing off gossip target [address={}, failures={}, backoff_ms={}]",
+                address, failures.count, (failures.until - now).as_millis());
+            failures.count
+        };
+
+        if count == max_retries {
+            warn!("gossip target unreachable [address={}, failures={}]",
+                address, count);
+            if let Some(handler) = self.handler.read().unwrap().as_ref() {
+                handler(address, count);
+            }
+        }
+
+        count
+    }
+
+    /// Returns the remaining backoff of `address`, if any.
+    pub fn remaining(&self, address: &SocketAddr, now: Instant)
+            -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        failures.get(address)
+            .map(|failures| failures.until.saturating_duration_since(now))
+            .filter(|remaining| *remaining > Duration::from_millis(0))
+    }
+
+    /// Clears the failures of `address` once it accepts a connection.
+    pub fn success(&self, address: &SocketAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(failures) = failures.remove(address) {
+            debug!("gossip target reachable [address={}, failures={}]",
+                address, failures.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, ManualClock};
+    use super::ConnectBackoff;
+
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn connect_backoff() {
+        let address: SocketAddr = "127.0.0.1:16740".parse().expect("parse");
+        let clock = ManualClock::new(0);
+        let backoff = ConnectBackoff::new();
+        backoff.set_limits(Duration::from_millis(100),
+            Duration::from_millis(400), 3);
+
+        let unreachable = Arc::new(Mutex::new(Vec::new()));
+        let unreachable_clone = unreachable.clone();
+        backoff.set_handler(Box::new(move |address, failures|
+            unreachable_clone.lock().unwrap().push((address, failures))));
+
+        // backoff doubles per failure with jitter up to the maximum
+        for (i, max_ms) in [100, 200, 400, 400].iter().enumerate() {
+            assert!(backoff.remaining(&address, clock.now()).is_none());
+            assert_eq!(backoff.failure(address, clock.now()), i as u32 + 1);
+            let remaining = backoff.remaining(&address, clock.now())
+                .expect("remaining backoff");
+            assert!(remaining >= Duration::from_millis(max_ms / 2));
+            assert!(remaining <= Duration::from_millis(*max_ms));
+            clock.advance(remaining);
+        }
+
+        // exhausted retry budgets are reported once
+        assert_eq!(*unreachable.lock().unwrap(), vec!((address, 3)));
+
+        // reachable targets start over
+        backoff.success(&address);
+        assert!(backoff.remaining(&address, clock.now()).is_none());
+        assert_eq!(backoff.failure(address, clock.now()), 1);
+    }
+}
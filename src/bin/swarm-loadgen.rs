@@ -0,0 +1,217 @@
+use swarm::prelude::{Cluster, ClusterBuilder, Swarm};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: swarm-loadgen [--members <n>] [--ip <addr>] \
+[--base-port <port>] [--seed <addr:port>] [--churn <steady|rolling|burst>] \
+[--churn-count <n>] [--churn-interval-ms <ms>] [--rounds <n>] \
+[--gossip-interval-ms <ms>] [--threads <n>]";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Churn {
+    Steady,
+    Rolling,
+    Burst,
+}
+
+struct Config {
+    base_port: u16,
+    churn: Churn,
+    churn_count: usize,
+    churn_interval: Duration,
+    gossip_interval_ms: u64,
+    ip_address: IpAddr,
+    members: usize,
+    rounds: usize,
+    seed_address: Option<SocketAddr>,
+    thread_count: u8,
+}
+
+struct Member {
+    cluster: Arc<Cluster>,
+    id: u32,
+    swarm: Swarm<Cluster>,
+}
+
+fn main() {
+    env_logger::init();
+
+    let config = match parse_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        },
+    };
+
+    if let Err(e) = run(&config) {
+        eprintln!("loadgen failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item=String>)
+        -> Result<Config, Box<dyn Error>> {
+    let mut config = Config {
+        base_port: 16000,
+        churn: Churn::Rolling,
+        churn_count: 1,
+        churn_interval: Duration::from_millis(2000),
+        gossip_interval_ms: 250,
+        ip_address: "127.0.0.1".parse()?,
+        members: 8,
+        rounds: 5,
+        seed_address: None,
+        thread_count: 2,
+    };
+
+    while let Some(arg) = args.next() {
+        let value = args.next()
+            .ok_or_else(|| format!("missing value for '{}'", arg))?;
+        match arg.as_str() {
+            "--base-port" => config.base_port = value.parse()?,
+            "--churn" => config.churn = match value.as_str() {
+                "steady" => Churn::Steady,
+                "rolling" => Churn::Rolling,
+                "burst" => Churn::Burst,
+                _ => return Err(format!("unknown churn '{}'", value).into()),
+            },
+            "--churn-count" => config.churn_count = value.parse()?,
+            "--churn-interval-ms" => config.churn_interval =
+                Duration::from_millis(value.parse()?),
+            "--gossip-interval-ms" => config.gossip_interval_ms =
+                value.parse()?,
+            "--ip" => config.ip_address = value.parse()?,
+            "--members" => config.members = value.parse()?,
+            "--rounds" => config.rounds = value.parse()?,
+            "--seed" => config.seed_address = Some(value.parse()?),
+            "--threads" => config.thread_count = value.parse()?,
+            _ => return Err(format!("unknown argument '{}'", arg).into()),
+        }
+    }
+
+    if config.members == 0 {
+        return Err("at least one member is required".into());
+    }
+
+    Ok(config)
+}
+
+fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+    // first member seeds the others unless targeting a cluster
+    let seed_address = config.seed_address.unwrap_or_else(||
+        SocketAddr::new(config.ip_address, config.base_port));
+
+    println!("starting {} members [seed={}]", config.members, seed_address);
+    let mut members = Vec::new();
+    for i in 0..config.members {
+        members.push(start_member(config, i as u32, seed_address)?);
+    }
+
+    let elapsed = wait_for_convergence(&members, config.churn_interval);
+    let mut total = traffic(&members);
+    report("startup", elapsed, total);
+
+    // restarted members reset their counters so retain the old totals
+    let mut retired = (0, 0);
+
+    for round in 0..config.rounds {
+        // select members to restart for this round
+        let count = match config.churn {
+            Churn::Steady => 0,
+            Churn::Rolling => 1,
+            Churn::Burst => config.churn_count,
+        };
+        let count = std::cmp::min(count, members.len());
+        let offset = (round * count) % members.len();
+        let indices: Vec<usize> = (0..count)
+            .map(|i| (offset + i) % members.len()).collect();
+
+        // stop and restart selected members with new incarnations
+        for index in indices.iter() {
+            members[*index].swarm.stop()?;
+            let metrics = members[*index].swarm.metrics();
+            retired.0 += metrics.bytes_sent;
+            retired.1 += metrics.bytes_received;
+        }
+
+        for index in indices.iter() {
+            let id = members[*index].id;
+            members[*index] = start_member(config, id, seed_address)?;
+        }
+
+        let elapsed = wait_for_convergence(&members, config.churn_interval);
+        let (sent, received) = traffic(&members);
+        let current = (sent + retired.0, received + retired.1);
+        report(&format!("round {} [restarted={}]", round, count), elapsed,
+            (current.0 - total.0, current.1 - total.1));
+        total = current;
+    }
+
+    for member in members.iter_mut() {
+        member.swarm.stop()?;
+    }
+
+    Ok(())
+}
+
+fn start_member(config: &Config, id: u32, seed_address: SocketAddr)
+        -> Result<Member, Box<dyn Error>> {
+    let port = config.base_port + id as u16;
+    let (mut swarm, cluster) = Swarm::new(id, config.ip_address, port,
+        Some(seed_address), ClusterBuilder::new());
+    swarm.start(config.thread_count, 50, config.gossip_interval_ms)?;
+
+    Ok(Member { cluster, id, swarm })
+}
+
+/// Blocks until every member has observed the current incarnation of
+/// every other member, returning the elapsed time or None on timeout.
+fn wait_for_convergence(members: &[Member], timeout: Duration)
+        -> Option<Duration> {
+    let instant = Instant::now();
+    while instant.elapsed() < timeout {
+        let incarnations: HashMap<u32, u64> = members.iter()
+            .filter_map(|member| member.cluster.nodes().into_iter()
+                .find(|node| node.get_id() == member.id)
+                .map(|node| (member.id, node.get_incarnation())))
+            .collect();
+
+        let converged = members.iter().all(|member| {
+            let view: HashMap<u32, u64> = member.cluster.nodes().iter()
+                .map(|node| (node.get_id(), node.get_incarnation()))
+                .collect();
+            incarnations.iter().all(|(id, incarnation)|
+                view.get(id) == Some(incarnation))
+        });
+
+        if converged {
+            return Some(instant.elapsed());
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    None
+}
+
+/// Sums bytes sent and received across all running members.
+fn traffic(members: &[Member]) -> (u64, u64) {
+    members.iter().map(|member| member.swarm.metrics())
+        .fold((0, 0), |(sent, received), metrics|
+            (sent + metrics.bytes_sent, received + metrics.bytes_received))
+}
+
+fn report(phase: &str, elapsed: Option<Duration>, traffic: (u64, u64)) {
+    match elapsed {
+        Some(elapsed) => println!("{}: converged in {}ms [sent={}B, received={}B]",
+            phase, elapsed.as_millis(), traffic.0, traffic.1),
+        None => println!("{}: did not converge [sent={}B, received={}B]",
+            phase, traffic.0, traffic.1),
+    }
+}
@@ -0,0 +1,143 @@
+use swarm::prelude::{AdminClient, NodeState};
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const USAGE: &str = "usage: swarmctl <addr:port> \
+<members|metadata|ring|stats|peers|remove <id>|\
+remove-node <id> <ttl_secs>|gossip>";
+
+enum Command {
+    Members,
+    Metadata,
+    Ring,
+    Stats,
+    Peers,
+    Remove(u32),
+    RemoveNode(u32, u64),
+    Gossip,
+}
+
+fn main() {
+    env_logger::init();
+
+    let (address, command) = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        },
+    };
+
+    if let Err(e) = run(&AdminClient::new(address), command) {
+        eprintln!("swarmctl failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item=String>)
+        -> Result<(SocketAddr, Command), Box<dyn Error>> {
+    let address = args.next().ok_or("missing node address")?.parse()?;
+    let command = match args.next().ok_or("missing command")?.as_str() {
+        "members" => Command::Members,
+        "metadata" => Command::Metadata,
+        "ring" => Command::Ring,
+        "stats" => Command::Stats,
+        "peers" => Command::Peers,
+        "remove" => Command::Remove(args.next()
+            .ok_or("missing node id for 'remove'")?.parse()?),
+        "remove-node" => Command::RemoveNode(args.next()
+                .ok_or("missing node id for 'remove-node'")?.parse()?,
+            args.next().ok_or("missing ttl for 'remove-node'")?.parse()?),
+        "gossip" => Command::Gossip,
+        command => return Err(format!("unknown command '{}'", command).into()),
+    };
+
+    if let Some(arg) = args.next() {
+        return Err(format!("unexpected argument '{}'", arg).into());
+    }
+
+    Ok((address, command))
+}
+
+fn run(client: &AdminClient, command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Members => {
+            println!("{:>10}  {:<21}  {:<7}  {:>11}  {:>7}",
+                "id", "address", "state", "incarnation", "version");
+            for member in client.members()? {
+                let state = match member.state {
+                    NodeState::Alive => "alive",
+                    NodeState::Suspect => "suspect",
+                    _ => "dead",
+                };
+
+                println!("{:>10}  {:<21}  {:<7}  {:>11}  {:>7}", member.id,
+                    member.address, state, member.incarnation, member.version);
+            }
+        },
+        Command::Metadata => {
+            for member in client.members()? {
+                println!("{} ({})", member.id, member.address);
+                if !member.roles.is_empty() {
+                    println!("  roles: {}", member.roles.join(","));
+                }
+                for (key, value) in member.metadata.iter() {
+                    println!("  {}={}", key, value);
+                }
+            }
+        },
+        Command::Ring => match client.ring()? {
+            Some((epoch, tokens)) => {
+                println!("epoch {}", epoch);
+                println!("{:>20}  {:>10}", "token", "id");
+                for (token, id) in tokens.iter() {
+                    println!("{:>20}  {:>10}", token, id);
+                }
+            },
+            None => println!("topology maintains no ring"),
+        },
+        Command::Stats => {
+            let stats = client.stats()?;
+            for (name, value) in [
+                    ("member_count", stats.member_count as u64),
+                    ("rounds_attempted", stats.rounds_attempted),
+                    ("rounds_succeeded", stats.rounds_succeeded),
+                    ("rounds_failed", stats.rounds_failed),
+                    ("rounds_deferred", stats.rounds_deferred),
+                    ("replies_succeeded", stats.replies_succeeded),
+                    ("replies_failed", stats.replies_failed),
+                    ("connection_errors", stats.connection_errors),
+                    ("bytes_sent", stats.bytes_sent),
+                    ("bytes_received", stats.bytes_received)] {
+                println!("{:<18}  {}", name, value);
+            }
+        },
+        Command::Peers => {
+            println!("{:<21}  {:>9}  {:>6}  {:>12}  {:>12}  {:>10}",
+                "address", "succeeded", "failed", "bytes_sent",
+                "bytes_recv", "latency_us");
+            for peer in client.peers()? {
+                println!("{:<21}  {:>9}  {:>6}  {:>12}  {:>12}  {:>10}",
+                    peer.address, peer.rounds_succeeded, peer.rounds_failed,
+                    peer.bytes_sent, peer.bytes_received,
+                    peer.last_latency.as_micros());
+            }
+        },
+        Command::Remove(id) => {
+            client.remove(id)?;
+            println!("marked node {} dead", id);
+        },
+        Command::RemoveNode(id, ttl_secs) => {
+            client.remove_node(id, Duration::from_secs(ttl_secs))?;
+            println!("removed node {} cluster-wide for {}s", id, ttl_secs);
+        },
+        Command::Gossip => {
+            client.gossip()?;
+            println!("triggered gossip round");
+        },
+    }
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use crate::node::NodeMap;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+struct BootstrapState {
+    attempted: HashSet<SocketAddr>,
+    candidates: Vec<SocketAddr>,
+    ids: Vec<u32>,
+    last_change: Option<Instant>,
+    next: usize,
+}
+
+/// Coordinates simultaneous cold starts. Until ready the gossiper
+/// contacts every candidate in turn rather than a single seed, so
+/// nodes seeding each other cannot settle into separate islands. The
+/// swarm is ready once every candidate has been tried and membership
+/// has not changed for the settle window.
+pub struct Bootstrap {
+    ready: AtomicBool,
+    settle_window: Duration,
+    state: Mutex<BootstrapState>,
+}
+
+impl Bootstrap {
+    pub fn new(candidates: Vec<SocketAddr>, settle_window: Duration)
+            -> Bootstrap {
+        Bootstrap {
+            ready: AtomicBool::new(false),
+            settle_window,
+            state: Mutex::new(BootstrapState {
+                attempted: HashSet::new(),
+                candidates: sort_candidates(candidates),
+                ids: Vec::new(),
+                last_change: None,
+                next: 0,
+            }),
+        }
+    }
+
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        self.state.lock().unwrap().candidates.clone()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Returns the next candidate to gossip with, skipping `address`.
+    pub fn next_addr(&self, address: &SocketAddr) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..state.candidates.len() {
+            let candidate = state.candidates[state.next];
+            state.next = (state.next + 1) % state.candidates.len();
+            if candidate != *address {
+                state.attempted.insert(candidate);
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+
+    /// Replaces the candidate list, for seed providers which discover
+    /// candidates at runtime.
+    #[cfg(feature = "k8s")]
+    pub fn set_candidates(&self, candidates: Vec<SocketAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.candidates = sort_candidates(candidates);
+        state.next = 0;
+    }
+
+    /// Records the current membership, returning true once ready.
+    pub fn observe(&self, address: &SocketAddr, nodes: &NodeMap,
+            now: Instant) -> bool {
+        if self.is_ready() {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let ids = nodes.ids();
+        if state.last_change.is_none() || ids != state.ids {
+            state.ids = ids;
+            state.last_change = Some(now);
+        }
+
+        let attempted = state.candidates.iter().all(|candidate|
+            candidate == address || state.attempted.contains(candidate));
+        let settled = state.last_change.map(|last_change|
+            now.saturating_duration_since(last_change) >= self.settle_window)
+            .unwrap_or(false);
+
+        if attempted && settled {
+            info!("bootstrap complete [members={}]", state.ids.len());
+            self.ready.store(true, Ordering::SeqCst);
+        }
+
+        self.is_ready()
+    }
+}
+
+fn sort_candidates(mut candidates: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    // every node walks candidates in the same order
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, ManualClock};
+    use crate::node::{Node, NodeMap};
+    use super::Bootstrap;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn bootstrap_settle() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let address = SocketAddr::new(ip_address, 12000);
+        let candidates = vec!(SocketAddr::new(ip_address, 12002),
+            address, SocketAddr::new(ip_address, 12001));
+
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, 12000));
+
+        let clock = ManualClock::new(0);
+        let bootstrap = Bootstrap::new(candidates,
+            Duration::from_millis(100));
+
+        // candidates are walked in order skipping the local address
+        assert_eq!(bootstrap.next_addr(&address).expect("next addr").port(),
+            12001);
+        assert!(!bootstrap.observe(&address, &nodes, clock.now()));
+        clock.advance(Duration::from_millis(100));
+        assert!(!bootstrap.observe(&address, &nodes, clock.now()));
+
+        // membership changes restart the settle window
+        assert_eq!(bootstrap.next_addr(&address).expect("next addr").port(),
+            12002);
+        nodes.insert(Node::new(1, ip_address, 12001));
+        assert!(!bootstrap.observe(&address, &nodes, clock.now()));
+        clock.advance(Duration::from_millis(100));
+        assert!(bootstrap.observe(&address, &nodes, clock.now()));
+        assert!(bootstrap.is_ready());
+    }
+}
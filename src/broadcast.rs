@@ -0,0 +1,232 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec;
+use crate::node::{self, Node, NodeMap};
+
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+
+/// Largest payload accepted by Swarm::broadcast.
+pub const MAX_PAYLOAD_BYTES: usize = 16 * 1024;
+// payload bytes piggybacked on a single exchange
+const MAX_BATCH_BYTES: usize = 32 * 1024;
+// each message is retransmitted this many times per log2(members)
+const RETRANSMIT_MULT: u32 = 3;
+// recently seen message ids remembered for deduplication
+const SEEN_CAPACITY: usize = 4096;
+
+type Handler = Box<dyn Fn(&Node, &[u8]) + Send + Sync>;
+
+#[derive(Clone)]
+struct Broadcast {
+    address: SocketAddr,
+    id: u64,
+    origin: u32,
+    payload: Vec<u8>,
+}
+
+/// User broadcasts piggybacked on gossip exchanges. Every exchange
+/// carries the least transmitted queued messages in both directions,
+/// and each message is retransmitted a bounded number of times scaled
+/// by log2 of the membership size, so broadcasts reach every member
+/// with high probability. Receivers deduplicate by message id.
+pub struct BroadcastQueue {
+    handler: RwLock<Option<Handler>>,
+    queue: Mutex<Vec<(u32, Broadcast)>>,
+    seen: Mutex<(HashSet<u64>, VecDeque<u64>)>,
+}
+
+impl BroadcastQueue {
+    pub fn new() -> BroadcastQueue {
+        BroadcastQueue {
+            handler: RwLock::new(None),
+            queue: Mutex::new(Vec::new()),
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+        }
+    }
+
+    pub fn set_handler(&self, handler: Handler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Queues `payload` from `origin`, returning the message id.
+    pub fn enqueue(&self, origin: &Node, payload: &[u8])
+            -> Result<u64, Box<dyn Error>> {
+        if payload.len() > MAX_PAYLOAD_BYTES {
+            return Err(format!("broadcast payload too large [length={}]",
+                payload.len()).into());
+        }
+
+        let broadcast = Broadcast {
+            address: origin.get_address(),
+            id: rand::random::<u64>(),
+            origin: origin.get_id(),
+            payload: payload.to_vec(),
+        };
+
+        self.observe(broadcast.id);
+        let id = broadcast.id;
+        self.queue.lock().unwrap().push((0, broadcast));
+        Ok(id)
+    }
+
+    /// Returns the number of messages still being retransmitted.
+    pub fn pending(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Reads a peer's piggybacked batch frame, dispatching and queueing
+    /// the messages not seen before. Corrupt batches are dropped.
+    pub fn read(&self, nodes: &NodeMap, reader: &mut impl Read)
+            -> Result<(), Box<dyn Error>> {
+        // corrupt frames are skipped -> the stream stays in sync
+        let frame = match codec::read_frame(reader)? {
+            Some(frame) => frame,
+            None => {
+                warn!("skipping corrupt broadcast frame");
+                return Ok(());
+            },
+        };
+
+        let reader = &mut frame.as_slice();
+        let count = reader.read_u16::<BigEndian>()?;
+        for _ in 0..count {
+            let id = reader.read_u64::<BigEndian>()?;
+            let origin = reader.read_u32::<BigEndian>()?;
+            let address: SocketAddr = node::read_string(reader)?.parse()?;
+            let length = reader.read_u32::<BigEndian>()? as usize;
+            if length > MAX_PAYLOAD_BYTES {
+                return Err(format!("broadcast payload too large [length={}]",
+                    length).into());
+            }
+
+            let mut payload = vec![0; length];
+            reader.read_exact(&mut payload)?;
+            if !self.observe(id) {
+                continue;
+            }
+
+            // origins may not have gossiped here yet
+            let node = nodes.get(origin).unwrap_or_else(||
+                Node::new(origin, address.ip(), address.port()));
+            if let Some(handler) = self.handler.read().unwrap().as_ref() {
+                handler(&node, &payload);
+            }
+
+            let broadcast = Broadcast { address, id, origin, payload };
+            self.queue.lock().unwrap().push((0, broadcast));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the least transmitted messages, retiring those which
+    /// reached the retransmit limit for `member_count` members.
+    pub fn write(&self, member_count: usize, writer: &mut impl Write)
+            -> Result<(), Box<dyn Error>> {
+        let limit = RETRANSMIT_MULT * (usize::BITS
+            - (member_count + 1).leading_zeros());
+        let batch: Vec<Broadcast> = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.sort_by_key(|(transmits, _)| *transmits);
+
+            let (mut batch, mut bytes) = (Vec::new(), 0);
+            for (transmits, broadcast) in queue.iter_mut() {
+                if bytes + broadcast.payload.len() > MAX_BATCH_BYTES
+                        || batch.len() == u16::MAX as usize {
+                    break;
+                }
+
+                bytes += broadcast.payload.len();
+                *transmits += 1;
+                batch.push(broadcast.clone());
+            }
+
+            queue.retain(|(transmits, _)| *transmits < limit);
+            batch
+        };
+
+        let mut buf = Vec::new();
+        buf.write_u16::<BigEndian>(batch.len() as u16)?;
+        for broadcast in batch.iter() {
+            buf.write_u64::<BigEndian>(broadcast.id)?;
+            buf.write_u32::<BigEndian>(broadcast.origin)?;
+            node::write_string(&broadcast.address.to_string(), &mut buf)?;
+            buf.write_u32::<BigEndian>(broadcast.payload.len() as u32)?;
+            buf.write_all(&broadcast.payload)?;
+        }
+
+        codec::write_frame(writer, &buf)
+    }
+
+    /// Records message `id`, returning false if it was seen before.
+    fn observe(&self, id: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let (ids, order) = &mut *seen;
+        if !ids.insert(id) {
+            return false;
+        }
+
+        order.push_back(id);
+        if order.len() > SEEN_CAPACITY {
+            if let Some(id) = order.pop_front() {
+                ids.remove(&id);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::BroadcastQueue;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn piggybacked_broadcasts() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let origin = Node::new(0, ip_address, 12000);
+        let nodes = NodeMap::new();
+        nodes.insert(origin.clone());
+
+        let sender = BroadcastQueue::new();
+        let receiver = BroadcastQueue::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        receiver.set_handler(Box::new(move |node, payload|
+            received_clone.lock().unwrap()
+                .push((node.get_id(), payload.to_vec()))));
+        assert!(sender.enqueue(&origin, &[0; 32 * 1024]).is_err());
+        sender.enqueue(&origin, b"hello").expect("enqueue");
+
+        // corrupt batches are dropped without failing the exchange
+        let mut buf = Vec::new();
+        sender.write(1, &mut buf).expect("write");
+        let last = buf.len() - 5;
+        buf[last] ^= 0xff;
+        receiver.read(&nodes, &mut buf.as_slice()).expect("read");
+        assert!(received.lock().unwrap().is_empty());
+
+        // duplicates are dispatched once
+        for _ in 0..2 {
+            let mut buf = Vec::new();
+            sender.write(1, &mut buf).expect("write");
+            receiver.read(&nodes, &mut buf.as_slice()).expect("read");
+        }
+        assert_eq!(*received.lock().unwrap(), vec!((0, b"hello".to_vec())));
+
+        // messages retire after their retransmit limit
+        assert_eq!(sender.pending(), 1);
+        for _ in 0..3 {
+            sender.write(1, &mut Vec::new()).expect("write");
+        }
+        assert_eq!(sender.pending(), 0);
+        assert_eq!(receiver.pending(), 1);
+    }
+}
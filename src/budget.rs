@@ -0,0 +1,163 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct BudgetState {
+    bytes: u64,
+    deferred: u32,
+    exchanges: u32,
+    interval_start: Instant,
+    outbound: u64,
+}
+
+/// Caps gossip exchanges and bytes per interval, counting both rounds
+/// this node starts and replies it serves. Rounds over budget are
+/// deferred and run at the start of the next interval, and bytes over
+/// budget are carried as debt so the long run average honors the cap.
+/// An optional outbound cap limits only the bytes this node sends.
+pub struct GossipBudget {
+    interval: Duration,
+    max_bytes: u64,
+    max_exchanges: u32,
+    max_outbound: u64,
+    state: Mutex<BudgetState>,
+}
+
+impl GossipBudget {
+    pub fn new(interval: Duration, max_exchanges: u32, max_bytes: u64,
+            now: Instant) -> GossipBudget {
+        GossipBudget {
+            interval,
+            max_bytes,
+            max_exchanges,
+            max_outbound: u64::MAX,
+            state: Mutex::new(BudgetState {
+                bytes: 0,
+                deferred: 0,
+                exchanges: 0,
+                interval_start: now,
+                outbound: 0,
+            }),
+        }
+    }
+
+    /// Caps the bytes sent per interval, in addition to total bytes.
+    pub fn max_outbound(mut self, max_outbound: u64) -> GossipBudget {
+        self.max_outbound = max_outbound;
+        self
+    }
+
+    /// Reserves one exchange, returning false if the budget for the
+    /// current interval is spent.
+    pub fn acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        if !self.available(&state) {
+            return false;
+        }
+
+        state.exchanges += 1;
+        true
+    }
+
+    /// Records bytes transferred by an acquired exchange.
+    pub fn consume(&self, sent: u64, received: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.bytes += sent + received;
+        state.outbound += sent;
+    }
+
+    /// Carries a round over to the next interval.
+    pub fn defer(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.deferred = std::cmp::min(state.deferred + 1, self.max_exchanges);
+    }
+
+    /// Takes a deferred round if one is pending and the budget allows.
+    pub fn take_deferred(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        if state.deferred == 0 || !self.available(&state) {
+            return false;
+        }
+
+        state.deferred -= 1;
+        true
+    }
+
+    fn available(&self, state: &BudgetState) -> bool {
+        state.exchanges < self.max_exchanges && state.bytes < self.max_bytes
+            && state.outbound < self.max_outbound
+    }
+
+    fn roll(&self, state: &mut BudgetState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.interval_start);
+        let intervals = (elapsed.as_nanos()
+            / std::cmp::max(self.interval.as_nanos(), 1)) as u32;
+        if intervals == 0 {
+            return;
+        }
+
+        // overflow from previous intervals is carried as debt
+        state.bytes = state.bytes
+            .saturating_sub(self.max_bytes.saturating_mul(intervals as u64));
+        state.outbound = state.outbound
+            .saturating_sub(self.max_outbound.saturating_mul(intervals as u64));
+        state.exchanges = 0;
+        state.interval_start += self.interval * intervals;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, ManualClock};
+    use super::GossipBudget;
+
+    use std::time::Duration;
+
+    #[test]
+    fn budget_carry() {
+        let clock = ManualClock::new(0);
+        let interval = Duration::from_millis(100);
+        let budget = GossipBudget::new(interval, 2, 1000, clock.now());
+
+        // exchange cap -> excess rounds are deferred
+        assert!(budget.acquire(clock.now()));
+        assert!(budget.acquire(clock.now()));
+        assert!(!budget.acquire(clock.now()));
+        budget.defer();
+        assert!(!budget.take_deferred(clock.now()));
+
+        clock.advance(interval);
+        assert!(budget.take_deferred(clock.now()));
+        assert!(!budget.take_deferred(clock.now()));
+
+        // byte overflow is carried into the next interval
+        assert!(budget.acquire(clock.now()));
+        budget.consume(2000, 500);
+        clock.advance(interval);
+        assert!(!budget.acquire(clock.now()));
+        clock.advance(interval);
+        assert!(budget.acquire(clock.now()));
+    }
+
+    #[test]
+    fn budget_outbound() {
+        let clock = ManualClock::new(0);
+        let interval = Duration::from_millis(100);
+        let budget = GossipBudget::new(interval, u32::MAX, u64::MAX,
+            clock.now()).max_outbound(1000);
+
+        // received bytes do not count against the outbound cap
+        assert!(budget.acquire(clock.now()));
+        budget.consume(400, 5000);
+        assert!(budget.acquire(clock.now()));
+        budget.consume(1600, 0);
+        assert!(!budget.acquire(clock.now()));
+
+        // sends over the cap are carried into the next interval
+        clock.advance(interval);
+        assert!(!budget.acquire(clock.now()));
+        clock.advance(interval);
+        assert!(budget.acquire(clock.now()));
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::{self, Read, Write};
+
+const BUFFER_CAPACITY: usize = 8192;
+
+/// Read and write buffers owned by a gossip thread and reused across
+/// every exchange it handles.
+pub struct ExchangeBuffers {
+    read: Vec<u8>,
+    write: Vec<u8>,
+}
+
+impl Default for ExchangeBuffers {
+    fn default() -> ExchangeBuffers {
+        ExchangeBuffers {
+            read: vec![0u8; BUFFER_CAPACITY],
+            write: Vec::with_capacity(BUFFER_CAPACITY),
+        }
+    }
+}
+
+impl ExchangeBuffers {
+    pub fn new() -> ExchangeBuffers {
+        ExchangeBuffers::default()
+    }
+}
+
+/// Duplex buffered stream for request / reply exchanges. Writes are
+/// coalesced until the next read or flush, so each protocol step goes
+/// out in as few packets and syscalls as possible. Callers must flush
+/// after their final write.
+pub struct BufferedStream<'a, S: Read + Write> {
+    buffers: &'a mut ExchangeBuffers,
+    end: usize,
+    position: usize,
+    stream: &'a mut S,
+}
+
+impl<'a, S: Read + Write> BufferedStream<'a, S> {
+    pub fn new(stream: &'a mut S, buffers: &'a mut ExchangeBuffers)
+            -> BufferedStream<'a, S> {
+        buffers.write.clear();
+        BufferedStream { buffers, end: 0, position: 0, stream }
+    }
+}
+
+impl<'a, S: Read + Write> Read for BufferedStream<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // peers only answer once our pending writes arrive
+        self.flush()?;
+
+        if self.position == self.end {
+            self.end = self.stream.read(&mut self.buffers.read)?;
+            self.position = 0;
+        }
+
+        let len = std::cmp::min(buf.len(), self.end - self.position);
+        buf[..len].copy_from_slice(
+            &self.buffers.read[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<'a, S: Read + Write> Write for BufferedStream<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffers.write.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffers.write.is_empty() {
+            self.stream.write_all(&self.buffers.write)?;
+            self.buffers.write.clear();
+        }
+
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferedStream, ExchangeBuffers};
+
+    use std::io::{Cursor, Read, Write};
+
+    #[test]
+    fn buffered_stream() {
+        let mut buffers = ExchangeBuffers::new();
+        let mut cursor = Cursor::new(vec![0u8; 4]);
+
+        {
+            let mut stream = BufferedStream::new(&mut cursor, &mut buffers);
+            stream.write_all(&[1, 2]).expect("write");
+            stream.write_all(&[3]).expect("write");
+
+            // reads flush pending writes first
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).expect("read");
+            assert_eq!(buf, [0]);
+
+            stream.write_all(&[4, 5]).expect("write");
+            stream.flush().expect("flush");
+        }
+
+        assert_eq!(cursor.into_inner(), vec!(1, 2, 3, 0, 4, 5));
+    }
+}
@@ -0,0 +1,396 @@
+use crate::Swarm;
+use crate::cidr::Cidr;
+use crate::clock::{Clock, SystemClock};
+use crate::node::TieBreaker;
+use crate::store::StateStore;
+use crate::swarm::{DEFAULT_GOSSIP_INTERVAL_MS, DEFAULT_THREAD_COUNT,
+    DEFAULT_THREAD_SLEEP_MS};
+use crate::topology::{Topology, TopologyBuilder};
+use crate::webhook::Webhook;
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Single entry point for Swarm construction, replacing Swarm::new, the
+/// individual setters, and the arguments of Swarm::start:
+///
+/// ```no_run
+/// use swarm::prelude::{DhtBuilder, SwarmBuilder};
+///
+/// let (swarm, dht) = SwarmBuilder::new(0, "127.0.0.1:15000".parse()?)
+///     .seed("127.0.0.1:15001".parse()?)
+///     .listener_threads(2, std::time::Duration::from_millis(50))
+///     .start(DhtBuilder::new(vec!(0)))?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct SwarmBuilder {
+    address: SocketAddr,
+    admin: bool,
+    allowed_cidrs: Vec<Cidr>,
+    bootstrap: Option<(Vec<SocketAddr>, Duration)>,
+    change_journal: Option<usize>,
+    clock: Option<Arc<dyn Clock>>,
+    cluster_epoch: Option<u64>,
+    cluster_name: Option<String>,
+    connect_backoff: Option<(Duration, Duration, u32)>,
+    connection_pool: Option<Duration>,
+    failure_timeouts: Option<(Duration, Duration)>,
+    gossip_budget: Option<(u32, u64)>,
+    gossip_interval: Duration,
+    heartbeat_timeout: Option<Duration>,
+    id: u32,
+    indexed_metadata: Vec<String>,
+    indirect_probes: Option<(usize, Duration)>,
+    max_local_health: Option<u32>,
+    membership_snapshots: Option<Duration>,
+    outbound_rate: Option<u64>,
+    partition_threshold: Option<u32>,
+    roles: Vec<String>,
+    seed_address: Option<SocketAddr>,
+    state_store: Option<Arc<dyn StateStore>>,
+    thread_count: u8,
+    thread_sleep: Duration,
+    tie_breaker: Option<TieBreaker>,
+    webhooks: Vec<Webhook>,
+}
+
+impl SwarmBuilder {
+    pub fn new(id: u32, address: SocketAddr) -> SwarmBuilder {
+        SwarmBuilder {
+            address,
+            admin: false,
+            allowed_cidrs: Vec::new(),
+            bootstrap: None,
+            change_journal: None,
+            clock: None,
+            cluster_epoch: None,
+            cluster_name: None,
+            connect_backoff: None,
+            connection_pool: None,
+            failure_timeouts: None,
+            gossip_budget: None,
+            gossip_interval: Duration::from_millis(DEFAULT_GOSSIP_INTERVAL_MS),
+            heartbeat_timeout: None,
+            id,
+            indexed_metadata: Vec::new(),
+            indirect_probes: None,
+            max_local_health: None,
+            membership_snapshots: None,
+            outbound_rate: None,
+            partition_threshold: None,
+            roles: Vec::new(),
+            seed_address: None,
+            state_store: None,
+            thread_count: DEFAULT_THREAD_COUNT,
+            thread_sleep: Duration::from_millis(DEFAULT_THREAD_SLEEP_MS),
+            tie_breaker: None,
+            webhooks: Vec::new(),
+        }
+    }
+
+    /// See Swarm::enable_admin.
+    pub fn admin(mut self) -> SwarmBuilder {
+        self.admin = true;
+        self
+    }
+
+    /// See Swarm::set_allowed_cidrs.
+    pub fn allowed_cidrs(mut self, allowed_cidrs: Vec<Cidr>)
+            -> SwarmBuilder {
+        self.allowed_cidrs = allowed_cidrs;
+        self
+    }
+
+    /// See Swarm::set_bootstrap.
+    pub fn bootstrap(mut self, candidates: Vec<SocketAddr>,
+            settle_window: Duration) -> SwarmBuilder {
+        self.bootstrap = Some((candidates, settle_window));
+        self
+    }
+
+    /// See Swarm::set_change_journal.
+    pub fn change_journal(mut self, capacity: usize) -> SwarmBuilder {
+        self.change_journal = Some(capacity);
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> SwarmBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// See Swarm::set_cluster_epoch.
+    pub fn cluster_epoch(mut self, cluster_epoch: u64) -> SwarmBuilder {
+        self.cluster_epoch = Some(cluster_epoch);
+        self
+    }
+
+    /// See Swarm::set_cluster_name.
+    pub fn cluster_name(mut self, cluster_name: &str) -> SwarmBuilder {
+        self.cluster_name = Some(cluster_name.to_string());
+        self
+    }
+
+    /// See Swarm::set_connect_backoff.
+    pub fn connect_backoff(mut self, base_backoff: Duration,
+            max_backoff: Duration, max_retries: u32) -> SwarmBuilder {
+        self.connect_backoff = Some((base_backoff, max_backoff, max_retries));
+        self
+    }
+
+    /// See Swarm::set_connection_pool.
+    pub fn connection_pool(mut self, idle_timeout: Duration)
+            -> SwarmBuilder {
+        self.connection_pool = Some(idle_timeout);
+        self
+    }
+
+    /// See Swarm::set_failure_timeouts.
+    pub fn failure_timeouts(mut self, suspect_timeout: Duration,
+            dead_timeout: Duration) -> SwarmBuilder {
+        self.failure_timeouts = Some((suspect_timeout, dead_timeout));
+        self
+    }
+
+    /// See Swarm::set_gossip_budget.
+    pub fn gossip_budget(mut self, max_exchanges: u32, max_bytes: u64)
+            -> SwarmBuilder {
+        self.gossip_budget = Some((max_exchanges, max_bytes));
+        self
+    }
+
+    pub fn gossip_interval(mut self, gossip_interval: Duration)
+            -> SwarmBuilder {
+        self.gossip_interval = gossip_interval;
+        self
+    }
+
+    /// See Swarm::set_heartbeat_timeout.
+    pub fn heartbeat_timeout(mut self, heartbeat_timeout: Duration)
+            -> SwarmBuilder {
+        self.heartbeat_timeout = Some(heartbeat_timeout);
+        self
+    }
+
+    /// Serves gossip on `thread_count` worker threads, fed connections
+    /// by one acceptor thread, while `thread_sleep` paces background
+    /// threads such as the change journal recorder. Zero threads
+    /// disables the listener.
+    pub fn listener_threads(mut self, thread_count: u8,
+            thread_sleep: Duration) -> SwarmBuilder {
+        self.thread_count = thread_count;
+        self.thread_sleep = thread_sleep;
+        self
+    }
+
+    /// See Swarm::index_metadata. May be called for several keys.
+    pub fn index_metadata(mut self, key: &str) -> SwarmBuilder {
+        self.indexed_metadata.push(key.to_string());
+        self
+    }
+
+    /// See Swarm::set_indirect_probes.
+    pub fn indirect_probes(mut self, count: usize, timeout: Duration)
+            -> SwarmBuilder {
+        self.indirect_probes = Some((count, timeout));
+        self
+    }
+
+    /// See Swarm::set_local_health.
+    pub fn local_health(mut self, max_local_health: u32) -> SwarmBuilder {
+        self.max_local_health = Some(max_local_health);
+        self
+    }
+
+    /// See Swarm::set_membership_snapshots.
+    pub fn membership_snapshots(mut self, interval: Duration)
+            -> SwarmBuilder {
+        self.membership_snapshots = Some(interval);
+        self
+    }
+
+    /// See Swarm::set_outbound_rate.
+    pub fn outbound_rate(mut self, bytes_per_second: u64) -> SwarmBuilder {
+        self.outbound_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// See Swarm::set_partition_threshold.
+    pub fn partition_threshold(mut self, rounds: u32) -> SwarmBuilder {
+        self.partition_threshold = Some(rounds);
+        self
+    }
+
+    /// See Swarm::set_roles. Invalid roles are logged and ignored by
+    /// build, and fail start.
+    pub fn roles(mut self, roles: &[&str]) -> SwarmBuilder {
+        self.roles = roles.iter().map(|role| role.to_string()).collect();
+        self
+    }
+
+    pub fn seed(mut self, seed_address: SocketAddr) -> SwarmBuilder {
+        self.seed_address = Some(seed_address);
+        self
+    }
+
+    pub fn state_store(mut self, state_store: Arc<dyn StateStore>)
+            -> SwarmBuilder {
+        self.state_store = Some(state_store);
+        self
+    }
+
+    /// See Swarm::set_tie_breaker.
+    pub fn tie_breaker(mut self, tie_breaker: TieBreaker) -> SwarmBuilder {
+        self.tie_breaker = Some(tie_breaker);
+        self
+    }
+
+    /// See Swarm::add_webhook.
+    pub fn webhook(mut self, webhook: Webhook) -> SwarmBuilder {
+        self.webhooks.push(webhook);
+        self
+    }
+
+    /// Returns a configured Swarm, ready to start with
+    /// Swarm::start_configured.
+    pub fn build<T: 'static + Topology + Sync + Send>(self,
+            topology_builder: impl TopologyBuilder<T>)
+            -> (Swarm<T>, Arc<T>) {
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let (mut swarm, topology) = Swarm::with_clock(self.id,
+            self.address.ip(), self.address.port(), self.seed_address, clock,
+            topology_builder);
+
+        if self.admin {
+            swarm.enable_admin();
+        }
+
+        if !self.allowed_cidrs.is_empty() {
+            swarm.set_allowed_cidrs(self.allowed_cidrs);
+        }
+
+        if let Some((candidates, settle_window)) = self.bootstrap {
+            swarm.set_bootstrap(candidates, settle_window);
+        }
+
+        if let Some(capacity) = self.change_journal {
+            swarm.set_change_journal(capacity);
+        }
+
+        if let Some(cluster_epoch) = self.cluster_epoch {
+            swarm.set_cluster_epoch(cluster_epoch);
+        }
+
+        if let Some(cluster_name) = self.cluster_name {
+            swarm.set_cluster_name(&cluster_name);
+        }
+
+        if let Some((base_backoff, max_backoff, max_retries)) =
+                self.connect_backoff {
+            swarm.set_connect_backoff(base_backoff, max_backoff, max_retries);
+        }
+
+        if let Some(idle_timeout) = self.connection_pool {
+            swarm.set_connection_pool(idle_timeout);
+        }
+
+        if let Some((suspect_timeout, dead_timeout)) = self.failure_timeouts {
+            swarm.set_failure_timeouts(suspect_timeout, dead_timeout);
+        }
+
+        if let Some((max_exchanges, max_bytes)) = self.gossip_budget {
+            swarm.set_gossip_budget(max_exchanges, max_bytes);
+        }
+
+        if let Some(heartbeat_timeout) = self.heartbeat_timeout {
+            swarm.set_heartbeat_timeout(heartbeat_timeout);
+        }
+
+        for key in self.indexed_metadata.iter() {
+            swarm.index_metadata(key);
+        }
+
+        if let Some((count, timeout)) = self.indirect_probes {
+            swarm.set_indirect_probes(count, timeout);
+        }
+
+        if let Some(max_local_health) = self.max_local_health {
+            swarm.set_local_health(max_local_health);
+        }
+
+        if let Some(interval) = self.membership_snapshots {
+            swarm.set_membership_snapshots(interval);
+        }
+
+        if let Some(bytes_per_second) = self.outbound_rate {
+            swarm.set_outbound_rate(bytes_per_second);
+        }
+
+        if let Some(rounds) = self.partition_threshold {
+            swarm.set_partition_threshold(rounds);
+        }
+
+        if !self.roles.is_empty() {
+            let roles: Vec<&str> =
+                self.roles.iter().map(|role| role.as_str()).collect();
+            if let Err(e) = swarm.set_roles(&roles) {
+                warn!("ignoring roles [error={}]", e);
+            }
+        }
+
+        if let Some(state_store) = self.state_store {
+            swarm.set_state_store(state_store);
+        }
+
+        if let Some(tie_breaker) = self.tie_breaker {
+            swarm.set_tie_breaker(tie_breaker);
+        }
+
+        for webhook in self.webhooks {
+            swarm.add_webhook(webhook);
+        }
+
+        swarm.set_thread_model(self.thread_count, self.thread_sleep,
+            self.gossip_interval);
+        (swarm, topology)
+    }
+
+    /// Builds and starts a Swarm, failing on roles which Swarm::set_roles
+    /// rejects rather than starting without them.
+    pub fn start<T: 'static + Topology + Sync + Send>(self,
+            topology_builder: impl TopologyBuilder<T>)
+            -> Result<(Swarm<T>, Arc<T>), Box<dyn Error>> {
+        crate::node::check_roles(self.roles.iter())?;
+        let (mut swarm, topology) = self.build(topology_builder);
+        swarm.start_configured()?;
+        Ok((swarm, topology))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::topology::cluster::ClusterBuilder;
+    use super::SwarmBuilder;
+
+    use std::time::Duration;
+
+    #[test]
+    fn swarm_builder() {
+        let address = "127.0.0.1:15900".parse().expect("parse address");
+        let (mut swarm, cluster) = SwarmBuilder::new(0, address)
+            .listener_threads(1, Duration::from_millis(20))
+            .gossip_interval(Duration::from_millis(50))
+            .change_journal(8)
+            .start(ClusterBuilder::new()).expect("swarm start");
+
+        assert!(swarm.is_ready());
+        assert_eq!(cluster.nodes().len(), 1);
+        swarm.stop().expect("swarm stop");
+
+        // stopped swarms restart with the configured thread model
+        swarm.start_configured().expect("swarm restart");
+        swarm.stop().expect("swarm stop");
+    }
+}
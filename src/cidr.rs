@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Block of IP addresses, parsed from CIDR notation such as
+/// `10.0.0.0/8` or `fd00::/8`. A bare address is a block of one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns true if `ip_address` falls within the block. IPv4-mapped
+    /// IPv6 addresses, as accepted by dual stack listeners, match
+    /// IPv4 blocks.
+    pub fn contains(&self, ip_address: IpAddr) -> bool {
+        match (self.address, ip_address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip_address)) =>
+                masked(u32::from(network) as u128, self.prefix_len, 32)
+                    == masked(u32::from(ip_address) as u128,
+                        self.prefix_len, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip_address)) =>
+                masked(u128::from(network), self.prefix_len, 128)
+                    == masked(u128::from(ip_address), self.prefix_len, 128),
+            _ => false,
+        }
+    }
+}
+
+// clears every bit past the first `prefix_len` of a `bits` wide address
+fn masked(address: u128, prefix_len: u8, bits: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        _ => address >> (bits - prefix_len) << (bits - prefix_len),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Box<dyn Error>;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+
+        let address: IpAddr = address.trim().parse()
+            .map_err(|e| format!("invalid cidr '{}': {}", value, e))?;
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse::<u8>()
+                .map_err(|e| format!("invalid cidr '{}': {}", value, e))?,
+            None => bits,
+        };
+
+        if prefix_len > bits {
+            return Err(format!("invalid cidr '{}': prefix longer than {} bits",
+                value, bits).into());
+        }
+
+        Ok(Cidr { address, prefix_len })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+
+    use std::net::IpAddr;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().expect("parse ip addr")
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.0.0.0/8".parse().expect("parse cidr");
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(!cidr.contains(ip("fd00::1")));
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+
+        let cidr: Cidr = "fd00::/8".parse().expect("parse cidr");
+        assert!(cidr.contains(ip("fd12::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+
+        // bare addresses and empty prefixes
+        let cidr: Cidr = "192.168.1.5".parse().expect("parse cidr");
+        assert!(cidr.contains(ip("192.168.1.5")));
+        assert!(!cidr.contains(ip("192.168.1.6")));
+        let cidr: Cidr = "0.0.0.0/0".parse().expect("parse cidr");
+        assert!(cidr.contains(ip("203.0.113.9")));
+
+        for value in ["10.0.0.0/33", "10.0.0/8", "10.0.0.0/x", "::/129"] {
+            assert!(value.parse::<Cidr>().is_err());
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// wait_until polls its condition at this interval
+#[cfg(feature = "net")]
+const WAIT_POLL_MS: u64 = 10;
+
+/// Source of time for gossip scheduling, incarnations, and any other
+/// time-dependent logic, so tests can substitute a ManualClock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+
+    /// Milliseconds since the unix epoch.
+    fn timestamp(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    fn timestamp(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// Clock which only moves when advanced. Sleeping advances the clock
+/// by the requested duration and returns immediately.
+pub struct ManualClock {
+    base: Instant,
+    elapsed: Mutex<Duration>,
+    timestamp: u64,
+}
+
+impl ManualClock {
+    pub fn new(timestamp: u64) -> ManualClock {
+        ManualClock {
+            base: Instant::now(),
+            elapsed: Mutex::new(Duration::from_millis(0)),
+            timestamp,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += duration;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp + self.elapsed().as_millis() as u64
+    }
+}
+
+/// Polls `condition` on `clock` until it holds, returning false if
+/// `timeout` elapses first.
+#[cfg(feature = "net")]
+pub(crate) fn wait_until<F: FnMut() -> bool>(clock: &dyn Clock,
+        timeout: Duration, mut condition: F) -> bool {
+    let instant = clock.now();
+    loop {
+        if condition() {
+            return true;
+        } else if clock.now().saturating_duration_since(instant) >= timeout {
+            return false;
+        }
+
+        clock.sleep(Duration::from_millis(WAIT_POLL_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock};
+
+    use std::time::Duration;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(1000);
+        let instant = clock.now();
+        assert_eq!(clock.timestamp(), 1000);
+
+        clock.sleep(Duration::from_millis(250));
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(clock.now() - instant, Duration::from_millis(300));
+        assert_eq!(clock.timestamp(), 1300);
+    }
+}
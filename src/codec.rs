@@ -0,0 +1,317 @@
+//! Wire codec of the gossip port. Every function is generic over Read
+//! or Write, so the same parsers serve TCP streams, in-memory
+//! transports and byte slices, which makes them straightforward to
+//! fuzz. Readers reject malformed input with an error, never a panic,
+//! and bound every length before allocating.
+//!
+//! Every connection opens with a header of a u64 trace id and an
+//! exchange kind byte, followed by the body of that exchange. Exchanges
+//! between members of one cluster continue the header with the cluster
+//! name and its u64 epoch, see write_cluster_header.
+//!
+//! Gossip messages riding on an exchange, such as broadcasts and
+//! piggybacked entries, travel in frames: a u32 payload length, the
+//! payload and a u32 CRC32 of the payload. A frame with a bad checksum
+//! is consumed whole, so receivers can skip it without desynchronizing
+//! the stream, while an oversized length rejects the exchange. Topology
+//! messages, such as node and token updates, travel in frames too, but
+//! a bad checksum there rejects the exchange rather than merging
+//! corrupted membership.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+pub use crate::control::ControlMessage;
+pub use crate::journal::MembershipDelta;
+pub use crate::node::{read_string, write_string, Node};
+pub use crate::ring::DhtSnapshot;
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+// delta frame kinds following the sequence number
+const UPSERT_FRAME: u8 = 0;
+const REMOVE_FRAME: u8 = 1;
+const RESET_FRAME: u8 = 2;
+const HEARTBEAT_FRAME: u8 = 3;
+
+/// Largest frame payload accepted by read_frame.
+pub const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+// crc32 (ieee 802.3, reflected) lookup table
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = match value & 1 {
+                1 => 0xedb88320 ^ (value >> 1),
+                _ => value >> 1,
+            };
+            bit += 1;
+        }
+
+        table[index] = value;
+        index += 1;
+    }
+
+    table
+}
+
+/// Computes the CRC32 (IEEE) checksum of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte|
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Reads a frame, returning its payload, or None if its checksum does
+/// not match. The whole frame is consumed either way.
+pub fn read_frame(reader: &mut impl Read)
+        -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let length = reader.read_u32::<BigEndian>()? as usize;
+    if length > MAX_FRAME_BYTES {
+        return Err(format!("frame too large [length={}]", length).into());
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    let checksum = reader.read_u32::<BigEndian>()?;
+    match crc32(&payload) == checksum {
+        true => Ok(Some(payload)),
+        false => {
+            debug!("frame checksum mismatch [length={}, checksum={:x}]",
+                length, checksum);
+            Ok(None)
+        },
+    }
+}
+
+pub fn write_frame(writer: &mut impl Write, payload: &[u8])
+        -> Result<(), Box<dyn Error>> {
+    if payload.len() > MAX_FRAME_BYTES {
+        return Err(format!("frame too large [length={}]",
+            payload.len()).into());
+    }
+
+    writer.write_u32::<BigEndian>(payload.len() as u32)?;
+    writer.write_all(payload)?;
+    writer.write_u32::<BigEndian>(crc32(payload))?;
+    Ok(())
+}
+
+/// Reads a connection header, returning the trace id and exchange kind.
+pub fn read_header(reader: &mut impl Read)
+        -> Result<(u64, u8), Box<dyn Error>> {
+    let trace_id = reader.read_u64::<BigEndian>()?;
+    let kind = reader.read_u8()?;
+    Ok((trace_id, kind))
+}
+
+pub fn write_header(writer: &mut impl Write, trace_id: u64, kind: u8)
+        -> Result<(), Box<dyn Error>> {
+    writer.write_u64::<BigEndian>(trace_id)?;
+    writer.write_u8(kind)?;
+    Ok(())
+}
+
+/// Reads the cluster name and epoch continuing the header of exchanges
+/// between members.
+pub fn read_cluster_header(reader: &mut impl Read)
+        -> Result<(String, u64), Box<dyn Error>> {
+    let cluster_name = read_string(reader)?;
+    let cluster_epoch = reader.read_u64::<BigEndian>()?;
+    Ok((cluster_name, cluster_epoch))
+}
+
+/// Writes a connection header naming the cluster and epoch of the
+/// requester, which members check before reading any state. Names are
+/// at most 255 bytes.
+pub fn write_cluster_header(writer: &mut impl Write, trace_id: u64,
+        kind: u8, cluster_name: &str, cluster_epoch: u64)
+        -> Result<(), Box<dyn Error>> {
+    write_header(writer, trace_id, kind)?;
+    write_string(cluster_name, writer)?;
+    writer.write_u64::<BigEndian>(cluster_epoch)?;
+    Ok(())
+}
+
+pub fn read_node(reader: &mut impl Read) -> Result<Node, Box<dyn Error>> {
+    Node::read(reader)
+}
+
+pub fn write_node(node: &Node, writer: &mut impl Write)
+        -> Result<(), Box<dyn Error>> {
+    node.write(writer)
+}
+
+/// Reads a control message along with the id of its addressee.
+pub fn read_control_message(reader: &mut impl Read)
+        -> Result<(u32, ControlMessage), Box<dyn Error>> {
+    ControlMessage::read(reader)
+}
+
+pub fn write_control_message(message: &ControlMessage, recipient: u32,
+        writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    message.write(recipient, writer)
+}
+
+/// Reads a subscription frame, returning its sequence number and delta,
+/// or no delta for heartbeats.
+pub fn read_delta(reader: &mut impl Read)
+        -> Result<(u64, Option<MembershipDelta>), Box<dyn Error>> {
+    let seq = reader.read_u64::<BigEndian>()?;
+    let delta = match reader.read_u8()? {
+        UPSERT_FRAME => Some(MembershipDelta::Upsert(Node::read(reader)?)),
+        REMOVE_FRAME =>
+            Some(MembershipDelta::Remove(reader.read_u32::<BigEndian>()?)),
+        RESET_FRAME => Some(MembershipDelta::Reset),
+        HEARTBEAT_FRAME => None,
+        kind => return Err(format!("unknown delta frame {}", kind).into()),
+    };
+
+    Ok((seq, delta))
+}
+
+/// Writes a subscription frame; no delta writes a heartbeat.
+pub fn write_delta(writer: &mut impl Write, seq: u64,
+        delta: Option<&MembershipDelta>) -> Result<(), Box<dyn Error>> {
+    writer.write_u64::<BigEndian>(seq)?;
+    match delta {
+        Some(MembershipDelta::Upsert(node)) => {
+            writer.write_u8(UPSERT_FRAME)?;
+            node.write(writer)?;
+        },
+        Some(MembershipDelta::Remove(id)) => {
+            writer.write_u8(REMOVE_FRAME)?;
+            writer.write_u32::<BigEndian>(*id)?;
+        },
+        Some(MembershipDelta::Reset) => writer.write_u8(RESET_FRAME)?,
+        None => writer.write_u8(HEARTBEAT_FRAME)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+    use super::{ControlMessage, MembershipDelta};
+
+    use std::error::Error;
+
+    // encodes a sample of every message the codec reads
+    fn samples() -> Vec<Vec<u8>> {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut node = Node::new(7, ip_address, 12000);
+        node.set_metadata("zone", "a");
+        node.add_metadata_value("shards", "1");
+        node.set_roles(["storage"]).expect("set roles");
+
+        let mut samples = vec!(Vec::new(); 5);
+        super::write_header(&mut samples[0], 42, 1).expect("write");
+        super::write_node(&node, &mut samples[1]).expect("write");
+        let message = ControlMessage { id: 1, payload: b"drain".to_vec(),
+            sender: 7 };
+        super::write_control_message(&message, 3, &mut samples[2])
+            .expect("write");
+        super::write_delta(&mut samples[3], 9,
+            Some(&MembershipDelta::Upsert(node))).expect("write");
+        super::write_delta(&mut samples[4], 10, None).expect("write");
+        samples
+    }
+
+    fn read_all(index: usize, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        let reader = &mut &*buf;
+        match index {
+            0 => super::read_header(reader).map(|_| ()),
+            1 => super::read_node(reader).map(|_| ()),
+            2 => super::read_control_message(reader).map(|_| ()),
+            _ => super::read_delta(reader).map(|_| ()),
+        }
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let samples = samples();
+        let (trace_id, kind) = super::read_header(&mut samples[0].as_slice())
+            .expect("read");
+        assert_eq!((trace_id, kind), (42, 1));
+
+        let node = super::read_node(&mut samples[1].as_slice()).expect("read");
+        assert_eq!(node.get_metadata("zone").expect("get metadata"), "a");
+        assert!(node.has_metadata_value("shards", "1"));
+
+        let (recipient, message) = super::read_control_message(
+            &mut samples[2].as_slice()).expect("read");
+        assert_eq!((recipient, message.payload), (3, b"drain".to_vec()));
+
+        assert!(matches!(super::read_delta(&mut samples[3].as_slice()),
+            Ok((9, Some(MembershipDelta::Upsert(_))))));
+        assert!(matches!(super::read_delta(&mut samples[4].as_slice()),
+            Ok((10, None))));
+    }
+
+    #[test]
+    fn codec_malformed_input() {
+        // truncated messages are rejected
+        for (index, sample) in samples().iter().enumerate() {
+            for len in 0..sample.len() {
+                assert!(read_all(index, &sample[..len]).is_err());
+            }
+        }
+
+        // corrupted messages never panic
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        for (index, sample) in samples().iter().enumerate() {
+            for _ in 0..512 {
+                let mut buf = sample.clone();
+                for _ in 0..4 {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let position = (state % buf.len() as u64) as usize;
+                    buf[position] = (state >> 32) as u8;
+                }
+
+                let _ = read_all(index, &buf);
+            }
+        }
+    }
+
+    #[test]
+    fn codec_frames() {
+        // standard check value of crc32
+        assert_eq!(super::crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(super::crc32(b""), 0);
+
+        let mut buf = Vec::new();
+        super::write_frame(&mut buf, b"first").expect("write");
+        super::write_frame(&mut buf, b"second").expect("write");
+        let reader = &mut buf.as_slice();
+        assert_eq!(super::read_frame(reader).expect("read"),
+            Some(b"first".to_vec()));
+        assert_eq!(super::read_frame(reader).expect("read"),
+            Some(b"second".to_vec()));
+        assert!(reader.is_empty());
+
+        // corrupt frames are skipped without losing sync
+        buf[5] ^= 0x01;
+        let reader = &mut buf.as_slice();
+        assert_eq!(super::read_frame(reader).expect("read"), None);
+        assert_eq!(super::read_frame(reader).expect("read"),
+            Some(b"second".to_vec()));
+
+        // truncated and oversized frames are rejected
+        for len in 0..13 {
+            assert!(super::read_frame(&mut &buf[..len]).is_err());
+        }
+
+        let mut oversized = Vec::new();
+        assert!(super::write_frame(&mut oversized,
+            &vec![0; super::MAX_FRAME_BYTES + 1]).is_err());
+        assert!(super::read_frame(&mut &[0xff; 16][..]).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+use crate::builder::SwarmBuilder;
+use crate::cidr::Cidr;
+use crate::swarm::{DEFAULT_GOSSIP_INTERVAL_MS, DEFAULT_THREAD_COUNT,
+    DEFAULT_THREAD_SLEEP_MS};
+use crate::topology::dht::DhtBuilder;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+enum Value {
+    List(Vec<String>),
+    Scalar(String),
+}
+
+/// Swarm parameters loaded from a configuration file. Files ending in
+/// `.yaml` or `.yml` are parsed as YAML, everything else as TOML. Both
+/// support the flat subset below (lists may also be written inline):
+///
+/// ```toml
+/// id = 1
+/// address = "10.0.0.1:15000"
+/// cluster_name = "orders"
+/// cluster_epoch = 2
+/// allowed_cidrs = ["10.0.0.0/8", "fd00::/8"]
+/// seeds = ["10.0.0.2:15000", "10.0.0.3:15000"]
+/// gossip_interval_ms = 1000
+/// thread_count = 4
+/// thread_sleep_ms = 50
+/// suspect_timeout_ms = 5000
+/// dead_timeout_ms = 15000
+/// heartbeat_timeout_ms = 10000
+/// bootstrap_settle_ms = 3000
+/// full_sync_interval_ms = 3600000
+/// tokens = [0, 6148914691236517205]
+/// ```
+///
+/// Neither parser is complete, they accept only:
+///
+/// - one `key = value` (TOML) or `key: value` (YAML) entry per line,
+///   without tables, nesting or multi-line strings
+/// - lists written inline as `[a, b]`, which TOML may spread over
+///   several lines, or as YAML block items `- a` below an empty key
+/// - strings in double quotes, where `\"`, `\\`, `\n` and `\t` are
+///   escapes, or in single quotes, taken literally up to the next `'`
+/// - `#` comments anywhere outside a string, so values containing `#`
+///   or `,` must be quoted
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SwarmConfig {
+    pub address: SocketAddr,
+    pub allowed_cidrs: Vec<Cidr>,
+    pub bootstrap_settle_ms: Option<u64>,
+    pub cluster_epoch: Option<u64>,
+    pub cluster_name: Option<String>,
+    pub dead_timeout_ms: Option<u64>,
+    pub full_sync_interval_ms: Option<u64>,
+    pub gossip_interval_ms: u64,
+    pub heartbeat_timeout_ms: Option<u64>,
+    pub id: u32,
+    pub seeds: Vec<SocketAddr>,
+    pub suspect_timeout_ms: Option<u64>,
+    pub thread_count: u8,
+    pub thread_sleep_ms: u64,
+    pub tokens: Vec<u64>,
+}
+
+impl SwarmConfig {
+    pub fn from_path(path: impl AsRef<Path>)
+            -> Result<SwarmConfig, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => SwarmConfig::from_yaml(&contents),
+            _ => SwarmConfig::from_toml(&contents),
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<SwarmConfig, Box<dyn Error>> {
+        let mut values = HashMap::new();
+        let mut lines = contents.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            let mut line = strip_comment(line).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, mut value) = split_entry(&line, '=', index)?;
+
+            // multi-line arrays continue until the closing bracket
+            if value.starts_with('[') {
+                while !value.ends_with(']') {
+                    let (_, next) = lines.next()
+                        .ok_or_else(|| format!("unterminated array [line={}]",
+                            index + 1))?;
+                    line.push_str(strip_comment(next).trim());
+                    value = split_entry(&line, '=', index)?.1;
+                }
+            }
+
+            values.insert(key, parse_value(&value));
+        }
+
+        SwarmConfig::from_values(values)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<SwarmConfig, Box<dyn Error>> {
+        let mut values = HashMap::new();
+        let mut list_key: Option<String> = None;
+        for (index, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() || line == "---" {
+                continue;
+            }
+
+            // block list items belong to the preceding empty key
+            if let Some(item) = line.strip_prefix('-') {
+                let key = list_key.as_ref().ok_or_else(||
+                    format!("list item without key [line={}]", index + 1))?;
+                if let Some(Value::List(items)) = values.get_mut(key) {
+                    items.push(unquote(item.trim()));
+                }
+                continue;
+            }
+
+            let (key, value) = split_entry(line, ':', index)?;
+            list_key = None;
+            if value.is_empty() {
+                values.insert(key.clone(), Value::List(Vec::new()));
+                list_key = Some(key);
+            } else {
+                values.insert(key, parse_value(&value));
+            }
+        }
+
+        SwarmConfig::from_values(values)
+    }
+
+    fn from_values(mut values: HashMap<String, Value>)
+            -> Result<SwarmConfig, Box<dyn Error>> {
+        let mut config = SwarmConfig {
+            address: scalar(&mut values, "address")?
+                .ok_or("missing config key 'address'")?.parse()?,
+            allowed_cidrs: Vec::new(),
+            bootstrap_settle_ms: parse(&mut values, "bootstrap_settle_ms")?,
+            cluster_epoch: parse(&mut values, "cluster_epoch")?,
+            cluster_name: scalar(&mut values, "cluster_name")?,
+            dead_timeout_ms: parse(&mut values, "dead_timeout_ms")?,
+            full_sync_interval_ms:
+                parse(&mut values, "full_sync_interval_ms")?,
+            gossip_interval_ms: parse(&mut values, "gossip_interval_ms")?
+                .unwrap_or(DEFAULT_GOSSIP_INTERVAL_MS),
+            heartbeat_timeout_ms: parse(&mut values, "heartbeat_timeout_ms")?,
+            id: parse(&mut values, "id")?
+                .ok_or("missing config key 'id'")?,
+            seeds: Vec::new(),
+            suspect_timeout_ms: parse(&mut values, "suspect_timeout_ms")?,
+            thread_count: parse(&mut values, "thread_count")?
+                .unwrap_or(DEFAULT_THREAD_COUNT),
+            thread_sleep_ms: parse(&mut values, "thread_sleep_ms")?
+                .unwrap_or(DEFAULT_THREAD_SLEEP_MS),
+            tokens: Vec::new(),
+        };
+
+        for cidr in list(&mut values, "allowed_cidrs")? {
+            config.allowed_cidrs.push(cidr.parse()?);
+        }
+
+        for seed in list(&mut values, "seeds")? {
+            config.seeds.push(seed.parse()?);
+        }
+
+        for token in list(&mut values, "tokens")? {
+            config.tokens.push(token.parse()?);
+        }
+
+        // reject typos rather than silently ignoring them
+        if let Some(key) = values.keys().next() {
+            return Err(format!("unknown config key '{}'", key).into());
+        }
+
+        if config.suspect_timeout_ms.is_some()
+                != config.dead_timeout_ms.is_some() {
+            return Err("suspect_timeout_ms and dead_timeout_ms must be set together".into());
+        }
+
+        if config.heartbeat_timeout_ms.is_some()
+                && config.suspect_timeout_ms.is_none() {
+            return Err("heartbeat_timeout_ms requires suspect_timeout_ms".into());
+        }
+
+        Ok(config)
+    }
+
+    /// Returns a DhtBuilder owning the configured tokens and running
+    /// full sync rounds at `full_sync_interval_ms`.
+    pub fn dht_builder(&self) -> DhtBuilder {
+        let builder = DhtBuilder::new(self.tokens.clone());
+        match self.full_sync_interval_ms {
+            Some(interval_ms) => builder
+                .full_sync_interval(Duration::from_millis(interval_ms)),
+            None => builder,
+        }
+    }
+
+    /// Returns a SwarmBuilder bound to the configured address. A single
+    /// seed is used as the seed address, while several seeds enable
+    /// bootstrap mode when `bootstrap_settle_ms` is set.
+    pub fn builder(&self) -> SwarmBuilder {
+        let mut builder = SwarmBuilder::new(self.id, self.address)
+            .gossip_interval(Duration::from_millis(self.gossip_interval_ms))
+            .listener_threads(self.thread_count,
+                Duration::from_millis(self.thread_sleep_ms));
+
+        if let Some(ref cluster_name) = self.cluster_name {
+            builder = builder.cluster_name(cluster_name);
+        }
+
+        if let Some(cluster_epoch) = self.cluster_epoch {
+            builder = builder.cluster_epoch(cluster_epoch);
+        }
+
+        if !self.allowed_cidrs.is_empty() {
+            builder = builder.allowed_cidrs(self.allowed_cidrs.clone());
+        }
+
+        if let Some(seed_address) = self.seeds.iter()
+                .find(|seed| **seed != self.address) {
+            builder = builder.seed(*seed_address);
+        }
+
+        if let (Some(suspect), Some(dead)) =
+                (self.suspect_timeout_ms, self.dead_timeout_ms) {
+            builder = builder.failure_timeouts(Duration::from_millis(suspect),
+                Duration::from_millis(dead));
+        }
+
+        if let Some(heartbeat_ms) = self.heartbeat_timeout_ms {
+            builder = builder.heartbeat_timeout(
+                Duration::from_millis(heartbeat_ms));
+        }
+
+        if let Some(settle_ms) = self.bootstrap_settle_ms {
+            builder = builder.bootstrap(self.seeds.clone(),
+                Duration::from_millis(settle_ms));
+        }
+
+        builder
+    }
+}
+
+fn list(values: &mut HashMap<String, Value>, key: &str)
+        -> Result<Vec<String>, Box<dyn Error>> {
+    match values.remove(key) {
+        Some(Value::List(items)) => Ok(items),
+        Some(Value::Scalar(_)) =>
+            Err(format!("config key '{}' must be a list", key).into()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn parse<V: std::str::FromStr>(values: &mut HashMap<String, Value>,
+        key: &str) -> Result<Option<V>, Box<dyn Error>>
+        where V::Err: Error + 'static {
+    match scalar(values, key)? {
+        Some(value) => Ok(Some(value.parse()?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_value(value: &str) -> Value {
+    match value.strip_prefix('[').and_then(|value| value.strip_suffix(']')) {
+        Some(items) => {
+            // split on commas outside quoted items
+            let mut start = 0;
+            let mut list = Vec::new();
+            for end in unquoted(items, ',').into_iter()
+                    .chain(std::iter::once(items.len())) {
+                let item = items[start..end].trim();
+                if !item.is_empty() {
+                    list.push(unquote(item));
+                }
+                start = end + 1;
+            }
+
+            Value::List(list)
+        },
+        None => Value::Scalar(unquote(value)),
+    }
+}
+
+fn scalar(values: &mut HashMap<String, Value>, key: &str)
+        -> Result<Option<String>, Box<dyn Error>> {
+    match values.remove(key) {
+        Some(Value::Scalar(value)) => Ok(Some(value)),
+        Some(Value::List(_)) =>
+            Err(format!("config key '{}' must be a scalar", key).into()),
+        None => Ok(None),
+    }
+}
+
+fn split_entry(line: &str, separator: char, index: usize)
+        -> Result<(String, String), Box<dyn Error>> {
+    let position = line.find(separator).ok_or_else(||
+        format!("malformed config entry [line={}]", index + 1))?;
+    let (key, value) = line.split_at(position);
+    Ok((key.trim().to_string(), value[1..].trim().to_string()))
+}
+
+fn strip_comment(line: &str) -> &str {
+    match unquoted(line, '#').first() {
+        Some(index) => &line[..*index],
+        None => line,
+    }
+}
+
+/// Returns the byte offsets of `target` outside quoted strings. Strings
+/// end at the quote character which opened them, and double quoted
+/// strings skip escaped characters.
+fn unquoted(line: &str, target: char) -> Vec<usize> {
+    let (mut offsets, mut quote, mut escaped) = (Vec::new(), None, false);
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == target => offsets.push(index),
+            None => {},
+        }
+    }
+
+    offsets
+}
+
+/// Strips one pair of matching quotes from `value`, resolving escapes
+/// in double quoted strings. Unquoted values are returned as is.
+fn unquote(value: &str) -> String {
+    let quote = match value.chars().next() {
+        Some(c) if (c == '"' || c == '\'') && value.len() > 1
+            && value.ends_with(c) => c,
+        _ => return value.to_string(),
+    };
+
+    let inner = &value[1..value.len() - 1];
+    if quote == '\'' {
+        return inner.to_string();
+    }
+
+    let (mut unescaped, mut chars) = (String::new(), inner.chars());
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            },
+            c => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SwarmConfig, Value};
+
+    use std::fs;
+
+    #[test]
+    fn config_formats() {
+        let toml = "# node one\nid = 1\naddress = \"127.0.0.1:15700\"\ncluster_name = \"orders\"\ncluster_epoch = 2\nallowed_cidrs = [\"10.0.0.0/8\"]\nseeds = [\n  \"127.0.0.1:15700\", # self\n  \"127.0.0.1:15701\",\n]\ngossip_interval_ms = 250\nsuspect_timeout_ms = 500\ndead_timeout_ms = 1500\nfull_sync_interval_ms = 60000\ntokens = [0, 100]\n";
+        let yaml = "---\nid: 1\naddress: \"127.0.0.1:15700\"\ncluster_name: orders\ncluster_epoch: 2\nallowed_cidrs:\n  - 10.0.0.0/8\nseeds:\n  - 127.0.0.1:15700\n  - '127.0.0.1:15701'\ngossip_interval_ms: 250  # fast\nsuspect_timeout_ms: 500\ndead_timeout_ms: 1500\nfull_sync_interval_ms: 60000\ntokens: [0, 100]\n";
+
+        let path = std::env::temp_dir().join("swarm-config-test.yaml");
+        fs::write(&path, yaml).expect("write config");
+        let config = SwarmConfig::from_path(&path).expect("parse yaml");
+        fs::remove_file(&path).expect("remove config");
+
+        assert_eq!(config, SwarmConfig::from_toml(toml).expect("parse toml"));
+        assert_eq!(config.id, 1);
+        assert_eq!(config.seeds.len(), 2);
+        assert_eq!((config.gossip_interval_ms, config.thread_count), (250, 4));
+        assert_eq!(config.tokens, vec!(0, 100));
+        assert_eq!(config.full_sync_interval_ms, Some(60000));
+        assert_eq!(config.allowed_cidrs,
+            vec!("10.0.0.0/8".parse().expect("parse cidr")));
+
+        let (swarm, dht) = config.builder().build(config.dht_builder());
+        assert_eq!(dht.snapshot().tokens.len(), 2);
+        assert_eq!(swarm.get_cluster_name(), "orders");
+        assert_eq!(swarm.get_cluster_epoch(), 2);
+
+        assert!(SwarmConfig::from_toml("id = 1").is_err());
+        assert!(SwarmConfig::from_toml(
+            "id = 1\naddress = \"127.0.0.1:1\"\ngossip_intervl_ms = 5").is_err());
+        assert!(SwarmConfig::from_toml(
+            "id = 1\naddress = \"127.0.0.1:1\"\nheartbeat_timeout_ms = 5").is_err());
+        assert!(SwarmConfig::from_toml(
+            "id = 1\naddress = \"127.0.0.1:1\"\nallowed_cidrs = [\"10.0.0.0/40\"]").is_err());
+    }
+
+    #[test]
+    fn config_quoting() {
+        let name = |config: Result<SwarmConfig, _>|
+            config.expect("parse config").cluster_name.expect("cluster name");
+
+        // comments start only outside the string that is open
+        assert_eq!(name(SwarmConfig::from_toml("id = 1\naddress = \"127.0.0.1:1\"\ncluster_name = \"it's # here\" # comment\n")),
+            "it's # here");
+        assert_eq!(name(SwarmConfig::from_yaml("id: 1\naddress: 127.0.0.1:1\ncluster_name: 'say \"hi\" # there' # comment\n")),
+            "say \"hi\" # there");
+
+        // escaped quotes do not close double quoted strings
+        assert_eq!(name(SwarmConfig::from_toml("id = 1\naddress = \"127.0.0.1:1\"\ncluster_name = \"a \\\"b # c\\\" \\\\\"\n")),
+            "a \"b # c\" \\");
+
+        // unquoted values end at a comment
+        assert_eq!(name(SwarmConfig::from_yaml("id: 1\naddress: 127.0.0.1:1\ncluster_name: orders#2\n")),
+            "orders");
+
+        // list items may hold commas and either quote
+        match super::parse_value("[\"a, 'b'\", 'c, \"d\"', e]") {
+            Value::List(items) => assert_eq!(items,
+                vec!("a, 'b'", "c, \"d\"", "e")),
+            Value::Scalar(_) => panic!("expected list"),
+        }
+    }
+}
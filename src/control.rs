@@ -0,0 +1,369 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::Clock;
+use crate::codec;
+use crate::exchange::CONTROL_EXCHANGE;
+use crate::node::{Node, NodeMap, NodeState};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+// rejects garbage lengths before allocating
+const MAX_PAYLOAD_BYTES: u32 = 64 * 1024;
+// recently dispatched message ids remembered for deduplication
+const SEEN_CAPACITY: usize = 1024;
+
+type Handler = Box<dyn Fn(&ControlMessage) + Send + Sync>;
+
+/// Message broadcast with Swarm::broadcast_control.
+#[derive(Clone, Debug)]
+pub struct ControlMessage {
+    pub id: u64,
+    pub payload: Vec<u8>,
+    pub sender: u32,
+}
+
+impl ControlMessage {
+    /// Reads a message along with the id of its addressee.
+    pub(crate) fn read(reader: &mut impl Read)
+            -> Result<(u32, ControlMessage), Box<dyn Error>> {
+        let id = reader.read_u64::<BigEndian>()?;
+        let sender = reader.read_u32::<BigEndian>()?;
+        let recipient = reader.read_u32::<BigEndian>()?;
+        let length = reader.read_u32::<BigEndian>()?;
+        if length > MAX_PAYLOAD_BYTES {
+            return Err(format!("control payload too large [length={}]",
+                length).into());
+        }
+
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        Ok((recipient, ControlMessage { id, payload, sender }))
+    }
+
+    pub(crate) fn write(&self, recipient: u32, writer: &mut impl Write)
+            -> Result<(), Box<dyn Error>> {
+        writer.write_u64::<BigEndian>(self.id)?;
+        writer.write_u32::<BigEndian>(self.sender)?;
+        writer.write_u32::<BigEndian>(recipient)?;
+        writer.write_u32::<BigEndian>(self.payload.len() as u32)?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+struct Inbox {
+    capacity: usize,
+    messages: HashMap<u32, VecDeque<(Instant, ControlMessage)>>,
+    ttl: Duration,
+}
+
+/// Sends control messages directly to every member. With an inbox
+/// enabled, messages for unreachable members are handed to their ring
+/// successor (the next alive member by id) which retains them, bounded
+/// and with a TTL, until the member is reachable again. Delivery is
+/// at-least-once: handlers may see a message again after a restart.
+pub struct ControlChannel {
+    handler: RwLock<Option<Handler>>,
+    id: u32,
+    inbox: Mutex<Option<Inbox>>,
+    seen: Mutex<(HashSet<u64>, VecDeque<u64>)>,
+    timeout: Duration,
+}
+
+impl ControlChannel {
+    pub fn new(id: u32) -> ControlChannel {
+        ControlChannel {
+            handler: RwLock::new(None),
+            id,
+            inbox: Mutex::new(None),
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    pub fn has_inbox(&self) -> bool {
+        self.inbox.lock().unwrap().is_some()
+    }
+
+    pub fn set_inbox(&self, capacity: usize, ttl: Duration) {
+        let mut inbox = self.inbox.lock().unwrap();
+        *inbox = Some(Inbox {
+            capacity: std::cmp::max(capacity, 1),
+            messages: HashMap::new(),
+            ttl,
+        });
+    }
+
+    pub fn set_handler(&self, handler: Handler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Returns the number of messages retained for member `id`.
+    pub fn retained(&self, id: u32) -> usize {
+        match self.inbox.lock().unwrap().as_ref() {
+            Some(inbox) => inbox.messages.get(&id)
+                .map(|messages| messages.len()).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Sends `payload` to every other member, returning the message id.
+    pub fn broadcast(&self, nodes: &NodeMap, payload: &[u8], now: Instant)
+            -> u64 {
+        let message = ControlMessage {
+            id: rand::random::<u64>(),
+            payload: payload.to_vec(),
+            sender: self.id,
+        };
+
+        let members = nodes.nodes();
+        for node in members.iter().filter(|node| node.get_id() != self.id) {
+            let delivered = node.state() == NodeState::Alive
+                && match self.send(nodes, &node.get_address(), node.get_id(),
+                    &message) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        debug!("control delivery failure [id={}]: {}",
+                            node.get_id(), e);
+                        false
+                    },
+                };
+
+            if !delivered {
+                self.hand_off(nodes, &members, node.get_id(), &message, now);
+            }
+        }
+
+        message.id
+    }
+
+    /// Answers a control exchange, dispatching messages addressed to
+    /// this member and retaining those held for others.
+    pub fn receive<S: Read + Write>(&self, stream: &mut S, now: Instant)
+            -> Result<(), Box<dyn Error>> {
+        let (recipient, message) = ControlMessage::read(stream)?;
+        let accepted = match recipient == self.id {
+            true => {
+                self.dispatch(&message);
+                true
+            },
+            false => self.retain(recipient, message, now),
+        };
+
+        stream.write_u8(accepted as u8)?;
+        Ok(())
+    }
+
+    /// Expires retained messages and delivers the rest to members which
+    /// are alive again.
+    pub fn redeliver(&self, nodes: &NodeMap, now: Instant) {
+        let mut pending = Vec::new();
+        if let Some(inbox) = self.inbox.lock().unwrap().as_mut() {
+            let ttl = inbox.ttl;
+            inbox.messages.retain(|_, messages| {
+                messages.retain(|(retained, _)| now - *retained < ttl);
+                !messages.is_empty()
+            });
+
+            for (recipient, messages) in inbox.messages.iter() {
+                match nodes.get(*recipient) {
+                    Some(ref node) if node.state() == NodeState::Alive =>
+                        pending.push((node.get_address(), *recipient,
+                            messages.iter().map(|(_, message)| message.clone())
+                                .collect::<Vec<ControlMessage>>())),
+                    _ => {},
+                }
+            }
+        }
+
+        for (address, recipient, messages) in pending {
+            let mut delivered = HashSet::new();
+            for message in messages {
+                match self.send(nodes, &address, recipient, &message) {
+                    Ok(true) => delivered.insert(message.id),
+                    Ok(false) => break,
+                    Err(e) => {
+                        debug!("control redelivery failure [id={}]: {}",
+                            recipient, e);
+                        break;
+                    },
+                };
+            }
+
+            if delivered.is_empty() {
+                continue;
+            }
+
+            debug!("redelivered control messages [id={}, count={}]",
+                recipient, delivered.len());
+            if let Some(inbox) = self.inbox.lock().unwrap().as_mut() {
+                if let Some(messages) = inbox.messages.get_mut(&recipient) {
+                    messages.retain(|(_, message)|
+                        !delivered.contains(&message.id));
+                }
+            }
+        }
+    }
+
+    fn dispatch(&self, message: &ControlMessage) {
+        {
+            let mut seen = self.seen.lock().unwrap();
+            let (ids, order) = &mut *seen;
+            if !ids.insert(message.id) {
+                return;
+            }
+
+            order.push_back(message.id);
+            if order.len() > SEEN_CAPACITY {
+                if let Some(id) = order.pop_front() {
+                    ids.remove(&id);
+                }
+            }
+        }
+
+        if let Some(handler) = self.handler.read().unwrap().as_ref() {
+            handler(message);
+        }
+    }
+
+    /// Hands a message for unreachable member `recipient` to the first
+    /// successor accepting it, falling back to the local inbox.
+    fn hand_off(&self, nodes: &NodeMap, members: &[Node], recipient: u32,
+            message: &ControlMessage, now: Instant) {
+        if !self.has_inbox() {
+            debug!("dropped control message [id={}, recipient={}]",
+                message.id, recipient);
+            return;
+        }
+
+        for node in successors(members, recipient) {
+            if node.get_id() == self.id {
+                break;
+            }
+
+            if let Ok(true) = self.send(nodes, &node.get_address(),
+                    recipient, message) {
+                debug!("handed off control message [recipient={}, successor={}]",
+                    recipient, node.get_id());
+                return;
+            }
+        }
+
+        self.retain(recipient, message.clone(), now);
+    }
+
+    fn retain(&self, recipient: u32, message: ControlMessage, now: Instant)
+            -> bool {
+        let mut inbox = self.inbox.lock().unwrap();
+        let inbox = match inbox.as_mut() {
+            Some(inbox) => inbox,
+            None => return false,
+        };
+
+        debug!("retaining control message [id={}, recipient={}]",
+            message.id, recipient);
+        let capacity = inbox.capacity;
+        let messages = inbox.messages.entry(recipient)
+            .or_insert_with(VecDeque::new);
+        if messages.iter().all(|(_, retained)| retained.id != message.id) {
+            messages.push_back((now, message));
+        }
+
+        if messages.len() > capacity {
+            messages.pop_front();
+        }
+
+        true
+    }
+
+    fn send(&self, nodes: &NodeMap, address: &SocketAddr, recipient: u32,
+            message: &ControlMessage) -> Result<bool, Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        codec::write_cluster_header(&mut stream, rand::random::<u64>(),
+            CONTROL_EXCHANGE, &nodes.get_cluster_name(),
+            nodes.get_cluster_epoch())?;
+        message.write(recipient, &mut stream)?;
+        Ok(stream.read_u8()? != 0)
+    }
+}
+
+/// Returns alive members ordered by id starting after `recipient`.
+fn successors(members: &[Node], recipient: u32) -> Vec<&Node> {
+    let mut alive: Vec<&Node> = members.iter().filter(|node|
+        node.get_id() != recipient && node.state() == NodeState::Alive)
+        .collect();
+    alive.sort_by_key(|node| (node.get_id() < recipient, node.get_id()));
+    alive
+}
+
+/// Redelivers retained messages every `interval` until shutdown.
+pub fn run(control: Arc<ControlChannel>, clock: Arc<dyn Clock>,
+        nodes: Arc<NodeMap>, interval: Duration, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        control.redeliver(&nodes, clock.now());
+        clock.sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::{ControlChannel, ControlMessage};
+
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn control_inbox() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let members: Vec<Node> = (0..4)
+            .map(|id| Node::new(id, ip_address, 12000 + id as u16)).collect();
+        let order: Vec<u32> = super::successors(&members, 2).iter()
+            .map(|node| node.get_id()).collect();
+        assert_eq!(order, vec!(3, 0, 1));
+
+        // successors retain messages addressed to other members
+        let control = ControlChannel::new(3);
+        let message = ControlMessage { id: 7, payload: vec!(1, 2), sender: 0 };
+        let mut buf = Vec::new();
+        message.write(2, &mut buf).expect("write message");
+        let mut stream = Cursor::new(buf.clone());
+        control.receive(&mut stream, Instant::now()).expect("receive");
+        assert_eq!(stream.into_inner().last(), Some(&0));
+
+        let now = Instant::now();
+        control.set_inbox(1, Duration::from_secs(60));
+        control.receive(&mut Cursor::new(buf.clone()), now).expect("receive");
+        control.receive(&mut Cursor::new(buf), now).expect("receive");
+        assert_eq!(control.retained(2), 1);
+
+        // expired messages are dropped
+        let nodes = NodeMap::new();
+        control.redeliver(&nodes, now + Duration::from_secs(61));
+        assert_eq!(control.retained(2), 0);
+
+        // addressed messages dispatch once
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        control.set_handler(Box::new(move |message|
+            received_clone.lock().unwrap().push(message.payload.clone())));
+        let mut buf = Vec::new();
+        message.write(3, &mut buf).expect("write message");
+        for _ in 0..2 {
+            control.receive(&mut Cursor::new(buf.clone()), now)
+                .expect("receive");
+        }
+        assert_eq!(*received.lock().unwrap(), vec!(vec!(1, 2)));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::node::{NodeMap, NodeState};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Marks peers suspect after `suspect_timeout` without a completed
+/// exchange and dead once they remain silent for a further
+/// `dead_timeout`. Any exchange or new incarnation revives a peer.
+///
+/// With a `heartbeat_timeout`, peers whose gossiped heartbeat counter
+/// stops advancing for that long are suspected as well, and are dead
+/// after a further `dead_timeout`, even while they answer exchanges.
+///
+/// With a `max_local_health`, timeouts scale with the local health
+/// score, as in Lifeguard: an overloaded member misses its own
+/// deadlines and would otherwise suspect healthy peers it was too slow
+/// to hear from. The score rises on signs of local trouble, up to
+/// `max_local_health`, and falls with every successful round.
+pub struct FailureDetector {
+    dead_timeout: Duration,
+    heartbeat_timeout: Option<Duration>,
+    // id -> (incarnation, heartbeat, instant heartbeat last advanced)
+    heartbeats: Mutex<HashMap<u32, (u64, u64, Instant)>>,
+    last_seen: Mutex<HashMap<u32, (u64, Instant)>>,
+    local_health: AtomicU32,
+    max_local_health: u32,
+    suspect_timeout: Duration,
+}
+
+impl FailureDetector {
+    pub fn new(suspect_timeout: Duration, dead_timeout: Duration,
+            heartbeat_timeout: Option<Duration>) -> FailureDetector {
+        FailureDetector {
+            dead_timeout,
+            heartbeat_timeout,
+            heartbeats: Mutex::new(HashMap::new()),
+            last_seen: Mutex::new(HashMap::new()),
+            local_health: AtomicU32::new(0),
+            max_local_health: 0,
+            suspect_timeout,
+        }
+    }
+
+    /// Returns a new detector with these timeouts and `heartbeat_timeout`.
+    pub fn with_heartbeat_timeout(&self, heartbeat_timeout: Option<Duration>)
+            -> FailureDetector {
+        FailureDetector::new(self.suspect_timeout, self.dead_timeout,
+            heartbeat_timeout).with_max_local_health(self.max_local_health)
+    }
+
+    /// Returns a new detector with these timeouts and
+    /// `max_local_health`, zero disabling local health scaling.
+    pub fn with_max_local_health(&self, max_local_health: u32)
+            -> FailureDetector {
+        let mut detector = FailureDetector::new(self.suspect_timeout,
+            self.dead_timeout, self.heartbeat_timeout);
+        detector.max_local_health = max_local_health;
+        detector
+    }
+
+    /// Returns the local health score: zero when healthy, otherwise
+    /// timeouts are multiplied by one more than the score.
+    pub fn local_health(&self) -> u32 {
+        self.local_health.load(Ordering::Relaxed)
+    }
+
+    /// Raises the local health score after a sign of local trouble,
+    /// such as a missed gossip deadline.
+    pub fn degrade_local_health(&self) {
+        let max_local_health = self.max_local_health;
+        let result = self.local_health.fetch_update(Ordering::Relaxed,
+            Ordering::Relaxed, |score| Some(score + 1)
+                .filter(|score| *score <= max_local_health));
+        if let Ok(score) = result {
+            debug!("degraded local health [score={}]", score + 1);
+        }
+    }
+
+    /// Lowers the local health score after a successful round.
+    pub fn restore_local_health(&self) {
+        let _ = self.local_health.fetch_update(Ordering::Relaxed,
+            Ordering::Relaxed, |score| score.checked_sub(1));
+    }
+
+    /// Records a completed exchange with `id`.
+    pub fn heard(&self, id: u32, nodes: &NodeMap, now: Instant) {
+        let incarnation = match nodes.get(id) {
+            Some(node) => node.get_incarnation(),
+            None => return,
+        };
+
+        let mut last_seen = self.last_seen.lock().unwrap();
+        last_seen.insert(id, (incarnation, now));
+    }
+
+    /// Updates the state of every peer of `local_id` from the time
+    /// elapsed since it was last heard from.
+    pub fn tick(&self, local_id: u32, nodes: &NodeMap, now: Instant) {
+        // unhealthy members wait longer before accusing peers
+        let scale = self.local_health() + 1;
+        let (suspect_timeout, dead_timeout) =
+            (self.suspect_timeout * scale, self.dead_timeout * scale);
+
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        let mut last_seen = self.last_seen.lock().unwrap();
+        for node in nodes.nodes() {
+            if node.get_id() == local_id {
+                continue;
+            }
+
+            // newly discovered peers and incarnations start the clock
+            let entry = last_seen.entry(node.get_id())
+                .or_insert((node.get_incarnation(), now));
+            if node.get_incarnation() > entry.0 {
+                *entry = (node.get_incarnation(), now);
+            }
+
+            let mut elapsed = now.saturating_duration_since(entry.1);
+
+            // stale heartbeats count as silence past the suspect timeout
+            if let Some(heartbeat_timeout) = self.heartbeat_timeout {
+                let heartbeat = (node.get_incarnation(), node.get_heartbeat());
+                let entry = heartbeats.entry(node.get_id())
+                    .or_insert((heartbeat.0, heartbeat.1, now));
+                if heartbeat != (entry.0, entry.1) {
+                    *entry = (heartbeat.0, heartbeat.1, now);
+                }
+
+                let stale = now.saturating_duration_since(entry.2);
+                elapsed = std::cmp::max(elapsed, (stale + suspect_timeout)
+                    .saturating_sub(heartbeat_timeout * scale));
+            }
+
+            let state = if elapsed >= suspect_timeout + dead_timeout {
+                NodeState::Dead
+            } else if elapsed >= suspect_timeout {
+                NodeState::Suspect
+            } else {
+                NodeState::Alive
+            };
+
+            if state != node.state() {
+                info!("updating node state [id={}, state={:?}]",
+                    node.get_id(), state);
+                nodes.update(node.get_id(), |node| node.set_state(state));
+            }
+        }
+
+        // forget nodes which have been removed
+        heartbeats.retain(|id, _| nodes.contains(*id));
+        last_seen.retain(|id, _| nodes.contains(*id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, ManualClock};
+    use crate::node::{Node, NodeMap, NodeState};
+    use super::FailureDetector;
+
+    use std::time::Duration;
+
+    #[test]
+    fn detector_states() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, 12000));
+        nodes.insert(Node::new(1, ip_address, 12001));
+
+        let clock = ManualClock::new(0);
+        let detector = FailureDetector::new(Duration::from_millis(100),
+            Duration::from_millis(200), None);
+        let state = |id| nodes.get(id).expect("get node").state();
+
+        detector.tick(0, &nodes, clock.now());
+        clock.advance(Duration::from_millis(150));
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(0), NodeState::Alive);
+        assert_eq!(state(1), NodeState::Suspect);
+
+        clock.advance(Duration::from_millis(150));
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Dead);
+
+        // exchanges revive peers
+        detector.heard(1, &nodes, clock.now());
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Alive);
+    }
+
+    #[test]
+    fn detector_heartbeats() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, 12000));
+        nodes.insert(Node::new(1, ip_address, 12001));
+
+        let clock = ManualClock::new(0);
+        let detector = FailureDetector::new(Duration::from_millis(100),
+            Duration::from_millis(200), Some(Duration::from_millis(300)));
+        let state = |id| nodes.get(id).expect("get node").state();
+
+        // peers answering exchanges are suspected once heartbeats stall
+        detector.tick(0, &nodes, clock.now());
+        for _ in 0..4 {
+            clock.advance(Duration::from_millis(90));
+            detector.heard(1, &nodes, clock.now());
+            detector.tick(0, &nodes, clock.now());
+        }
+        assert_eq!(state(1), NodeState::Suspect);
+
+        clock.advance(Duration::from_millis(200));
+        detector.heard(1, &nodes, clock.now());
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Dead);
+
+        // advancing heartbeats revive peers
+        nodes.heartbeat(1, 0, 1);
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Alive);
+
+        // heartbeats of other incarnations are ignored
+        assert!(nodes.heartbeat(1, 1, 5));
+        assert_eq!(nodes.get(1).expect("get node").get_heartbeat(), 1);
+    }
+
+    #[test]
+    fn detector_local_health() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, 12000));
+        nodes.insert(Node::new(1, ip_address, 12001));
+
+        let clock = ManualClock::new(0);
+        let detector = FailureDetector::new(Duration::from_millis(100),
+            Duration::from_millis(200), None).with_max_local_health(2);
+        let state = |id| nodes.get(id).expect("get node").state();
+
+        // scores are capped at max_local_health
+        for _ in 0..3 {
+            detector.degrade_local_health();
+        }
+        assert_eq!(detector.local_health(), 2);
+
+        // unhealthy members scale timeouts by one more than their score
+        detector.tick(0, &nodes, clock.now());
+        clock.advance(Duration::from_millis(250));
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Alive);
+
+        clock.advance(Duration::from_millis(100));
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Suspect);
+
+        // successful rounds restore health
+        for _ in 0..3 {
+            detector.restore_local_health();
+        }
+        assert_eq!(detector.local_health(), 0);
+        detector.tick(0, &nodes, clock.now());
+        assert_eq!(state(1), NodeState::Dead);
+
+        // detectors without max_local_health never degrade
+        let detector = FailureDetector::new(Duration::from_millis(100),
+            Duration::from_millis(200), None);
+        detector.degrade_local_health();
+        assert_eq!(detector.local_health(), 0);
+    }
+}
@@ -0,0 +1,201 @@
+use futures_core::Stream;
+use tower::discover::Change;
+
+use crate::node::{Node, NodeMap, NodeState};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 500;
+
+type MakeService<S> = Box<dyn FnMut(&Node, SocketAddr) -> S + Send>;
+
+/// tower Discover stream of alive swarm members, built with
+/// Swarm::discover, so balancers such as tower::balance::p2c track
+/// membership without glue code. Members may be filtered by a role
+/// metadata value and served on a port advertised in metadata rather
+/// than their gossip port. Membership is checked for changes every
+/// poll interval while the stream is pending.
+pub struct Discovery<S> {
+    known: HashMap<u32, SocketAddr>,
+    make_service: MakeService<S>,
+    nodes: Arc<NodeMap>,
+    pending: VecDeque<Change<u32, S>>,
+    poll_interval: Duration,
+    port_key: Option<String>,
+    role: Option<(String, String)>,
+    timer: Arc<AtomicBool>,
+}
+
+impl<S> Discovery<S> {
+    pub(crate) fn new(nodes: Arc<NodeMap>, make_service: MakeService<S>)
+            -> Discovery<S> {
+        Discovery {
+            known: HashMap::new(),
+            make_service,
+            nodes,
+            pending: VecDeque::new(),
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            port_key: None,
+            role: None,
+            timer: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Checks membership for changes every `poll_interval`, 500ms by
+    /// default.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Discovery<S> {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Serves members on the port held by metadata `key`, skipping
+    /// members which do not advertise one.
+    pub fn port_key(mut self, key: &str) -> Discovery<S> {
+        self.port_key = Some(key.to_string());
+        self
+    }
+
+    /// Only discovers members whose metadata `key` equals `value`.
+    pub fn role(mut self, key: &str, value: &str) -> Discovery<S> {
+        self.role = Some((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Returns the service address of `node` if it is discoverable.
+    fn address(&self, node: &Node) -> Option<SocketAddr> {
+        if node.state() != NodeState::Alive {
+            return None;
+        }
+
+        if let Some((key, value)) = self.role.as_ref() {
+            if node.get_metadata(key) != Some(value) {
+                return None;
+            }
+        }
+
+        match self.port_key.as_ref() {
+            Some(key) => node.get_metadata(key)
+                .and_then(|port| port.parse::<u16>().ok())
+                .map(|port| SocketAddr::new(*node.get_ip_address(), port)),
+            None => Some(node.get_address()),
+        }
+    }
+
+    /// Queues changes between the known and current members.
+    fn refresh(&mut self) {
+        let mut current = HashMap::new();
+        for node in self.nodes.nodes() {
+            if let Some(address) = self.address(&node) {
+                current.insert(node.get_id(), (node, address));
+            }
+        }
+
+        let removed: Vec<u32> = self.known.keys()
+            .filter(|id| !current.contains_key(id)).copied().collect();
+        for id in removed {
+            debug!("discovery removing member [id={}]", id);
+            self.known.remove(&id);
+            self.pending.push_back(Change::Remove(id));
+        }
+
+        for (id, (node, address)) in current {
+            // unchanged members keep their service -> moved ones are replaced
+            if self.known.get(&id) == Some(&address) {
+                continue;
+            }
+
+            debug!("discovery inserting member [id={}, address={}]",
+                id, address);
+            self.known.insert(id, address);
+            let service = (self.make_service)(&node, address);
+            self.pending.push_back(Change::Insert(id, service));
+        }
+    }
+}
+
+impl<S> Unpin for Discovery<S> {}
+
+impl<S> Stream for Discovery<S> {
+    type Item = Result<Change<u32, S>, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>)
+            -> Poll<Option<Self::Item>> {
+        let discovery = self.get_mut();
+        if discovery.pending.is_empty() {
+            discovery.refresh();
+        }
+
+        if let Some(change) = discovery.pending.pop_front() {
+            return Poll::Ready(Some(Ok(change)));
+        }
+
+        // wake the task once the poll interval elapses
+        if !discovery.timer.swap(true, Ordering::AcqRel) {
+            let (timer, waker) = (discovery.timer.clone(), cx.waker().clone());
+            let poll_interval = discovery.poll_interval;
+            std::thread::spawn(move || {
+                std::thread::sleep(poll_interval);
+                timer.store(false, Ordering::Release);
+                waker.wake();
+            });
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap, NodeState};
+    use super::Discovery;
+
+    use futures_core::Stream;
+    use tower::discover::Change;
+
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+
+    fn poll(discovery: &mut Discovery<SocketAddr>)
+            -> Poll<Option<Change<u32, SocketAddr>>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        Pin::new(discovery).poll_next(&mut cx)
+            .map(|change| change.map(|change| change.expect("change")))
+    }
+
+    #[test]
+    fn discover_members() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        let mut api = Node::new(1, ip_address, 12001);
+        api.set_metadata("role", "api");
+        api.set_metadata("grpc_port", "50051");
+        nodes.insert(api);
+        nodes.insert(Node::new(2, ip_address, 12002));
+
+        let mut discovery = Discovery::new(nodes.clone(),
+            Box::new(|_, address| address))
+            .role("role", "api").port_key("grpc_port");
+        fn is_discover<D: tower::discover::Discover>(_: &D) {}
+        is_discover(&discovery);
+
+        let address = SocketAddr::new(ip_address, 50051);
+        assert!(matches!(poll(&mut discovery),
+            Poll::Ready(Some(Change::Insert(1, ref a))) if *a == address));
+        assert!(poll(&mut discovery).is_pending());
+
+        // dead members are removed
+        nodes.update(1, |node| node.set_state(NodeState::Dead));
+        assert!(matches!(poll(&mut discovery),
+            Poll::Ready(Some(Change::Remove(1)))));
+        assert!(poll(&mut discovery).is_pending());
+    }
+}
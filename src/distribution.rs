@@ -0,0 +1,347 @@
+use crate::namespace::NAMESPACE_SEPARATOR;
+use crate::node::{MetadataBatch, Node, NodeMap};
+
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hasher;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Metadata namespace holding distributed configuration.
+pub const CONFIG_NAMESPACE: &str = "config";
+
+// suffixes of the metadata fields describing one configuration key
+const FIELD_SEPARATOR: char = '#';
+const PIN_FIELD: &str = "pin";
+const PINNED_FIELD: &str = "pinned";
+const ROLLOUT_FIELD: &str = "rollout";
+const STABLE_FIELD: &str = "stable";
+const VERSION_FIELD: &str = "version";
+
+/// Members receiving the staged value of a configuration key. Members
+/// outside the rollout keep the stable value.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Rollout {
+    All,
+    /// Members are selected by a hash of the key and their id, so
+    /// raising the percentage only ever adds members.
+    Percentage(u8),
+    /// Members whose "zone" metadata is listed.
+    Zones(Vec<String>),
+}
+
+impl Rollout {
+    pub fn targets(&self, key: &str, node: &Node) -> bool {
+        match self {
+            Rollout::All => true,
+            Rollout::Percentage(percentage) => {
+                let mut hasher = DefaultHasher::new();
+                hasher.write(key.as_bytes());
+                hasher.write_u32(node.get_id());
+                hasher.finish() % 100 < *percentage as u64
+            },
+            Rollout::Zones(zones) => node.get_metadata("zone")
+                .map(|zone| zones.contains(zone)).unwrap_or(false),
+        }
+    }
+}
+
+impl Display for Rollout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Rollout::All => write!(f, "all"),
+            Rollout::Percentage(percentage) =>
+                write!(f, "percentage:{}", percentage),
+            Rollout::Zones(zones) => write!(f, "zones:{}", zones.join(",")),
+        }
+    }
+}
+
+impl FromStr for Rollout {
+    type Err = Box<dyn Error>;
+
+    fn from_str(value: &str) -> Result<Rollout, Box<dyn Error>> {
+        let mut fields = value.splitn(2, ':');
+        match (fields.next(), fields.next()) {
+            (Some("all"), None) => Ok(Rollout::All),
+            (Some("percentage"), Some(percentage)) =>
+                Ok(Rollout::Percentage(percentage.parse()?)),
+            (Some("zones"), Some(zones)) => Ok(Rollout::Zones(zones
+                .split(',').map(|zone| zone.to_string()).collect())),
+            _ => Err(format!("invalid rollout '{}'", value).into()),
+        }
+    }
+}
+
+/// Latest published state of a configuration key. A None value means
+/// the key is unset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigEntry {
+    pub publisher: u32,
+    pub rollout: Rollout,
+    pub stable: Option<String>,
+    pub value: Option<String>,
+    pub version: u64,
+}
+
+impl ConfigEntry {
+    fn read(node: &Node, key: &str) -> Option<ConfigEntry> {
+        let field = |name| node.get_metadata(&field_key(key, name));
+        let version = field(VERSION_FIELD)?.parse().ok()?;
+        let rollout = field(ROLLOUT_FIELD)?.parse().ok()?;
+
+        Some(ConfigEntry {
+            publisher: node.get_id(),
+            rollout,
+            stable: field(STABLE_FIELD).cloned(),
+            value: node.get_metadata(&value_key(key)).cloned(),
+            version,
+        })
+    }
+
+    fn write(&self, key: &str, batch: &mut MetadataBatch) {
+        batch.set(&field_key(key, VERSION_FIELD), &self.version.to_string());
+        batch.set(&field_key(key, ROLLOUT_FIELD), &self.rollout.to_string());
+        set_optional(batch, &field_key(key, STABLE_FIELD), &self.stable);
+        set_optional(batch, &value_key(key), &self.value);
+    }
+
+    /// Returns the value `node` applies.
+    pub fn effective(&self, key: &str, node: &Node) -> Option<&String> {
+        match self.rollout.targets(key, node) {
+            true => self.value.as_ref(),
+            false => self.stable.as_ref(),
+        }
+    }
+}
+
+/// Handle pushing configuration values, such as feature flags and
+/// tuning parameters, cluster-wide through gossiped metadata. Any
+/// member may publish; the highest version wins. Values are staged to
+/// a Rollout first and rolled back to the stable value if needed:
+///
+/// ```no_run
+/// # use swarm::prelude::{ClusterBuilder, Rollout, Swarm};
+/// # let address = "127.0.0.1".parse()?;
+/// # let (swarm, _) = Swarm::new(0, address, 15000, None, ClusterBuilder::new());
+/// let config = swarm.config_distribution();
+/// config.publish("cache_mb", "512", Rollout::Percentage(10))?;
+/// config.rollback("cache_mb")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone)]
+pub struct ConfigDistribution {
+    id: u32,
+    nodes: Arc<NodeMap>,
+}
+
+impl ConfigDistribution {
+    pub(crate) fn new(id: u32, nodes: Arc<NodeMap>) -> ConfigDistribution {
+        ConfigDistribution { id, nodes }
+    }
+
+    /// Returns the newest published entry across all members.
+    pub fn entry(&self, key: &str) -> Option<ConfigEntry> {
+        self.nodes.nodes().iter()
+            .filter_map(|node| ConfigEntry::read(node, key))
+            .max_by_key(|entry| (entry.version, entry.publisher))
+    }
+
+    /// Returns the value applied by the local node, honoring pins.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let node = self.nodes.get(self.id)?;
+        if node.get_metadata(&field_key(key, PIN_FIELD)).is_some() {
+            return node.get_metadata(&field_key(key, PINNED_FIELD)).cloned();
+        }
+
+        self.entry(key)?.effective(key, &node).cloned()
+    }
+
+    /// Stages `value` to the members in `rollout`, returning the new
+    /// version. Republishing with Rollout::All completes the rollout.
+    pub fn publish(&self, key: &str, value: &str, rollout: Rollout)
+            -> Result<u64, Box<dyn Error>> {
+        self.stage(key, Some(value.to_string()), rollout)
+    }
+
+    /// Unsets `key` on the members in `rollout`.
+    pub fn unset(&self, key: &str, rollout: Rollout)
+            -> Result<u64, Box<dyn Error>> {
+        self.stage(key, None, rollout)
+    }
+
+    /// Publishes the stable value to every member, returning the new
+    /// version.
+    pub fn rollback(&self, key: &str) -> Result<u64, Box<dyn Error>> {
+        let entry = self.entry(key).ok_or_else(||
+            format!("unknown config key '{}'", key))?;
+        debug!("rolling back config [key={}, version={}]",
+            key, entry.version);
+        self.write(key, ConfigEntry {
+            publisher: self.id,
+            rollout: Rollout::All,
+            stable: entry.stable.clone(),
+            value: entry.stable,
+            version: entry.version + 1,
+        })
+    }
+
+    /// Freezes the value currently applied by the local node, ignoring
+    /// newer versions until Self::unpin. Returns the pinned version.
+    pub fn pin(&self, key: &str) -> Result<u64, Box<dyn Error>> {
+        let node = self.nodes.get(self.id).ok_or("local node missing")?;
+        let entry = self.entry(key).ok_or_else(||
+            format!("unknown config key '{}'", key))?;
+        let value = entry.effective(key, &node).cloned();
+
+        debug!("pinning config [key={}, version={}]", key, entry.version);
+        self.nodes.update(self.id, |node| {
+            node.update_metadata(|batch| {
+                batch.set(&field_key(key, PIN_FIELD),
+                    &entry.version.to_string());
+                set_optional(batch, &field_key(key, PINNED_FIELD), &value);
+            });
+        });
+        Ok(entry.version)
+    }
+
+    pub fn unpin(&self, key: &str) {
+        debug!("unpinning config [key={}]", key);
+        self.nodes.update(self.id, |node| {
+            node.update_metadata(|batch| {
+                batch.remove(&field_key(key, PIN_FIELD));
+                batch.remove(&field_key(key, PINNED_FIELD));
+            });
+        });
+    }
+
+    /// Returns the ids of members receiving the staged value of `key`,
+    /// to follow rollout progress.
+    pub fn targets(&self, key: &str) -> Vec<u32> {
+        let entry = match self.entry(key) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+
+        let mut ids: Vec<u32> = self.nodes.nodes().iter()
+            .filter(|node| entry.rollout.targets(key, node))
+            .map(|node| node.get_id()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn stage(&self, key: &str, value: Option<String>, rollout: Rollout)
+            -> Result<u64, Box<dyn Error>> {
+        if key.is_empty() || key.contains(FIELD_SEPARATOR) {
+            return Err(format!("invalid config key '{}'", key).into());
+        }
+
+        if let Rollout::Percentage(percentage) = rollout {
+            if percentage > 100 {
+                return Err(format!("invalid rollout percentage {}",
+                    percentage).into());
+            }
+        }
+
+        // members outside the rollout keep the last fully rolled out value
+        let (stable, version) = match self.entry(key) {
+            Some(entry) if entry.rollout == Rollout::All =>
+                (entry.value, entry.version),
+            Some(entry) => (entry.stable, entry.version),
+            None => (None, 0),
+        };
+
+        debug!("publishing config [key={}, version={}, rollout={}]",
+            key, version + 1, rollout);
+        self.write(key, ConfigEntry { publisher: self.id,
+            rollout, stable, value, version: version + 1 })
+    }
+
+    fn write(&self, key: &str, entry: ConfigEntry)
+            -> Result<u64, Box<dyn Error>> {
+        let version = entry.version;
+        let updated = self.nodes.update(self.id, |node| {
+            node.update_metadata(|batch| entry.write(key, batch));
+        });
+
+        match updated {
+            true => Ok(version),
+            false => Err("local node missing".into()),
+        }
+    }
+}
+
+fn field_key(key: &str, field: &str) -> String {
+    format!("{}{}{}", value_key(key), FIELD_SEPARATOR, field)
+}
+
+fn set_optional(batch: &mut MetadataBatch, key: &str, value: &Option<String>) {
+    match value {
+        Some(value) => batch.set(key, value),
+        None => batch.remove(key),
+    }
+}
+
+fn value_key(key: &str) -> String {
+    format!("{}{}{}", CONFIG_NAMESPACE, NAMESPACE_SEPARATOR, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::{ConfigDistribution, Rollout};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn staged_rollout() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..20 {
+            let mut node = Node::new(id, ip_address, 12000 + id as u16);
+            node.set_metadata("zone", if id < 10 { "a" } else { "b" });
+            nodes.insert(node);
+        }
+
+        let publisher = ConfigDistribution::new(0, nodes.clone());
+        let member = ConfigDistribution::new(15, nodes.clone());
+        assert_eq!(publisher.publish("flag", "off", Rollout::All)
+            .expect("publish"), 1);
+        assert_eq!(member.get("flag").as_deref(), Some("off"));
+
+        // zone rollouts leave other members on the stable value
+        member.publish("flag", "on", Rollout::Zones(vec!("a".to_string())))
+            .expect("publish");
+        assert_eq!(member.entry("flag").expect("entry").version, 2);
+        assert_eq!(publisher.get("flag").as_deref(), Some("on"));
+        assert_eq!(member.get("flag").as_deref(), Some("off"));
+        assert_eq!(publisher.targets("flag"), (0..10).collect::<Vec<u32>>());
+
+        // pinned members ignore newer versions
+        member.pin("flag").expect("pin");
+
+        // percentage rollouts only grow
+        publisher.publish("flag", "on", Rollout::Percentage(20))
+            .expect("publish");
+        let small = publisher.targets("flag");
+        publisher.publish("flag", "on", Rollout::Percentage(60))
+            .expect("publish");
+        let large = publisher.targets("flag");
+        assert!(small.iter().all(|id| large.contains(id)));
+        assert!(publisher.publish("flag", "on", Rollout::Percentage(101))
+            .is_err());
+
+        publisher.publish("flag", "on", Rollout::All).expect("publish");
+        assert_eq!(member.get("flag").as_deref(), Some("off"));
+        member.unpin("flag");
+        assert_eq!(member.get("flag").as_deref(), Some("on"));
+
+        publisher.publish("flag", "broken", Rollout::Percentage(100))
+            .expect("publish");
+        assert_eq!(publisher.rollback("flag").expect("rollback"), 7);
+        assert_eq!(member.get("flag").as_deref(), Some("on"));
+        assert!(publisher.rollback("missing").is_err());
+    }
+}
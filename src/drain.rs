@@ -0,0 +1,166 @@
+use crate::clock::Clock;
+use crate::node::{NodeMap, NodeState};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 30000;
+
+type DrainCallback = Box<dyn FnOnce(u32, Instant) + Send>;
+
+/// Downstream connection registered with Swarm::register_connection.
+/// Dropping the handle deregisters a connection closed normally.
+pub struct ConnectionHandle {
+    drainer: Arc<ConnectionDrainer>,
+    id: u64,
+    peer: u32,
+}
+
+impl ConnectionHandle {
+    pub fn get_peer(&self) -> u32 {
+        self.peer
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.drainer.deregister(self.peer, self.id);
+    }
+}
+
+/// Tracks application connections per peer and invokes their drain
+/// callbacks, with a teardown deadline, once the peer leaves the swarm
+/// or is declared dead. Suspect peers keep their connections.
+pub struct ConnectionDrainer {
+    connections: Mutex<HashMap<u32, HashMap<u64, DrainCallback>>>,
+    next_id: AtomicU64,
+    timeout: Mutex<Duration>,
+}
+
+impl ConnectionDrainer {
+    pub fn new() -> ConnectionDrainer {
+        ConnectionDrainer {
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            timeout: Mutex::new(
+                Duration::from_millis(DEFAULT_DRAIN_TIMEOUT_MS)),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    /// Returns the number of registered connections to `peer`.
+    pub fn connections(&self, peer: u32) -> usize {
+        self.connections.lock().unwrap().get(&peer)
+            .map(|callbacks| callbacks.len()).unwrap_or(0)
+    }
+
+    pub fn register(self: &Arc<Self>, peer: u32, on_drain: DrainCallback)
+            -> ConnectionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().entry(peer)
+            .or_default().insert(id, on_drain);
+        ConnectionHandle { drainer: self.clone(), id, peer }
+    }
+
+    /// Invokes the drain callbacks of peers which left or are dead,
+    /// returning the number of connections drained.
+    pub fn drain(&self, nodes: &NodeMap, now: Instant) -> usize {
+        let drained: Vec<(u32, HashMap<u64, DrainCallback>)> = {
+            let mut connections = self.connections.lock().unwrap();
+            let peers: Vec<u32> = connections.keys().copied()
+                .filter(|peer| nodes.get(*peer).map(|node|
+                    node.state() == NodeState::Dead).unwrap_or(true))
+                .collect();
+            peers.into_iter().filter_map(|peer| connections.remove(&peer)
+                .map(|callbacks| (peer, callbacks))).collect()
+        };
+
+        // callbacks run unlocked -> they may drop their handles
+        let deadline = now + *self.timeout.lock().unwrap();
+        let mut count = 0;
+        for (peer, callbacks) in drained {
+            info!("draining peer connections [id={}, count={}]",
+                peer, callbacks.len());
+            for (_, on_drain) in callbacks {
+                on_drain(peer, deadline);
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    fn deregister(&self, peer: u32, id: u64) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(callbacks) = connections.get_mut(&peer) {
+            callbacks.remove(&id);
+            if callbacks.is_empty() {
+                connections.remove(&peer);
+            }
+        }
+    }
+}
+
+/// Drains connections to departed peers every `interval` until
+/// shutdown.
+pub fn run(drainer: Arc<ConnectionDrainer>, clock: Arc<dyn Clock>,
+        nodes: Arc<NodeMap>, interval: Duration, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        drainer.drain(&nodes, clock.now());
+        clock.sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap, NodeState};
+    use super::ConnectionDrainer;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn connection_draining() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        for id in 1..3 {
+            nodes.insert(Node::new(id, ip_address, 12000 + id as u16));
+        }
+
+        let drainer = Arc::new(ConnectionDrainer::new());
+        drainer.set_timeout(Duration::from_secs(5));
+        let drained = Arc::new(Mutex::new(Vec::new()));
+        let register = |peer: u32| {
+            let drained = drained.clone();
+            drainer.register(peer, Box::new(move |peer, deadline|
+                drained.lock().unwrap().push((peer, deadline))))
+        };
+
+        let _first = register(1);
+        let closed = register(1);
+        let _second = register(2);
+        drop(closed);
+        assert_eq!(drainer.connections(1), 1);
+
+        // suspect peers keep their connections
+        let now = Instant::now();
+        nodes.update(1, |node| node.set_state(NodeState::Suspect));
+        assert_eq!(drainer.drain(&nodes, now), 0);
+
+        // dead and departed peers are drained once
+        nodes.update(1, |node| node.set_state(NodeState::Dead));
+        nodes.remove(2);
+        assert_eq!(drainer.drain(&nodes, now), 2);
+        assert_eq!(drainer.drain(&nodes, now), 0);
+
+        let mut drained = drained.lock().unwrap().clone();
+        drained.sort_by_key(|(peer, _)| *peer);
+        let deadline = now + Duration::from_secs(5);
+        assert_eq!(drained, vec!((1, deadline), (2, deadline)));
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// exchange header kinds following the trace id
+/// One-off query which is never deduplicated.
+pub const UNTRACKED_EXCHANGE: u8 = 0;
+/// Gossip exchange, followed by the requester id and an accept flag.
+pub const TRACKED_EXCHANGE: u8 = 1;
+/// Persistent heartbeat channel, followed by the requester id.
+pub const KEEPALIVE_EXCHANGE: u8 = 2;
+/// Membership delta stream, followed by the sequence to resume after.
+pub const SUBSCRIBE_EXCHANGE: u8 = 3;
+/// Federation summary exchange between gateways of separate swarms.
+pub const FEDERATION_EXCHANGE: u8 = 4;
+/// Control message, followed by the message and its addressee.
+pub const CONTROL_EXCHANGE: u8 = 5;
+/// Plumtree broadcast message, followed by its sender.
+pub const PLUMTREE_EXCHANGE: u8 = 6;
+/// Published topic message, followed by its publisher and topic.
+pub const PUBSUB_EXCHANGE: u8 = 7;
+/// Lock request, followed by the operation, requester and lock name.
+pub const LOCK_EXCHANGE: u8 = 8;
+/// Persistent gossip connection, followed by the requester id and its
+/// idle timeout, then consecutive tracked exchanges each framed by a
+/// trace id.
+pub const POOLED_EXCHANGE: u8 = 9;
+/// Admin query, followed by the operation and its arguments.
+pub const ADMIN_EXCHANGE: u8 = 10;
+/// Registered service connection, followed by the message type.
+pub const SERVICE_EXCHANGE: u8 = 11;
+/// Liveness probe, followed by the address to probe on the requester's
+/// behalf, empty to probe the replier itself, and the probe timeout.
+pub const PROBE_EXCHANGE: u8 = 12;
+
+/// Returns true for exchanges between members of one cluster, whose
+/// header continues with the cluster name and epoch. Admin queries,
+/// subscriptions, federation, keepalives and probes carry none: they
+/// serve clients outside the cluster. Admin queries include removals,
+/// so Swarm::enable_admin trusts whoever reaches the gossip port.
+pub fn is_cluster_exchange(kind: u8) -> bool {
+    matches!(kind, UNTRACKED_EXCHANGE | TRACKED_EXCHANGE | CONTROL_EXCHANGE
+        | PLUMTREE_EXCHANGE | PUBSUB_EXCHANGE | LOCK_EXCHANGE
+        | POOLED_EXCHANGE | SERVICE_EXCHANGE)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Tracks in-progress gossip exchanges by peer id so two members
+/// gossiping to each other simultaneously complete only one exchange.
+pub struct Exchanges {
+    id: u32,
+    peers: Mutex<(HashSet<u32>, HashSet<u32>)>,
+}
+
+impl Exchanges {
+    pub fn new(id: u32) -> Exchanges {
+        Exchanges {
+            id,
+            peers: Mutex::new((HashSet::new(), HashSet::new())),
+        }
+    }
+
+    /// Registers an inbound exchange from `peer_id`. Fails when an
+    /// inbound exchange with the peer is already running, or when an
+    /// outbound one is and the local id is lower -> the lower id's
+    /// request wins on both sides.
+    pub fn inbound(&self, peer_id: u32) -> Option<ExchangeGuard<'_>> {
+        let mut peers = self.peers.lock().unwrap();
+        let (inbound, outbound) = &mut *peers;
+        if inbound.contains(&peer_id)
+                || (outbound.contains(&peer_id) && self.id < peer_id) {
+            return None;
+        }
+
+        inbound.insert(peer_id);
+        Some(ExchangeGuard {
+            direction: Direction::Inbound,
+            exchanges: self,
+            peer_id,
+        })
+    }
+
+    /// Registers an outbound exchange to `peer_id`, failing when the
+    /// peer is already exchanging with us.
+    pub fn outbound(&self, peer_id: u32) -> Option<ExchangeGuard<'_>> {
+        let mut peers = self.peers.lock().unwrap();
+        let (inbound, outbound) = &mut *peers;
+        if inbound.contains(&peer_id) || outbound.contains(&peer_id) {
+            return None;
+        }
+
+        outbound.insert(peer_id);
+        Some(ExchangeGuard {
+            direction: Direction::Outbound,
+            exchanges: self,
+            peer_id,
+        })
+    }
+}
+
+pub struct ExchangeGuard<'a> {
+    direction: Direction,
+    exchanges: &'a Exchanges,
+    peer_id: u32,
+}
+
+impl<'a> Drop for ExchangeGuard<'a> {
+    fn drop(&mut self) {
+        let mut peers = self.exchanges.peers.lock().unwrap();
+        match self.direction {
+            Direction::Inbound => peers.0.remove(&self.peer_id),
+            Direction::Outbound => peers.1.remove(&self.peer_id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Exchanges;
+
+    #[test]
+    fn exchange_dedup() {
+        let exchanges = Exchanges::new(1);
+
+        // inbound exchanges block outbound ones to the same peer
+        let guard = exchanges.inbound(2).expect("inbound exchange");
+        assert!(exchanges.outbound(2).is_none());
+        assert!(exchanges.inbound(2).is_none());
+        assert!(exchanges.outbound(3).is_some());
+        drop(guard);
+
+        // simultaneous exchanges -> the lower id's request wins
+        let _low = exchanges.outbound(0).expect("outbound exchange");
+        let _high = exchanges.outbound(2).expect("outbound exchange");
+        assert!(exchanges.inbound(0).is_some());
+        assert!(exchanges.inbound(2).is_none());
+    }
+}
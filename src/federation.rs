@@ -0,0 +1,244 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::Clock;
+use crate::codec;
+use crate::exchange::FEDERATION_EXCHANGE;
+use crate::node::{self, NodeMap, NodeState};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_ENDPOINT_KEY: &str = "endpoint";
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+/// Summarized membership of one swarm, as exchanged between gateways.
+#[derive(Clone, Debug)]
+pub struct ClusterSummary {
+    pub alive: u32,
+    pub dead: u32,
+    /// Values of the federation endpoint metadata key across members.
+    pub endpoints: Vec<String>,
+    pub members: u32,
+    pub name: String,
+    pub suspect: u32,
+    /// When this gateway last received the summary.
+    pub updated: Instant,
+}
+
+impl ClusterSummary {
+    fn read(reader: &mut impl Read, updated: Instant)
+            -> Result<ClusterSummary, Box<dyn Error>> {
+        let name = node::read_string(reader)?;
+        let members = reader.read_u32::<BigEndian>()?;
+        let alive = reader.read_u32::<BigEndian>()?;
+        let suspect = reader.read_u32::<BigEndian>()?;
+        let dead = reader.read_u32::<BigEndian>()?;
+
+        let endpoint_count = reader.read_u16::<BigEndian>()?;
+        let mut endpoints = Vec::with_capacity(endpoint_count as usize);
+        for _ in 0..endpoint_count {
+            endpoints.push(node::read_string(reader)?);
+        }
+
+        Ok(ClusterSummary { alive, dead, endpoints,
+            members, name, suspect, updated })
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        node::write_string(&self.name, writer)?;
+        writer.write_u32::<BigEndian>(self.members)?;
+        writer.write_u32::<BigEndian>(self.alive)?;
+        writer.write_u32::<BigEndian>(self.suspect)?;
+        writer.write_u32::<BigEndian>(self.dead)?;
+
+        writer.write_u16::<BigEndian>(self.endpoints.len() as u16)?;
+        for endpoint in self.endpoints.iter() {
+            node::write_string(endpoint, writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bridges independent swarms, such as one per region. Gateway members
+/// periodically exchange a summary of their swarm (member counts,
+/// health, and service endpoints advertised in metadata) with the
+/// gateways of remote swarms. Only gateways hold remote summaries.
+pub struct Federation {
+    endpoint_key: String,
+    gateways: Vec<SocketAddr>,
+    name: String,
+    remote: RwLock<HashMap<String, ClusterSummary>>,
+    timeout: Duration,
+}
+
+impl Federation {
+    pub fn new(name: &str) -> Federation {
+        Federation {
+            endpoint_key: DEFAULT_ENDPOINT_KEY.to_string(),
+            gateways: Vec::new(),
+            name: name.to_string(),
+            remote: RwLock::new(HashMap::new()),
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Collects service endpoints from `key` instead of "endpoint".
+    pub fn endpoint_key(mut self, key: &str) -> Federation {
+        self.endpoint_key = key.to_string();
+        self
+    }
+
+    /// Adds the gossip address of a remote swarm's gateway.
+    pub fn gateway(mut self, address: SocketAddr) -> Federation {
+        self.gateways.push(address);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Federation {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the latest summary of every remote swarm by name.
+    pub fn remote_clusters(&self) -> Vec<ClusterSummary> {
+        let remote = self.remote.read().unwrap();
+        let mut summaries: Vec<ClusterSummary> =
+            remote.values().cloned().collect();
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    pub fn summarize(&self, nodes: &NodeMap, now: Instant) -> ClusterSummary {
+        let mut summary = ClusterSummary {
+            alive: 0,
+            dead: 0,
+            endpoints: Vec::new(),
+            members: 0,
+            name: self.name.clone(),
+            suspect: 0,
+            updated: now,
+        };
+
+        for node in nodes.nodes() {
+            summary.members += 1;
+            match node.state() {
+                NodeState::Alive => summary.alive += 1,
+                NodeState::Suspect => summary.suspect += 1,
+                NodeState::Dead => summary.dead += 1,
+            }
+
+            if let (NodeState::Alive, Some(endpoint)) =
+                    (node.state(), node.get_metadata(&self.endpoint_key)) {
+                summary.endpoints.push(endpoint.clone());
+            }
+        }
+
+        summary.endpoints.sort();
+        summary
+    }
+
+    /// Exchanges summaries with the gateway at `address`.
+    pub fn request(&self, address: &SocketAddr, nodes: &NodeMap,
+            now: Instant) -> Result<(), Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let trace_id = rand::random::<u64>();
+        let _trace_guard = crate::trace::enter(trace_id);
+        codec::write_header(&mut stream, trace_id, FEDERATION_EXCHANGE)?;
+        self.summarize(nodes, now).write(&mut stream)?;
+
+        let summary = ClusterSummary::read(&mut stream, now)?;
+        self.register(summary);
+        Ok(())
+    }
+
+    /// Answers a remote gateway with the local summary.
+    pub fn reply<S: Read + Write>(&self, stream: &mut S, nodes: &NodeMap,
+            now: Instant) -> Result<(), Box<dyn Error>> {
+        let summary = ClusterSummary::read(stream, now)?;
+        self.summarize(nodes, now).write(stream)?;
+        self.register(summary);
+        Ok(())
+    }
+
+    fn register(&self, summary: ClusterSummary) {
+        // misconfigured gateways may point back at their own swarm
+        if summary.name == self.name {
+            return;
+        }
+
+        debug!("updated remote cluster [name={}, members={}, alive={}]",
+            summary.name, summary.members, summary.alive);
+        let mut remote = self.remote.write().unwrap();
+        remote.insert(summary.name.clone(), summary);
+    }
+}
+
+/// Exchanges summaries with every remote gateway each `interval`
+/// until shutdown.
+pub fn run(federation: Arc<Federation>, clock: Arc<dyn Clock>,
+        nodes: Arc<NodeMap>, interval: Duration, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        for address in federation.gateways.iter() {
+            if let Err(e) = federation.request(address, &nodes, clock.now()) {
+                debug!("federation exchange failure [address={}]: {}",
+                    address, e);
+            }
+        }
+
+        clock.sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap, NodeState};
+    use super::Federation;
+
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    #[test]
+    fn federation_summary() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        let mut node = Node::new(0, ip_address, 12000);
+        node.set_metadata("endpoint", "http://10.0.0.1:80");
+        nodes.insert(node);
+        let mut dead = Node::new(1, ip_address, 12001);
+        dead.set_state(NodeState::Dead);
+        nodes.insert(dead);
+
+        let local = Federation::new("us-east");
+        let remote = Federation::new("eu-west");
+        let summary = local.summarize(&nodes, Instant::now());
+        assert_eq!((summary.members, summary.alive, summary.dead), (2, 1, 1));
+        assert_eq!(summary.endpoints, vec!("http://10.0.0.1:80".to_string()));
+
+        // remote gateway replies with its own summary
+        let mut buf = Vec::new();
+        summary.write(&mut buf).expect("write summary");
+        let remote_nodes = NodeMap::new();
+        remote_nodes.insert(Node::new(5, ip_address, 13000));
+        let mut stream = Cursor::new(buf);
+        remote.reply(&mut stream, &remote_nodes, Instant::now())
+            .expect("reply");
+
+        let clusters = remote.remote_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!((clusters[0].name.as_str(), clusters[0].alive),
+            ("us-east", 1));
+    }
+}
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A parsed plain http url. TLS is not supported; https endpoints
+/// must be reached through a local proxy.
+#[derive(Clone, Debug)]
+pub struct HttpUrl {
+    host: String,
+    path: String,
+    port: u16,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, Box<dyn Error>> {
+        let remainder = url.strip_prefix("http://")
+            .ok_or_else(|| format!("unsupported url '{}'", url))?;
+
+        // split authority and path
+        let (authority, path) = match remainder.find('/') {
+            Some(index) => remainder.split_at(index),
+            None => (remainder, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority.ends_with(']') =>
+                (&authority[..index], authority[index+1..].parse::<u16>()?),
+            _ => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(format!("url '{}' missing host", url).into());
+        }
+
+        Ok(HttpUrl {
+            host: host.to_string(),
+            path: path.trim_end_matches('/').to_string(),
+            port,
+        })
+    }
+
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn get_path(&self) -> &str {
+        match self.path.is_empty() {
+            true => "/",
+            false => &self.path,
+        }
+    }
+}
+
+/// Sends a single HTTP/1.0 request to `url` joined with `path`,
+/// returning the response status and body.
+pub fn request(url: &HttpUrl, method: &str, path: &str,
+        headers: &[(&str, &str)], body: &str, timeout: Duration)
+        -> Result<(u16, String), Box<dyn Error>> {
+    let address = (url.host.trim_matches(|c| c == '[' || c == ']'),
+            url.port).to_socket_addrs()?.next()
+        .ok_or_else(|| format!("unresolved host '{}'", url.host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // write request -> http/1.0 responses are never chunked
+    let path = match (url.path.as_str(), path) {
+        ("", "") => "/".to_string(),
+        (base, path) => format!("{}{}", base, path),
+    };
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}:{}\r\n",
+        method, path, url.host, url.port);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    // read response until the server closes the connection
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+
+    let status = response.split_whitespace().nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or("malformed http response")?;
+    let body = response.find("\r\n\r\n")
+        .map(|index| response[index+4..].to_string())
+        .unwrap_or_default();
+
+    Ok((status, body))
+}
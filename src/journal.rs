@@ -0,0 +1,239 @@
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::codec;
+use crate::exchange::SUBSCRIBE_EXCHANGE;
+use crate::node::{Node, NodeMap};
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// subscribers heartbeat at least this often to detect closed consumers
+const HEARTBEAT_MS: u64 = 500;
+
+/// Membership change streamed to subscribers. Upserts carry the full
+/// node record, so applying a delta twice is harmless. A reset means
+/// the requested sequence is no longer journaled: consumers clear
+/// their state and receive a snapshot of every member.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum MembershipDelta {
+    Upsert(Node),
+    Remove(u32),
+    Reset,
+}
+
+struct JournalState {
+    entries: VecDeque<(u64, MembershipDelta)>,
+    last_seq: u64,
+    versions: HashMap<u32, (u64, u64)>,
+}
+
+/// Bounded journal of membership deltas numbered by a sequence which
+/// increases by one per delta. Only the latest `capacity` deltas are
+/// retained.
+pub struct ChangeJournal {
+    appended: Condvar,
+    capacity: usize,
+    state: Mutex<JournalState>,
+}
+
+impl ChangeJournal {
+    pub fn new(capacity: usize) -> ChangeJournal {
+        ChangeJournal {
+            appended: Condvar::new(),
+            capacity: std::cmp::max(capacity, 1),
+            state: Mutex::new(JournalState {
+                entries: VecDeque::new(),
+                last_seq: 0,
+                versions: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().unwrap().last_seq
+    }
+
+    /// Appends a delta for every node changed or removed since the
+    /// previous call, returning the number appended.
+    pub fn record(&self, nodes: &NodeMap) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut deltas = Vec::new();
+        let mut versions = HashMap::new();
+        for node in nodes.nodes() {
+            let version = (node.get_incarnation(), node.get_version());
+            if state.versions.get(&node.get_id()) != Some(&version) {
+                deltas.push(MembershipDelta::Upsert(node.clone()));
+            }
+
+            versions.insert(node.get_id(), version);
+        }
+
+        let mut removed: Vec<u32> = state.versions.keys()
+            .filter(|id| !versions.contains_key(id)).copied().collect();
+        removed.sort_unstable();
+        deltas.extend(removed.into_iter().map(MembershipDelta::Remove));
+
+        let count = deltas.len();
+        for delta in deltas {
+            state.last_seq += 1;
+            let seq = state.last_seq;
+            state.entries.push_back((seq, delta));
+            if state.entries.len() > self.capacity {
+                state.entries.pop_front();
+            }
+        }
+
+        state.versions = versions;
+        if count != 0 {
+            self.appended.notify_all();
+        }
+
+        count
+    }
+
+    /// Waits up to `timeout` for deltas after `seq`, returning None if
+    /// some were truncated.
+    fn wait(&self, seq: u64, timeout: Duration)
+            -> Option<Vec<(u64, MembershipDelta)>> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.appended.wait_timeout_while(state, timeout,
+            |state| state.last_seq <= seq).unwrap();
+        since(&state, seq)
+    }
+}
+
+fn since(state: &JournalState, seq: u64)
+        -> Option<Vec<(u64, MembershipDelta)>> {
+    let first_seq = state.entries.front()
+        .map(|(seq, _)| *seq).unwrap_or(state.last_seq + 1);
+    // sequences ahead of the journal predate a restart
+    if seq + 1 < first_seq || seq > state.last_seq {
+        return None;
+    }
+
+    Some(state.entries.iter().filter(|(entry_seq, _)| *entry_seq > seq)
+        .cloned().collect())
+}
+
+/// Streams deltas after `seq` to an external consumer until the
+/// connection closes or the swarm shuts down. Consumers too far behind
+/// are reset and sent a snapshot.
+pub fn serve(stream: TcpStream, mut seq: u64, journal: Arc<ChangeJournal>,
+        nodes: Arc<NodeMap>, shutdown: Arc<AtomicBool>)
+        -> Result<(), Box<dyn Error>> {
+    debug!("opened subscription [seq={}]", seq);
+    let mut writer = BufWriter::new(stream);
+    let heartbeat = Duration::from_millis(HEARTBEAT_MS);
+    while !shutdown.load(Ordering::Relaxed) {
+        let deltas = match journal.wait(seq, heartbeat) {
+            Some(deltas) => deltas,
+            None => {
+                // resend every member under the current sequence
+                seq = journal.last_seq();
+                debug!("resetting subscription [seq={}]", seq);
+                codec::write_delta(&mut writer, seq,
+                    Some(&MembershipDelta::Reset))?;
+                nodes.nodes().into_iter().map(|node|
+                    (seq, MembershipDelta::Upsert(node))).collect()
+            },
+        };
+
+        if deltas.is_empty() {
+            codec::write_delta(&mut writer, seq, None)?;
+        }
+
+        for (delta_seq, delta) in deltas.iter() {
+            codec::write_delta(&mut writer, *delta_seq, Some(delta))?;
+            seq = *delta_seq;
+        }
+
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Consumer side of a delta stream, for services mirroring cluster
+/// membership without joining it. Reconnect with Subscription::get_seq
+/// to resume where a broken stream left off.
+pub struct Subscription {
+    seq: u64,
+    stream: TcpStream,
+}
+
+impl Subscription {
+    /// Subscribes to deltas after `seq`; zero streams from the start.
+    pub fn connect(address: &SocketAddr, seq: u64, timeout: Duration)
+            -> Result<Subscription, Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+
+        codec::write_header(&mut stream, rand::random::<u64>(),
+            SUBSCRIBE_EXCHANGE)?;
+        stream.write_u64::<BigEndian>(seq)?;
+
+        Ok(Subscription { seq, stream })
+    }
+
+    /// Returns the sequence of the last delta received.
+    pub fn get_seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Blocks until the next delta arrives, skipping heartbeats.
+    pub fn next_delta(&mut self)
+            -> Result<(u64, MembershipDelta), Box<dyn Error>> {
+        loop {
+            if let (seq, Some(delta)) = codec::read_delta(&mut self.stream)? {
+                self.seq = seq;
+                return Ok((seq, delta));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::{ChangeJournal, MembershipDelta};
+
+    use std::time::Duration;
+
+    #[test]
+    fn change_journal() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, 12000));
+        nodes.insert(Node::new(1, ip_address, 12001));
+
+        let journal = ChangeJournal::new(3);
+        assert_eq!(journal.record(&nodes), 2);
+        assert_eq!(journal.record(&nodes), 0);
+
+        // metadata changes and removals append deltas
+        nodes.update(1, |node| node.set_metadata("k", "v"));
+        nodes.remove(0);
+        assert_eq!(journal.record(&nodes), 2);
+        assert_eq!(journal.last_seq(), 4);
+
+        let timeout = Duration::from_millis(0);
+        let deltas = journal.wait(2, timeout).expect("retained");
+        assert_eq!(deltas.len(), 2);
+        assert!(matches!(deltas[1], (4, MembershipDelta::Remove(0))));
+        assert!(matches!(deltas[0], (3, MembershipDelta::Upsert(ref node))
+            if node.get_metadata("k").is_some()));
+
+        // truncated sequences require a reset
+        assert!(journal.wait(1, timeout).is_some());
+        assert!(journal.wait(0, timeout).is_none());
+        assert_eq!(journal.wait(4, timeout).map(|deltas| deltas.len()),
+            Some(0));
+        assert!(journal.wait(5, timeout).is_none());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::bootstrap::Bootstrap;
+use crate::clock::Clock;
+use crate::http::{self, HttpUrl};
+use crate::secret::Secret;
+
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const DEFAULT_REFRESH_MS: u64 = 30000;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+// refresh threads wake at least this often to observe shutdown
+const SLEEP_STEP_MS: u64 = 500;
+
+/// Seed provider listing the pods matching a label selector through
+/// the Kubernetes API. Pod IPs, paired with the gossip port, become
+/// bootstrap candidates and are refreshed periodically. The API is
+/// reached over plain http, typically a `kubectl proxy` sidecar.
+#[derive(Clone, Debug)]
+pub struct KubernetesSeeds {
+    api_url: HttpUrl,
+    label_selector: String,
+    namespace: String,
+    port: u16,
+    refresh_interval: Duration,
+    settle_window: Duration,
+    timeout: Duration,
+    token: Option<Secret>,
+}
+
+impl KubernetesSeeds {
+    pub fn new(api_url: &str, namespace: &str, label_selector: &str,
+            port: u16) -> Result<KubernetesSeeds, Box<dyn Error>> {
+        Ok(KubernetesSeeds {
+            api_url: HttpUrl::parse(api_url)?,
+            label_selector: label_selector.to_string(),
+            namespace: namespace.to_string(),
+            port,
+            refresh_interval: Duration::from_millis(DEFAULT_REFRESH_MS),
+            settle_window: Duration::from_millis(DEFAULT_REFRESH_MS / 10),
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            token: None,
+        })
+    }
+
+    pub fn refresh_interval(mut self, refresh_interval: Duration)
+            -> KubernetesSeeds {
+        self.refresh_interval = refresh_interval;
+        self
+    }
+
+    /// Membership must be stable this long before bootstrap completes.
+    pub fn settle_window(mut self, settle_window: Duration)
+            -> KubernetesSeeds {
+        self.settle_window = settle_window;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> KubernetesSeeds {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends `token` as a bearer token, for example the pod service
+    /// account token when the proxy forwards credentials.
+    pub fn token(mut self, token: Secret) -> KubernetesSeeds {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn get_settle_window(&self) -> Duration {
+        self.settle_window
+    }
+
+    /// Lists the gossip addresses of every matching pod.
+    pub fn list(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        let path = format!("/api/v1/namespaces/{}/pods?labelSelector={}",
+            self.namespace, encode(&self.label_selector));
+
+        let authorization = self.token.as_ref()
+            .map(|token| format!("Bearer {}", token.expose()));
+        let mut headers = vec!(("Accept", "application/json"));
+        if let Some(authorization) = authorization.as_ref() {
+            headers.push(("Authorization", authorization));
+        }
+
+        let (status, body) = http::request(&self.api_url, "GET", &path,
+            &headers, "", self.timeout)?;
+        if status != 200 {
+            return Err(format!("pod list failed [status={}]", status).into());
+        }
+
+        Ok(pod_ips(&body).into_iter()
+            .map(|ip_address| SocketAddr::new(ip_address, self.port))
+            .collect())
+    }
+}
+
+/// Refreshes bootstrap candidates from the pod list every refresh
+/// interval until shutdown.
+pub fn refresh(clock: Arc<dyn Clock>, seeds: KubernetesSeeds,
+        bootstrap: Arc<Bootstrap>, shutdown: Arc<AtomicBool>) {
+    let step = Duration::from_millis(SLEEP_STEP_MS);
+    while !shutdown.load(Ordering::Relaxed) {
+        match seeds.list() {
+            Ok(candidates) => {
+                debug!("refreshed kubernetes seeds [count={}]",
+                    candidates.len());
+                bootstrap.set_candidates(candidates);
+            },
+            Err(e) => warn!("kubernetes seed refresh failure: {}", e),
+        }
+
+        // sleep in steps to observe shutdown
+        let start = clock.now();
+        while !shutdown.load(Ordering::Relaxed) && clock.now()
+                .saturating_duration_since(start) < seeds.refresh_interval {
+            clock.sleep(step);
+        }
+    }
+}
+
+fn encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+            | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// extracts every "podIP" value from a pod list without a json parser;
+// pods not yet scheduled carry no ip and are skipped
+fn pod_ips(body: &str) -> Vec<IpAddr> {
+    let mut ip_addresses = Vec::new();
+    let mut remainder = body;
+    while let Some(index) = remainder.find("\"podIP\"") {
+        remainder = &remainder[index + "\"podIP\"".len()..];
+        let value = remainder.trim_start()
+            .strip_prefix(':').map(|value| value.trim_start())
+            .and_then(|value| value.strip_prefix('"'))
+            .and_then(|value| value.split('"').next());
+
+        if let Some(Ok(ip_address)) = value.map(|value| value.parse()) {
+            ip_addresses.push(ip_address);
+        }
+    }
+
+    ip_addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, pod_ips, KubernetesSeeds};
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn kubernetes_seeds() {
+        assert_eq!(encode("app=swarm,tier in (a)"),
+            "app%3Dswarm%2Ctier%20in%20%28a%29");
+        assert_eq!(pod_ips("{\"items\":[{\"status\":{\"podIP\": \"10.0.0.2\"}},{\"status\":{\"phase\":\"Pending\"}},{\"status\":{\"podIP\":\"10.0.0.1\"}}]}"),
+            vec!("10.0.0.2".parse::<std::net::IpAddr>().unwrap(),
+                "10.0.0.1".parse().unwrap()));
+
+        let listener = TcpListener::bind("127.0.0.1:15600").expect("bind");
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).ends_with("\r\n\r\n") {
+                let len = stream.read(&mut buf).expect("read");
+                request.extend_from_slice(&buf[..len]);
+            }
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n{\"items\":[{\"status\":{\"podIP\":\"10.0.0.3\"}}]}")
+                .expect("write");
+            String::from_utf8(request).expect("utf8")
+        });
+
+        let seeds = KubernetesSeeds::new("http://127.0.0.1:15600",
+            "default", "app=swarm", 15000).expect("parse url");
+        let addresses = seeds.list().expect("list");
+        assert_eq!(addresses, vec!("10.0.0.3:15000".parse().unwrap()));
+
+        let request = server.join().expect("server");
+        assert!(request.starts_with(
+            "GET /api/v1/namespaces/default/pods?labelSelector=app%3Dswarm HTTP/1.0\r\n"));
+    }
+}
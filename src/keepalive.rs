@@ -0,0 +1,105 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::Clock;
+use crate::codec;
+use crate::exchange::KEEPALIVE_EXCHANGE;
+
+use std::error::Error;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// responders wake at least this often to observe shutdown
+const RESPONDER_TIMEOUT_MS: u64 = 500;
+
+/// State change of a monitored peer reported to Swarm::monitor
+/// callbacks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum KeepaliveEvent {
+    Connected,
+    Lost,
+}
+
+/// Heartbeats `address` every `interval` over a dedicated connection,
+/// reporting state changes to `callback` as soon as a heartbeat fails
+/// and reconnecting until shutdown.
+pub fn monitor<F: Fn(u32, KeepaliveEvent)>(clock: Arc<dyn Clock>, id: u32,
+        local_id: u32, address: SocketAddr, interval: Duration,
+        shutdown: Arc<AtomicBool>, callback: F) {
+    let mut state = None;
+    let mut stream: Option<TcpStream> = None;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        // open channel if disconnected
+        if stream.is_none() {
+            match connect(address, interval, local_id) {
+                Ok(connected) => stream = Some(connected),
+                Err(e) => debug!("keepalive connection failure [id={}]: {}",
+                    id, e),
+            }
+        }
+
+        // send heartbeat and wait for the echo
+        let event = match stream.as_mut() {
+            Some(stream) => match stream.write_u8(0)
+                    .and_then(|_| stream.read_u8()) {
+                Ok(_) => KeepaliveEvent::Connected,
+                Err(e) => {
+                    debug!("keepalive heartbeat failure [id={}]: {}", id, e);
+                    KeepaliveEvent::Lost
+                },
+            },
+            None => KeepaliveEvent::Lost,
+        };
+
+        if event == KeepaliveEvent::Lost {
+            stream = None;
+        }
+
+        if state != Some(event) {
+            info!("keepalive state changed [id={}, state={:?}]", id, event);
+            callback(id, event);
+            state = Some(event);
+        }
+
+        clock.sleep(interval);
+    }
+}
+
+fn connect(address: SocketAddr, timeout: Duration, local_id: u32)
+        -> Result<TcpStream, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    codec::write_header(&mut stream, rand::random::<u64>(), KEEPALIVE_EXCHANGE)?;
+    stream.write_u32::<BigEndian>(local_id)?;
+    Ok(stream)
+}
+
+/// Echoes heartbeats from `peer_id` until the channel closes or the
+/// swarm shuts down.
+pub fn respond(mut stream: TcpStream, peer_id: u32,
+        shutdown: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
+    debug!("opened keepalive channel [peer_id={}]", peer_id);
+    stream.set_read_timeout(
+        Some(Duration::from_millis(RESPONDER_TIMEOUT_MS)))?;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match stream.read_u8() {
+            Ok(heartbeat) => stream.write_u8(heartbeat)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::TimedOut => {},
+            Err(e) => {
+                debug!("closed keepalive channel [peer_id={}]: {}",
+                    peer_id, e);
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
@@ -1,292 +1,98 @@
-#[macro_use]
+
+// only the gossip layer logs
+#[cfg_attr(feature = "net", macro_use)]
 extern crate log;
 
+#[cfg(feature = "net")]
+mod admin;
+#[cfg(feature = "net")]
+mod backoff;
+#[cfg(feature = "net")]
+mod bootstrap;
+#[cfg(feature = "net")]
+mod broadcast;
+#[cfg(feature = "net")]
+mod buffer;
+#[cfg(feature = "net")]
+mod builder;
+#[cfg(feature = "net")]
+mod budget;
+#[cfg(feature = "net")]
+mod cidr;
+mod clock;
+#[cfg(feature = "net")]
+pub mod codec;
+#[cfg(feature = "net")]
+mod config;
+#[cfg(feature = "net")]
+mod control;
+#[cfg(feature = "net")]
+mod detector;
+#[cfg(feature = "discover")]
+mod discover;
+#[cfg(feature = "net")]
+mod distribution;
+#[cfg(feature = "net")]
+mod drain;
+#[cfg(feature = "net")]
+mod exchange;
+#[cfg(feature = "net")]
+mod federation;
+#[cfg(feature = "net")]
+mod hmac;
+#[cfg(feature = "net")]
+mod http;
+#[cfg(feature = "net")]
+mod journal;
+#[cfg(feature = "k8s")]
+mod k8s;
+#[cfg(feature = "net")]
+mod keepalive;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod merkle;
+#[cfg(feature = "net")]
+mod metrics;
+#[cfg(feature = "net")]
+mod namespace;
 mod node;
-use node::Node;
+#[cfg(feature = "net")]
+mod partition;
+#[cfg(feature = "net")]
+mod phase;
+#[cfg(feature = "net")]
+mod piggyback;
+#[cfg(feature = "net")]
+mod plumtree;
+#[cfg(feature = "net")]
+mod pool;
+#[cfg(feature = "net")]
+mod preflight;
+#[cfg(feature = "net")]
+mod probe;
 pub mod prelude;
+mod ring;
+mod secret;
+#[cfg(feature = "net")]
+mod service;
+#[cfg(feature = "net")]
+pub mod sim;
+#[cfg(feature = "net")]
+mod snapshot;
+#[cfg(feature = "http-status")]
+mod status;
+mod store;
+#[cfg(feature = "net")]
+mod swarm;
+#[cfg(feature = "net")]
+pub use swarm::{Swarm, TimeoutError};
+#[cfg(feature = "net")]
+mod threads;
+#[cfg(feature = "net")]
 mod topology;
-use topology::{Topology, TopologyBuilder};
-
-use std::collections::HashMap;
-use std::error::Error;
-use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
-
-pub struct Swarm<T: 'static + Topology + Sync + Send> {
-    address: SocketAddr,
-    id: u32,
-    join_handles: Vec<JoinHandle<()>>,
-    nodes: Arc<RwLock<HashMap<u32, Node>>>,
-    seed_address: Option<SocketAddr>,
-    shutdown: Arc<AtomicBool>,
-    topology: Arc<T>,
-}
-
-impl<T: 'static + Topology + Sync + Send> Swarm<T> {
-    pub fn new(id: u32, ip_address: IpAddr, port: u16,
-            seed_address: Option<SocketAddr>,
-            topology_builder: impl TopologyBuilder<T>)
-            -> (Swarm<T>, Arc<T>) {
-        info!("initializing swarm [id={}, address={}:{}, seed_addr={:?}]",
-            id, ip_address, port, seed_address);
-
-        // initialize nodes
-        let nodes = Arc::new(RwLock::new(HashMap::new()));
-        {
-            let mut nodes = nodes.write().unwrap();
-            nodes.insert(id, Node::new(id, ip_address, port));
-        }
-
-        // initialize topology
-        let topology = 
-            Arc::new(topology_builder.build(id, nodes.clone()));
-
-        // initialize swarm
-        let swarm = Swarm {
-            address: SocketAddr::new(ip_address, port), 
-            id,
-            join_handles: Vec::new(),
-            nodes,
-            seed_address,
-            shutdown: Arc::new(AtomicBool::new(true)),
-            topology: topology.clone(),
-        };
-
-        (swarm, topology)
-    }
-
-    pub fn set_metadata(&mut self, key: &str, value: &str) {
-        debug!("setting metadata [key={}, value={}]", key, value);
-        let mut nodes = self.nodes.write().unwrap();
-        let node = nodes.get_mut(&self.id).unwrap();
-        node.set_metadata(key, value);
-    }
-
-    pub fn start(&mut self, thread_count: u8, thread_sleep_ms: u64,
-            gossip_interval_ms: u64) -> Result<(), Box<dyn Error>> {
-        info!("starting [thread_count={}, thread_sleep_ms={}, gossip_interval_ms={}]", 
-            thread_count, thread_sleep_ms, gossip_interval_ms);
-
-        // set shutdown false
-        self.shutdown.store(false, Ordering::Relaxed);
-
-        // start TcpListener 
-        debug!("opening tcp listener [address={}]", self.address);
-        let listener = TcpListener::bind(self.address)?;
-
-        // start gossip listening threads
-        debug!("starting gossip listeners [thread_count={}]", thread_count);
-        for _ in 0..thread_count {
-            // clone gossip reply variables
-            let listener_clone = listener.try_clone()?;
-            listener_clone.set_nonblocking(true)?;
-            let shutdown_clone = self.shutdown.clone();
-            let thread_sleep = Duration::from_millis(thread_sleep_ms);
-            let topology_clone = self.topology.clone();
-
-            // start gossip reply threads
-            let join_handle = thread::spawn(move || {
-                if let Err(e) = gossip_listener(listener_clone,
-                        shutdown_clone, thread_sleep, topology_clone) {
-                    error!("gossip listener failed: {}", e);
-                }
-            });
-
-            // capture gossip listener thread JoinHandle
-            self.join_handles.push(join_handle);
-        }
-
-        // clone gossip request variables
-        let gossip_interval = Duration::from_millis(gossip_interval_ms);
-        let id = self.id;
-        let seed_address = self.seed_address;
-        let shutdown_clone = self.shutdown.clone();
-        let topology_clone = self.topology.clone();
-
-        // start gossip request thread
-        debug!("starting gossiper thread");
-        let join_handle = thread::spawn(move || {
-            if let Err(e) = gossiper(gossip_interval, id,
-                    seed_address, shutdown_clone, topology_clone) {
-                error!("gossiper failed: {}", e);
-            }
-        });
-
-        // capture gossip request thread JoinHandle
-        self.join_handles.push(join_handle);
-
-        Ok(())
-    }
-
-    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
-        info!("stopping swarm");
-
-        // check if already shutdown
-        if self.shutdown.load(Ordering::Relaxed) {
-            return Ok(());
-        }
-
-        // perform shutdown
-        self.shutdown.store(true, Ordering::Relaxed);
-
-        // join threads
-        while !self.join_handles.is_empty() {
-            let join_handle = self.join_handles.pop().unwrap();
-            if let Err(e) = join_handle.join() {
-                warn!("join thread failure: {:?}", e);
-            }
-        }
-
-        Ok(())
-    }
-}
-
-fn gossip_listener<T: 'static + Topology + Sync + Send>(
-        listener: TcpListener, shutdown: Arc<AtomicBool>,
-        thread_sleep: Duration, topology: Arc<T>)
-        -> Result<(), Box<dyn Error>> {
-    for result in listener.incoming() {
-        match result {
-            Ok(mut stream) => {
-                // handle topology gossip reply
-                if let Err(e) = topology.reply(&mut stream) {
-                    warn!("topology gossip reply failure: {}", e);
-                }
-
-                // shutdown gossip connection
-                if let Err(e) = stream.shutdown(Shutdown::Both) {
-                    warn!("gossip shutdown failure: {}", e);
-                }
-            },
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // no connection available -> sleep
-                thread::sleep(thread_sleep);
-            },
-            Err(ref e) if e.kind() !=
-                    std::io::ErrorKind::WouldBlock => {
-                // unknown error
-                warn!("gossip connection failure: {}", e);
-            },
-            _ => {}, // e.kind() == std::io::ErrorKind::WouldBlock
-        }
-
-        // check if shutdown
-        if shutdown.load(Ordering::Relaxed) {
-            break;
-        }
-    }
-
-    Ok(())
-}
-
-fn gossiper<T: 'static + Topology + Sync + Send>(
-        gossip_interval: Duration, id: u32,
-        seed_address: Option<SocketAddr>, shutdown: Arc<AtomicBool>,
-        topology: Arc<T>) -> Result<(), Box<dyn Error>> {
-    let mut instant = Instant::now();
-    instant -= gossip_interval;
-
-    loop {
-        // check if shutdown
-        if shutdown.load(Ordering::Relaxed) {
-            break;
-        }
-
-        // sleep
-        let elapsed = instant.elapsed();
-        if elapsed < gossip_interval {
-            thread::sleep(gossip_interval - elapsed);
-        }
-
-        // reset instance
-        instant = Instant::now();
-
-        // retrieve gossip address
-        let socket_addr = match topology.gossip_addr(id, &seed_address) {
-            Some(socket_addr) => socket_addr,
-            None => continue,
-        };
-
-        // connect to SocketAddr
-        let mut stream = match TcpStream::connect(&socket_addr) {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("gossip connection failure: {}", e);
-                continue;
-            },
-        };
-
-        // send topology gossip request
-        if let Err(e) = topology.request(id, &mut stream) {
-            warn!("gossip request failure: {}", e);
-        }
-
-        // shutdown gossip connection
-        if let Err(e) = stream.shutdown(Shutdown::Both) {
-            warn!("gossip shutdown failure: {}", e);
-        }
-    }
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::prelude::{ClusterBuilder, Swarm};
-
-    #[test]
-    fn cycle_swarm() {
-	// initialize topology builder
-        let cluster_builder = ClusterBuilder::new();
-
-	// initialize swarm
-        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
-        let (mut swarm, _cluster) =
-            Swarm::new(0, ip_address, 12000, None, cluster_builder);
-
-	// start swarm
-	swarm.start(2, 50, 2000).expect("swarm start");
-
-        // stop swarm
-        swarm.stop().expect("swarm stop")
-    }
-
-    #[test]
-    fn node_gossip() {
-        let port = 13000;
-        let swarm_count = 4;
-        let sleep_ms = 1000;
-
-        // start multiple swarm instances
-        let mut swarms = Vec::new();
-        let seed_address = None;
-        for i in 0..swarm_count {
-            // initialize topology builder
-            let cluster_builder = ClusterBuilder::new();
-
-            // initialize swarm
-            let ip_address = "127.0.0.1".parse()
-                .expect("parse ip addr");
-            let (mut swarm, _cluster) = Swarm::new(0, ip_address,
-                port + i, seed_address, cluster_builder);
-
-            // start swarm
-            swarm.start(2, 50, 75).expect("swarm start");
-
-            // add swarm to vector
-            swarms.push(swarm);
-        }
-
-        // sleep sleep_ms
-        let sleep_duration = std::time::Duration::from_millis(sleep_ms);
-        std::thread::sleep(sleep_duration);
-
-        // stop swarms
-        for i in 0..swarm_count {
-            swarms[i as usize].stop().expect("swarm stop")
-        }
-    }
-}
+#[cfg(feature = "net")]
+mod trace;
+#[cfg(feature = "net")]
+mod transport;
+#[cfg(feature = "net")]
+mod webhook;
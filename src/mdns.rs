@@ -0,0 +1,88 @@
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::node::{MergeStatus, Node, NodeMap};
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const SERVICE_TYPE: &str = "_swarm._tcp.local.";
+const ID_PROPERTY: &str = "id";
+
+// discovery threads wake at least this often to observe shutdown
+const RECV_TIMEOUT_MS: u64 = 500;
+
+/// Advertises the local gossip address over mDNS / DNS-SD and
+/// registers every peer discovered on the local network, so LAN
+/// members need no seed address. Runs until shutdown.
+pub fn start(id: u32, address: SocketAddr, nodes: Arc<NodeMap>,
+        shutdown: Arc<AtomicBool>)
+        -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let daemon = ServiceDaemon::new()?;
+
+    // advertise local node -> unspecified addresses use every interface
+    let instance = format!("swarm-{}-{}", id, address.port());
+    let host_name = format!("{}.local.", instance);
+    let id_value = id.to_string();
+    let properties = [(ID_PROPERTY, id_value.as_str())];
+    let service_info = match address.ip().is_unspecified() {
+        true => ServiceInfo::new(SERVICE_TYPE, &instance, &host_name,
+            "", address.port(), &properties[..])?.enable_addr_auto(),
+        false => ServiceInfo::new(SERVICE_TYPE, &instance, &host_name,
+            address.ip(), address.port(), &properties[..])?,
+    };
+
+    let fullname = service_info.get_fullname().to_string();
+    daemon.register(service_info)?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    debug!("started mdns discovery [instance={}]", instance);
+
+    let join_handle = thread::spawn(move || {
+        let timeout = Duration::from_millis(RECV_TIMEOUT_MS);
+        while !shutdown.load(Ordering::Relaxed) {
+            let service_info = match receiver.recv_timeout(timeout) {
+                Ok(ServiceEvent::ServiceResolved(service_info)) =>
+                    service_info,
+                Ok(_) => continue,
+                Err(_) if !receiver.is_disconnected() => continue,
+                Err(e) => {
+                    warn!("mdns discovery failure: {}", e);
+                    break;
+                },
+            };
+
+            register(id, &nodes, &service_info);
+        }
+
+        // withdraw advertisement
+        if let Err(e) = daemon.unregister(&fullname)
+                .and_then(|_| daemon.shutdown()) {
+            warn!("mdns shutdown failure: {}", e);
+        }
+    });
+
+    Ok(join_handle)
+}
+
+fn register(id: u32, nodes: &NodeMap, service_info: &ServiceInfo) {
+    let peer_id = match service_info.get_property_val_str(ID_PROPERTY)
+            .and_then(|value| value.parse::<u32>().ok()) {
+        Some(peer_id) if peer_id != id => peer_id,
+        _ => return,
+    };
+
+    // the first advertised address suffices, gossip corrects the rest
+    let ip_address = match service_info.get_addresses().iter().min() {
+        Some(ip_address) => *ip_address,
+        None => return,
+    };
+
+    let node = Node::new(peer_id, ip_address, service_info.get_port());
+    if nodes.merge(node) == MergeStatus::Inserted {
+        info!("discovered node [id={}, address={}:{}]",
+            peer_id, ip_address, service_info.get_port());
+    }
+}
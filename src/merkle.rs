@@ -0,0 +1,140 @@
+use xxhash_rust::xxh64::Xxh64;
+
+use std::collections::BTreeMap;
+#[cfg(feature = "net")]
+use std::ops::Range;
+
+/// Number of levels below the root.
+pub const DEPTH: usize = 8;
+
+const LEAF_COUNT: usize = 1 << DEPTH;
+const LEAF_SHIFT: u32 = 64 - DEPTH as u32;
+
+/// Binary hash tree over the token map where each leaf covers an equal
+/// segment of the token space, so two peers can descend from the root
+/// to find exactly which segments differ. Hashes are xxh64 over
+/// big-endian encodings, so every build, native or wasm, on any Rust
+/// release agrees on them.
+pub struct MerkleTree {
+    hashes: Vec<u64>,
+}
+
+impl MerkleTree {
+    pub fn new(tokens: &BTreeMap<u64, u32>) -> MerkleTree {
+        // hash tokens into their leaf segments
+        let mut leaves: Vec<Xxh64> =
+            (0..LEAF_COUNT).map(|_| Xxh64::new(0)).collect();
+        for (token, id) in tokens.iter() {
+            let hasher = &mut leaves[segment(*token)];
+            hasher.update(&token.to_be_bytes());
+            hasher.update(&id.to_be_bytes());
+        }
+
+        // compute internal hashes bottom up in heap order
+        let mut hashes = vec![0u64; 2 * LEAF_COUNT - 1];
+        for (i, hasher) in leaves.iter().enumerate() {
+            hashes[LEAF_COUNT - 1 + i] = hasher.digest();
+        }
+
+        for index in (0..LEAF_COUNT - 1).rev() {
+            let mut hasher = Xxh64::new(0);
+            hasher.update(&hashes[2 * index + 1].to_be_bytes());
+            hasher.update(&hashes[2 * index + 2].to_be_bytes());
+            hashes[index] = hasher.digest();
+        }
+
+        MerkleTree { hashes }
+    }
+
+    pub fn hash(&self, index: usize) -> u64 {
+        self.hashes[index]
+    }
+
+    pub fn root(&self) -> u64 {
+        self.hashes[0]
+    }
+}
+
+#[cfg(feature = "net")]
+pub fn children(index: usize) -> (usize, usize) {
+    (2 * index + 1, 2 * index + 2)
+}
+
+/// Returns the indices of the nodes `depth` levels below the root.
+#[cfg(feature = "net")]
+pub fn level(depth: usize) -> Range<usize> {
+    (1 << depth) - 1..(2 << depth) - 1
+}
+
+/// Returns the inclusive token range covered by the leaf at `index`,
+/// which must lie in `level(DEPTH)`.
+#[cfg(feature = "net")]
+pub fn leaf_range(index: usize) -> (u64, u64) {
+    debug_assert!(level(DEPTH).contains(&index));
+    let segment = (index - (LEAF_COUNT - 1)) as u64;
+    let start = segment << LEAF_SHIFT;
+    (start, start | (u64::MAX >> DEPTH))
+}
+
+fn segment(token: u64) -> usize {
+    (token >> LEAF_SHIFT) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "net")]
+    use super::{children, leaf_range, level};
+    use super::{MerkleTree, DEPTH};
+
+    use std::collections::BTreeMap;
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn merkle_diff() {
+        let mut tokens = BTreeMap::new();
+        tokens.insert(0, 0);
+        tokens.insert(6148914691236516864, 1);
+        let tree_a = MerkleTree::new(&tokens);
+
+        tokens.insert(12297829382473033728, 2);
+        let tree_b = MerkleTree::new(&tokens);
+        assert_ne!(tree_a.root(), tree_b.root());
+
+        // descend to the single differing leaf
+        let mut index = 0;
+        for _ in 0..DEPTH {
+            let (left, right) = children(index);
+            index = match tree_a.hash(left) != tree_b.hash(left) {
+                true => left,
+                false => right,
+            };
+        }
+
+        let (start, end) = leaf_range(index);
+        assert!(start <= 12297829382473033728);
+        assert!(12297829382473033728 <= end);
+
+        // levels partition the heap indices
+        assert_eq!(level(0), 0..1);
+        assert_eq!(level(1), 1..3);
+        assert_eq!(level(DEPTH), (1 << DEPTH) - 1..(2 << DEPTH) - 1);
+        assert_eq!(leaf_range(level(DEPTH).start).0, 0);
+        assert_eq!(leaf_range(level(DEPTH).end - 1).1, u64::MAX);
+    }
+
+    #[test]
+    fn merkle_stable() {
+        // reference digests -> peers agree across toolchains and targets
+        // empty leaves hash like xxh64 of no input
+        let mut tokens = BTreeMap::new();
+        let tree = MerkleTree::new(&tokens);
+        assert_eq!(tree.hash((1 << DEPTH) - 1), 0xef46db3751d8e999);
+        assert_eq!(tree.root(), 6213912859973264274);
+
+        tokens.insert(0, 0);
+        tokens.insert(6148914691236516864, 1);
+        let tree = MerkleTree::new(&tokens);
+        assert_eq!(tree.hash((1 << DEPTH) - 1), 17252927351209727994);
+        assert_eq!(tree.root(), 13674001546956936490);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Gossip counters shared by the listener and gossiper threads.
+#[derive(Default)]
+pub struct Metrics {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    connection_errors: AtomicU64,
+    peers: Mutex<HashMap<SocketAddr, PeerMetrics>>,
+    replies_failed: AtomicU64,
+    replies_succeeded: AtomicU64,
+    rounds_attempted: AtomicU64,
+    rounds_deferred: AtomicU64,
+    rounds_failed: AtomicU64,
+    rounds_succeeded: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a gossip round with the peer at `address`.
+    pub fn peer_round(&self, address: SocketAddr, success: bool,
+            bytes_sent: u64, bytes_received: u64, latency: Duration) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(address)
+            .or_insert_with(|| PeerMetrics {
+                address,
+                bytes_received: 0,
+                bytes_sent: 0,
+                last_latency: Duration::from_millis(0),
+                rounds_failed: 0,
+                rounds_succeeded: 0,
+            });
+        match success {
+            true => peer.rounds_succeeded += 1,
+            false => peer.rounds_failed += 1,
+        }
+
+        peer.bytes_sent += bytes_sent;
+        peer.bytes_received += bytes_received;
+        peer.last_latency = latency;
+    }
+
+    /// Returns the counters of every peer gossiped with, ordered by
+    /// address.
+    pub fn peer_snapshot(&self) -> Vec<PeerMetrics> {
+        let mut peers: Vec<PeerMetrics> =
+            self.peers.lock().unwrap().values().cloned().collect();
+        peers.sort_by_key(|peer| peer.address);
+        peers
+    }
+
+    pub fn reply(&self, success: bool) {
+        match success {
+            true => self.replies_succeeded.fetch_add(1, Ordering::Relaxed),
+            false => self.replies_failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn round_attempted(&self) {
+        self.rounds_attempted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn round_deferred(&self) {
+        self.rounds_deferred.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn round_completed(&self, success: bool) {
+        match success {
+            true => self.rounds_succeeded.fetch_add(1, Ordering::Relaxed),
+            false => self.rounds_failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn snapshot(&self, member_count: usize) -> MetricsSnapshot {
+        MetricsSnapshot {
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            connection_errors: self.connection_errors.load(Ordering::Relaxed),
+            member_count,
+            replies_failed: self.replies_failed.load(Ordering::Relaxed),
+            replies_succeeded: self.replies_succeeded.load(Ordering::Relaxed),
+            rounds_attempted: self.rounds_attempted.load(Ordering::Relaxed),
+            rounds_deferred: self.rounds_deferred.load(Ordering::Relaxed),
+            rounds_failed: self.rounds_failed.load(Ordering::Relaxed),
+            rounds_succeeded: self.rounds_succeeded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of the gossip counters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub connection_errors: u64,
+    pub member_count: usize,
+    pub replies_failed: u64,
+    pub replies_succeeded: u64,
+    pub rounds_attempted: u64,
+    pub rounds_deferred: u64,
+    pub rounds_failed: u64,
+    pub rounds_succeeded: u64,
+}
+
+/// Gossip counters of rounds this node started with a single peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerMetrics {
+    pub address: SocketAddr,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub last_latency: Duration,
+    pub rounds_failed: u64,
+    pub rounds_succeeded: u64,
+}
+
+/// Stream wrapper counting bytes read and written, both for this
+/// exchange and into the shared Metrics.
+pub struct MeteredStream<'a, S: Read + Write> {
+    bytes_received: u64,
+    bytes_sent: u64,
+    metrics: Arc<Metrics>,
+    stream: &'a mut S,
+}
+
+impl<'a, S: Read + Write> MeteredStream<'a, S> {
+    pub fn new(stream: &'a mut S, metrics: Arc<Metrics>)
+            -> MeteredStream<'a, S> {
+        MeteredStream { bytes_received: 0, bytes_sent: 0, metrics, stream }
+    }
+
+    pub fn get_bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn get_bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+}
+
+impl<'a, S: Read + Write> Read for MeteredStream<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.bytes_received += len as u64;
+        self.metrics.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+impl<'a, S: Read + Write> Write for MeteredStream<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.bytes_sent += len as u64;
+        self.metrics.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MeteredStream, Metrics};
+
+    use std::io::{Cursor, Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn metered_stream() {
+        let metrics = Arc::new(Metrics::new());
+        let mut cursor = Cursor::new(vec![0u8; 8]);
+
+        {
+            let mut stream = MeteredStream::new(&mut cursor, metrics.clone());
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).expect("read");
+            stream.write_all(&[1, 2]).expect("write");
+            assert_eq!(stream.get_bytes_received(), 4);
+            assert_eq!(stream.get_bytes_sent(), 2);
+        }
+
+        let snapshot = metrics.snapshot(1);
+        assert_eq!(snapshot.bytes_received, 4);
+        assert_eq!(snapshot.bytes_sent, 2);
+    }
+
+    #[test]
+    fn peer_metrics() {
+        let metrics = Metrics::new();
+        let first = "127.0.0.1:15701".parse().expect("parse");
+        let second = "127.0.0.1:15700".parse().expect("parse");
+        metrics.peer_round(first, true, 10, 20, Duration::from_millis(3));
+        metrics.peer_round(first, false, 5, 0, Duration::from_millis(7));
+        metrics.peer_round(second, true, 1, 2, Duration::from_millis(1));
+
+        // peers are ordered by address with counters accumulated
+        let peers = metrics.peer_snapshot();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].address, second);
+        assert_eq!((peers[1].rounds_succeeded, peers[1].rounds_failed), (1, 1));
+        assert_eq!((peers[1].bytes_sent, peers[1].bytes_received), (15, 20));
+        assert_eq!(peers[1].last_latency, Duration::from_millis(7));
+    }
+}
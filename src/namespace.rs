@@ -0,0 +1,103 @@
+use crate::node::{Node, NodeMap};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// Handle scoping metadata keys under a namespace prefix so multiple
+/// libraries can share one Swarm without key collisions.
+#[derive(Clone)]
+pub struct MetadataNamespace {
+    id: u32,
+    namespace: String,
+    nodes: Arc<NodeMap>,
+}
+
+impl MetadataNamespace {
+    pub(crate) fn new(id: u32, namespace: &str, nodes: Arc<NodeMap>)
+            -> Result<MetadataNamespace, Box<dyn Error>> {
+        if namespace.is_empty()
+                || namespace.contains(NAMESPACE_SEPARATOR) {
+            return Err(format!("invalid metadata namespace [namespace={}]",
+                namespace).into());
+        }
+
+        Ok(MetadataNamespace { id, namespace: namespace.to_string(), nodes })
+    }
+
+    /// Returns every key and value in this namespace for the node.
+    pub fn entries(&self, node: &Node) -> BTreeMap<String, String> {
+        let prefix = self.prefix();
+        node.metadata().filter_map(|(key, value)| key.strip_prefix(&prefix)
+                .map(|key| (key.to_string(), value.to_string())))
+            .collect()
+    }
+
+    /// Returns a key from the local node.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let node = self.nodes.get(self.id)?;
+        self.get_node(&node, key)
+    }
+
+    pub fn get_node(&self, node: &Node, key: &str) -> Option<String> {
+        node.get_metadata(&self.key(key)).cloned()
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn remove(&self, key: &str) {
+        let key = self.key(key);
+        debug!("removing metadata [key={}]", key);
+        self.nodes.update(self.id, |node| {
+            node.remove_metadata(&key);
+        });
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let key = self.key(key);
+        debug!("setting metadata [key={}, value={}]",
+            key, crate::secret::redact(&key, value));
+        self.nodes.update(self.id, |node| node.set_metadata(&key, value));
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix(), key)
+    }
+
+    fn prefix(&self) -> String {
+        format!("{}{}", self.namespace, NAMESPACE_SEPARATOR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::MetadataNamespace;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn namespace_isolation() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        nodes.insert(Node::new(0, ip_address, 12000));
+
+        let rpc = MetadataNamespace::new(0, "rpc", nodes.clone())
+            .expect("rpc namespace");
+        let xfer = MetadataNamespace::new(0, "xfer", nodes.clone())
+            .expect("xfer namespace");
+        assert!(MetadataNamespace::new(0, "a/b", nodes.clone()).is_err());
+
+        rpc.set("addr", "127.0.0.1:12002");
+        xfer.set("addr", "127.0.0.1:12003");
+        assert_eq!(rpc.get("addr").expect("get"), "127.0.0.1:12002");
+        assert_eq!(xfer.get("addr").expect("get"), "127.0.0.1:12003");
+
+        let node = nodes.get(0).expect("get node");
+        assert_eq!(rpc.entries(&node).len(), 1);
+    }
+}
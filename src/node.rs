@@ -1,46 +1,340 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
-use std::collections::BTreeMap;
+use crate::secret::{is_sensitive_key, redact};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+#[cfg(feature = "net")]
+use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
 use std::hash::Hasher;
 use std::iter::Iterator;
 use std::io::{Read, Write};
 use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "net")]
+use std::sync::RwLock;
+#[cfg(feature = "net")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "net")]
+const SHARD_COUNT: usize = 16;
+
+/// Metadata key set on members draining before shutdown. See
+/// Swarm::drain.
+pub const DRAINING_KEY: &str = "draining";
+
+// indexed metadata key -> value -> ids of nodes holding it
+#[cfg(feature = "net")]
+type MetadataIndex = HashMap<String, HashMap<String, BTreeSet<u32>>>;
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Node {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    confirmed: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    heartbeat: u64,
     id: u32,
+    incarnation: u64,
     ip_address: IpAddr,
-    metadata: BTreeMap<String, String>,
+    metadata: BTreeMap<String, MetadataEntry>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    metadata_sets: BTreeMap<String, MetadataSet>,
     port: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    roles: BTreeSet<String>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    state: NodeState,
+    version: u64,
+}
+
+/// Metadata value stamped with the wall clock milliseconds of its last
+/// update, merged per key with last-write-wins semantics. Removed keys
+/// are kept as tombstones (no value) so the deletion itself gossips;
+/// they are dropped when the node starts a new incarnation.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct MetadataEntry {
+    timestamp: u64,
+    value: Option<String>,
+}
+
+impl MetadataEntry {
+    /// Returns true if this entry wins over `other` under LWW, breaking
+    /// timestamp ties on the value so every peer picks the same entry.
+    #[cfg(feature = "net")]
+    fn wins(&self, other: &MetadataEntry) -> bool {
+        (self.timestamp, &self.value) > (other.timestamp, &other.value)
+    }
+}
+
+/// Set of values under one metadata key with observed-remove
+/// semantics. Every add carries a unique tag and removals tombstone
+/// only the tags they observed, so merges keep any add they have not
+/// seen removed, even one made concurrently by another writer.
+/// Removed tags are dropped when the node starts a new incarnation.
+#[derive(Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+struct MetadataSet {
+    adds: BTreeMap<String, BTreeSet<u64>>,
+    removed: BTreeSet<u64>,
+}
+
+impl MetadataSet {
+    fn contains(&self, value: &str) -> bool {
+        self.adds.get(value).map(|tags| self.is_live(tags)).unwrap_or(false)
+    }
+
+    fn is_live(&self, tags: &BTreeSet<u64>) -> bool {
+        tags.iter().any(|tag| !self.removed.contains(tag))
+    }
+
+    /// Drops removed tags along with the adds they tombstone.
+    #[cfg(feature = "net")]
+    fn compact(&mut self) {
+        let removed = std::mem::take(&mut self.removed);
+        for tags in self.adds.values_mut() {
+            tags.retain(|tag| !removed.contains(tag));
+        }
+
+        self.adds.retain(|_, tags| !tags.is_empty());
+    }
+
+    /// Unions the adds and tombstones of `other` into this set,
+    /// returning true if it changed.
+    #[cfg(feature = "net")]
+    fn merge(&mut self, other: &MetadataSet) -> bool {
+        let mut changed = false;
+        for (value, tags) in other.adds.iter() {
+            let current = self.adds.entry(value.clone()).or_default();
+            for tag in tags.iter() {
+                changed |= current.insert(*tag);
+            }
+        }
+
+        for tag in other.removed.iter() {
+            changed |= self.removed.insert(*tag);
+        }
+
+        changed
+    }
+
+    fn values(&self) -> impl Iterator<Item=&String> {
+        self.adds.iter().filter(move |(_, tags)| self.is_live(tags))
+            .map(|(value, _)| value)
+    }
+}
+
+/// Locally observed liveness of a node. States are never gossiped;
+/// every member runs its own failure detection and a new incarnation
+/// always starts alive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum NodeState {
+    #[default]
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// Chooses between conflicting records of one node, those sharing an
+/// incarnation and version but advertising different addresses, as
+/// when a healed partition finds the same id claimed in both halves.
+/// Every member must use the same tie breaker to converge.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum TieBreaker {
+    /// Keeps the record with the lower address.
+    #[default]
+    LowestAddress,
+    /// Keeps the record with the higher address.
+    HighestAddress,
+    /// Keeps the record with the most recently updated metadata,
+    /// falling back to the lower address.
+    NewestMetadata,
+}
+
+#[cfg(feature = "net")]
+impl TieBreaker {
+    /// Returns true if `remote` wins over the conflicting `local`.
+    fn prefers(&self, local: &Node, remote: &Node) -> bool {
+        let newest = |node: &Node| node.metadata.values()
+            .map(|entry| entry.timestamp).max().unwrap_or(0);
+        match self {
+            TieBreaker::LowestAddress =>
+                remote.get_address() < local.get_address(),
+            TieBreaker::HighestAddress =>
+                remote.get_address() > local.get_address(),
+            TieBreaker::NewestMetadata =>
+                (newest(remote), std::cmp::Reverse(remote.get_address()))
+                    > (newest(local), std::cmp::Reverse(local.get_address())),
+        }
+    }
+}
+
+/// Outcome of merging a gossiped node into a NodeMap.
+#[cfg(feature = "net")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeStatus {
+    Inserted,
+    Updated,
+    Stale,
 }
 
 impl Node {
     pub fn new(id: u32, ip_address: IpAddr, port: u16) -> Node {
-        Node { id, ip_address, metadata: BTreeMap::new(), port }
+        Node {
+            confirmed: 0,
+            heartbeat: 0,
+            id,
+            incarnation: 0,
+            ip_address,
+            metadata: BTreeMap::new(),
+            metadata_sets: BTreeMap::new(),
+            port,
+            roles: BTreeSet::new(),
+            state: NodeState::Alive,
+            version: 0,
+        }
     }
 
     pub fn get_address(&self) -> SocketAddr {
         SocketAddr::new(self.ip_address, self.port)
     }
 
+    /// Wall clock milliseconds at which the node was last confirmed
+    /// alive, directly or transitively through peers. Confirmations
+    /// gossip separately from the record and never change its version.
+    pub fn get_confirmed(&self) -> u64 {
+        self.confirmed
+    }
+
+    /// Heartbeat counter of the node's current incarnation, advanced by
+    /// the node every gossip round. Heartbeats gossip alongside
+    /// confirmations and never change the record's version.
+    pub fn get_heartbeat(&self) -> u64 {
+        self.heartbeat
+    }
+
     pub fn get_id(&self) -> u32 {
         self.id
     }
 
+    /// Generation of the node process, bumped on every restart so
+    /// records from a previous run are replaced even if their address,
+    /// metadata, or version differ.
+    pub fn get_incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
     pub fn get_ip_address(&self) -> &IpAddr {
         &self.ip_address
     }
 
     pub fn get_metadata(&self, key: &str) -> Option<&String> {
-        self.metadata.get(key)
+        self.metadata.get(key).and_then(|entry| entry.value.as_ref())
+    }
+
+    pub fn get_metadata_timestamp(&self, key: &str) -> Option<u64> {
+        self.metadata.get(key).map(|entry| entry.timestamp)
+    }
+
+    /// Returns true while the node drains before shutdown.
+    pub fn is_draining(&self) -> bool {
+        self.get_metadata(DRAINING_KEY).is_some()
+    }
+
+    pub fn metadata(&self) -> impl Iterator<Item=(&String, &String)> {
+        self.metadata.iter().filter_map(|(key, entry)|
+            entry.value.as_ref().map(|value| (key, value)))
+    }
+
+    /// Values of the set-valued metadata `key`, in order. Set-valued
+    /// keys are separate from the single-valued ones of get_metadata.
+    pub fn metadata_values(&self, key: &str) -> impl Iterator<Item=&String> {
+        self.metadata_sets.get(key).into_iter()
+            .flat_map(|set| set.values())
+    }
+
+    /// Returns true if the set-valued metadata `key` holds `value`.
+    pub fn has_metadata_value(&self, key: &str, value: &str) -> bool {
+        self.metadata_sets.get(key)
+            .map(|set| set.contains(value)).unwrap_or(false)
     }
 
     pub fn get_port(&self) -> u16 {
         self.port
     }
 
+    /// Returns true if the node declared `role`.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Roles the node declared, such as "frontend" or "storage", in
+    /// order. Unlike metadata, roles are replaced as a whole on every
+    /// change.
+    pub fn roles(&self) -> impl Iterator<Item=&String> {
+        self.roles.iter()
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.state
+    }
+
+    /// Metadata version, incremented by the owning node on every
+    /// metadata change. Only the owner writes its own metadata, so a
+    /// single counter orders updates without a full vector clock.
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns true if this record is newer than `other` and should
+    /// replace it during gossip merges.
+    pub fn supersedes(&self, other: &Node) -> bool {
+        (self.incarnation, self.version) > (other.incarnation, other.version)
+    }
+
+    /// Merges a record of the same incarnation into this one, keeping
+    /// the newest entry for every metadata key and the union of every
+    /// metadata set. Returns true if this record changed.
+    #[cfg(feature = "net")]
+    fn merge(&mut self, other: &Node) -> bool {
+        let mut changed = false;
+        if other.version > self.version {
+            self.ip_address = other.ip_address;
+            self.port = other.port;
+            self.roles = other.roles.clone();
+            self.version = other.version;
+            changed = true;
+        }
+
+        for (key, entry) in other.metadata.iter() {
+            let wins = match self.metadata.get(key) {
+                Some(current) => entry.wins(current),
+                None => true,
+            };
+
+            if wins {
+                self.metadata.insert(key.clone(), entry.clone());
+                changed = true;
+            }
+        }
+
+        for (key, set) in other.metadata_sets.iter() {
+            changed |= self.metadata_sets.entry(key.clone())
+                .or_default().merge(set);
+        }
+
+        changed
+    }
+
     pub fn read(reader: &mut impl Read)
             -> Result<Node, Box<dyn Error>> {
         // read id
@@ -63,21 +357,191 @@ impl Node {
         let port = reader.read_u16::<BigEndian>()?;
 
         let mut node = Node::new(id, ip_address, port);
+        node.incarnation = reader.read_u64::<BigEndian>()?;
+        node.version = reader.read_u64::<BigEndian>()?;
 
         // read metadata
         let metadata_len = reader.read_u16::<BigEndian>()?;
         for _ in 0..metadata_len {
             let key = read_string(reader)?;
-            let value = read_string(reader)?;
+            let value = match reader.read_u8()? {
+                0 => None,
+                _ => Some(read_string(reader)?),
+            };
+            let timestamp = reader.read_u64::<BigEndian>()?;
+
+            node.metadata.insert(key, MetadataEntry { timestamp, value });
+        }
+
+        // read roles
+        for _ in 0..reader.read_u8()? {
+            node.roles.insert(read_string(reader)?);
+        }
+
+        // read metadata sets
+        for _ in 0..reader.read_u16::<BigEndian>()? {
+            let key = read_string(reader)?;
+            let mut set = MetadataSet::default();
+            for _ in 0..reader.read_u16::<BigEndian>()? {
+                let value = read_string(reader)?;
+                let tags = set.adds.entry(value).or_default();
+                for _ in 0..reader.read_u16::<BigEndian>()? {
+                    tags.insert(reader.read_u64::<BigEndian>()?);
+                }
+            }
 
-            node.set_metadata(&key, &value);
+            for _ in 0..reader.read_u16::<BigEndian>()? {
+                set.removed.insert(reader.read_u64::<BigEndian>()?);
+            }
+
+            node.metadata_sets.insert(key, set);
         }
 
         Ok(node)
     }
 
+    /// Starts a new incarnation. Peers replace records of older
+    /// incarnations outright, so removal tombstones have nothing left
+    /// to override and are dropped.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_incarnation(&mut self, incarnation: u64) {
+        if incarnation > self.incarnation {
+            self.metadata.retain(|_, entry| entry.value.is_some());
+            for set in self.metadata_sets.values_mut() {
+                set.compact();
+            }
+
+            self.metadata_sets.retain(|_, set| !set.adds.is_empty());
+        }
+
+        self.incarnation = incarnation;
+    }
+
+    #[cfg(feature = "net")]
+    pub(crate) fn set_port(&mut self, port: u16) {
+        self.port = port;
+        self.version += 1;
+    }
+
+    /// Replaces the roles of the node, returning false if they were
+    /// unchanged. Fails on more than 255 roles or roles longer than 255
+    /// bytes, leaving the roles as they were.
+    pub fn set_roles<S: AsRef<str>>(&mut self,
+            roles: impl IntoIterator<Item=S>) -> Result<bool, Box<dyn Error>> {
+        let roles: BTreeSet<String> = roles.into_iter()
+            .map(|role| role.as_ref().to_string()).collect();
+        check_roles(roles.iter())?;
+        if roles == self.roles {
+            return Ok(false);
+        }
+
+        self.roles = roles;
+        self.version += 1;
+        Ok(true)
+    }
+
+    #[cfg(feature = "net")]
+    pub(crate) fn set_state(&mut self, state: NodeState) {
+        self.state = state;
+    }
+
+    /// Adds `value` to the set-valued metadata `key`, returning false
+    /// if it was already present.
+    pub fn add_metadata_value(&mut self, key: &str, value: &str) -> bool {
+        if self.has_metadata_value(key, value) {
+            return false;
+        }
+
+        // tag adds uniquely -> concurrent writers share versions
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key.as_bytes());
+        hasher.write(value.as_bytes());
+        hasher.write_u64(self.version);
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos()).unwrap_or(0));
+
+        self.metadata_sets.entry(key.to_string()).or_default().adds
+            .entry(value.to_string()).or_default().insert(hasher.finish());
+        self.version += 1;
+        true
+    }
+
+    /// Removes `value` from the set-valued metadata `key`, tombstoning
+    /// the adds observed so far. Returns false if it was not present.
+    pub fn remove_metadata_value(&mut self, key: &str, value: &str) -> bool {
+        let set = match self.metadata_sets.get_mut(key) {
+            Some(set) if set.contains(value) => set,
+            _ => return false,
+        };
+
+        let tags = set.adds.get(value).cloned().unwrap_or_default();
+        set.removed.extend(tags);
+        self.version += 1;
+        true
+    }
+
+    /// Removes a key, leaving a tombstone which gossips the removal.
+    /// Returns false if the key was not set.
+    pub fn remove_metadata(&mut self, key: &str) -> bool {
+        if self.get_metadata(key).is_none() {
+            return false;
+        }
+
+        self.put_metadata_entry(key, None);
+        self.version += 1;
+        true
+    }
+
     pub fn set_metadata(&mut self, key: &str, value: &str) {
-        self.metadata.insert(key.to_string(), value.to_string());
+        self.put_metadata_entry(key, Some(value.to_string()));
+        self.version += 1;
+    }
+
+    /// Applies every change staged by `f` under a single version bump,
+    /// returning the number of keys changed.
+    pub fn update_metadata<F: FnOnce(&mut MetadataBatch)>(&mut self, f: F)
+            -> usize {
+        let changes = {
+            let mut batch = MetadataBatch { changes: BTreeMap::new(),
+                node: self };
+            f(&mut batch);
+            batch.changes
+        };
+
+        let mut count = 0;
+        for (key, value) in changes {
+            // removing absent keys would only gossip a useless tombstone
+            if value.is_none() && self.get_metadata(&key).is_none() {
+                continue;
+            }
+
+            self.put_metadata_entry(&key, value);
+            count += 1;
+        }
+
+        if count != 0 {
+            self.version += 1;
+        }
+
+        count
+    }
+
+    fn put_metadata_entry(&mut self, key: &str, value: Option<String>) {
+        // timestamps never move backwards for a key
+        let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64).unwrap_or(0);
+        if let Some(previous) = self.metadata.get(key) {
+            timestamp = std::cmp::max(timestamp, previous.timestamp + 1);
+        }
+
+        let entry = MetadataEntry { timestamp, value };
+        if let Some(mut previous) =
+                self.metadata.insert(key.to_string(), entry) {
+            if let (true, Some(value)) =
+                    (is_sensitive_key(key), previous.value.as_mut()) {
+                value.zeroize();
+            }
+        }
     }
 
     pub fn write(&self, writer: &mut impl Write)
@@ -97,31 +561,582 @@ impl Node {
             },
         }
         writer.write_u16::<BigEndian>(self.port)?;
+        writer.write_u64::<BigEndian>(self.incarnation)?;
+        writer.write_u64::<BigEndian>(self.version)?;
 
-        // write metadata
-        writer.write_u16::<BigEndian>(self.metadata.len() as u16)?;
-        for (key, value) in self.metadata.iter() {
+        // write metadata -> counts which do not fit fail the write
+        // rather than corrupting every field after them
+        write_count(writer, self.metadata.len(), "metadata keys")?;
+        for (key, entry) in self.metadata.iter() {
             write_string(key, writer)?;
-            write_string(value, writer)?;
+            match entry.value {
+                Some(ref value) => {
+                    writer.write_u8(1)?;
+                    write_string(value, writer)?;
+                },
+                None => writer.write_u8(0)?,
+            }
+            writer.write_u64::<BigEndian>(entry.timestamp)?;
+        }
+
+        // write roles
+        match u8::try_from(self.roles.len()) {
+            Ok(count) => writer.write_u8(count)?,
+            Err(_) => return Err(format!("too many roles [count={}]",
+                self.roles.len()).into()),
+        }
+        for role in self.roles.iter() {
+            write_string(role, writer)?;
+        }
+
+        // write metadata sets
+        write_count(writer, self.metadata_sets.len(), "metadata sets")?;
+        for (key, set) in self.metadata_sets.iter() {
+            write_string(key, writer)?;
+            write_count(writer, set.adds.len(), "metadata set values")?;
+            for (value, tags) in set.adds.iter() {
+                write_string(value, writer)?;
+                write_count(writer, tags.len(), "metadata set tags")?;
+                for tag in tags.iter() {
+                    writer.write_u64::<BigEndian>(*tag)?;
+                }
+            }
+
+            write_count(writer, set.removed.len(), "metadata set tags")?;
+            for tag in set.removed.iter() {
+                writer.write_u64::<BigEndian>(*tag)?;
+            }
         }
 
         Ok(())
     }
 }
 
+/// Metadata changes staged by Node::update_metadata. Reads observe
+/// the staged changes on top of the node's current metadata.
+pub struct MetadataBatch<'a> {
+    changes: BTreeMap<String, Option<String>>,
+    node: &'a Node,
+}
+
+impl<'a> MetadataBatch<'a> {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        match self.changes.get(key) {
+            Some(value) => value.as_ref(),
+            None => self.node.get_metadata(key),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.changes.insert(key.to_string(), None);
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.changes.insert(key.to_string(), Some(value.to_string()));
+    }
+}
+
+impl Debug for Node {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let metadata: BTreeMap<&str, &str> = self.metadata()
+            .map(|(key, value)| (key.as_str(), redact(key, value)))
+            .collect();
+        let metadata_sets: BTreeMap<&str, Vec<&String>> = self.metadata_sets
+            .iter().map(|(key, set)| (key.as_str(), set.values().collect()))
+            .collect();
+
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("address", &self.get_address())
+            .field("incarnation", &self.incarnation)
+            .field("version", &self.version)
+            .field("state", &self.state)
+            .field("roles", &self.roles)
+            .field("metadata", &metadata)
+            .field("metadata_sets", &metadata_sets)
+            .finish()
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // zero secret-bearing metadata values before release
+        for (key, entry) in self.metadata.iter_mut() {
+            if let (true, Some(value)) =
+                    (is_sensitive_key(key), entry.value.as_mut()) {
+                value.zeroize();
+            }
+        }
+    }
+}
+
+/// Round-trip times measured to a peer, from sending an exchange header
+/// to the peer admitting the exchange.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerStats {
+    pub last_rtt: Duration,
+    pub min_rtt: Duration,
+    /// Number of round trips measured.
+    pub samples: u64,
+    /// Moving average weighting each sample by 1/8, like TCP's SRTT.
+    pub smoothed_rtt: Duration,
+}
+
+/// Membership map sharded by node id so concurrent gossip replies only
+/// contend when they touch the same shard. Metadata keys registered
+/// with index_metadata are indexed by value for nodes_with_metadata.
+#[cfg(feature = "net")]
+pub struct NodeMap {
+    cluster_epoch: AtomicU64,
+    cluster_name: RwLock<String>,
+    conflicts: AtomicU64,
+    index: RwLock<MetadataIndex>,
+    peer_stats: RwLock<BTreeMap<u32, PeerStats>>,
+    shards: Vec<RwLock<HashMap<u32, Node>>>,
+    tie_breaker: RwLock<TieBreaker>,
+    tombstones: RwLock<BTreeMap<u32, u64>>,
+}
+
+#[cfg(feature = "net")]
+impl Default for NodeMap {
+    fn default() -> Self {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| RwLock::new(HashMap::new())).collect();
+        NodeMap {
+            cluster_epoch: AtomicU64::new(0),
+            cluster_name: RwLock::new(String::new()),
+            conflicts: AtomicU64::new(0),
+            index: RwLock::new(HashMap::new()),
+            peer_stats: RwLock::new(BTreeMap::new()),
+            shards,
+            tie_breaker: RwLock::new(TieBreaker::default()),
+            tombstones: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl NodeMap {
+    pub fn new() -> NodeMap {
+        NodeMap::default()
+    }
+
+    fn shard(&self, id: u32) -> &RwLock<HashMap<u32, Node>> {
+        &self.shards[id as usize % SHARD_COUNT]
+    }
+
+    /// Moves node `id` to its current values in the metadata index,
+    /// called with its shard locked so index updates are ordered.
+    fn reindex(&self, id: u32, node: Option<&Node>) {
+        if self.index.read().unwrap().is_empty() {
+            return;
+        }
+
+        let mut index = self.index.write().unwrap();
+        for (key, values) in index.iter_mut() {
+            let value = node.and_then(|node| node.get_metadata(key));
+            values.retain(|indexed, ids| {
+                if Some(indexed) != value {
+                    ids.remove(&id);
+                }
+
+                !ids.is_empty()
+            });
+
+            if let Some(value) = value {
+                values.entry(value.clone()).or_default().insert(id);
+            }
+        }
+    }
+
+    /// Increments the heartbeat counter of node `id`, returning false if
+    /// the node is unknown.
+    pub fn beat(&self, id: u32) -> bool {
+        self.update(id, |node| node.heartbeat += 1)
+    }
+
+    /// Raises the epoch of the cluster this membership belongs to to
+    /// `epoch`, returning the previous epoch. Epochs never decrease.
+    pub fn advance_cluster_epoch(&self, epoch: u64) -> u64 {
+        self.cluster_epoch.fetch_max(epoch, Ordering::SeqCst)
+    }
+
+    pub fn get_cluster_epoch(&self) -> u64 {
+        self.cluster_epoch.load(Ordering::SeqCst)
+    }
+
+    pub fn set_cluster_epoch(&self, epoch: u64) {
+        self.cluster_epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// Returns the name of the cluster this membership belongs to,
+    /// which members exchange ahead of any state.
+    pub fn get_cluster_name(&self) -> String {
+        self.cluster_name.read().unwrap().clone()
+    }
+
+    pub fn set_cluster_name(&self, cluster_name: &str) {
+        *self.cluster_name.write().unwrap() = cluster_name.to_string();
+    }
+
+    /// Advances the confirmation timestamp of node `id`, returning
+    /// false if the node is unknown.
+    pub fn confirm(&self, id: u32, timestamp: u64) -> bool {
+        self.update(id, |node|
+            node.confirmed = std::cmp::max(node.confirmed, timestamp))
+    }
+
+    /// Returns the number of conflicting records resolved by the tie
+    /// breaker since this map was created.
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        let shard = self.shard(id).read().unwrap();
+        shard.contains_key(&id)
+    }
+
+    /// Returns the id of the node listening on `address` without
+    /// copying any nodes.
+    pub fn find_id(&self, address: &SocketAddr) -> Option<u32> {
+        self.shards.iter().find_map(|shard| shard.read().unwrap().values()
+            .find(|node| node.get_address() == *address)
+            .map(|node| node.get_id()))
+    }
+
+    pub fn get(&self, id: u32) -> Option<Node> {
+        let shard = self.shard(id).read().unwrap();
+        shard.get(&id).cloned()
+    }
+
+    pub fn hash(&self) -> u64 {
+        hash_nodes(self.nodes().iter())
+    }
+
+    /// Advances the heartbeat counter of node `id` if `incarnation` is
+    /// its current one, returning false if the node is unknown.
+    pub fn heartbeat(&self, id: u32, incarnation: u64, heartbeat: u64)
+            -> bool {
+        self.update(id, |node| if node.incarnation == incarnation {
+            node.heartbeat = std::cmp::max(node.heartbeat, heartbeat);
+        })
+    }
+
+    /// Indexes metadata `key` by value, so nodes_with_metadata answers
+    /// lookups of it without scanning every node.
+    pub fn index_metadata(&self, key: &str) {
+        // hold every shard -> no update is missed while building
+        let shards: Vec<_> = self.shards.iter()
+            .map(|shard| shard.read().unwrap()).collect();
+
+        let mut values: HashMap<String, BTreeSet<u32>> = HashMap::new();
+        for node in shards.iter().flat_map(|shard| shard.values()) {
+            if let Some(value) = node.get_metadata(key) {
+                values.entry(value.clone()).or_default().insert(node.id);
+            }
+        }
+
+        self.index.write().unwrap().insert(key.to_string(), values);
+    }
+
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            ids.extend(shard.keys());
+        }
+
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn insert(&self, node: Node) -> Option<Node> {
+        let mut shard = self.shard(node.get_id()).write().unwrap();
+        self.reindex(node.get_id(), Some(&node));
+        shard.insert(node.get_id(), node)
+    }
+
+    /// Inserts every node while holding all shard locks, so readers
+    /// observe either none or all of the batch. Returns the number of
+    /// previously unknown nodes.
+    pub fn insert_all(&self, nodes: impl IntoIterator<Item=Node>) -> usize {
+        let mut shards: Vec<_> = self.shards.iter()
+            .map(|shard| shard.write().unwrap()).collect();
+
+        let mut count = 0;
+        for node in nodes {
+            let shard = &mut shards[node.get_id() as usize % SHARD_COUNT];
+            self.reindex(node.get_id(), Some(&node));
+            if shard.insert(node.get_id(), node).is_none() {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Merges a gossiped node: a newer incarnation replaces the
+    /// registered record outright, the same incarnation is merged per
+    /// metadata key, and an older incarnation is ignored. Conflicting
+    /// records of the same incarnation and version are not merged;
+    /// the tie breaker keeps one of them whole.
+    pub fn merge(&self, node: Node) -> MergeStatus {
+        let tie_breaker = *self.tie_breaker.read().unwrap();
+        let id = node.get_id();
+        let mut shard = self.shard(id).write().unwrap();
+        let status = match shard.get_mut(&id) {
+            Some(current) if node.incarnation < current.incarnation =>
+                MergeStatus::Stale,
+            Some(current) if node.incarnation == current.incarnation
+                    && node.version == current.version
+                    && node.get_address() != current.get_address() => {
+                self.conflicts.fetch_add(1, Ordering::Relaxed);
+                match tie_breaker.prefers(current, &node) {
+                    true => {
+                        let mut node = node;
+                        node.confirmed = current.confirmed;
+                        node.heartbeat = current.heartbeat;
+                        node.state = current.state;
+                        *current = node;
+                        MergeStatus::Updated
+                    },
+                    false => MergeStatus::Stale,
+                }
+            },
+            Some(current) if node.incarnation == current.incarnation =>
+                match current.merge(&node) {
+                    true => MergeStatus::Updated,
+                    false => MergeStatus::Stale,
+                },
+            Some(current) => {
+                let mut node = node;
+                node.confirmed = std::cmp::max(node.confirmed,
+                    current.confirmed);
+                shard.insert(node.get_id(), node);
+                MergeStatus::Updated
+            },
+            None => {
+                shard.insert(node.get_id(), node);
+                MergeStatus::Inserted
+            },
+        };
+
+        if status != MergeStatus::Stale {
+            self.reindex(id, shard.get(&id));
+        }
+
+        status
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    /// Returns a copy of every node matching `predicate` ordered by id,
+    /// without copying the others.
+    pub fn nodes_where<F: Fn(&Node) -> bool>(&self, predicate: F)
+            -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            nodes.extend(shard.values()
+                .filter(|node| predicate(node)).cloned());
+        }
+
+        nodes.sort_unstable_by_key(|node| node.get_id());
+        nodes
+    }
+
+    /// Returns the nodes whose metadata `key` is `value` ordered by id,
+    /// through the index if `key` is indexed and by scanning otherwise.
+    pub fn nodes_with_metadata(&self, key: &str, value: &str) -> Vec<Node> {
+        // release the index before locking shards, which reindex
+        // acquires in the opposite order
+        let ids = self.index.read().unwrap().get(key)
+            .map(|values| values.get(value).cloned().unwrap_or_default());
+        let ids = match ids {
+            Some(ids) => ids,
+            None => return self.nodes_where(|node|
+                node.get_metadata(key).map(|v| v == value).unwrap_or(false)),
+        };
+
+        ids.into_iter().filter_map(|id| self.get(id))
+            .filter(|node| node.get_metadata(key).map(|v| v == value)
+                .unwrap_or(false))
+            .collect()
+    }
+
+    /// Returns a copy of every node ordered by id.
+    pub fn nodes(&self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            nodes.extend(shard.values().cloned());
+        }
+
+        nodes.sort_unstable_by_key(|node| node.get_id());
+        nodes
+    }
+
+    /// Returns the round-trip times of every peer measured, by id.
+    pub fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        self.peer_stats.read().unwrap().clone()
+    }
+
+    /// Records a round trip to node `id`.
+    pub fn record_rtt(&self, id: u32, rtt: Duration) {
+        let mut peer_stats = self.peer_stats.write().unwrap();
+        let stats = peer_stats.entry(id).or_insert(PeerStats {
+            last_rtt: rtt,
+            min_rtt: rtt,
+            samples: 0,
+            smoothed_rtt: rtt,
+        });
+
+        stats.last_rtt = rtt;
+        stats.min_rtt = std::cmp::min(stats.min_rtt, rtt);
+        stats.samples += 1;
+        stats.smoothed_rtt = (stats.smoothed_rtt * 7 + rtt) / 8;
+    }
+
+    pub fn remove(&self, id: u32) -> Option<Node> {
+        self.peer_stats.write().unwrap().remove(&id);
+        let mut shard = self.shard(id).write().unwrap();
+        self.reindex(id, None);
+        shard.remove(&id)
+    }
+
+    /// Removes node `id` and refuses its records until the wall clock
+    /// timestamp `expires`, keeping the later expiry of an existing
+    /// tombstone. Returns true if the tombstone was added or extended.
+    pub fn tombstone(&self, id: u32, expires: u64) -> bool {
+        let mut tombstones = self.tombstones.write().unwrap();
+        let expiry = tombstones.entry(id).or_insert(0);
+        if *expiry >= expires {
+            return false;
+        }
+
+        *expiry = expires;
+        self.remove(id);
+        true
+    }
+
+    /// Returns true if node `id` holds a tombstone unexpired at `now`.
+    pub fn is_tombstoned(&self, id: u32, now: u64) -> bool {
+        self.tombstones.read().unwrap().get(&id)
+            .map(|expires| *expires > now).unwrap_or(false)
+    }
+
+    /// Returns the tombstones unexpired at `now` with their expiries,
+    /// dropping expired ones.
+    pub fn tombstones(&self, now: u64) -> BTreeMap<u32, u64> {
+        let mut tombstones = self.tombstones.write().unwrap();
+        tombstones.retain(|_, expires| *expires > now);
+        tombstones.clone()
+    }
+
+    /// Forgets every member other than `id`, along with tombstones.
+    /// Returns the number of members removed.
+    pub fn reset(&self, id: u32) -> usize {
+        self.tombstones.write().unwrap().clear();
+        let removed: Vec<u32> = self.ids().into_iter()
+            .filter(|node_id| *node_id != id).collect();
+        for node_id in removed.iter() {
+            self.remove(*node_id);
+        }
+
+        removed.len()
+    }
+
+    pub fn set_tie_breaker(&self, tie_breaker: TieBreaker) {
+        *self.tie_breaker.write().unwrap() = tie_breaker;
+    }
+
+    pub fn update<F: FnOnce(&mut Node)>(&self, id: u32, f: F) -> bool {
+        let mut shard = self.shard(id).write().unwrap();
+        match shard.get_mut(&id) {
+            Some(node) => {
+                f(node);
+                self.reindex(id, Some(node));
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(feature = "net")]
 pub fn hash_nodes<'a>(nodes: impl Iterator<Item=&'a Node>) -> u64 {
     let mut hasher = DefaultHasher::new();
     for node in nodes {
         hasher.write_u32(node.get_id());
-        for (key, value) in node.metadata.iter() {
+        hasher.write_u64(node.incarnation);
+        hasher.write_u64(node.version);
+        for (key, entry) in node.metadata.iter() {
             hasher.write(key.as_bytes());
-            hasher.write(value.as_bytes());
+            match entry.value {
+                Some(ref value) => {
+                    hasher.write_u8(1);
+                    hasher.write(value.as_bytes());
+                },
+                None => hasher.write_u8(0),
+            }
+            hasher.write_u64(entry.timestamp);
+        }
+
+        for role in node.roles.iter() {
+            hasher.write(role.as_bytes());
+        }
+
+        for (key, set) in node.metadata_sets.iter() {
+            hasher.write(key.as_bytes());
+            for (value, tags) in set.adds.iter() {
+                hasher.write(value.as_bytes());
+                for tag in tags.iter() {
+                    hasher.write_u64(*tag);
+                }
+            }
+
+            for tag in set.removed.iter() {
+                hasher.write_u64(*tag);
+            }
         }
     }
 
     hasher.finish()
 }
 
+/// Fails if `roles` would not fit the wire format of Node::write.
+pub(crate) fn check_roles<S: AsRef<str>>(
+        roles: impl ExactSizeIterator<Item=S>) -> Result<(), Box<dyn Error>> {
+    if roles.len() > u8::MAX as usize {
+        return Err(format!("too many roles [count={}]", roles.len()).into());
+    }
+
+    for role in roles {
+        if role.as_ref().len() > u8::MAX as usize {
+            return Err(format!("role too long [length={}]",
+                role.as_ref().len()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `count` as a u16, failing on counts which do not fit.
+pub(crate) fn write_count(writer: &mut impl Write, count: usize,
+        name: &str) -> Result<(), Box<dyn Error>> {
+    let count = u16::try_from(count).map_err(|_|
+        format!("too many {} [count={}]", name, count))?;
+    writer.write_u16::<BigEndian>(count)?;
+    Ok(())
+}
+
 pub fn read_string(reader: &mut impl Read)
         -> Result<String, Box<dyn Error>> {
     let len = reader.read_u8()?;
@@ -130,9 +1145,445 @@ pub fn read_string(reader: &mut impl Read)
     Ok(String::from_utf8(buf)?)
 }
 
+/// Writes `value` behind a u8 length, failing on values longer than
+/// 255 bytes.
 pub fn write_string(value: &str, writer: &mut impl Write)
         -> Result<(), Box<dyn Error>> {
-    writer.write_u8(value.len() as u8)?;
+    let len = u8::try_from(value.len()).map_err(|_|
+        format!("string too long [length={}]", value.len()))?;
+    writer.write_u8(len)?;
     writer.write_all(value.as_bytes())?;
     Ok(())
 }
+
+// exercises NodeMap, which only the gossip layer builds
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::{MergeStatus, MetadataEntry, Node, NodeMap, TieBreaker,
+        SHARD_COUNT};
+
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn node_merge() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+
+        let mut node = Node::new(0, ip_address, 12000);
+        node.set_metadata("rpc_addr", "127.0.0.1:12002");
+        let stale = node.clone();
+        node.set_metadata("rpc_addr", "127.0.0.1:12004");
+
+        // serialize and deserialize through the wire format
+        let mut buf = Vec::new();
+        node.write(&mut buf).expect("write node");
+        let node = Node::read(&mut buf.as_slice()).expect("read node");
+        assert_eq!(node.get_version(), 2);
+
+        assert_eq!(nodes.merge(node.clone()), MergeStatus::Inserted);
+        assert_eq!(nodes.merge(node), MergeStatus::Stale);
+        assert_eq!(nodes.merge(stale.clone()), MergeStatus::Stale);
+        assert_eq!(nodes.get(0).expect("get node")
+            .get_metadata("rpc_addr").expect("get metadata"),
+            "127.0.0.1:12004");
+
+        // concurrent updates merge per key
+        let mut left = nodes.get(0).expect("get node");
+        let mut right = left.clone();
+        left.set_metadata("xfer_addr", "127.0.0.1:12003");
+        right.set_metadata("rpc_addr", "127.0.0.1:12006");
+        assert_eq!(nodes.merge(left), MergeStatus::Updated);
+        assert_eq!(nodes.merge(right), MergeStatus::Updated);
+
+        let node = nodes.get(0).expect("get node");
+        assert_eq!(node.get_metadata("xfer_addr").expect("get metadata"),
+            "127.0.0.1:12003");
+        assert_eq!(node.get_metadata("rpc_addr").expect("get metadata"),
+            "127.0.0.1:12006");
+
+        // removals propagate as tombstones
+        let mut removed = nodes.get(0).expect("get node");
+        assert!(removed.remove_metadata("xfer_addr"));
+        assert!(!removed.remove_metadata("xfer_addr"));
+        assert_eq!(nodes.merge(removed), MergeStatus::Updated);
+        assert!(nodes.get(0).expect("get node")
+            .get_metadata("xfer_addr").is_none());
+
+        // new incarnations drop tombstones
+        let mut node = nodes.get(0).expect("get node");
+        assert!(node.get_metadata_timestamp("xfer_addr").is_some());
+        node.set_incarnation(node.get_incarnation() + 1);
+        assert!(node.get_metadata_timestamp("xfer_addr").is_none());
+        assert!(node.get_metadata("rpc_addr").is_some());
+
+        // restarted node supersedes regardless of version
+        let mut restarted = Node::new(0, ip_address, 12100);
+        restarted.set_incarnation(stale.get_incarnation() + 1);
+        assert_eq!(nodes.merge(restarted), MergeStatus::Updated);
+        assert_eq!(nodes.get(0).expect("get node").get_port(), 12100);
+    }
+
+    #[test]
+    fn node_metadata_lww() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let base = Node::new(0, ip_address, 12000);
+        let write = |key: &str, timestamp, value: Option<&str>| {
+            let mut node = base.clone();
+            node.metadata.insert(key.to_string(), MetadataEntry {
+                timestamp, value: value.map(|value| value.to_string()) });
+            node
+        };
+
+        // merges converge on the same entry in either order
+        let converge = |left: &Node, right: &Node, key: &str| {
+            let values: Vec<Option<String>> = [(left, right), (right, left)]
+                .iter().map(|(first, second)| {
+                    let nodes = NodeMap::new();
+                    nodes.merge(base.clone());
+                    nodes.merge((*first).clone());
+                    nodes.merge((*second).clone());
+                    nodes.get(0).expect("get node").get_metadata(key).cloned()
+                }).collect();
+            assert_eq!(values[0], values[1]);
+            values[0].clone()
+        };
+
+        // concurrent writes at equal incarnation -> latest timestamp wins
+        assert_eq!(converge(&write("zone", 10, Some("a")),
+            &write("zone", 20, Some("b")), "zone"), Some("b".to_string()));
+        assert_eq!(converge(&write("zone", 20, None),
+            &write("zone", 10, Some("a")), "zone"), None);
+
+        // equal timestamps -> larger value wins, values beat removals
+        assert_eq!(converge(&write("zone", 30, Some("x")),
+            &write("zone", 30, Some("y")), "zone"), Some("y".to_string()));
+        assert_eq!(converge(&write("zone", 30, None),
+            &write("zone", 30, Some("x")), "zone"), Some("x".to_string()));
+
+        // newer incarnations replace the record, older ones are stale
+        let nodes = NodeMap::new();
+        let mut current = write("zone", 30, Some("y"));
+        current.metadata.insert("rack".to_string(),
+            MetadataEntry { timestamp: 30, value: Some("r1".to_string()) });
+        nodes.merge(current);
+        let mut restarted = write("zone", 5, Some("z"));
+        restarted.set_incarnation(base.get_incarnation() + 1);
+        assert_eq!(nodes.merge(restarted.clone()), MergeStatus::Updated);
+        let node = nodes.get(0).expect("get node");
+        assert_eq!(node.get_metadata("zone").map(|v| v.as_str()), Some("z"));
+        assert!(node.get_metadata("rack").is_none());
+        assert_eq!(nodes.merge(write("zone", 40, Some("w"))),
+            MergeStatus::Stale);
+        assert_eq!(nodes.get(0).expect("get node").get_metadata("zone"),
+            restarted.get_metadata("zone"));
+    }
+
+    #[test]
+    fn node_map_shards() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let node = |id: u32| Node::new(id, ip_address, 13000 + id as u16);
+
+        // ids spread over every shard, several per shard
+        let ids: Vec<u32> = (0..10 * SHARD_COUNT as u32).map(|i| i * 7)
+            .collect();
+        let (forward, backward) = (NodeMap::new(), NodeMap::new());
+        for id in ids.iter() {
+            assert!(forward.insert(node(*id)).is_none());
+        }
+        assert_eq!(backward.insert_all(ids.iter().rev()
+            .map(|id| node(*id))), ids.len());
+        assert!(forward.insert(node(7)).is_some());
+
+        // iteration is ordered by id whatever the insertion order
+        for nodes in [&forward, &backward] {
+            assert_eq!(nodes.len(), ids.len());
+            assert_eq!(nodes.ids(), ids);
+            assert_eq!(nodes.nodes().iter().map(|node| node.get_id())
+                .collect::<Vec<u32>>(), ids);
+            assert_eq!(nodes.nodes_where(|node| node.get_id() % 2 == 0)
+                .iter().map(|node| node.get_id()).collect::<Vec<u32>>(),
+                ids.iter().copied().filter(|id| id % 2 == 0)
+                    .collect::<Vec<u32>>());
+            assert!(ids.iter().all(|id| nodes.contains(*id)));
+            assert!(!nodes.contains(1) && !nodes.contains(3));
+            assert_eq!(nodes.find_id(&node(133).get_address()), Some(133));
+        }
+        assert_eq!(forward.hash(), backward.hash());
+
+        // removals affect only their own shard entries
+        let removed: Vec<u32> = ids.iter().copied()
+            .filter(|id| *id as usize % SHARD_COUNT == 3).collect();
+        assert!(removed.len() > 1);
+        for id in removed.iter() {
+            assert_eq!(forward.remove(*id).map(|node| node.get_id()),
+                Some(*id));
+            assert!(forward.remove(*id).is_none());
+        }
+        assert_eq!(forward.len(), ids.len() - removed.len());
+        assert!(removed.iter().all(|id| !forward.contains(*id)));
+        assert_ne!(forward.hash(), backward.hash());
+        for id in removed.iter() {
+            backward.remove(*id);
+        }
+        assert_eq!(forward.ids(), backward.ids());
+        assert_eq!(forward.hash(), backward.hash());
+    }
+
+    #[test]
+    fn node_conflicts() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let left = Node::new(0, ip_address, 12000);
+        let mut right = Node::new(0, ip_address, 12001);
+        right.set_incarnation(left.get_incarnation());
+
+        // every tie breaker converges regardless of merge order
+        for (tie_breaker, port) in [(TieBreaker::LowestAddress, 12000),
+                (TieBreaker::HighestAddress, 12001),
+                (TieBreaker::NewestMetadata, 12000)] {
+            for (first, second) in [(&left, &right), (&right, &left)] {
+                let nodes = NodeMap::new();
+                nodes.set_tie_breaker(tie_breaker);
+                nodes.merge(first.clone());
+                nodes.merge(second.clone());
+                assert_eq!(nodes.get(0).expect("get node").get_port(), port);
+                assert_eq!(nodes.conflicts(), 1);
+            }
+        }
+
+        // newer incarnations are not conflicts
+        let nodes = NodeMap::new();
+        nodes.merge(left.clone());
+        let mut restarted = right.clone();
+        restarted.set_incarnation(left.get_incarnation() + 1);
+        assert_eq!(nodes.merge(restarted), MergeStatus::Updated);
+        assert_eq!(nodes.conflicts(), 0);
+    }
+
+    #[test]
+    fn node_update_metadata() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut node = Node::new(0, ip_address, 12000);
+        node.set_metadata("rpc_addr", "127.0.0.1:12002");
+
+        let count = node.update_metadata(|batch| {
+            batch.set("xfer_addr", "127.0.0.1:12003");
+            batch.remove("rpc_addr");
+            batch.remove("missing");
+            assert!(batch.get("rpc_addr").is_none());
+            assert_eq!(batch.get("xfer_addr").expect("get metadata"),
+                "127.0.0.1:12003");
+        });
+
+        // every change shares a single version bump
+        assert_eq!(count, 2);
+        assert_eq!(node.get_version(), 2);
+        assert!(node.get_metadata("rpc_addr").is_none());
+        assert!(node.get_metadata_timestamp("missing").is_none());
+        assert_eq!(node.update_metadata(|_| {}), 0);
+        assert_eq!(node.get_version(), 2);
+    }
+
+    #[test]
+    fn node_metadata_sets() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        let mut node = Node::new(0, ip_address, 12000);
+        assert!(node.add_metadata_value("shards", "1"));
+        assert!(!node.add_metadata_value("shards", "1"));
+        assert!(node.add_metadata_value("shards", "2"));
+        nodes.insert(node.clone());
+
+        // concurrent removes only drop the adds they observed
+        let mut left = node.clone();
+        let mut right = node;
+        assert!(left.remove_metadata_value("shards", "1"));
+        assert!(!left.remove_metadata_value("shards", "1"));
+        assert!(left.add_metadata_value("shards", "3"));
+        assert!(right.remove_metadata_value("shards", "2"));
+        assert!(right.add_metadata_value("shards", "2"));
+
+        // sets travel through the wire format and merge in any order
+        for (first, second) in [(&left, &right), (&right, &left)] {
+            let merged = NodeMap::new();
+            for node in [first, second] {
+                let mut buf = Vec::new();
+                node.write(&mut buf).expect("write node");
+                merged.merge(Node::read(&mut buf.as_slice())
+                    .expect("read node"));
+            }
+
+            let node = merged.get(0).expect("get node");
+            assert_eq!(node.metadata_values("shards").collect::<Vec<_>>(),
+                vec!("2", "3"));
+            assert!(!node.has_metadata_value("shards", "1"));
+            assert_eq!(node.metadata_values("missing").count(), 0);
+        }
+
+        assert_eq!(nodes.merge(left), MergeStatus::Updated);
+        assert_eq!(nodes.merge(right), MergeStatus::Updated);
+        assert!(nodes.get(0).expect("get node").has_metadata_value("shards", "3"));
+
+        // new incarnations drop removed tags and emptied sets
+        let mut node = nodes.get(0).expect("get node");
+        assert!(node.remove_metadata_value("shards", "2"));
+        assert!(node.add_metadata_value("zones", "a"));
+        assert!(node.remove_metadata_value("zones", "a"));
+        node.set_incarnation(node.get_incarnation() + 1);
+        let set = node.metadata_sets.get("shards").expect("get set");
+        assert!(set.removed.is_empty());
+        assert_eq!(set.adds.keys().collect::<Vec<_>>(), vec!("3"));
+        assert!(!node.metadata_sets.contains_key("zones"));
+    }
+
+    #[test]
+    fn node_roles() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        let mut node = Node::new(0, ip_address, 12000);
+        nodes.insert(node.clone());
+
+        assert!(node.set_roles(["storage", "frontend"]).expect("set roles"));
+        assert!(!node.set_roles(["frontend", "storage"]).expect("set roles"));
+        assert_eq!(node.get_version(), 1);
+
+        // counts and lengths which do not fit the wire format fail
+        let many: Vec<String> = (0..256).map(|i| i.to_string()).collect();
+        assert!(node.set_roles(&many).is_err());
+        assert!(node.set_roles(["a".repeat(256)]).is_err());
+        assert_eq!(node.roles().count(), 2);
+        let mut wide = Node::new(1, ip_address, 12001);
+        for i in 0..=u16::MAX as u32 {
+            wide.metadata.insert(i.to_string(), MetadataEntry {
+                timestamp: 0, value: None });
+        }
+        assert!(wide.write(&mut Vec::new()).is_err());
+
+        // roles travel through the wire format and replace older ones
+        let mut buf = Vec::new();
+        node.write(&mut buf).expect("write node");
+        let node = Node::read(&mut buf.as_slice()).expect("read node");
+        assert_eq!(node.roles().collect::<Vec<_>>(),
+            vec!("frontend", "storage"));
+        assert_eq!(nodes.merge(node), MergeStatus::Updated);
+
+        let merged = nodes.get(0).expect("get node");
+        assert!(merged.has_role("storage"));
+        assert!(!merged.has_role("coordinator"));
+    }
+
+    #[test]
+    fn node_metadata_index() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        for id in 0..4 {
+            let mut node = Node::new(id, ip_address, 12000 + id as u16);
+            node.set_metadata("dc", if id % 2 == 0 { "east" } else { "west" });
+            nodes.insert(node);
+        }
+
+        let ids = |nodes: Vec<Node>| nodes.iter()
+            .map(|node| node.get_id()).collect::<Vec<_>>();
+        assert_eq!(ids(nodes.nodes_where(|node| node.get_id() > 1)),
+            vec!(2, 3));
+        assert_eq!(ids(nodes.nodes_with_metadata("dc", "east")), vec!(0, 2));
+
+        // indexed lookups follow updates, merges and removals
+        nodes.index_metadata("dc");
+        assert_eq!(ids(nodes.nodes_with_metadata("dc", "west")), vec!(1, 3));
+        nodes.update(1, |node| node.set_metadata("dc", "east"));
+        let mut node = nodes.get(2).expect("get node");
+        node.set_metadata("dc", "north");
+        assert_eq!(nodes.merge(node), MergeStatus::Updated);
+        nodes.remove(0);
+
+        assert_eq!(ids(nodes.nodes_with_metadata("dc", "east")), vec!(1));
+        assert_eq!(ids(nodes.nodes_with_metadata("dc", "west")), vec!(3));
+        assert_eq!(ids(nodes.nodes_with_metadata("dc", "north")), vec!(2));
+        assert!(nodes.nodes_with_metadata("dc", "south").is_empty());
+    }
+
+    #[test]
+    fn node_metadata_index_concurrent() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        nodes.index_metadata("dc");
+        for id in 0..SHARD_COUNT as u32 {
+            nodes.insert(Node::new(id, ip_address, 12000 + id as u16));
+        }
+
+        // merges reindex while scans of unindexed keys lock shards
+        let (sender, receiver) = mpsc::channel();
+        let writer = nodes.clone();
+        let merges = thread::spawn(move || {
+            for i in 0..20000 {
+                let mut node = writer.get(i % SHARD_COUNT as u32)
+                    .expect("get node");
+                node.set_metadata("dc", &i.to_string());
+                writer.merge(node);
+            }
+        });
+
+        let reader = nodes.clone();
+        let queries = thread::spawn(move || {
+            for _ in 0..20000 {
+                reader.nodes_with_metadata("rack", "a");
+            }
+        });
+
+        thread::spawn(move || {
+            merges.join().expect("merges");
+            queries.join().expect("queries");
+            let _ = sender.send(());
+        });
+        assert!(receiver.recv_timeout(Duration::from_secs(30)).is_ok(),
+            "metadata index deadlock");
+    }
+
+    #[test]
+    fn node_peer_stats() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(1, ip_address, 12001));
+        for millis in [8, 16, 4] {
+            nodes.record_rtt(1, Duration::from_millis(millis));
+        }
+
+        // smoothed round trips weight each sample by 1/8
+        let stats = nodes.peer_stats()[&1];
+        assert_eq!((stats.last_rtt, stats.min_rtt, stats.samples),
+            (Duration::from_millis(4), Duration::from_millis(4), 3));
+        assert_eq!(stats.smoothed_rtt, Duration::from_micros(8375));
+
+        nodes.remove(1);
+        assert!(nodes.peer_stats().is_empty());
+    }
+
+    #[test]
+    fn node_tombstones() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(1, ip_address, 12001));
+
+        // tombstones remove the node and keep the later expiry
+        assert!(nodes.tombstone(1, 200));
+        assert!(!nodes.contains(1));
+        assert!(!nodes.tombstone(1, 100));
+        assert!(nodes.is_tombstoned(1, 150));
+        assert!(!nodes.is_tombstoned(2, 150));
+
+        // expired tombstones are dropped
+        assert_eq!(nodes.tombstones(150).len(), 1);
+        assert!(!nodes.is_tombstoned(1, 200));
+        assert!(nodes.tombstones(200).is_empty());
+        assert!(nodes.tombstone(1, 300));
+
+        // resets keep only the local node
+        nodes.insert(Node::new(0, ip_address, 12000));
+        nodes.insert(Node::new(2, ip_address, 12002));
+        assert_eq!(nodes.reset(0), 1);
+        assert_eq!(nodes.ids(), vec!(0));
+        assert!(!nodes.is_tombstoned(1, 250));
+    }
+}
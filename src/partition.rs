@@ -0,0 +1,156 @@
+use std::sync::{Mutex, RwLock};
+
+const DEFAULT_THRESHOLD: u32 = 10;
+
+type Handler = Box<dyn Fn(PartitionEvent) + Send + Sync>;
+
+/// Partition state change reported to Swarm::on_partition handlers.
+/// `Merged` reports conflicting member records resolved by the tie
+/// breaker, as when the halves of a split brain gossip again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum PartitionEvent {
+    Partitioned,
+    Rejoined,
+    Merged { conflicts: u64 },
+}
+
+struct PartitionState {
+    conflicts: u64,
+    failed_rounds: u32,
+    partitioned: bool,
+}
+
+/// Detects isolation from the swarm. A node whose gossip rounds fail
+/// `threshold` times in a row, because every peer it tries is
+/// unreachable, is partitioned until a round succeeds again. While
+/// partitioned the gossiper contacts seeds instead of its stale peers.
+pub struct PartitionDetector {
+    handlers: RwLock<Vec<Handler>>,
+    state: Mutex<PartitionState>,
+    threshold: RwLock<u32>,
+}
+
+impl PartitionDetector {
+    pub fn new() -> PartitionDetector {
+        PartitionDetector {
+            handlers: RwLock::new(Vec::new()),
+            state: Mutex::new(PartitionState {
+                conflicts: 0,
+                failed_rounds: 0,
+                partitioned: false,
+            }),
+            threshold: RwLock::new(DEFAULT_THRESHOLD),
+        }
+    }
+
+    pub fn add_handler(&self, handler: Handler) {
+        self.handlers.write().unwrap().push(handler);
+    }
+
+    pub fn set_threshold(&self, threshold: u32) {
+        *self.threshold.write().unwrap() = std::cmp::max(threshold, 1);
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.state.lock().unwrap().partitioned
+    }
+
+    /// Records the outcome of a gossip round.
+    pub fn round(&self, succeeded: bool) {
+        let threshold = *self.threshold.read().unwrap();
+        let event = {
+            let mut state = self.state.lock().unwrap();
+            if succeeded {
+                state.failed_rounds = 0;
+            } else {
+                state.failed_rounds = state.failed_rounds.saturating_add(1);
+            }
+
+            match (state.partitioned, state.failed_rounds) {
+                (true, 0) => {
+                    state.partitioned = false;
+                    Some(PartitionEvent::Rejoined)
+                },
+                (false, failed_rounds) if failed_rounds >= threshold => {
+                    state.partitioned = true;
+                    Some(PartitionEvent::Partitioned)
+                },
+                _ => None,
+            }
+        };
+
+        if let Some(event) = event {
+            match event {
+                PartitionEvent::Partitioned => warn!("partitioned from swarm -> contacting seeds [failed_rounds={}]",
+                    threshold),
+                _ => info!("rejoined swarm"),
+            }
+
+            self.emit(event);
+        }
+    }
+
+    /// Reports conflicts resolved since the last call, given the total
+    /// of NodeMap::conflicts.
+    pub fn observe_conflicts(&self, conflicts: u64) {
+        let resolved = {
+            let mut state = self.state.lock().unwrap();
+            let resolved = conflicts.saturating_sub(state.conflicts);
+            state.conflicts = conflicts;
+            resolved
+        };
+
+        if resolved != 0 {
+            warn!("merged conflicting member records [conflicts={}]",
+                resolved);
+            self.emit(PartitionEvent::Merged { conflicts: resolved });
+        }
+    }
+
+    fn emit(&self, event: PartitionEvent) {
+        for handler in self.handlers.read().unwrap().iter() {
+            handler(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PartitionDetector, PartitionEvent};
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn partition_detection() {
+        let detector = PartitionDetector::new();
+        detector.set_threshold(3);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        detector.add_handler(Box::new(move |event|
+            events_clone.lock().unwrap().push(event)));
+
+        // successful rounds reset the failure count
+        for succeeded in [false, false, true, false, false] {
+            detector.round(succeeded);
+        }
+        assert!(!detector.is_partitioned());
+
+        // consecutive failures partition once
+        for _ in 0..3 {
+            detector.round(false);
+        }
+        assert!(detector.is_partitioned());
+
+        detector.round(true);
+        detector.round(true);
+        assert!(!detector.is_partitioned());
+
+        // resolved conflicts are reported once
+        detector.observe_conflicts(2);
+        detector.observe_conflicts(2);
+        assert_eq!(*events.lock().unwrap(),
+            vec!(PartitionEvent::Partitioned, PartitionEvent::Rejoined,
+                PartitionEvent::Merged { conflicts: 2 }));
+    }
+}
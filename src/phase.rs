@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// exchanges arriving within one window are considered simultaneous
+const WINDOW_MS: u128 = 10;
+// phase locking requires this many receives with at least this share
+// of them in the busiest window
+const LOCK_MIN_RECEIVES: u64 = 4;
+const LOCK_WINDOW_SHARE: f64 = 0.5;
+
+/// Gossip concurrency of the last completed gossip interval.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhaseSnapshot {
+    /// Share of receives arriving in the busiest 10ms window.
+    pub busiest_window_share: f64,
+    /// Times the local gossiper shifted its phase.
+    pub dephase_count: u64,
+    pub max_concurrent_receives: u64,
+    pub max_concurrent_sends: u64,
+    /// True when most peers gossiped within the same 10ms window.
+    pub phase_locked: bool,
+    pub receives: u64,
+    pub sends: u64,
+}
+
+struct PhaseState {
+    dephase_pending: bool,
+    interval: Duration,
+    last: PhaseSnapshot,
+    max_receives: u64,
+    max_sends: u64,
+    receives: u64,
+    sends: u64,
+    start: Instant,
+    windows: HashMap<u128, u64>,
+}
+
+/// Tracks how gossip exchanges are spread across each interval to
+/// detect phase locking, where members gossip in lockstep and swamp
+/// peers (typically the seed after a large restart). With auto-dephase
+/// enabled, members observing phase locking shift their own gossip
+/// phase by a random delay.
+pub struct PhaseTracker {
+    auto_dephase: AtomicBool,
+    receiving: AtomicU64,
+    sending: AtomicU64,
+    state: Mutex<PhaseState>,
+}
+
+impl PhaseTracker {
+    pub fn new(interval: Duration, now: Instant) -> PhaseTracker {
+        PhaseTracker {
+            auto_dephase: AtomicBool::new(false),
+            receiving: AtomicU64::new(0),
+            sending: AtomicU64::new(0),
+            state: Mutex::new(PhaseState {
+                dephase_pending: false,
+                interval,
+                last: PhaseSnapshot::default(),
+                max_receives: 0,
+                max_sends: 0,
+                receives: 0,
+                sends: 0,
+                start: now,
+                windows: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn set_auto_dephase(&self, auto_dephase: bool) {
+        self.auto_dephase.store(auto_dephase, Ordering::Relaxed);
+    }
+
+    /// Restarts tracking for a new gossip interval length.
+    pub fn reset(&self, interval: Duration, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = interval;
+        state.start = now;
+        state.windows.clear();
+        state.receives = 0;
+        state.sends = 0;
+    }
+
+    /// Records an inbound exchange lasting until the guard is dropped.
+    pub fn receive(&self, now: Instant) -> PhaseGuard<'_> {
+        let concurrent = self.receiving.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        let window = (now - state.start).as_millis() / WINDOW_MS;
+        *state.windows.entry(window).or_insert(0) += 1;
+        state.receives += 1;
+        state.max_receives = std::cmp::max(state.max_receives, concurrent);
+        PhaseGuard { counter: &self.receiving }
+    }
+
+    /// Records an outbound exchange lasting until the guard is dropped.
+    pub fn send(&self, now: Instant) -> PhaseGuard<'_> {
+        let concurrent = self.sending.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        state.sends += 1;
+        state.max_sends = std::cmp::max(state.max_sends, concurrent);
+        PhaseGuard { counter: &self.sending }
+    }
+
+    pub fn snapshot(&self, now: Instant) -> PhaseSnapshot {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        state.last.clone()
+    }
+
+    /// Returns a random delay to shift the gossip phase by once phase
+    /// locking was detected with auto-dephase enabled.
+    pub fn take_dephase(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+        if !state.dephase_pending {
+            return None;
+        }
+
+        state.dephase_pending = false;
+        state.last.dephase_count += 1;
+        Some(state.interval.mul_f64(rand::random::<f64>()))
+    }
+
+    fn roll(&self, state: &mut PhaseState, now: Instant) {
+        if now - state.start < state.interval {
+            return;
+        }
+
+        let busiest = state.windows.values().copied().max().unwrap_or(0);
+        let busiest_window_share = match state.receives {
+            0 => 0.0,
+            receives => busiest as f64 / receives as f64,
+        };
+        let phase_locked = state.receives >= LOCK_MIN_RECEIVES
+            && busiest_window_share >= LOCK_WINDOW_SHARE;
+        if phase_locked {
+            warn!("gossip phase locking detected [receives={}, busiest_window={}]",
+                state.receives, busiest);
+        }
+
+        state.last = PhaseSnapshot {
+            busiest_window_share,
+            dephase_count: state.last.dephase_count,
+            max_concurrent_receives: state.max_receives,
+            max_concurrent_sends: state.max_sends,
+            phase_locked,
+            receives: state.receives,
+            sends: state.sends,
+        };
+        state.dephase_pending = phase_locked
+            && self.auto_dephase.load(Ordering::Relaxed);
+
+        // intervals without exchanges are skipped entirely
+        let elapsed = (now - state.start).as_nanos()
+            / std::cmp::max(state.interval.as_nanos(), 1);
+        state.start += state.interval * elapsed as u32;
+        state.windows.clear();
+        state.max_receives = self.receiving.load(Ordering::Relaxed);
+        state.max_sends = self.sending.load(Ordering::Relaxed);
+        state.receives = 0;
+        state.sends = 0;
+    }
+}
+
+/// Ends a tracked exchange when dropped.
+pub struct PhaseGuard<'a> {
+    counter: &'a AtomicU64,
+}
+
+impl Drop for PhaseGuard<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhaseTracker;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn phase_locking() {
+        let interval = Duration::from_millis(1000);
+        let start = Instant::now();
+        let tracker = PhaseTracker::new(interval, start);
+        tracker.set_auto_dephase(true);
+
+        // spread receives are not locked
+        for i in 0..5 {
+            let _guard = tracker.receive(start + Duration::from_millis(i * 150));
+        }
+        let snapshot = tracker.snapshot(start + interval);
+        assert_eq!(snapshot.receives, 5);
+        assert!(!snapshot.phase_locked);
+        assert!(tracker.take_dephase(start + interval).is_none());
+
+        // concurrent receives in one window are
+        let offset = start + interval + Duration::from_millis(500);
+        let guards: Vec<_> = (0..6).map(|i|
+            tracker.receive(offset + Duration::from_millis(i))).collect();
+        let _send_guard = tracker.send(offset);
+        drop(guards);
+
+        let snapshot = tracker.snapshot(start + interval * 2);
+        assert!(snapshot.phase_locked);
+        assert_eq!((snapshot.max_concurrent_receives,
+            snapshot.max_concurrent_sends), (6, 1));
+        let delay = tracker.take_dephase(start + interval * 2)
+            .expect("dephase");
+        assert!(delay < interval);
+        assert!(tracker.take_dephase(start + interval * 2).is_none());
+        assert_eq!(tracker.snapshot(start + interval * 2).dephase_count, 1);
+    }
+}
@@ -0,0 +1,181 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec;
+use crate::node;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::{Mutex, RwLock};
+
+/// Largest value accepted by Swarm::piggyback.
+pub const MAX_VALUE_BYTES: usize = 1024;
+// combined value bytes of every piggybacked key
+const MAX_TOTAL_BYTES: usize = 8 * 1024;
+
+// key -> (version, value)
+type Entries = BTreeMap<String, (u64, Vec<u8>)>;
+type Handler = Box<dyn Fn(u32, &str, &[u8]) + Send + Sync>;
+
+/// Application key / value blobs piggybacked on gossip exchanges, such
+/// as load hints. Every exchange carries the values the peer has not
+/// received from this member yet, in both directions, and receivers
+/// pass them to the handler with the sending member's id. Values only
+/// reach direct gossip partners and are never forwarded.
+pub struct Piggyback {
+    delivered: Mutex<HashMap<u32, HashMap<String, u64>>>,
+    entries: Mutex<(u64, Entries)>,
+    handler: RwLock<Option<Handler>>,
+}
+
+impl Piggyback {
+    pub fn new() -> Piggyback {
+        Piggyback {
+            delivered: Mutex::new(HashMap::new()),
+            entries: Mutex::new((0, BTreeMap::new())),
+            handler: RwLock::new(None),
+        }
+    }
+
+    pub fn set_handler(&self, handler: Handler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Replaces the value of `key`, sending it with the next exchange
+    /// with every peer.
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        if key.len() > u8::MAX as usize || value.len() > MAX_VALUE_BYTES {
+            return Err(format!("piggyback entry too large [key={}, length={}]",
+                key, value.len()).into());
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let (version, entries) = &mut *entries;
+        let total: usize = entries.iter()
+            .filter(|(entry_key, _)| entry_key.as_str() != key)
+            .map(|(_, (_, value))| value.len()).sum();
+        if total + value.len() > MAX_TOTAL_BYTES {
+            return Err(format!("piggyback entries too large [length={}]",
+                total + value.len()).into());
+        }
+
+        *version += 1;
+        entries.insert(key.to_string(), (*version, value.to_vec()));
+        Ok(())
+    }
+
+    /// Stops sending `key`. Peers keep the last value they received.
+    pub fn remove(&self, key: &str) -> bool {
+        self.entries.lock().unwrap().1.remove(key).is_some()
+    }
+
+    /// Reads the frame of values piggybacked by a peer, passing each to
+    /// the handler. Corrupt frames are dropped.
+    pub fn read(&self, reader: &mut impl Read) -> Result<(), Box<dyn Error>> {
+        // corrupt frames are skipped -> the stream stays in sync
+        let frame = match codec::read_frame(reader)? {
+            Some(frame) => frame,
+            None => {
+                warn!("skipping corrupt piggyback frame");
+                return Ok(());
+            },
+        };
+
+        let reader = &mut frame.as_slice();
+        let sender = reader.read_u32::<BigEndian>()?;
+        let count = reader.read_u16::<BigEndian>()?;
+        for _ in 0..count {
+            let key = node::read_string(reader)?;
+            let length = reader.read_u32::<BigEndian>()? as usize;
+            if length > MAX_VALUE_BYTES {
+                return Err(format!("piggyback entry too large [key={}, length={}]",
+                    key, length).into());
+            }
+
+            let mut value = vec![0; length];
+            reader.read_exact(&mut value)?;
+            debug!("received piggyback entry [sender={}, key={}, length={}]",
+                sender, key, length);
+            if let Some(handler) = self.handler.read().unwrap().as_ref() {
+                handler(sender, &key, &value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the values `peer_id` has not received from the local
+    /// member `id`. Unknown peers, such as seeds, receive every value.
+    pub fn write(&self, id: u32, peer_id: Option<u32>,
+            writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let batch: Vec<(String, Vec<u8>)> = {
+            let entries = self.entries.lock().unwrap();
+            let mut delivered = self.delivered.lock().unwrap();
+            let mut delivered = peer_id.map(|peer_id|
+                delivered.entry(peer_id).or_default());
+            entries.1.iter().filter(|(key, (version, _))| {
+                match delivered.as_mut() {
+                    Some(delivered) => delivered.insert(key.to_string(),
+                        *version) != Some(*version),
+                    None => true,
+                }
+            }).map(|(key, (_, value))| (key.clone(), value.clone())).collect()
+        };
+
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(id)?;
+        buf.write_u16::<BigEndian>(batch.len() as u16)?;
+        for (key, value) in batch.iter() {
+            node::write_string(key, &mut buf)?;
+            buf.write_u32::<BigEndian>(value.len() as u32)?;
+            buf.write_all(value)?;
+        }
+
+        codec::write_frame(writer, &buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Piggyback;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn piggyback_entries() {
+        let sender = Piggyback::new();
+        let receiver = Piggyback::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        receiver.set_handler(Box::new(move |sender, key, value|
+            received_clone.lock().unwrap()
+                .push((sender, key.to_string(), value.to_vec()))));
+        assert!(sender.set("load", &[0; 2048]).is_err());
+        sender.set("load", b"0.5").expect("set");
+        sender.set("zone", b"a").expect("set");
+
+        let exchange = |peer_id| {
+            let mut buf = Vec::new();
+            sender.write(0, peer_id, &mut buf).expect("write");
+            receiver.read(&mut buf.as_slice()).expect("read");
+            received.lock().unwrap().drain(..)
+                .map(|(sender, key, value)| (sender, key,
+                    String::from_utf8(value).expect("utf8")))
+                .collect::<Vec<_>>()
+        };
+
+        // known peers receive each value once
+        assert_eq!(exchange(Some(1)), vec!((0, "load".to_string(),
+            "0.5".to_string()), (0, "zone".to_string(), "a".to_string())));
+        assert!(exchange(Some(1)).is_empty());
+
+        sender.set("load", b"0.7").expect("set");
+        assert!(sender.remove("zone"));
+        assert_eq!(exchange(Some(1)),
+            vec!((0, "load".to_string(), "0.7".to_string())));
+
+        // unknown peers receive every value
+        assert_eq!(exchange(None).len(), 1);
+        assert_eq!(exchange(None).len(), 1);
+    }
+}
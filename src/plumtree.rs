@@ -0,0 +1,425 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::Clock;
+use crate::codec;
+use crate::exchange::PLUMTREE_EXCHANGE;
+use crate::node::{Node, NodeMap, NodeState};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+// rejects garbage lengths before allocating
+const MAX_PAYLOAD_BYTES: u32 = 64 * 1024;
+// delivered messages kept for answering grafts
+const RECEIVED_CAPACITY: usize = 1024;
+
+// message kinds
+const GOSSIP: u8 = 0;
+const IHAVE: u8 = 1;
+const GRAFT: u8 = 2;
+const PRUNE: u8 = 3;
+
+type Handler = Box<dyn Fn(u32, &[u8]) + Send + Sync>;
+
+#[derive(Clone, Debug)]
+struct TreeMessage {
+    id: u64,
+    kind: u8,
+    origin: u32,
+    payload: Vec<u8>,
+    round: u32,
+}
+
+impl TreeMessage {
+    fn control(kind: u8, id: u64, round: u32) -> TreeMessage {
+        TreeMessage { id, kind, origin: 0, payload: Vec::new(), round }
+    }
+
+    /// Reads a message along with the id of its sender.
+    fn read(reader: &mut impl Read)
+            -> Result<(u32, TreeMessage), Box<dyn Error>> {
+        let sender = reader.read_u32::<BigEndian>()?;
+        let kind = reader.read_u8()?;
+        let id = reader.read_u64::<BigEndian>()?;
+        let origin = reader.read_u32::<BigEndian>()?;
+        let round = reader.read_u32::<BigEndian>()?;
+        let length = reader.read_u32::<BigEndian>()?;
+        if length > MAX_PAYLOAD_BYTES {
+            return Err(format!("broadcast payload too large [length={}]",
+                length).into());
+        }
+
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        Ok((sender, TreeMessage { id, kind, origin, payload, round }))
+    }
+
+    fn write(&self, sender: u32, writer: &mut impl Write)
+            -> Result<(), Box<dyn Error>> {
+        writer.write_u32::<BigEndian>(sender)?;
+        writer.write_u8(self.kind)?;
+        writer.write_u64::<BigEndian>(self.id)?;
+        writer.write_u32::<BigEndian>(self.origin)?;
+        writer.write_u32::<BigEndian>(self.round)?;
+        writer.write_u32::<BigEndian>(self.payload.len() as u32)?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TreeState {
+    eager: HashSet<u32>,
+    lazy: HashSet<u32>,
+    // announcements queued for the next tick
+    lazy_queue: Vec<(u32, TreeMessage)>,
+    // announced but undelivered messages -> announcers to graft from
+    missing: HashMap<u64, (Instant, VecDeque<(u32, u32)>)>,
+    received: HashMap<u64, TreeMessage>,
+    received_order: VecDeque<u64>,
+}
+
+/// Epidemic broadcast tree (Plumtree) over swarm membership. Messages
+/// are pushed eagerly along tree links and announced lazily on the
+/// remaining links. Members receiving a duplicate prune the link it
+/// arrived on, so the eager links converge to a spanning tree, and
+/// members missing an announced message graft the announcing link back
+/// after the graft timeout, repairing the tree.
+pub struct Plumtree {
+    graft_timeout: Duration,
+    handler: RwLock<Option<Handler>>,
+    id: u32,
+    nodes: Arc<NodeMap>,
+    state: Mutex<TreeState>,
+    timeout: Duration,
+}
+
+impl Plumtree {
+    pub fn new(id: u32, nodes: Arc<NodeMap>, graft_timeout: Duration)
+            -> Plumtree {
+        Plumtree {
+            graft_timeout,
+            handler: RwLock::new(None),
+            id,
+            nodes,
+            state: Mutex::new(TreeState::default()),
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Returns the ids of eager and lazy links.
+    pub fn links(&self) -> (Vec<u32>, Vec<u32>) {
+        let state = self.state.lock().unwrap();
+        let mut eager: Vec<u32> = state.eager.iter().copied().collect();
+        let mut lazy: Vec<u32> = state.lazy.iter().copied().collect();
+        eager.sort_unstable();
+        lazy.sort_unstable();
+        (eager, lazy)
+    }
+
+    /// Calls `handler` with the origin and payload of every message
+    /// broadcast by other members.
+    pub fn on_message<F: 'static + Fn(u32, &[u8]) + Send + Sync>(
+            &self, handler: F) {
+        *self.handler.write().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Broadcasts `payload` to every member, returning the message id.
+    pub fn broadcast(&self, payload: &[u8]) -> u64 {
+        let members = self.nodes.nodes();
+        let (id, outbound) = {
+            let mut state = self.state.lock().unwrap();
+            self.sync(&mut state, &members);
+            let message = TreeMessage {
+                id: rand::random::<u64>(),
+                kind: GOSSIP,
+                origin: self.id,
+                payload: payload.to_vec(),
+                round: 0,
+            };
+
+            (message.id, self.push(&mut state, None, message))
+        };
+
+        self.deliver(&members, outbound);
+        id
+    }
+
+    /// Handles a message sent by another member.
+    pub fn receive<S: Read + Write>(&self, stream: &mut S, now: Instant)
+            -> Result<(), Box<dyn Error>> {
+        let (sender, message) = TreeMessage::read(stream)?;
+        let outbound = self.handle(sender, message, now);
+        self.deliver(&self.nodes.nodes(), outbound);
+        Ok(())
+    }
+
+    /// Flushes queued announcements and grafts messages announced
+    /// but not received within the graft timeout.
+    pub fn tick(&self, now: Instant) {
+        let members = self.nodes.nodes();
+        let outbound = {
+            let mut state = self.state.lock().unwrap();
+            self.sync(&mut state, &members);
+            self.expire(&mut state, now)
+        };
+
+        self.deliver(&members, outbound);
+    }
+
+    fn dispatch(&self, message: &TreeMessage) {
+        if let Some(handler) = self.handler.read().unwrap().as_ref() {
+            handler(message.origin, &message.payload);
+        }
+    }
+
+    fn expire(&self, state: &mut TreeState, now: Instant)
+            -> Vec<(u32, TreeMessage)> {
+        let mut outbound: Vec<(u32, TreeMessage)> =
+            state.lazy_queue.drain(..).collect();
+
+        let graft_timeout = self.graft_timeout;
+        let mut grafts = Vec::new();
+        for (id, (announced, announcers)) in state.missing.iter_mut() {
+            if now - *announced < graft_timeout {
+                continue;
+            }
+
+            // retry with the next announcer after another timeout
+            if let Some((peer, round)) = announcers.pop_front() {
+                *announced = now;
+                grafts.push((peer, TreeMessage::control(GRAFT, *id, round)));
+            }
+        }
+
+        state.missing.retain(|_, (_, announcers)| !announcers.is_empty());
+        for (peer, message) in grafts {
+            debug!("grafting broadcast link [id={}, message_id={}]",
+                peer, message.id);
+            state.lazy.remove(&peer);
+            state.eager.insert(peer);
+            outbound.push((peer, message));
+        }
+
+        outbound
+    }
+
+    fn handle(&self, sender: u32, message: TreeMessage, now: Instant)
+            -> Vec<(u32, TreeMessage)> {
+        let mut state = self.state.lock().unwrap();
+        match message.kind {
+            GOSSIP if state.received.contains_key(&message.id) => {
+                // duplicate -> demote the link it arrived on
+                debug!("pruning broadcast link [id={}, message_id={}]",
+                    sender, message.id);
+                state.eager.remove(&sender);
+                state.lazy.insert(sender);
+                vec!((sender, TreeMessage::control(PRUNE,
+                    message.id, message.round)))
+            },
+            GOSSIP => {
+                state.missing.remove(&message.id);
+                state.lazy.remove(&sender);
+                state.eager.insert(sender);
+                drop(state);
+                self.dispatch(&message);
+
+                let mut state = self.state.lock().unwrap();
+                let message = TreeMessage { round: message.round + 1,
+                    ..message };
+                self.push(&mut state, Some(sender), message)
+            },
+            IHAVE => {
+                if !state.received.contains_key(&message.id) {
+                    state.missing.entry(message.id)
+                        .or_insert_with(|| (now, VecDeque::new())).1
+                        .push_back((sender, message.round));
+                }
+
+                Vec::new()
+            },
+            GRAFT => {
+                state.lazy.remove(&sender);
+                state.eager.insert(sender);
+                match state.received.get(&message.id) {
+                    Some(message) => vec!((sender, message.clone())),
+                    None => Vec::new(),
+                }
+            },
+            PRUNE => {
+                state.eager.remove(&sender);
+                state.lazy.insert(sender);
+                Vec::new()
+            },
+            kind => {
+                debug!("unknown broadcast message kind [kind={}]", kind);
+                Vec::new()
+            },
+        }
+    }
+
+    /// Records `message` as received, returning the eager pushes and
+    /// queueing announcements for lazy links, excluding `sender`.
+    fn push(&self, state: &mut TreeState, sender: Option<u32>,
+            message: TreeMessage) -> Vec<(u32, TreeMessage)> {
+        let outbound = state.eager.iter()
+            .filter(|peer| Some(**peer) != sender)
+            .map(|peer| (*peer, message.clone())).collect();
+        let announcement = TreeMessage::control(IHAVE,
+            message.id, message.round);
+        let announcements: Vec<(u32, TreeMessage)> = state.lazy.iter()
+            .filter(|peer| Some(**peer) != sender)
+            .map(|peer| (*peer, announcement.clone())).collect();
+        state.lazy_queue.extend(announcements);
+
+        state.received_order.push_back(message.id);
+        state.received.insert(message.id, message);
+        if state.received_order.len() > RECEIVED_CAPACITY {
+            if let Some(id) = state.received_order.pop_front() {
+                state.received.remove(&id);
+            }
+        }
+
+        outbound
+    }
+
+    /// Links new alive members eagerly and drops departed ones.
+    fn sync(&self, state: &mut TreeState, members: &[Node]) {
+        let alive: HashSet<u32> = members.iter()
+            .filter(|node| node.get_id() != self.id
+                && node.state() == NodeState::Alive)
+            .map(|node| node.get_id()).collect();
+        state.eager.retain(|peer| alive.contains(peer));
+        state.lazy.retain(|peer| alive.contains(peer));
+        for peer in alive {
+            if !state.lazy.contains(&peer) {
+                state.eager.insert(peer);
+            }
+        }
+    }
+
+    fn deliver(&self, members: &[Node], outbound: Vec<(u32, TreeMessage)>) {
+        for (peer, message) in outbound {
+            let address = match members.iter()
+                    .find(|node| node.get_id() == peer) {
+                Some(node) => node.get_address(),
+                None => continue,
+            };
+
+            if let Err(e) = self.send(&address, &message) {
+                debug!("broadcast delivery failure [id={}]: {}", peer, e);
+            }
+        }
+    }
+
+    fn send(&self, address: &SocketAddr, message: &TreeMessage)
+            -> Result<(), Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(address, self.timeout)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        codec::write_cluster_header(&mut stream, rand::random::<u64>(),
+            PLUMTREE_EXCHANGE, &self.nodes.get_cluster_name(),
+            self.nodes.get_cluster_epoch())?;
+        message.write(self.id, &mut stream)?;
+        Ok(())
+    }
+}
+
+/// Flushes announcements and grafts every `interval` until shutdown.
+pub fn run(plumtree: Arc<Plumtree>, clock: Arc<dyn Clock>,
+        interval: Duration, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        plumtree.tick(clock.now());
+        clock.sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap};
+    use super::{Plumtree, TreeMessage, GOSSIP, GRAFT, IHAVE, PRUNE};
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn broadcast_tree() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..4 {
+            nodes.insert(Node::new(id, ip_address, 12000 + id as u16));
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let trees: Vec<Plumtree> = (0..4).map(|id| {
+            let tree = Plumtree::new(id, nodes.clone(),
+                Duration::from_millis(100));
+            let received = received.clone();
+            tree.on_message(move |origin, payload|
+                received.lock().unwrap().push((id, origin, payload.to_vec())));
+            tree.sync(&mut tree.state.lock().unwrap(), &nodes.nodes());
+            tree
+        }).collect();
+
+        // deliver messages in memory until quiescent
+        let now = Instant::now();
+        let run = |sender: u32, outbound: Vec<(u32, TreeMessage)>| {
+            let mut queue: VecDeque<(u32, u32, TreeMessage)> = outbound
+                .into_iter().map(|(peer, message)| (sender, peer, message))
+                .collect();
+            let mut kinds = Vec::new();
+            while let Some((sender, peer, message)) = queue.pop_front() {
+                kinds.push(message.kind);
+                let outbound = trees[peer as usize]
+                    .handle(sender, message, now);
+                queue.extend(outbound.into_iter()
+                    .map(|(next, message)| (peer, next, message)));
+            }
+            kinds
+        };
+        let flood = |tree: &Plumtree, payload: &[u8]| {
+            let mut state = tree.state.lock().unwrap();
+            let message = TreeMessage { id: rand::random::<u64>(),
+                kind: GOSSIP, origin: tree.id, payload: payload.to_vec(),
+                round: 0 };
+            tree.push(&mut state, None, message)
+        };
+
+        // the first broadcast floods and prunes redundant links
+        let kinds = run(0, flood(&trees[0], b"first"));
+        assert!(kinds.contains(&PRUNE));
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        // later broadcasts follow the tree without duplicates
+        received.lock().unwrap().clear();
+        let kinds = run(0, flood(&trees[0], b"second"));
+        assert_eq!(kinds.iter().filter(|kind| **kind == GOSSIP).count(), 3);
+        assert!(!kinds.contains(&PRUNE));
+        let mut ids: Vec<u32> = received.lock().unwrap().iter()
+            .map(|(id, origin, payload)| {
+                assert_eq!((*origin, payload.as_slice()), (0, &b"second"[..]));
+                *id
+            }).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec!(1, 2, 3));
+
+        // announced but missing messages are grafted after the timeout
+        let tree = &trees[1];
+        let outbound = tree.handle(2, TreeMessage::control(IHAVE, 7, 1), now);
+        assert!(outbound.is_empty());
+        let mut state = tree.state.lock().unwrap();
+        assert!(tree.expire(&mut state, now).iter()
+            .all(|(_, message)| message.kind == IHAVE));
+        let outbound = tree.expire(&mut state, now + Duration::from_millis(100));
+        assert!(matches!(outbound.as_slice(),
+            [(2, message)] if message.kind == GRAFT && message.id == 7));
+        assert!(state.eager.contains(&2));
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Gossip connections kept open between rounds, keyed by peer address.
+/// The gossiper checks a connection out for each round and back in
+/// once the round succeeds, so consecutive rounds to the same peer
+/// share one connection. Connections idle longer than `idle_timeout`
+/// are closed.
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<SocketAddr, (TcpStream, Instant)>>,
+    idle_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            connections: Mutex::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    /// Returns the idle connection to `address`, if one has not expired.
+    pub fn checkout(&self, address: &SocketAddr, now: Instant)
+            -> Option<TcpStream> {
+        let mut connections = self.connections.lock().unwrap();
+        self.expire(&mut connections, now);
+        connections.remove(address).map(|(stream, _)| stream)
+    }
+
+    /// Returns `stream` to the pool after a successful round.
+    pub fn checkin(&self, address: SocketAddr, stream: TcpStream,
+            now: Instant) {
+        let mut connections = self.connections.lock().unwrap();
+        self.expire(&mut connections, now);
+        connections.insert(address, (stream, now));
+    }
+
+    pub fn get_idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Returns the number of idle connections.
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn expire(&self, connections: &mut HashMap<SocketAddr,
+            (TcpStream, Instant)>, now: Instant) {
+        connections.retain(|address, (_, last_used)| {
+            let expired = now.saturating_duration_since(*last_used)
+                >= self.idle_timeout;
+            if expired {
+                debug!("closing idle gossip connection [address={}]",
+                    address);
+            }
+
+            !expired
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, Clock, ManualClock, SystemClock};
+    use crate::prelude::{Cluster, ClusterBuilder, Swarm};
+    use super::ConnectionPool;
+
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    #[test]
+    fn pool_expiry() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = listener.local_addr().expect("local addr");
+        let clock = ManualClock::new(0);
+        let pool = ConnectionPool::new(Duration::from_millis(100));
+
+        let stream = TcpStream::connect(address).expect("connect");
+        pool.checkin(address, stream, clock.now());
+        assert_eq!(pool.len(), 1);
+        assert!(pool.checkout(&address, clock.now()).is_some());
+        assert!(pool.checkout(&address, clock.now()).is_none());
+
+        // idle connections are closed
+        let stream = TcpStream::connect(address).expect("connect");
+        pool.checkin(address, stream, clock.now());
+        clock.advance(Duration::from_millis(100));
+        assert!(pool.checkout(&address, clock.now()).is_none());
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn pooled_gossip() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        let mut clusters = Vec::new();
+        for i in 0..3 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, cluster) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            swarm.set_connection_pool(Duration::from_secs(5));
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            clusters.push(cluster);
+        }
+
+        // rounds reuse the pooled connection of each peer
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            swarms.iter().zip(clusters.iter()).all(|(swarm, cluster)|
+                cluster.nodes().len() == 3 && swarm.pooled_connections() > 0
+                    && swarm.metrics().rounds_succeeded > 3)),
+            "pooled rounds");
+        for (swarm, cluster) in swarms.iter().zip(clusters.iter()) {
+            assert_eq!(cluster.nodes().len(), 3);
+            assert!(swarm.pooled_connections() > 0);
+            let metrics = swarm.metrics();
+            assert!(metrics.rounds_succeeded > 3);
+            assert_eq!(metrics.rounds_failed, 0);
+        }
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::clock::Clock;
+
+use std::fmt;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+const DIAL_TIMEOUT_MS: u64 = 500;
+// descriptors reserved beyond gossip connections for the application
+const FD_HEADROOM: u64 = 64;
+// 2020-01-01 -> earlier wall clocks are unset or badly skewed
+const MIN_TIMESTAMP_MS: u64 = 1_577_836_800_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreflightStatus {
+    Pass,
+    /// Likely to cause trouble, but does not prevent starting.
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug)]
+pub struct PreflightCheck {
+    pub detail: String,
+    pub name: &'static str,
+    pub status: PreflightStatus,
+}
+
+/// Results of Swarm::preflight, in the order the checks ran.
+#[derive(Clone, Debug)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Returns true unless a check failed.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter()
+            .all(|check| check.status != PreflightStatus::Fail)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PreflightCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in self.checks.iter() {
+            let status = match check.status {
+                PreflightStatus::Pass => "ok",
+                PreflightStatus::Warn => "warn",
+                PreflightStatus::Fail => "FAIL",
+            };
+            writeln!(f, "{:<5} {:<18} {}", status, check.name, check.detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn check(name: &'static str, status: PreflightStatus, detail: String)
+        -> PreflightCheck {
+    PreflightCheck { detail, name, status }
+}
+
+/// Binds the gossip address, returning the listener for the advertise
+/// check. Running swarms already hold the address.
+pub fn bind(address: &SocketAddr, running: bool)
+        -> (PreflightCheck, Option<TcpListener>) {
+    if running {
+        return (check("bind", PreflightStatus::Pass,
+            format!("{} held by the running swarm", address)), None);
+    }
+
+    match TcpListener::bind(address) {
+        Ok(listener) => (check("bind", PreflightStatus::Pass,
+            format!("bound {}", address)), Some(listener)),
+        Err(e) => (check("bind", PreflightStatus::Fail,
+            format!("cannot bind {}: {}", address, e)), None),
+    }
+}
+
+/// Dials the advertised address through `listener`, or the running
+/// swarm when there is none.
+pub fn advertise(address: &SocketAddr, listener: Option<&TcpListener>)
+        -> PreflightCheck {
+    let target = match listener.map(|listener| listener.local_addr()) {
+        Some(Ok(local)) => SocketAddr::new(address.ip(), local.port()),
+        Some(Err(e)) => return check("advertise", PreflightStatus::Fail,
+            format!("listener address unavailable: {}", e)),
+        None if address.port() == 0 => return check("advertise",
+            PreflightStatus::Fail, "no bound listener to dial".to_string()),
+        None => *address,
+    };
+
+    if address.ip().is_unspecified() {
+        return check("advertise", PreflightStatus::Fail, format!(
+            "{} is unspecified -> peers cannot dial it", address.ip()));
+    }
+
+    let timeout = Duration::from_millis(DIAL_TIMEOUT_MS);
+    match TcpStream::connect_timeout(&target, timeout) {
+        Ok(_) => check("advertise", PreflightStatus::Pass,
+            format!("dialed {}", target)),
+        Err(e) => check("advertise", PreflightStatus::Fail,
+            format!("cannot dial {}: {}", target, e)),
+    }
+}
+
+/// Checks the wall clock is set and the monotonic clock advances.
+pub fn clock(clock: &dyn Clock) -> PreflightCheck {
+    let timestamp = clock.timestamp();
+    if timestamp < MIN_TIMESTAMP_MS {
+        return check("clock", PreflightStatus::Fail, format!(
+            "wall clock reads {}ms since epoch -> unset", timestamp));
+    }
+
+    let start = clock.now();
+    clock.sleep(Duration::from_millis(1));
+    if clock.now() <= start {
+        return check("clock", PreflightStatus::Warn,
+            "monotonic clock did not advance".to_string());
+    }
+
+    check("clock", PreflightStatus::Pass,
+        format!("wall clock {}ms since epoch", timestamp))
+}
+
+/// Compares the open file soft limit against the descriptors needed
+/// by `thread_count` listener threads and `peer_count` peers.
+pub fn file_descriptors(limits: Option<(u64, u64)>, thread_count: u8,
+        peer_count: usize) -> PreflightCheck {
+    let (limit, open) = match limits {
+        Some(limits) => limits,
+        None => return check("file_descriptors", PreflightStatus::Pass,
+            "limits unavailable on this platform".to_string()),
+    };
+
+    // each peer may hold a gossip and keepalive connection at once
+    let required = thread_count as u64 + peer_count as u64 * 2 + FD_HEADROOM;
+    let available = limit.saturating_sub(open);
+    let status = match available {
+        available if available < required => PreflightStatus::Fail,
+        available if available < required * 2 => PreflightStatus::Warn,
+        _ => PreflightStatus::Pass,
+    };
+
+    check("file_descriptors", status, format!(
+        "{} of {} available, {} required", available, limit, required))
+}
+
+/// Returns the open file soft limit and open descriptor count.
+pub fn process_limits() -> Option<(u64, u64)> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let limit = parse_open_files(&limits)?;
+    let open = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    Some((limit, open))
+}
+
+fn parse_open_files(limits: &str) -> Option<u64> {
+    let line = limits.lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    match line["Max open files".len()..].split_whitespace().next()? {
+        "unlimited" => Some(u64::MAX),
+        soft => soft.parse().ok(),
+    }
+}
+
+/// Dials every seed, warning when none answer: seeds may simply not be
+/// up yet when a swarm starts at once.
+pub fn seeds(seeds: &[SocketAddr]) -> PreflightCheck {
+    if seeds.is_empty() {
+        return check("seeds", PreflightStatus::Pass,
+            "no seeds -> starting a new swarm".to_string());
+    }
+
+    let timeout = Duration::from_millis(DIAL_TIMEOUT_MS);
+    let unreachable: Vec<String> = seeds.iter()
+        .filter(|seed| TcpStream::connect_timeout(seed, timeout).is_err())
+        .map(|seed| seed.to_string()).collect();
+    match unreachable.len() {
+        0 => check("seeds", PreflightStatus::Pass,
+            format!("{} reachable", seeds.len())),
+        count if count == seeds.len() => check("seeds", PreflightStatus::Warn,
+            format!("none reachable: {}", unreachable.join(", "))),
+        _ => check("seeds", PreflightStatus::Pass,
+            format!("unreachable: {}", unreachable.join(", "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use super::PreflightStatus;
+
+    use std::net::{SocketAddr, TcpListener};
+
+    #[test]
+    fn preflight_checks() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let address = SocketAddr::new(ip_address, 0);
+        let (check, listener) = super::bind(&address, false);
+        assert_eq!(check.status, PreflightStatus::Pass);
+        let listener = listener.expect("listener");
+        assert_eq!(super::advertise(&address, Some(&listener)).status,
+            PreflightStatus::Pass);
+
+        // taken addresses cannot be bound
+        let taken = listener.local_addr().expect("local addr");
+        let (check, _) = super::bind(&taken, false);
+        assert_eq!(check.status, PreflightStatus::Fail);
+        assert_eq!(super::seeds(&[taken]).status, PreflightStatus::Pass);
+        drop(listener);
+
+        let free = TcpListener::bind(address).expect("bind")
+            .local_addr().expect("local addr");
+        assert_eq!(super::seeds(&[free]).status, PreflightStatus::Warn);
+
+        // unset wall clocks fail
+        assert_eq!(super::clock(&ManualClock::new(0)).status,
+            PreflightStatus::Fail);
+        assert_eq!(super::clock(&ManualClock::new(1_700_000_000_000)).status,
+            PreflightStatus::Pass);
+
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \nMax open files            1024                 524288               files     \n";
+        assert_eq!(super::parse_open_files(limits), Some(1024));
+        assert_eq!(super::file_descriptors(Some((1024, 10)), 4, 100).status,
+            PreflightStatus::Pass);
+        assert_eq!(super::file_descriptors(Some((256, 10)), 4, 100).status,
+            PreflightStatus::Fail);
+    }
+}
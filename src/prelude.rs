@@ -1,3 +1,98 @@
-pub use crate::Swarm;
-pub use crate::topology::cluster::ClusterBuilder;
+//! Stable public API. Every type users name is re-exported here, while
+//! the modules defining them stay private so internals can move freely.
+//! Enums and configuration structs which may grow are
+//! `#[non_exhaustive]`.
+
+// membership
+#[cfg(feature = "net")]
+pub use crate::{Swarm, TimeoutError};
+#[cfg(feature = "net")]
+pub use crate::builder::SwarmBuilder;
+#[cfg(feature = "net")]
+pub use crate::config::SwarmConfig;
+pub use crate::node::{MetadataBatch, Node, NodeState, PeerStats,
+    TieBreaker, DRAINING_KEY};
+
+// topologies
+pub use crate::ring::{DhtSnapshot, RangeMovement, RingHasher, RingOperation,
+    RingPlan, TokenChange, XxHasher};
+#[cfg(feature = "net")]
+pub use crate::topology::{OBSERVER_KEY, Topology};
+#[cfg(feature = "net")]
+pub use crate::topology::cluster::{Cluster, ClusterBuilder, ClusterSnapshot};
+#[cfg(feature = "net")]
 pub use crate::topology::dht::{Dht, DhtBuilder};
+#[cfg(feature = "net")]
+pub use crate::topology::hyparview::{HyParView, HyParViewBuilder};
+#[cfg(feature = "net")]
+pub use crate::topology::policy::{Expression, MembershipPolicy};
+#[cfg(feature = "net")]
+pub use crate::topology::ring::{Chord, ChordBuilder};
+#[cfg(feature = "net")]
+pub use crate::topology::selector::{BRIDGE_KEY, DATACENTER_KEY,
+    LatencySelector, PeerSelector, ProximitySelector, RandomSelector,
+    RoundRobinSelector, StalenessSelector, TieredSelector};
+
+// events and services
+#[cfg(feature = "net")]
+pub use crate::admin::{AdminClient, AdminMember};
+#[cfg(feature = "net")]
+pub use crate::control::ControlMessage;
+#[cfg(feature = "discover")]
+pub use crate::discover::Discovery;
+#[cfg(feature = "net")]
+pub use crate::distribution::{ConfigDistribution, ConfigEntry, Rollout};
+#[cfg(feature = "net")]
+pub use crate::drain::ConnectionHandle;
+#[cfg(feature = "net")]
+pub use crate::federation::{ClusterSummary, Federation};
+#[cfg(feature = "net")]
+pub use crate::journal::{MembershipDelta, Subscription};
+#[cfg(feature = "k8s")]
+pub use crate::k8s::KubernetesSeeds;
+#[cfg(feature = "net")]
+pub use crate::keepalive::KeepaliveEvent;
+#[cfg(feature = "net")]
+pub use crate::namespace::MetadataNamespace;
+#[cfg(feature = "net")]
+pub use crate::partition::PartitionEvent;
+#[cfg(feature = "net")]
+pub use crate::plumtree::Plumtree;
+#[cfg(all(feature = "net", feature = "tokio"))]
+pub use crate::service::asynchronous::{AsyncError, AsyncService,
+    AsyncSwarmService};
+#[cfg(feature = "net")]
+pub use crate::service::dispatch::SwarmService;
+#[cfg(feature = "net")]
+pub use crate::service::election::Election;
+#[cfg(feature = "net")]
+pub use crate::service::lock::{LockLease, LockService};
+#[cfg(feature = "net")]
+pub use crate::service::pubsub::PubSub;
+#[cfg(feature = "net")]
+pub use crate::webhook::{ClusterEvent, Webhook};
+
+// observability
+#[cfg(feature = "net")]
+pub use crate::metrics::{MetricsSnapshot, PeerMetrics};
+#[cfg(feature = "net")]
+pub use crate::phase::PhaseSnapshot;
+#[cfg(feature = "net")]
+pub use crate::preflight::{PreflightCheck, PreflightReport, PreflightStatus};
+
+// infrastructure
+#[cfg(feature = "net")]
+pub use crate::cidr::Cidr;
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::merkle::MerkleTree;
+pub use crate::secret::Secret;
+pub use crate::store::{StateStore, EPOCH_KEY, IDENTITY_KEY,
+    INCARNATION_KEY, LOCAL_TOKENS_KEY, MEMBERSHIP_KEY, TOKENS_KEY,
+    TOMBSTONES_KEY};
+pub use crate::store::file::FileStore;
+pub use crate::store::memory::MemoryStore;
+#[cfg(feature = "net")]
+pub use crate::transport::{MemoryListener, MemoryStream, MemoryTransport,
+    TcpTransport, Transport};
+#[cfg(feature = "sled")]
+pub use crate::store::sled::SledStore;
@@ -0,0 +1,154 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec;
+use crate::exchange::PROBE_EXCHANGE;
+use crate::node::{NodeMap, NodeState};
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Indirect probing, as in SWIM's ping-req. When a gossip round with a
+/// peer fails, `count` other alive members are asked to reach the peer
+/// on our behalf, and any one succeeding vouches for it, so a broken
+/// route between two members alone does not get the peer suspected.
+#[derive(Clone, Copy, Debug)]
+pub struct IndirectProbes {
+    count: usize,
+    timeout: Duration,
+}
+
+impl IndirectProbes {
+    pub fn new(count: usize, timeout: Duration) -> IndirectProbes {
+        IndirectProbes { count, timeout }
+    }
+
+    /// Asks up to `count` random alive members of `nodes`, other than
+    /// the local node `id`, to probe member `target_id` in parallel.
+    /// Returns true if any of them reached it.
+    pub fn probe(&self, id: u32, target_id: u32, nodes: &NodeMap) -> bool {
+        let target = match nodes.get(target_id) {
+            Some(node) => node.get_address(),
+            None => return false,
+        };
+
+        let mut helpers: Vec<SocketAddr> = nodes.nodes_where(|node|
+                node.get_id() != id && node.get_id() != target_id
+                    && node.state() == NodeState::Alive)
+            .iter().map(|node| node.get_address()).collect();
+        while helpers.len() > self.count {
+            helpers.swap_remove(rand::random::<usize>() % helpers.len());
+        }
+
+        debug!("probing indirectly [target_id={}, helpers={}]",
+            target_id, helpers.len());
+        std::thread::scope(|scope| {
+            let probes: Vec<_> = helpers.iter().map(|helper| scope.spawn(
+                move || match request(*helper, Some(target), self.timeout) {
+                    Ok(reached) => reached,
+                    Err(e) => {
+                        debug!("indirect probe failure [target_id={}, helper={}]: {}",
+                            target_id, helper, e);
+                        false
+                    },
+                })).collect();
+
+            // join every probe -> none outlives the round
+            let reached: Vec<bool> = probes.into_iter()
+                .map(|probe| probe.join().unwrap_or(false)).collect();
+            reached.contains(&true)
+        })
+    }
+}
+
+/// Asks the member at `address` to probe `target` within `timeout`, or
+/// probes the member itself when there is no target.
+fn request(address: SocketAddr, target: Option<SocketAddr>,
+        timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_nodelay(true)?;
+    // relayed probes wait out the helper's own attempt
+    stream.set_read_timeout(Some(timeout * 2))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut buf = Vec::new();
+    codec::write_header(&mut buf, rand::random::<u64>(), PROBE_EXCHANGE)?;
+    let target = target.map(|target| target.to_string()).unwrap_or_default();
+    codec::write_string(&target, &mut buf)?;
+    buf.write_u64::<BigEndian>(timeout.as_millis() as u64)?;
+    stream.write_all(&buf)?;
+
+    Ok(stream.read_u8()? == 1)
+}
+
+/// Answers a probe exchange: direct probes are acknowledged, indirect
+/// ones are relayed to their target and answered with the outcome.
+pub fn serve(stream: &mut (impl Read + Write))
+        -> Result<(), Box<dyn Error>> {
+    let target = codec::read_string(stream)?;
+    let timeout = Duration::from_millis(stream.read_u64::<BigEndian>()?);
+
+    let reached = match target.is_empty() {
+        true => true,
+        false => match request(target.parse()?, None, timeout) {
+            Ok(reached) => reached,
+            Err(e) => {
+                debug!("probe failure [target={}]: {}", target, e);
+                false
+            },
+        },
+    };
+
+    stream.write_u8(reached as u8)?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::node::{Node, NodeMap};
+    use crate::prelude::{ClusterBuilder, Swarm};
+    use super::IndirectProbes;
+
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn indirect_probes() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms = Vec::new();
+        for id in 1..3 {
+            let (mut swarm, _) =
+                Swarm::new(id, ip_address, 0, None, ClusterBuilder::new());
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        // node 0 probes node 2 through node 1 and a closed port
+        let closed = TcpListener::bind("127.0.0.1:0").expect("bind")
+            .local_addr().expect("local addr");
+        let nodes = NodeMap::new();
+        nodes.insert(Node::new(0, ip_address, closed.port()));
+        nodes.insert(Node::new(3, ip_address, closed.port()));
+        for (id, swarm) in (1..3).zip(swarms.iter()) {
+            let address = swarm.local_addr().expect("local addr");
+            nodes.insert(Node::new(id, address.ip(), address.port()));
+        }
+
+        let probes = IndirectProbes::new(2, Duration::from_millis(200));
+        assert!(probes.probe(0, 2, &nodes));
+        assert!(!probes.probe(0, 4, &nodes));
+
+        // unreachable targets are not vouched for
+        let mut swarm = swarms.pop().expect("swarm");
+        swarm.stop().expect("swarm stop");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            !probes.probe(0, 2, &nodes)), "unreachable target");
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
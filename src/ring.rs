@@ -0,0 +1,323 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::merkle::MerkleTree;
+use crate::node::Node;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{Read, Write};
+
+/// Hashes keys and vnode seeds onto the ring. Members of a swarm must
+/// share one hasher, so the hash must be stable across processes,
+/// platforms and releases.
+pub trait RingHasher: Send + Sync {
+    fn hash(&self, key: &[u8]) -> u64;
+}
+
+/// Default RingHasher: 64-bit xxHash with a zero seed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XxHasher;
+
+impl RingHasher for XxHasher {
+    fn hash(&self, key: &[u8]) -> u64 {
+        xxhash_rust::xxh64::xxh64(key, 0)
+    }
+}
+
+/// Returns the id owning `token`: the owner of the smallest token
+/// larger than it, wrapping around to the lowest token.
+pub fn locate(tokens: &BTreeMap<u64, u32>, token: u64) -> Option<u32> {
+    use std::ops::Bound::{Excluded, Unbounded};
+    tokens.range((Excluded(token), Unbounded)).next()
+        .or_else(|| tokens.iter().next())
+        .map(|(_, id)| *id)
+}
+
+/// Point-in-time copy of a DHT ring which can be exported with
+/// `write` and decoded anywhere, including wasm clients, to route
+/// tokens identically to cluster members.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct DhtSnapshot {
+    pub epoch: u64,
+    pub nodes: HashMap<u32, Node>,
+    pub tokens: BTreeMap<u64, u32>,
+}
+
+impl DhtSnapshot {
+    /// Root of the token digest, equal to a member's digest when both
+    /// hold the same ring.
+    pub fn digest(&self) -> u64 {
+        MerkleTree::new(&self.tokens).root()
+    }
+
+    pub fn locate(&self, token: u64) -> Option<&Node> {
+        locate(&self.tokens, token).and_then(|id| self.nodes.get(&id))
+    }
+
+    /// Computes the ring changes and data movements `operation` would
+    /// cause without applying them, so operators can review its impact.
+    pub fn plan(&self, operation: &RingOperation)
+            -> Result<RingPlan, Box<dyn Error>> {
+        let mut tokens = self.tokens.clone();
+        match operation {
+            RingOperation::Decommission(id) => {
+                tokens.retain(|_, owner| owner != id);
+                if tokens.len() == self.tokens.len() {
+                    return Err(format!("node owns no tokens [id={}]",
+                        id).into());
+                } else if tokens.is_empty() {
+                    return Err("cannot decommission the last owner".into());
+                }
+            },
+            RingOperation::Rebalance => {
+                // evenly spaced tokens dealt round-robin across members
+                let mut ids: Vec<u32> = self.nodes.keys().copied()
+                    .chain(self.tokens.values().copied()).collect();
+                ids.sort_unstable();
+                ids.dedup();
+                if ids.is_empty() {
+                    return Err("cannot rebalance an empty ring".into());
+                }
+
+                let count = std::cmp::max(self.tokens.len(), ids.len()) as u64;
+                let step = u64::MAX / count;
+                tokens = (0..count).map(|i|
+                    (i * step, ids[(i % ids.len() as u64) as usize])).collect();
+            },
+            RingOperation::TransferTokens { tokens: transfers, to } => {
+                if !self.nodes.contains_key(to) {
+                    return Err(format!("unknown node [id={}]", to).into());
+                }
+
+                for token in transfers.iter() {
+                    match tokens.get_mut(token) {
+                        Some(owner) => *owner = *to,
+                        None => return Err(format!("unknown token [token={}]",
+                            token).into()),
+                    }
+                }
+            },
+        }
+
+        Ok(RingPlan::new(&self.tokens, tokens))
+    }
+
+    pub fn read(reader: &mut impl Read)
+            -> Result<DhtSnapshot, Box<dyn Error>> {
+        let epoch = reader.read_u64::<BigEndian>()?;
+
+        // read nodes
+        let mut nodes = HashMap::new();
+        let node_count = reader.read_u32::<BigEndian>()?;
+        for _ in 0..node_count {
+            let node = Node::read(reader)?;
+            nodes.insert(node.get_id(), node);
+        }
+
+        // read tokens
+        let mut tokens = BTreeMap::new();
+        let token_count = reader.read_u32::<BigEndian>()?;
+        for _ in 0..token_count {
+            let token = reader.read_u64::<BigEndian>()?;
+            let id = reader.read_u32::<BigEndian>()?;
+            tokens.insert(token, id);
+        }
+
+        Ok(DhtSnapshot { epoch, nodes, tokens })
+    }
+
+    pub fn write(&self, writer: &mut impl Write)
+            -> Result<(), Box<dyn Error>> {
+        writer.write_u64::<BigEndian>(self.epoch)?;
+
+        // write nodes ordered by id so encodings are deterministic
+        let mut nodes: Vec<&Node> = self.nodes.values().collect();
+        nodes.sort_unstable_by_key(|node| node.get_id());
+        writer.write_u32::<BigEndian>(nodes.len() as u32)?;
+        for node in nodes {
+            node.write(writer)?;
+        }
+
+        // write tokens
+        writer.write_u32::<BigEndian>(self.tokens.len() as u32)?;
+        for (token, id) in self.tokens.iter() {
+            writer.write_u64::<BigEndian>(*token)?;
+            writer.write_u32::<BigEndian>(*id)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Ring change reviewed with DhtSnapshot::plan.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum RingOperation {
+    /// Removes every token of a node, leaving its ranges to successors.
+    Decommission(u32),
+    /// Replaces the ring with evenly spaced tokens spread across every
+    /// member, including members owning no tokens.
+    Rebalance,
+    /// Reassigns existing tokens to another member.
+    TransferTokens { tokens: Vec<u64>, to: u32 },
+}
+
+/// Token added, removed, or reassigned by a plan.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenChange {
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+    pub token: u64,
+}
+
+/// Tokens from `start` (inclusive) to `end` (exclusive) whose data moves
+/// between owners, wrapping past u64::MAX when `start >= end`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeMovement {
+    pub end: u64,
+    pub from: u32,
+    pub start: u64,
+    pub to: u32,
+}
+
+/// Intended outcome of a RingOperation: the resulting ring, the token
+/// changes producing it, and the ranges whose data must move.
+#[derive(Clone, Debug)]
+pub struct RingPlan {
+    pub changes: Vec<TokenChange>,
+    pub movements: Vec<RangeMovement>,
+    pub tokens: BTreeMap<u64, u32>,
+}
+
+impl RingPlan {
+    pub(crate) fn new(current: &BTreeMap<u64, u32>,
+            tokens: BTreeMap<u64, u32>)
+            -> RingPlan {
+        let mut changes = Vec::new();
+        let positions: BTreeSet<u64> = current.keys()
+            .chain(tokens.keys()).copied().collect();
+        for token in positions.iter() {
+            let (from, to) = (current.get(token).copied(),
+                tokens.get(token).copied());
+            if from != to {
+                changes.push(TokenChange { from, to, token: *token });
+            }
+        }
+
+        // ownership is constant between consecutive token positions
+        let boundaries: Vec<u64> = positions.into_iter().collect();
+        let mut movements: Vec<RangeMovement> = Vec::new();
+        for (index, start) in boundaries.iter().enumerate() {
+            let end = boundaries[(index + 1) % boundaries.len()];
+            let owners = (locate(current, *start), locate(&tokens, *start));
+            let (from, to) = match owners {
+                (Some(from), Some(to)) if from != to => (from, to),
+                _ => continue,
+            };
+
+            match movements.last_mut() {
+                Some(last) if last.end == *start
+                        && (last.from, last.to) == (from, to) =>
+                    last.end = end,
+                _ => movements.push(RangeMovement {
+                    end, from, start: *start, to }),
+            }
+        }
+
+        RingPlan { changes, movements, tokens }
+    }
+}
+
+impl Display for RingPlan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let owner = |id: Option<u32>| id.map(|id| id.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        for change in self.changes.iter() {
+            writeln!(f, "token {}: {} -> {}", change.token,
+                owner(change.from), owner(change.to))?;
+        }
+
+        for movement in self.movements.iter() {
+            writeln!(f, "move [{}, {}): {} -> {}", movement.start,
+                movement.end, movement.from, movement.to)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+    use super::{DhtSnapshot, RangeMovement, RingHasher, RingOperation,
+        TokenChange, XxHasher};
+
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn snapshot_locate() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut nodes = HashMap::new();
+        nodes.insert(0, Node::new(0, ip_address, 12000));
+        nodes.insert(1, Node::new(1, ip_address, 12001));
+
+        let mut tokens = BTreeMap::new();
+        tokens.insert(100, 0);
+        tokens.insert(200, 1);
+
+        let snapshot = DhtSnapshot { epoch: 4, nodes, tokens };
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf).expect("write snapshot");
+        let snapshot = DhtSnapshot::read(&mut buf.as_slice())
+            .expect("read snapshot");
+
+        assert_eq!(snapshot.epoch, 4);
+        assert_eq!(snapshot.locate(50).expect("locate").get_id(), 0);
+        assert_eq!(snapshot.locate(100).expect("locate").get_id(), 1);
+        assert_eq!(snapshot.locate(250).expect("locate").get_id(), 0);
+    }
+
+    #[test]
+    fn xxhash_stable() {
+        // reference xxh64 digests -> rings agree across releases
+        assert_eq!(XxHasher.hash(b""), 0xef46db3751d8e999);
+        assert_eq!(XxHasher.hash(b"abc"), 0x44bc2cf5ad770999);
+    }
+
+    #[test]
+    fn ring_plan() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut nodes = HashMap::new();
+        let mut tokens = BTreeMap::new();
+        for id in 0..3 {
+            nodes.insert(id, Node::new(id, ip_address, 12000 + id as u16));
+            tokens.insert(100 * (id as u64 + 1), id);
+        }
+        let snapshot = DhtSnapshot { epoch: 1, nodes, tokens };
+
+        // decommissioned ranges move to the successor
+        let plan = snapshot.plan(&RingOperation::Decommission(1))
+            .expect("plan");
+        assert_eq!(plan.changes, vec!(TokenChange {
+            from: Some(1), to: None, token: 200 }));
+        assert_eq!(plan.movements, vec!(RangeMovement {
+            end: 200, from: 1, start: 100, to: 2 }));
+        assert_eq!(snapshot.tokens.len(), 3);
+        assert!(snapshot.plan(&RingOperation::Decommission(7)).is_err());
+
+        let plan = snapshot.plan(&RingOperation::TransferTokens {
+            tokens: vec!(300), to: 0 }).expect("plan");
+        assert_eq!(plan.movements, vec!(RangeMovement {
+            end: 300, from: 2, start: 200, to: 0 }));
+        assert!(plan.to_string().contains("token 300: 2 -> 0"));
+
+        let plan = snapshot.plan(&RingOperation::Rebalance).expect("plan");
+        assert_eq!(plan.tokens.len(), 3);
+        assert_eq!(plan.tokens.values().copied().collect::<Vec<u32>>(),
+            vec!(0, 1, 2));
+    }
+}
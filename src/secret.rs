@@ -0,0 +1,71 @@
+use zeroize::Zeroizing;
+
+use std::fmt::{self, Debug, Formatter};
+
+const REDACTED: &str = "[REDACTED]";
+
+// metadata keys containing any of these are treated as secret-bearing
+const SENSITIVE_KEY_PATTERNS: &[&str] = &["secret", "password",
+    "passwd", "private", "credential", "auth", "apikey", "api_key"];
+
+/// A sensitive value (cluster secret, signing key, etc) which is
+/// zeroed from memory when dropped and never printed in Debug output.
+#[derive(Clone)]
+pub struct Secret {
+    value: Zeroizing<String>,
+}
+
+impl Secret {
+    pub fn new(value: &str) -> Secret {
+        Secret { value: Zeroizing::new(value.to_string()) }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret { value: Zeroizing::new(value) }
+    }
+}
+
+pub fn is_sensitive_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SENSITIVE_KEY_PATTERNS.iter().any(|pattern| key.contains(pattern))
+}
+
+pub fn redact<'a>(key: &str, value: &'a str) -> &'a str {
+    match is_sensitive_key(key) {
+        true => REDACTED,
+        false => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sensitive_key, redact, Secret};
+
+    #[test]
+    fn secret_debug() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(secret.expose(), "hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+    }
+
+    #[test]
+    fn redact_metadata() {
+        assert!(is_sensitive_key("db_PASSWORD"));
+        assert!(!is_sensitive_key("rpc_addr"));
+        assert_eq!(redact("auth_token", "abc"), "[REDACTED]");
+        assert_eq!(redact("rpc_addr", "127.0.0.1:12002"),
+            "127.0.0.1:12002");
+    }
+}
@@ -0,0 +1,184 @@
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+
+use crate::codec;
+use crate::exchange::SERVICE_EXCHANGE;
+use crate::service::dispatch::SwarmService;
+
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Error of async service exchanges, which may cross runtime threads.
+pub type AsyncError = Box<dyn Error + Send + Sync>;
+
+/// SwarmService variant for handlers awaiting their own I/O, such as
+/// database lookups or RPC fan-out. Each connection is answered by a
+/// task on a tokio runtime rather than occupying a thread while it
+/// waits. Register it with Swarm::register_service through AsyncService.
+pub trait AsyncSwarmService: 'static + Send + Sync {
+    /// Result of a completed request.
+    type Response: Send;
+
+    /// Message types this service handles, unique across services.
+    fn message_types(&self) -> Vec<u8>;
+
+    /// Requester half of an exchange, over a stream already connected
+    /// to the service on a member.
+    fn request(&self, message_type: u8, stream: TcpStream)
+        -> impl Future<Output = Result<Self::Response, AsyncError>> + Send;
+
+    /// Replier half of an exchange.
+    fn reply(&self, message_type: u8, peer_address: Option<SocketAddr>,
+        stream: TcpStream)
+        -> impl Future<Output = Result<(), AsyncError>> + Send;
+}
+
+/// Adapts an AsyncSwarmService to Swarm::register_service, replying on
+/// the runtime of `handle`.
+pub struct AsyncService<S: AsyncSwarmService> {
+    cluster_epoch: u64,
+    cluster_name: String,
+    handle: Handle,
+    service: Arc<S>,
+}
+
+impl<S: AsyncSwarmService> AsyncService<S> {
+    pub fn new(service: S, handle: Handle) -> AsyncService<S> {
+        AsyncService {
+            cluster_epoch: 0,
+            cluster_name: String::new(),
+            handle,
+            service: Arc::new(service),
+        }
+    }
+
+    /// Names the cluster and epoch requests are sent within, which
+    /// members check before serving them. See Swarm::set_cluster_name
+    /// and Swarm::set_cluster_epoch. Defaults to the empty name and
+    /// epoch 0.
+    pub fn cluster(mut self, cluster_name: &str, cluster_epoch: u64)
+            -> AsyncService<S> {
+        self.cluster_epoch = cluster_epoch;
+        self.cluster_name = cluster_name.to_string();
+        self
+    }
+
+    /// Connects to the service on the member listening at `address` and
+    /// runs the requester half for `message_type`.
+    pub async fn request(&self, address: SocketAddr, message_type: u8)
+            -> Result<S::Response, AsyncError> {
+        let mut buf = Vec::new();
+        codec::write_cluster_header(&mut buf, rand::random::<u64>(),
+                SERVICE_EXCHANGE, &self.cluster_name, self.cluster_epoch)
+            .map_err(|e| e.to_string())?;
+        buf.push(message_type);
+
+        let mut stream = TcpStream::connect(address).await?;
+        stream.write_all(&buf).await?;
+        self.service.request(message_type, stream).await
+    }
+}
+
+impl<S: AsyncSwarmService> SwarmService for AsyncService<S> {
+    fn message_types(&self) -> Vec<u8> {
+        self.service.message_types()
+    }
+
+    fn handle(&self, message_type: u8, peer_address: Option<SocketAddr>,
+            stream: std::net::TcpStream) -> Result<(), Box<dyn Error>> {
+        // tokio streams register with the reactor of the current runtime
+        stream.set_nonblocking(true)?;
+        let _guard = self.handle.enter();
+        let stream = TcpStream::from_std(stream)?;
+
+        let service = self.service.clone();
+        self.handle.spawn(async move {
+            if let Err(e) = service.reply(message_type, peer_address,
+                    stream).await {
+                debug!("async service failure [message_type={}]: {}",
+                    message_type, e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+
+    use crate::prelude::{ClusterBuilder, Swarm};
+    use super::{AsyncError, AsyncService, AsyncSwarmService};
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    // answers with its message type followed by the request echoed
+    struct EchoService;
+
+    impl AsyncSwarmService for EchoService {
+        type Response = Vec<u8>;
+
+        fn message_types(&self) -> Vec<u8> {
+            vec!(1)
+        }
+
+        async fn request(&self, _: u8, mut stream: TcpStream)
+                -> Result<Vec<u8>, AsyncError> {
+            stream.write_all(b"ping").await?;
+            let mut buf = vec![0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            Ok(buf)
+        }
+
+        async fn reply(&self, message_type: u8, _: Option<SocketAddr>,
+                mut stream: TcpStream) -> Result<(), AsyncError> {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&[message_type]).await?;
+            stream.write_all(&buf).await?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn async_service() {
+        let runtime = Runtime::new().expect("runtime");
+        let service = Arc::new(AsyncService::new(EchoService,
+            runtime.handle().clone()));
+
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, _) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.register_service(service.clone()).expect("register service");
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // concurrent requests are answered by runtime tasks
+        let responses = runtime.block_on(async {
+            let requests: Vec<_> = (0..4).map(|_| {
+                let service = service.clone();
+                tokio::spawn(async move { service.request(address, 1).await
+                    .map_err(|e| e.to_string()) })
+            }).collect();
+
+            let mut responses = Vec::new();
+            for request in requests {
+                responses.push(request.await.expect("join"));
+            }
+            responses
+        });
+
+        for response in responses {
+            assert_eq!(response.expect("request"), b"\x01ping".to_vec());
+        }
+
+        swarm.stop().expect("swarm stop");
+    }
+}
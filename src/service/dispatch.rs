@@ -0,0 +1,156 @@
+use byteorder::WriteBytesExt;
+
+use crate::codec;
+use crate::exchange::SERVICE_EXCHANGE;
+use crate::node::NodeMap;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, RwLock};
+
+/// Application protocol sharing the gossip port. Connections opened
+/// with Swarm::connect_service for one of the service's message types
+/// are handed to `handle` on a dedicated thread once their header is
+/// read, so services may hold them open as long as they need.
+///
+/// Clients outside the swarm frame connections the same way: a random
+/// u64 trace id, SERVICE_EXCHANGE and the message type byte, followed
+/// by the service's own protocol.
+pub trait SwarmService: Send + Sync {
+    /// Message types this service handles, unique across services.
+    fn message_types(&self) -> Vec<u8>;
+
+    fn handle(&self, message_type: u8, peer_address: Option<SocketAddr>,
+        stream: TcpStream) -> Result<(), Box<dyn Error>>;
+}
+
+/// Dispatch table from message types to registered services.
+pub struct ServiceRegistry {
+    services: RwLock<HashMap<u8, Arc<dyn SwarmService>>>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> ServiceRegistry {
+        ServiceRegistry {
+            services: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Registers `service` for each of its message types, failing
+    /// without registering any if one is already taken.
+    pub fn register(&self, service: Arc<dyn SwarmService>)
+            -> Result<(), Box<dyn Error>> {
+        let message_types = service.message_types();
+        let mut services = self.services.write().unwrap();
+        if let Some(message_type) = message_types.iter()
+                .find(|message_type| services.contains_key(message_type)) {
+            return Err(format!("service message type registered [message_type={}]",
+                message_type).into());
+        }
+
+        for message_type in message_types {
+            debug!("registering service [message_type={}]", message_type);
+            services.insert(message_type, service.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, message_type: u8) -> Option<Arc<dyn SwarmService>> {
+        self.services.read().unwrap().get(&message_type).cloned()
+    }
+}
+
+/// Opens a connection to the service handling `message_type` on the
+/// member listening at `address`, which must belong to `nodes`'
+/// cluster.
+pub fn connect(nodes: &NodeMap, address: SocketAddr, message_type: u8)
+        -> Result<TcpStream, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut buf = Vec::new();
+    codec::write_cluster_header(&mut buf, rand::random::<u64>(),
+        SERVICE_EXCHANGE, &nodes.get_cluster_name(),
+        nodes.get_cluster_epoch())?;
+    buf.write_u8(message_type)?;
+    stream.write_all(&buf)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::{ClusterBuilder, Swarm, SwarmService};
+
+    use std::error::Error;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // answers with its message type followed by the request echoed
+    struct EchoService(Vec<u8>);
+
+    impl SwarmService for EchoService {
+        fn message_types(&self) -> Vec<u8> {
+            self.0.clone()
+        }
+
+        fn handle(&self, message_type: u8, _: Option<SocketAddr>,
+                mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf)?;
+            stream.write_all(&[message_type])?;
+            stream.write_all(&buf)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn service_dispatch() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let seed_address = SocketAddr::new(ip_address, 16780);
+        let mut swarms = Vec::new();
+        for i in 0..2 {
+            let seed_address = Some(seed_address).filter(|_| i != 0);
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address,
+                16780 + i, seed_address, ClusterBuilder::new());
+            if i == 0 {
+                swarm.register_service(Arc::new(EchoService(vec!(1, 2))))
+                    .expect("register service");
+                assert!(swarm.register_service(
+                    Arc::new(EchoService(vec!(3, 2)))).is_err());
+            }
+
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        swarms[1].wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        // services share the gossip port with membership
+        for message_type in [1, 2] {
+            let mut stream = swarms[1].connect_service(0, message_type)
+                .expect("connect service");
+            stream.write_all(b"ping").expect("write");
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).expect("read");
+            assert_eq!(&buf, &[message_type, b'p', b'i', b'n', b'g']);
+        }
+
+        // unregistered message types are closed
+        let mut stream = swarms[1].connect_service(0, 3)
+            .expect("connect service");
+        stream.set_read_timeout(Some(Duration::from_millis(1000)))
+            .expect("set timeout");
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).expect("read"), 0);
+        assert!(swarms[1].connect_service(7, 1).is_err());
+        assert_eq!(swarms[1].metrics().member_count, 2);
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::clock::Clock;
+use crate::namespace::MetadataNamespace;
+use crate::node::{Node, NodeMap, NodeState};
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Metadata namespace holding candidacy and leadership terms.
+pub const ELECTION_NAMESPACE: &str = "election";
+const CANDIDATE_KEY: &str = "candidate";
+const TERM_KEY: &str = "term";
+
+type Handler = Box<dyn Fn(Option<&Node>, u64) + Send + Sync>;
+
+/// Leader election over gossiped membership: the alive candidate with
+/// the highest id leads. A member taking over leadership publishes a
+/// term one above every term it has seen, so terms increase with each
+/// change of leader. Members only agree on the leader once membership
+/// converges, and each side of a partition elects its own leader, so
+/// fencing work with the term is recommended.
+pub struct Election {
+    handler: RwLock<Option<Handler>>,
+    id: u32,
+    leader: Mutex<Option<u32>>,
+    namespace: MetadataNamespace,
+    nodes: Arc<NodeMap>,
+}
+
+impl Election {
+    pub(crate) fn new(id: u32, nodes: Arc<NodeMap>) -> Election {
+        Election {
+            handler: RwLock::new(None),
+            id,
+            leader: Mutex::new(None),
+            namespace: MetadataNamespace::new(id, ELECTION_NAMESPACE,
+                nodes.clone()).unwrap(),
+            nodes,
+        }
+    }
+
+    /// Returns the alive candidate with the highest id.
+    pub fn current_leader(&self) -> Option<Node> {
+        self.nodes.nodes().into_iter()
+            .filter(|node| node.state() == NodeState::Alive
+                && self.namespace.get_node(node, CANDIDATE_KEY)
+                    .map(|candidate| candidate != "false").unwrap_or(true))
+            .max_by_key(|node| node.get_id())
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.current_leader().map(|node| node.get_id() == self.id)
+            .unwrap_or(false)
+    }
+
+    /// Calls `handler` with the new leader and its term whenever
+    /// leadership changes.
+    pub fn on_change<F: 'static + Fn(Option<&Node>, u64) + Send + Sync>(
+            &self, handler: F) {
+        *self.handler.write().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Withdraws the local node from, or returns it to, candidacy.
+    pub fn set_candidate(&self, candidate: bool) {
+        match candidate {
+            true => self.namespace.remove(CANDIDATE_KEY),
+            false => self.namespace.set(CANDIDATE_KEY, "false"),
+        }
+    }
+
+    /// Returns the term published by the current leader, 0 until the
+    /// leader has published one.
+    pub fn term(&self) -> u64 {
+        self.current_leader().map(|node| self.term_of(&node)).unwrap_or(0)
+    }
+
+    /// Detects leadership changes, publishing a new term when the local
+    /// node takes over.
+    pub fn tick(&self) {
+        let leader = self.current_leader();
+        let leader_id = leader.as_ref().map(|node| node.get_id());
+        {
+            let mut previous = self.leader.lock().unwrap();
+            if *previous == leader_id {
+                return;
+            }
+
+            *previous = leader_id;
+        }
+
+        let mut term = leader.as_ref().map(|node| self.term_of(node))
+            .unwrap_or(0);
+        if leader_id == Some(self.id) {
+            term = self.nodes.nodes().iter()
+                .map(|node| self.term_of(node)).max().unwrap_or(0) + 1;
+            self.namespace.set(TERM_KEY, &term.to_string());
+        }
+
+        info!("leadership changed [leader={:?}, term={}]", leader_id, term);
+        if let Some(handler) = self.handler.read().unwrap().as_ref() {
+            handler(leader.as_ref(), term);
+        }
+    }
+
+    fn term_of(&self, node: &Node) -> u64 {
+        self.namespace.get_node(node, TERM_KEY)
+            .and_then(|term| term.parse().ok()).unwrap_or(0)
+    }
+}
+
+/// Detects leadership changes every `interval` until shutdown.
+pub fn run(election: Arc<Election>, clock: Arc<dyn Clock>,
+        interval: Duration, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        election.tick();
+        clock.sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, NodeMap, NodeState};
+    use super::Election;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn highest_alive_leads() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..3 {
+            nodes.insert(Node::new(id, ip_address, 12000 + id as u16));
+        }
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let elections: Vec<Election> = (0..3).map(|id| {
+            let election = Election::new(id, nodes.clone());
+            let changes = changes.clone();
+            election.on_change(move |leader, term| changes.lock().unwrap()
+                .push((id, leader.map(|node| node.get_id()), term)));
+            election
+        }).collect();
+
+        // the highest id leads and publishes the first term
+        elections[2].tick();
+        elections[1].tick();
+        assert!(elections[2].is_leader());
+        assert_eq!(elections[0].term(), 1);
+
+        // withdrawn and dead members are skipped
+        elections[1].set_candidate(false);
+        nodes.update(2, |node| node.set_state(NodeState::Dead));
+        assert_eq!(elections[1].current_leader()
+            .map(|node| node.get_id()), Some(0));
+        elections[0].tick();
+        assert_eq!(elections[1].term(), 2);
+
+        assert_eq!(*changes.lock().unwrap(), vec!((2, Some(2), 1),
+            (1, Some(2), 1), (0, Some(0), 2)));
+    }
+}
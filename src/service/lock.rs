@@ -0,0 +1,312 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::{Clock, SystemClock};
+use crate::codec;
+use crate::exchange::LOCK_EXCHANGE;
+use crate::node::{self, Node, NodeMap};
+use crate::ring::{RingHasher, XxHasher};
+use crate::service::election::Election;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+const MAX_LEASE_MS: u64 = 24 * 60 * 60 * 1000;
+const RETRY_INTERVAL_MS: u64 = 50;
+
+// lock operations
+const ACQUIRE: u8 = 0;
+const RELEASE: u8 = 1;
+
+type Coordinator = Box<dyn Fn(&str) -> Option<Node> + Send + Sync>;
+
+/// Named advisory lock held until released or its lease expires.
+#[derive(Clone, Debug)]
+pub struct LockLease {
+    coordinator: u32,
+    fence: u64,
+    name: String,
+}
+
+impl LockLease {
+    /// Fencing token, increasing with every grant by a coordinator, for
+    /// rejecting writes from holders whose lease expired.
+    pub fn get_fence(&self) -> u64 {
+        self.fence
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+struct Held {
+    expires: Instant,
+    fence: u64,
+    owner: u32,
+}
+
+/// Advisory locks coordinated by a single member per lock name, the
+/// elected leader by default or the member chosen by a custom
+/// coordinator such as the Dht token owner. Coordinators keep locks in
+/// memory, so locks are lost when coordination moves, and leases bound
+/// how long a failed holder blocks others.
+pub struct LockService {
+    coordinator: RwLock<Option<Coordinator>>,
+    election: Arc<Election>,
+    fence: AtomicU64,
+    held: Mutex<HashMap<String, Held>>,
+    id: u32,
+    nodes: Arc<NodeMap>,
+    timeout: Duration,
+}
+
+impl LockService {
+    pub(crate) fn new(id: u32, election: Arc<Election>, nodes: Arc<NodeMap>)
+            -> LockService {
+        LockService {
+            coordinator: RwLock::new(None),
+            election,
+            // wall clock seeded -> fences rarely regress across coordinators
+            fence: AtomicU64::new(SystemClock.timestamp() << 16),
+            held: Mutex::new(HashMap::new()),
+            id,
+            nodes,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Returns a token for `name` as the default XxHasher places it, for
+    /// locating coordinators on a ring.
+    pub fn token(name: &str) -> u64 {
+        XxHasher.hash(name.as_bytes())
+    }
+
+    /// Acquires lock `name` for `lease`, at most a day, retrying until
+    /// `timeout`. Acquiring a lock already held by the local node renews
+    /// it.
+    pub fn acquire(&self, name: &str, lease: Duration, timeout: Duration)
+            -> Result<LockLease, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        lease_expiry(name, lease, Instant::now())?;
+        loop {
+            let coordinator = self.coordinator(name)
+                .ok_or("no lock coordinator available")?;
+            let result = match coordinator.get_id() == self.id {
+                true => self.grant(name, self.id, lease, Instant::now()),
+                false => self.request(&coordinator, ACQUIRE, name, lease),
+            };
+
+            match result {
+                Ok(Some(fence)) => return Ok(LockLease {
+                    coordinator: coordinator.get_id(),
+                    fence,
+                    name: name.to_string(),
+                }),
+                Ok(None) => {},
+                Err(e) => debug!("lock request failure [name={}, coordinator={}]: {}",
+                    name, coordinator.get_id(), e),
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("lock acquire timed out [name={}]",
+                    name).into());
+            }
+
+            std::thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+        }
+    }
+
+    /// Releases `lease` at the coordinator which granted it.
+    pub fn release(&self, lease: LockLease) -> Result<(), Box<dyn Error>> {
+        if lease.coordinator == self.id {
+            self.revoke(&lease.name, self.id);
+            return Ok(());
+        }
+
+        let coordinator = self.nodes.get(lease.coordinator)
+            .ok_or("lock coordinator unknown")?;
+        self.request(&coordinator, RELEASE, &lease.name, Duration::default())
+            .map(|_| ())
+    }
+
+    /// Handles a lock request from another member.
+    pub fn receive<S: Read + Write>(&self, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        let operation = stream.read_u8()?;
+        let owner = stream.read_u32::<BigEndian>()?;
+        let name = node::read_string(stream)?;
+        let lease = Duration::from_millis(stream.read_u64::<BigEndian>()?);
+
+        let fence = match operation {
+            ACQUIRE => self.grant(&name, owner, lease, Instant::now())?,
+            RELEASE => {
+                self.revoke(&name, owner);
+                Some(0)
+            },
+            _ => return Err(format!("unknown lock operation [operation={}]",
+                operation).into()),
+        };
+
+        stream.write_u8(fence.is_some() as u8)?;
+        stream.write_u64::<BigEndian>(fence.unwrap_or(0))?;
+        Ok(())
+    }
+
+    /// Chooses coordinators with `coordinator` instead of the leader,
+    /// for example `move |name| dht.locate(LockService::token(name))`.
+    pub fn set_coordinator<F>(&self, coordinator: F)
+            where F: 'static + Fn(&str) -> Option<Node> + Send + Sync {
+        *self.coordinator.write().unwrap() = Some(Box::new(coordinator));
+    }
+
+    fn coordinator(&self, name: &str) -> Option<Node> {
+        match self.coordinator.read().unwrap().as_ref() {
+            Some(coordinator) => coordinator(name),
+            None => self.election.current_leader(),
+        }
+    }
+
+    /// Grants `name` to `owner` if free, expired or already theirs,
+    /// returning the fencing token.
+    fn grant(&self, name: &str, owner: u32, lease: Duration, now: Instant)
+            -> Result<Option<u64>, Box<dyn Error>> {
+        let expires = lease_expiry(name, lease, now)?;
+        let mut held = self.held.lock().unwrap();
+        Ok(match held.get_mut(name) {
+            Some(lock) if lock.owner != owner && lock.expires > now => None,
+            Some(lock) if lock.owner == owner && lock.expires > now => {
+                lock.expires = expires;
+                Some(lock.fence)
+            },
+            _ => {
+                let fence = self.fence.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("granting lock [name={}, owner={}, fence={}]",
+                    name, owner, fence);
+                held.insert(name.to_string(),
+                    Held { expires, fence, owner });
+                Some(fence)
+            },
+        })
+    }
+
+    fn request(&self, coordinator: &Node, operation: u8, name: &str,
+            lease: Duration) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(
+            &coordinator.get_address(), self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut buf = Vec::new();
+        codec::write_cluster_header(&mut buf, rand::random::<u64>(),
+            LOCK_EXCHANGE, &self.nodes.get_cluster_name(),
+            self.nodes.get_cluster_epoch())?;
+        buf.write_u8(operation)?;
+        buf.write_u32::<BigEndian>(self.id)?;
+        node::write_string(name, &mut buf)?;
+        buf.write_u64::<BigEndian>(lease.as_millis() as u64)?;
+        stream.write_all(&buf)?;
+
+        let granted = stream.read_u8()? != 0;
+        let fence = stream.read_u64::<BigEndian>()?;
+        Ok(Some(fence).filter(|_| granted))
+    }
+
+    fn revoke(&self, name: &str, owner: u32) {
+        let mut held = self.held.lock().unwrap();
+        if held.get(name).map(|lock| lock.owner == owner).unwrap_or(false) {
+            debug!("releasing lock [name={}, owner={}]", name, owner);
+            held.remove(name);
+        }
+    }
+}
+
+/// Returns when a lease of lock `name` granted at `now` expires,
+/// rejecting leases longer than a day or past the range of Instant.
+fn lease_expiry(name: &str, lease: Duration, now: Instant)
+        -> Result<Instant, Box<dyn Error>> {
+    Some(lease).filter(|lease| *lease <= Duration::from_millis(MAX_LEASE_MS))
+        .and_then(|lease| now.checked_add(lease))
+        .ok_or_else(|| format!("lock lease out of range [name={}, lease_ms={}]",
+            name, lease.as_millis()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use crate::clock::{wait_until, SystemClock};
+    use crate::node;
+    use crate::prelude::{Cluster, ClusterBuilder, Swarm};
+    use crate::transport::MemoryStream;
+    use super::{LockService, ACQUIRE};
+
+    use std::time::Duration;
+
+    #[test]
+    fn advisory_locks() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        for i in 0..2 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        for swarm in swarms.iter() {
+            swarm.wait_for_members(2, Duration::from_secs(5))
+                .expect("wait for members");
+        }
+        let (local, remote) = (swarms[1].locks(), swarms[0].locks());
+        let lease = Duration::from_secs(10);
+        let timeout = Duration::from_millis(200);
+
+        // remote members acquire through the leader
+        let held = remote.acquire("jobs", lease, timeout).expect("acquire");
+        assert!(local.acquire("jobs", lease, timeout).is_err());
+        let renewed = remote.acquire("jobs", lease, timeout).expect("renew");
+        assert_eq!(renewed.get_fence(), held.get_fence());
+
+        remote.release(held).expect("release");
+        let short = local.acquire("jobs", Duration::from_millis(100), timeout)
+            .expect("acquire");
+        assert!(short.get_fence() > renewed.get_fence());
+
+        // expired leases no longer block others
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            remote.acquire("jobs", lease, timeout).is_ok()), "lease expiry");
+
+        // leases out of range are rejected, locally and at the
+        // coordinator, without poisoning its locks
+        let day = Duration::from_millis(super::MAX_LEASE_MS);
+        assert!(remote.acquire("jobs", day + lease, timeout).is_err());
+        assert!(local.acquire("queue", Duration::MAX, timeout).is_err());
+        let (mut client, mut server) = MemoryStream::pair();
+        client.write_u8(ACQUIRE).expect("write");
+        client.write_u32::<BigEndian>(0).expect("write");
+        node::write_string("queue", &mut client).expect("write");
+        client.write_u64::<BigEndian>(u64::MAX).expect("write");
+        assert!(local.receive(&mut server).is_err());
+        local.acquire("queue", day, timeout).expect("acquire");
+        remote.acquire("jobs", lease, timeout).expect("renew");
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn lock_token_stable() {
+        // reference xxh64 digests -> members agree on coordinators
+        assert_eq!(LockService::token("jobs"), 5350645347482740656);
+        assert_eq!(LockService::token(""), 0xef46db3751d8e999);
+    }
+}
@@ -0,0 +1,7 @@
+// application services built on swarm membership and metadata
+#[cfg(feature = "tokio")]
+pub(crate) mod asynchronous;
+pub(crate) mod dispatch;
+pub(crate) mod election;
+pub(crate) mod lock;
+pub(crate) mod pubsub;
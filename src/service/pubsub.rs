@@ -0,0 +1,196 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::codec;
+use crate::exchange::PUBSUB_EXCHANGE;
+use crate::namespace::MetadataNamespace;
+use crate::node::{self, Node, NodeMap, NodeState};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+// rejects garbage lengths before allocating
+const MAX_PAYLOAD_BYTES: u32 = 64 * 1024;
+/// Metadata namespace holding topic subscriptions.
+pub const PUBSUB_NAMESPACE: &str = "pubsub";
+
+type Handler = Box<dyn Fn(u32, &[u8]) + Send + Sync>;
+
+/// Topic based publish / subscribe. Members advertise their topic
+/// subscriptions in the "pubsub" metadata namespace, which gossip
+/// spreads, and publishers send each message directly to the alive
+/// members subscribed to its topic. Delivery is best-effort: members
+/// whose subscription has not reached the publisher yet miss messages.
+pub struct PubSub {
+    handlers: RwLock<HashMap<String, Handler>>,
+    id: u32,
+    namespace: MetadataNamespace,
+    nodes: Arc<NodeMap>,
+    timeout: Duration,
+}
+
+impl PubSub {
+    pub(crate) fn new(id: u32, nodes: Arc<NodeMap>) -> PubSub {
+        PubSub {
+            handlers: RwLock::new(HashMap::new()),
+            id,
+            namespace: MetadataNamespace::new(id, PUBSUB_NAMESPACE,
+                nodes.clone()).unwrap(),
+            nodes,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Publishes `payload` to the subscribers of `topic`, returning the
+    /// number of members it was delivered to.
+    pub fn publish(&self, topic: &str, payload: &[u8])
+            -> Result<usize, Box<dyn Error>> {
+        if payload.len() > MAX_PAYLOAD_BYTES as usize {
+            return Err(format!("publish payload too large [length={}]",
+                payload.len()).into());
+        }
+
+        let mut delivered = 0;
+        for node in self.subscribers(topic) {
+            if node.get_id() == self.id {
+                self.dispatch(topic, self.id, payload);
+                delivered += 1;
+                continue;
+            }
+
+            match self.send(&node.get_address(), topic, payload) {
+                Ok(_) => delivered += 1,
+                Err(e) => debug!("publish failure [id={}, topic={}]: {}",
+                    node.get_id(), topic, e),
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Handles a message published by another member.
+    pub fn receive<S: Read + Write>(&self, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        let publisher = stream.read_u32::<BigEndian>()?;
+        let topic = node::read_string(stream)?;
+        let length = stream.read_u32::<BigEndian>()?;
+        if length > MAX_PAYLOAD_BYTES {
+            return Err(format!("publish payload too large [length={}]",
+                length).into());
+        }
+
+        let mut payload = vec![0; length as usize];
+        stream.read_exact(&mut payload)?;
+        self.dispatch(&topic, publisher, &payload);
+        Ok(())
+    }
+
+    /// Calls `handler` with the publisher id and payload of every
+    /// message published to `topic`, advertising the subscription.
+    pub fn subscribe<F: 'static + Fn(u32, &[u8]) + Send + Sync>(
+            &self, topic: &str, handler: F) {
+        self.handlers.write().unwrap()
+            .insert(topic.to_string(), Box::new(handler));
+        self.namespace.set(topic, "");
+    }
+
+    /// Returns the alive members subscribed to `topic`.
+    pub fn subscribers(&self, topic: &str) -> Vec<Node> {
+        self.nodes.nodes().into_iter().filter(|node|
+            node.state() == NodeState::Alive
+                && self.namespace.get_node(node, topic).is_some())
+            .collect()
+    }
+
+    /// Returns the topics the local node is subscribed to.
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> =
+            self.handlers.read().unwrap().keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    pub fn unsubscribe(&self, topic: &str) {
+        self.handlers.write().unwrap().remove(topic);
+        self.namespace.remove(topic);
+    }
+
+    fn dispatch(&self, topic: &str, publisher: u32, payload: &[u8]) {
+        // subscriptions may have been dropped since they were gossiped
+        match self.handlers.read().unwrap().get(topic) {
+            Some(handler) => handler(publisher, payload),
+            None => debug!("dropping unsubscribed message [topic={}]", topic),
+        }
+    }
+
+    fn send(&self, address: &SocketAddr, topic: &str, payload: &[u8])
+            -> Result<(), Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(address, self.timeout)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut buf = Vec::with_capacity(payload.len() + topic.len() + 24);
+        codec::write_cluster_header(&mut buf, rand::random::<u64>(),
+            PUBSUB_EXCHANGE, &self.nodes.get_cluster_name(),
+            self.nodes.get_cluster_epoch())?;
+        buf.write_u32::<BigEndian>(self.id)?;
+        node::write_string(topic, &mut buf)?;
+        buf.write_u32::<BigEndian>(payload.len() as u32)?;
+        buf.write_all(payload)?;
+        stream.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::prelude::{Cluster, ClusterBuilder, Swarm};
+
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn topic_routing() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        let mut pubsubs = Vec::new();
+        for i in 0..3 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            let pubsub = swarm.pubsub();
+            let received = received.clone();
+            pubsub.subscribe(if i == 2 { "metrics" } else { "alerts" },
+                move |publisher, payload| received.lock().unwrap()
+                    .push((i, publisher, payload.to_vec())));
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            pubsubs.push(pubsub);
+        }
+
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            pubsubs[2].subscribers("alerts").len() == 2), "subscribers");
+
+        // messages reach subscribers of their topic only
+        assert_eq!(pubsubs[2].publish("alerts", b"disk").expect("publish"), 2);
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            received.lock().unwrap().len() == 2), "delivery");
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(received, vec!((0, 2, b"disk".to_vec()),
+            (1, 2, b"disk".to_vec())));
+
+        pubsubs[1].unsubscribe("alerts");
+        assert_eq!(pubsubs[1].topics().len(), 0);
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Deterministic gossip simulation for tests. Simulated members run the
+//! topology exchanges and failure detection of a Swarm over a
+//! MemoryTransport, one gossip round at a time in id order, while a
+//! virtual clock stands in for wall time. Joins, failures and
+//! partitions are scripted between rounds.
+//!
+//! Runs are reproducible when every topology uses a deterministic
+//! PeerSelector, such as RoundRobinSelector or StalenessSelector.
+//!
+//! ```
+//! use swarm::prelude::{ClusterBuilder, RoundRobinSelector};
+//! use swarm::sim::Simulation;
+//!
+//! use std::time::Duration;
+//!
+//! let mut sim = Simulation::new(Duration::from_millis(100));
+//! for id in 0..4 {
+//!     let builder = ClusterBuilder::new()
+//!         .peer_selector(RoundRobinSelector::default());
+//!     sim.join(id, Some(0).filter(|_| id != 0), builder).unwrap();
+//! }
+//!
+//! let elapsed = sim.run_until(20, |sim| sim.is_converged());
+//! assert!(elapsed.is_some());
+//! ```
+
+use crate::clock::{Clock, ManualClock};
+use crate::detector::FailureDetector;
+use crate::node::{Node, NodeMap, NodeState};
+use crate::topology::{Topology, TopologyBuilder};
+use crate::transport::{MemoryListener, MemoryTransport, Transport};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+// simulated members listen at 10.0.0.0 + id on this port
+const PORT: u16 = 15000;
+
+/// Simulated cluster of members sharing one virtual clock.
+pub struct Simulation<T: 'static + Topology + Sync + Send> {
+    clock: Arc<ManualClock>,
+    failure_timeouts: Option<(Duration, Duration)>,
+    gossip_interval: Duration,
+    members: BTreeMap<u32, Member<T>>,
+    transport: MemoryTransport,
+}
+
+struct Member<T> {
+    address: SocketAddr,
+    failure_detector: Option<FailureDetector>,
+    group: usize,
+    listener: Option<MemoryListener>,
+    nodes: Arc<NodeMap>,
+    seed_address: Option<SocketAddr>,
+    topology: T,
+}
+
+impl<T: 'static + Topology + Sync + Send> Simulation<T> {
+    pub fn new(gossip_interval: Duration) -> Simulation<T> {
+        Simulation {
+            clock: Arc::new(ManualClock::new(0)),
+            failure_timeouts: None,
+            gossip_interval,
+            members: BTreeMap::new(),
+            transport: MemoryTransport::new(),
+        }
+    }
+
+    /// Runs failure detection on members joining afterwards. See
+    /// Swarm::set_failure_timeouts.
+    pub fn failure_timeouts(mut self, suspect_timeout: Duration,
+            dead_timeout: Duration) -> Simulation<T> {
+        self.failure_timeouts = Some((suspect_timeout, dead_timeout));
+        self
+    }
+
+    /// Returns the gossip address of member `id`.
+    pub fn address(id: u32) -> SocketAddr {
+        let ip_address = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0))
+            .wrapping_add(id));
+        SocketAddr::new(IpAddr::V4(ip_address), PORT)
+    }
+
+    /// Adds member `id`, which joins through member `seed` on its
+    /// first round. New members start outside every partition.
+    pub fn join(&mut self, id: u32, seed: Option<u32>,
+            topology_builder: impl TopologyBuilder<T>)
+            -> Result<(), Box<dyn Error>> {
+        if self.members.contains_key(&id) {
+            return Err(format!("member exists [id={}]", id).into());
+        }
+
+        let address = Simulation::<T>::address(id);
+        let nodes = Arc::new(NodeMap::new());
+        nodes.insert(Node::new(id, address.ip(), address.port()));
+        let member = Member {
+            address,
+            failure_detector: self.failure_timeouts
+                .map(|(suspect_timeout, dead_timeout)| FailureDetector::new(
+                    suspect_timeout, dead_timeout, None)),
+            group: 0,
+            listener: Some(self.transport.bind(address)?),
+            topology: topology_builder.build(id, nodes.clone(),
+                self.clock.clone()),
+            nodes,
+            seed_address: seed.map(Simulation::<T>::address),
+        };
+
+        debug!("joining simulated member [id={}, seed={:?}]", id, seed);
+        self.members.insert(id, member);
+        Ok(())
+    }
+
+    /// Stops member `id` as if it crashed: it neither gossips nor
+    /// accepts exchanges until recovered. Returns false if the member is
+    /// unknown or already failed.
+    pub fn fail(&mut self, id: u32) -> bool {
+        debug!("failing simulated member [id={}]", id);
+        match self.members.get_mut(&id) {
+            Some(member) => member.listener.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Resumes a failed member with the state it held when it failed.
+    pub fn recover(&mut self, id: u32) -> Result<(), Box<dyn Error>> {
+        debug!("recovering simulated member [id={}]", id);
+        let member = self.members.get_mut(&id)
+            .ok_or_else(|| format!("unknown member [id={}]", id))?;
+        if member.listener.is_none() {
+            member.listener = Some(self.transport.bind(member.address)?);
+        }
+
+        Ok(())
+    }
+
+    /// Splits the members into `groups` which cannot reach each other.
+    /// Members left out of every group form one more group together.
+    pub fn partition(&mut self, groups: &[&[u32]]) {
+        debug!("partitioning simulated members [groups={:?}]", groups);
+        for member in self.members.values_mut() {
+            member.group = 0;
+        }
+
+        for (index, group) in groups.iter().enumerate() {
+            for id in group.iter() {
+                if let Some(member) = self.members.get_mut(id) {
+                    member.group = index + 1;
+                }
+            }
+        }
+    }
+
+    /// Removes every partition.
+    pub fn heal(&mut self) {
+        self.partition(&[]);
+    }
+
+    /// Virtual time elapsed since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Returns the membership of member `id` as it sees it.
+    pub fn nodes(&self, id: u32) -> Vec<Node> {
+        self.members.get(&id)
+            .map(|member| member.nodes.nodes()).unwrap_or_default()
+    }
+
+    pub fn topology(&self, id: u32) -> Option<&T> {
+        self.members.get(&id).map(|member| &member.topology)
+    }
+
+    /// Returns true if every running member sees every other running
+    /// member alive.
+    pub fn is_converged(&self) -> bool {
+        let running: Vec<u32> = self.members.iter()
+            .filter(|(_, member)| member.listener.is_some())
+            .map(|(id, _)| *id).collect();
+
+        running.iter().all(|id| {
+            let nodes = &self.members[id].nodes;
+            running.iter().all(|peer_id| nodes.get(*peer_id)
+                .map(|node| node.state() == NodeState::Alive)
+                .unwrap_or(false))
+        })
+    }
+
+    /// Runs one gossip round on every running member in id order, then
+    /// advances the virtual clock by the gossip interval.
+    pub fn step(&mut self) {
+        let now = self.clock.now();
+        for (id, member) in self.members.iter() {
+            if member.listener.is_none() {
+                continue;
+            }
+
+            // mirror the gossiper's round preparation
+            member.nodes.beat(*id);
+            if let Some(ref failure_detector) = member.failure_detector {
+                failure_detector.tick(*id, &member.nodes, now);
+            }
+
+            member.topology.tick();
+
+            let address = match member.topology
+                    .gossip_addr(*id, &member.seed_address) {
+                Some(address) => address,
+                None => continue,
+            };
+
+            // partitioned and failed peers are unreachable
+            let (peer_id, peer) = match self.members.iter()
+                    .find(|(_, peer)| peer.address == address) {
+                Some((peer_id, peer)) if peer.group == member.group
+                    && peer.listener.is_some() => (*peer_id, peer),
+                _ => {
+                    debug!("simulated peer unreachable [id={}, address={}]",
+                        id, address);
+                    continue;
+                },
+            };
+
+            let (client, server) = match self.transport.connect(address)
+                    .and_then(|client| peer.listener.as_ref().unwrap()
+                        .accept().map(|server| (client, server))) {
+                Ok(streams) => streams,
+                Err(e) => {
+                    debug!("simulated connection failure [id={}]: {}", id, e);
+                    continue;
+                },
+            };
+
+            // streams are dropped on failure -> the other side ends too
+            let peer_topology = &peer.topology;
+            let (requested, replied) = std::thread::scope(|scope| {
+                let replier = scope.spawn(move || {
+                    let mut server = server;
+                    peer_topology.reply(&mut server).is_ok()
+                });
+
+                let mut client = client;
+                let requested = member.topology.request(*id, &mut client)
+                    .is_ok();
+                drop(client);
+                (requested, replier.join().unwrap_or(false))
+            });
+
+            debug!("simulated gossip round [id={}, peer_id={}, success={}]",
+                id, peer_id, requested && replied);
+            if requested && replied {
+                if let Some(ref failure_detector) = member.failure_detector {
+                    failure_detector.heard(peer_id, &member.nodes, now);
+                }
+
+                if let Some(ref failure_detector) = peer.failure_detector {
+                    failure_detector.heard(*id, &peer.nodes, now);
+                }
+            }
+        }
+
+        self.clock.advance(self.gossip_interval);
+    }
+
+    /// Runs rounds until `predicate` holds, checking it before every
+    /// round, and returns the virtual time elapsed since the call. Gives
+    /// up with None after `max_rounds` rounds.
+    pub fn run_until<F: Fn(&Simulation<T>) -> bool>(&mut self,
+            max_rounds: usize, predicate: F) -> Option<Duration> {
+        let start = self.elapsed();
+        for _ in 0..max_rounds {
+            if predicate(self) {
+                return Some(self.elapsed() - start);
+            }
+
+            self.step();
+        }
+
+        match predicate(self) {
+            true => Some(self.elapsed() - start),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::NodeState;
+    use crate::prelude::{Cluster, ClusterBuilder, RoundRobinSelector};
+    use super::Simulation;
+
+    use std::time::Duration;
+
+    fn builder() -> ClusterBuilder {
+        ClusterBuilder::new().peer_selector(RoundRobinSelector::default())
+    }
+
+    fn cluster(size: u32) -> Simulation<Cluster> {
+        let mut sim = Simulation::new(Duration::from_millis(100))
+            .failure_timeouts(Duration::from_millis(500),
+                Duration::from_millis(1000));
+        for id in 0..size {
+            sim.join(id, Some(0).filter(|_| id != 0), builder())
+                .expect("join");
+        }
+
+        sim
+    }
+
+    #[test]
+    fn simulated_convergence() {
+        // identical scripts converge in identical virtual time
+        let elapsed: Vec<_> = (0..2).map(|_| cluster(8)
+            .run_until(50, |sim| sim.is_converged())
+            .expect("converge")).collect();
+        assert_eq!(elapsed[0], elapsed[1]);
+        assert!(elapsed[0] > Duration::from_millis(0));
+
+        let mut sim = cluster(4);
+        assert!(sim.join(0, None, builder()).is_err());
+        sim.run_until(50, |sim| sim.is_converged()).expect("converge");
+
+        // failed members are suspected then declared dead
+        assert!(sim.fail(3));
+        assert!(!sim.fail(3));
+        let state = |sim: &Simulation<Cluster>| sim.nodes(0).iter()
+            .find(|node| node.get_id() == 3).map(|node| node.state());
+        let suspected = sim.run_until(50,
+            |sim| state(sim) == Some(NodeState::Suspect)).expect("suspect");
+        assert!(suspected >= Duration::from_millis(500));
+        sim.run_until(50, |sim| state(sim) == Some(NodeState::Dead))
+            .expect("dead");
+        assert!(sim.is_converged());
+    }
+
+    #[test]
+    fn simulated_partition() {
+        let mut sim = Simulation::new(Duration::from_millis(100));
+        for id in 0..4 {
+            sim.join(id, Some(0).filter(|_| id != 0), builder())
+                .expect("join");
+        }
+        sim.run_until(50, |sim| sim.is_converged()).expect("converge");
+
+        // joins spread only within their side of a partition
+        sim.partition(&[&[2, 3]]);
+        sim.join(4, Some(0), builder()).expect("join");
+        sim.run_until(20, |_| false);
+        assert!(sim.nodes(1).iter().any(|node| node.get_id() == 4));
+        assert!(!sim.nodes(2).iter().any(|node| node.get_id() == 4));
+        assert!(!sim.is_converged());
+
+        sim.heal();
+        sim.run_until(50, |sim| sim.is_converged()).expect("converge");
+    }
+}
@@ -0,0 +1,98 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::node::{MergeStatus, Node, NodeMap};
+use crate::store::{StateStore, MEMBERSHIP_KEY};
+use crate::topology::Topology;
+
+use std::error::Error;
+
+/// Writes every known node, and the topology state, to `store`.
+pub fn save<T: Topology>(nodes: &NodeMap, topology: &T,
+        store: &dyn StateStore) -> Result<(), Box<dyn Error>> {
+    let nodes = nodes.nodes();
+    let mut buf = Vec::new();
+    buf.write_u32::<BigEndian>(nodes.len() as u32)?;
+    for node in nodes.iter() {
+        node.write(&mut buf)?;
+    }
+
+    store.put(MEMBERSHIP_KEY, &buf)?;
+    topology.save(store)?;
+    debug!("saved membership snapshot [count={}]", nodes.len());
+    Ok(())
+}
+
+/// Seeds `nodes` and the topology from the snapshot in `store`, if
+/// any, skipping the local node `id`. Returns the number of nodes
+/// registered.
+pub fn load<T: Topology>(id: u32, nodes: &NodeMap, topology: &T,
+        store: &dyn StateStore) -> Result<usize, Box<dyn Error>> {
+    let buf = match store.get(MEMBERSHIP_KEY)? {
+        Some(buf) => buf,
+        None => return Ok(0),
+    };
+
+    let mut reader = buf.as_slice();
+    let mut count = 0;
+    for _ in 0..reader.read_u32::<BigEndian>()? {
+        let node = Node::read(&mut reader)?;
+        if node.get_id() != id
+                && nodes.merge(node) == MergeStatus::Inserted {
+            count += 1;
+        }
+    }
+
+    topology.load(store)?;
+    info!("loaded membership snapshot [count={}]", count);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::prelude::{DhtBuilder, MemoryStore, StateStore, Swarm};
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn membership_snapshots() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let seed_address = SocketAddr::new(ip_address, 16740);
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStore::new());
+        let mut swarms = Vec::new();
+        let mut dhts = Vec::new();
+        for i in 0..2 {
+            let seed_address = Some(seed_address).filter(|_| i != 0);
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address,
+                16740 + i, seed_address, DhtBuilder::new(vec!(i as u64)));
+            if i == 1 {
+                swarm.set_state_store(store.clone());
+                swarm.set_membership_snapshots(Duration::from_millis(50));
+            }
+
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        // stop snapshots the ring member 1 converged on
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || dhts[1].token_counts().len() == 2), "convergence");
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+
+        // restarted members rejoin from the snapshot without a seed
+        let (mut swarm, dht) = Swarm::new(1, ip_address, 16741, None,
+            DhtBuilder::new(vec!(1)));
+        swarm.set_state_store(store);
+        swarm.set_membership_snapshots(Duration::from_millis(50));
+        swarm.start(1, 20, 50).expect("swarm start");
+        assert_eq!(dht.nodes().len(), 2);
+        assert_eq!(dht.locate(0).expect("locate").get_id(), 1);
+        assert_eq!(dht.locate(1).expect("locate").get_id(), 0);
+        swarm.stop().expect("swarm stop");
+    }
+}
@@ -0,0 +1,240 @@
+use crate::metrics::Metrics;
+use crate::node::{NodeMap, NodeState};
+use crate::secret;
+use crate::topology::Topology;
+
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// status thread polls accepts at this interval to observe shutdown
+const ACCEPT_SLEEP_MS: u64 = 50;
+const MAX_REQUEST_BYTES: usize = 8192;
+const TIMEOUT_MS: u64 = 2000;
+
+/// Serves read-only JSON cluster state over HTTP at `address`:
+/// `/members`, `/ring` and `/metrics`. Runs until shutdown.
+pub fn start<T: 'static + Topology + Send + Sync>(address: SocketAddr,
+        nodes: Arc<NodeMap>, topology: Arc<T>, metrics: Arc<Metrics>,
+        shutdown: Arc<AtomicBool>)
+        -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    info!("started http status endpoint [address={}]",
+        listener.local_addr()?);
+
+    let join_handle = thread::spawn(move || {
+        while !shutdown.load(Ordering::Relaxed) {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_SLEEP_MS));
+                    continue;
+                },
+                Err(e) => {
+                    warn!("http status accept failure: {}", e);
+                    continue;
+                },
+            };
+
+            if let Err(e) = serve(&mut stream, &nodes, topology.as_ref(),
+                    &metrics) {
+                debug!("http status request failure: {}", e);
+            }
+        }
+    });
+
+    Ok(join_handle)
+}
+
+fn serve<T: Topology>(stream: &mut TcpStream, nodes: &NodeMap,
+        topology: &T, metrics: &Metrics) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(TIMEOUT_MS)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(TIMEOUT_MS)))?;
+
+    // read request head -> bodies are ignored
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let len = stream.read(&mut buf)?;
+        if len == 0 || request.len() + len > MAX_REQUEST_BYTES {
+            break;
+        }
+
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut fields = request.split_whitespace();
+    let (method, path) = (fields.next().unwrap_or(""),
+        fields.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, body) = match (method, path) {
+        ("GET", path) => match route(path, nodes, topology, metrics) {
+            Some(body) => ("200 OK", body),
+            None => ("404 Not Found",
+                "{\"error\":\"not found\"}".to_string()),
+        },
+        _ => ("405 Method Not Allowed",
+            "{\"error\":\"method not allowed\"}".to_string()),
+    };
+
+    debug!("served http status request [method={}, path={}, status={}]",
+        method, path, status);
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Renders the JSON body of `path`, if it exists.
+fn route<T: Topology>(path: &str, nodes: &NodeMap, topology: &T,
+        metrics: &Metrics) -> Option<String> {
+    match path.trim_end_matches('/') {
+        "/members" => {
+            let members: Vec<String> = nodes.nodes().iter().map(|node| {
+                let state = match node.state() {
+                    NodeState::Alive => "alive",
+                    NodeState::Suspect => "suspect",
+                    NodeState::Dead => "dead",
+                };
+
+                let metadata: Vec<String> = node.metadata()
+                    .map(|(key, value)| format!("{}:{}", json_string(key),
+                        json_string(secret::redact(key, value))))
+                    .collect();
+                let roles: Vec<String> =
+                    node.roles().map(|role| json_string(role)).collect();
+                format!("{{\"id\":{},\"address\":\"{}\",\"state\":\"{}\",\"incarnation\":{},\"version\":{},\"roles\":[{}],\"metadata\":{{{}}}}}",
+                    node.get_id(), node.get_address(), state,
+                    node.get_incarnation(), node.get_version(),
+                    roles.join(","), metadata.join(","))
+            }).collect();
+            Some(format!("[{}]", members.join(",")))
+        },
+        "/ring" => match (topology.ring_epoch(), topology.ring_tokens()) {
+            (Some(epoch), Some(tokens)) => {
+                let tokens: Vec<String> = tokens.iter()
+                    .map(|(token, id)|
+                        format!("{{\"token\":{},\"id\":{}}}", token, id))
+                    .collect();
+                Some(format!("{{\"epoch\":{},\"tokens\":[{}]}}",
+                    epoch, tokens.join(",")))
+            },
+            _ => Some("null".to_string()),
+        },
+        "/metrics" => {
+            let snapshot = metrics.snapshot(nodes.len());
+            let peers: Vec<String> = metrics.peer_snapshot().iter()
+                .map(|peer| format!("{{\"address\":\"{}\",\"rounds_succeeded\":{},\"rounds_failed\":{},\"bytes_sent\":{},\"bytes_received\":{},\"last_latency_us\":{}}}",
+                    peer.address, peer.rounds_succeeded, peer.rounds_failed,
+                    peer.bytes_sent, peer.bytes_received,
+                    peer.last_latency.as_micros()))
+                .collect();
+            Some(format!("{{\"member_count\":{},\"rounds_attempted\":{},\"rounds_succeeded\":{},\"rounds_failed\":{},\"rounds_deferred\":{},\"replies_succeeded\":{},\"replies_failed\":{},\"connection_errors\":{},\"bytes_sent\":{},\"bytes_received\":{},\"peers\":[{}]}}",
+                snapshot.member_count, snapshot.rounds_attempted,
+                snapshot.rounds_succeeded, snapshot.rounds_failed,
+                snapshot.rounds_deferred, snapshot.replies_succeeded,
+                snapshot.replies_failed, snapshot.connection_errors,
+                snapshot.bytes_sent, snapshot.bytes_received,
+                peers.join(",")))
+        },
+        _ => None,
+    }
+}
+
+/// Quotes and escapes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 =>
+                json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::http::{self, HttpUrl};
+    use crate::prelude::{DhtBuilder, Swarm, Topology};
+    use super::json_string;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn http_status() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let seed_address = SocketAddr::new(ip_address, 16760);
+        let mut swarms = Vec::new();
+        let mut dhts = Vec::new();
+        for i in 0..2 {
+            let seed_address = Some(seed_address).filter(|_| i != 0);
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address,
+                16760 + i, seed_address, DhtBuilder::new(vec!(i as u64)));
+            if i == 0 {
+                swarm.set_metadata("region", "eu");
+                swarm.enable_http_status(SocketAddr::new(ip_address, 16762));
+            }
+
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        // the ring converges and member 0 measures a round with member 1
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            dhts[0].token_counts().len() == 2
+                && dhts[0].peer_stats().contains_key(&1)), "convergence");
+        let url = HttpUrl::parse("http://127.0.0.1:16762").expect("parse");
+        let timeout = Duration::from_millis(1000);
+        let get = |path| http::request(&url, "GET", path, &[], "", timeout)
+            .expect("http request");
+
+        let (status, body) = get("/members");
+        assert_eq!(status, 200);
+        assert!(body.starts_with("[{\"id\":0,\"address\":\"127.0.0.1:16760\",\"state\":\"alive\""));
+        assert!(body.contains("\"region\":\"eu\"}"));
+        assert!(body.contains("\"id\":1,"));
+
+        let (status, body) = get("/ring");
+        assert_eq!(status, 200);
+        assert!(body.ends_with("\"tokens\":[{\"token\":0,\"id\":0},{\"token\":1,\"id\":1}]}"));
+
+        let (status, body) = get("/metrics");
+        assert_eq!(status, 200);
+        assert!(body.starts_with("{\"member_count\":2,"));
+        assert!(body.contains("\"address\":\"127.0.0.1:16761\""));
+
+        assert_eq!(get("/unknown").0, 404);
+        assert_eq!(http::request(&url, "POST", "/members", &[], "", timeout)
+            .expect("http request").0, 405);
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::store::StateStore;
+
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct FileStore {
+    directory: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(directory: impl AsRef<Path>)
+            -> Result<FileStore, Box<dyn Error>> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        Ok(FileStore { directory, lock: Mutex::new(()) })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Box<dyn Error>> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()
+                || c == '_' || c == '-' || c == '.') {
+            return Err(format!("invalid store key [key={}]", key).into());
+        }
+
+        Ok(self.directory.join(key))
+    }
+}
+
+impl StateStore for FileStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let path = self.path(key)?;
+        let _lock = self.lock.lock().unwrap();
+        match fs::read(&path) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.path(key)?;
+        let tmp_path = path.with_extension("tmp");
+        let _lock = self.lock.lock().unwrap();
+
+        // write to temporary file and rename so updates are atomic
+        fs::write(&tmp_path, value)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.path(key)?;
+        let _lock = self.lock.lock().unwrap();
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{INCARNATION_KEY, StateStore};
+    use super::FileStore;
+
+    #[test]
+    fn file_store_cycle() {
+        let directory = std::env::temp_dir()
+            .join(format!("swarm-file-store-{}", std::process::id()));
+        let store = FileStore::new(&directory).expect("create store");
+
+        assert_eq!(store.get_u64(INCARNATION_KEY).expect("get"), None);
+        store.put_u64(INCARNATION_KEY, 3).expect("put");
+        assert_eq!(store.get_u64(INCARNATION_KEY).expect("get"), Some(3));
+        store.remove(INCARNATION_KEY).expect("remove");
+        assert_eq!(store.get(INCARNATION_KEY).expect("get"), None);
+        assert!(store.put("../escape", &[0]).is_err());
+
+        std::fs::remove_dir_all(&directory).expect("remove directory");
+    }
+}
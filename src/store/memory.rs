@@ -0,0 +1,35 @@
+use crate::store::StateStore;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    values: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let values = self.values.read().unwrap();
+        Ok(values.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut values = self.values.write().unwrap();
+        values.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let mut values = self.values.write().unwrap();
+        values.remove(key);
+        Ok(())
+    }
+}
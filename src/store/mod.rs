@@ -0,0 +1,37 @@
+use byteorder::{BigEndian, ByteOrder};
+
+pub(crate) mod file;
+pub(crate) mod memory;
+#[cfg(feature = "sled")]
+pub(crate) mod sled;
+
+use std::error::Error;
+
+pub const EPOCH_KEY: &str = "epoch";
+pub const IDENTITY_KEY: &str = "identity";
+pub const INCARNATION_KEY: &str = "incarnation";
+pub const LOCAL_TOKENS_KEY: &str = "local_tokens";
+pub const MEMBERSHIP_KEY: &str = "membership";
+pub const TOKENS_KEY: &str = "tokens";
+pub const TOMBSTONES_KEY: &str = "tombstones";
+
+pub trait StateStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn remove(&self, key: &str) -> Result<(), Box<dyn Error>>;
+
+    fn get_u64(&self, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.get(key)? {
+            Some(ref buf) if buf.len() == 8 =>
+                Ok(Some(BigEndian::read_u64(buf))),
+            Some(_) => Err(format!("invalid u64 value [key={}]", key).into()),
+            None => Ok(None),
+        }
+    }
+
+    fn put_u64(&self, key: &str, value: u64) -> Result<(), Box<dyn Error>> {
+        let mut buf = [0u8; 8];
+        BigEndian::write_u64(&mut buf, value);
+        self.put(key, &buf)
+    }
+}
@@ -0,0 +1,33 @@
+use crate::store::StateStore;
+
+use std::error::Error;
+use std::path::Path;
+
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn new(path: impl AsRef<Path>)
+            -> Result<SledStore, Box<dyn Error>> {
+        Ok(SledStore { db: sled::open(path)? })
+    }
+}
+
+impl StateStore for SledStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.db.insert(key, value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.db.remove(key)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,2777 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::TcpListener as MioListener;
+
+use crate::admin::{self, RoundTrigger};
+use crate::backoff::ConnectBackoff;
+use crate::bootstrap::Bootstrap;
+use crate::broadcast::BroadcastQueue;
+use crate::buffer::{BufferedStream, ExchangeBuffers};
+use crate::budget::GossipBudget;
+use crate::cidr::Cidr;
+use crate::clock::{self, Clock, SystemClock};
+use crate::codec;
+use crate::control::{self, ControlChannel, ControlMessage};
+use crate::detector::FailureDetector;
+#[cfg(feature = "discover")]
+use crate::discover::Discovery;
+use crate::distribution::ConfigDistribution;
+use crate::drain::{self, ConnectionDrainer, ConnectionHandle};
+use crate::exchange::{self, Exchanges, ADMIN_EXCHANGE, CONTROL_EXCHANGE,
+    FEDERATION_EXCHANGE, KEEPALIVE_EXCHANGE, LOCK_EXCHANGE,
+    PLUMTREE_EXCHANGE, POOLED_EXCHANGE, PROBE_EXCHANGE, PUBSUB_EXCHANGE,
+    SERVICE_EXCHANGE, SUBSCRIBE_EXCHANGE, TRACKED_EXCHANGE,
+    UNTRACKED_EXCHANGE};
+use crate::federation::{self, Federation};
+use crate::journal::{self, ChangeJournal};
+use crate::keepalive::{self, KeepaliveEvent};
+use crate::metrics::{MeteredStream, Metrics, MetricsSnapshot, PeerMetrics};
+use crate::namespace::MetadataNamespace;
+use crate::node::{MetadataBatch, Node, NodeMap, TieBreaker, DRAINING_KEY};
+use crate::partition::{PartitionDetector, PartitionEvent};
+use crate::phase::{PhaseSnapshot, PhaseTracker};
+use crate::piggyback::Piggyback;
+use crate::plumtree::{self, Plumtree};
+use crate::pool::ConnectionPool;
+use crate::preflight::{self, PreflightReport};
+use crate::probe::{self, IndirectProbes};
+use crate::secret;
+use crate::service::dispatch::{self, ServiceRegistry, SwarmService};
+use crate::service::election::{self, Election};
+use crate::service::lock::LockService;
+use crate::service::pubsub::PubSub;
+use crate::snapshot;
+use crate::store::{self, StateStore};
+use crate::store::memory::MemoryStore;
+use crate::threads::{ConnectionThreads, WorkerSockets};
+use crate::topology::{Topology, TopologyBuilder};
+use crate::trace::{self, ExchangeSpan};
+use crate::webhook::{self, Webhook};
+
+use std::error::Error;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// thread model used by SwarmBuilder and Swarm::start_configured
+pub(crate) const DEFAULT_GOSSIP_INTERVAL_MS: u64 = 1000;
+pub(crate) const DEFAULT_THREAD_COUNT: u8 = 4;
+pub(crate) const DEFAULT_THREAD_SLEEP_MS: u64 = 50;
+// pooled connection threads wake at least this often to observe shutdown
+const POOLED_POLL_MS: u64 = 500;
+// listener poll tokens and events handled per wakeup
+const EVENT_CAPACITY: usize = 16;
+const LISTENER_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+// admission reply to peers of an older cluster epoch
+const STALE_EPOCH: u8 = 2;
+
+/// Returned by Swarm::wait_for_members when the membership map still
+/// held fewer than `count` nodes once the timeout elapsed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeoutError {
+    pub count: usize,
+    pub members: usize,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out waiting for members [count={}, members={}]",
+            self.count, self.members)
+    }
+}
+
+impl Error for TimeoutError {}
+
+pub struct Swarm<T: 'static + Topology + Sync + Send> {
+    address: SocketAddr,
+    admin: bool,
+    allowed_cidrs: Vec<Cidr>,
+    bootstrap: Option<Arc<Bootstrap>>,
+    broadcasts: Arc<BroadcastQueue>,
+    budget: Option<Arc<GossipBudget>>,
+    budget_limits: Option<(u32, u64)>,
+    change_journal: Option<Arc<ChangeJournal>>,
+    clock: Arc<dyn Clock>,
+    connect_backoff: Arc<ConnectBackoff>,
+    connection_threads: Arc<ConnectionThreads>,
+    control: Arc<ControlChannel>,
+    drainer: Arc<ConnectionDrainer>,
+    draining: Arc<AtomicBool>,
+    election: Arc<Election>,
+    exchanges: Arc<Exchanges>,
+    failure_detector: Option<Arc<FailureDetector>>,
+    federation: Option<Arc<Federation>>,
+    heartbeat_timeout: Option<Duration>,
+    id: u32,
+    indirect_probes: Option<IndirectProbes>,
+    join_handles: Vec<JoinHandle<()>>,
+    #[cfg(feature = "k8s")]
+    kubernetes_seeds: Option<crate::k8s::KubernetesSeeds>,
+    locks: Arc<LockService>,
+    max_local_health: u32,
+    #[cfg(feature = "mdns")]
+    mdns: bool,
+    metrics: Arc<Metrics>,
+    nodes: Arc<NodeMap>,
+    outbound_rate: Option<u64>,
+    partition: Arc<PartitionDetector>,
+    phase: Arc<PhaseTracker>,
+    piggyback: Arc<Piggyback>,
+    plumtree: Option<Arc<Plumtree>>,
+    pool: Option<Arc<ConnectionPool>>,
+    pubsub: Arc<PubSub>,
+    seed_address: Option<SocketAddr>,
+    services: Arc<ServiceRegistry>,
+    shutdown: Arc<AtomicBool>,
+    snapshot_interval: Option<Duration>,
+    state_store: Arc<dyn StateStore>,
+    #[cfg(feature = "http-status")]
+    status_address: Option<SocketAddr>,
+    thread_model: (u8, u64, u64),
+    topology: Arc<T>,
+    trigger: Arc<RoundTrigger>,
+    wakers: Vec<Waker>,
+    webhooks: Vec<Webhook>,
+    worker_sockets: Arc<WorkerSockets>,
+}
+
+impl<T: 'static + Topology + Sync + Send> Swarm<T> {
+    pub fn new(id: u32, ip_address: IpAddr, port: u16,
+            seed_address: Option<SocketAddr>,
+            topology_builder: impl TopologyBuilder<T>)
+            -> (Swarm<T>, Arc<T>) {
+        Swarm::with_clock(id, ip_address, port, seed_address,
+            Arc::new(SystemClock), topology_builder)
+    }
+
+    /// Creates a swarm reading time from `clock`, which is shared with
+    /// the topology built by `topology_builder`.
+    pub fn with_clock(id: u32, ip_address: IpAddr, port: u16,
+            seed_address: Option<SocketAddr>, clock: Arc<dyn Clock>,
+            topology_builder: impl TopologyBuilder<T>)
+            -> (Swarm<T>, Arc<T>) {
+        info!("initializing swarm [id={}, address={}:{}, seed_addr={:?}]",
+            id, ip_address, port, seed_address);
+
+        // initialize nodes
+        let nodes = Arc::new(NodeMap::new());
+        nodes.insert(Node::new(id, ip_address, port));
+
+        // initialize topology
+        let topology = Arc::new(
+            topology_builder.build(id, nodes.clone(), clock.clone()));
+
+        // initialize services
+        let election = Arc::new(Election::new(id, nodes.clone()));
+        let locks = Arc::new(LockService::new(id,
+            election.clone(), nodes.clone()));
+
+        // initialize swarm
+        let swarm = Swarm {
+            address: SocketAddr::new(ip_address, port), 
+            admin: false,
+            allowed_cidrs: Vec::new(),
+            bootstrap: None,
+            broadcasts: Arc::new(BroadcastQueue::new()),
+            budget: None,
+            budget_limits: None,
+            change_journal: None,
+            clock: clock.clone(),
+            connect_backoff: Arc::new(ConnectBackoff::new()),
+            control: Arc::new(ControlChannel::new(id)),
+            connection_threads: Arc::new(ConnectionThreads::new()),
+            drainer: Arc::new(ConnectionDrainer::new()),
+            draining: Arc::new(AtomicBool::new(false)),
+            election,
+            exchanges: Arc::new(Exchanges::new(id)),
+            failure_detector: None,
+            federation: None,
+            heartbeat_timeout: None,
+            id,
+            indirect_probes: None,
+            join_handles: Vec::new(),
+            #[cfg(feature = "k8s")]
+            kubernetes_seeds: None,
+            locks,
+            max_local_health: 0,
+            #[cfg(feature = "mdns")]
+            mdns: false,
+            metrics: Arc::new(Metrics::new()),
+            nodes: nodes.clone(),
+            outbound_rate: None,
+            partition: Arc::new(PartitionDetector::new()),
+            phase: Arc::new(PhaseTracker::new(
+                Duration::from_millis(DEFAULT_GOSSIP_INTERVAL_MS),
+                clock.now())),
+            piggyback: Arc::new(Piggyback::new()),
+            plumtree: None,
+            pool: None,
+            pubsub: Arc::new(PubSub::new(id, nodes)),
+            seed_address,
+            services: Arc::new(ServiceRegistry::new()),
+            shutdown: Arc::new(AtomicBool::new(true)),
+            snapshot_interval: None,
+            state_store: Arc::new(MemoryStore::new()),
+            #[cfg(feature = "http-status")]
+            status_address: None,
+            thread_model: (DEFAULT_THREAD_COUNT, DEFAULT_THREAD_SLEEP_MS,
+                DEFAULT_GOSSIP_INTERVAL_MS),
+            topology: topology.clone(),
+            trigger: Arc::new(RoundTrigger::new()),
+            wakers: Vec::new(),
+            webhooks: Vec::new(),
+            worker_sockets: Arc::new(WorkerSockets::new()),
+        };
+
+        (swarm, topology)
+    }
+
+    /// Enables bootstrap mode for clusters whose members start at the
+    /// same time: every candidate is contacted in turn until membership
+    /// has been stable for `settle_window`. See Swarm::is_ready.
+    pub fn set_bootstrap(&mut self, candidates: Vec<SocketAddr>,
+            settle_window: Duration) {
+        self.bootstrap = Some(Arc::new(
+            Bootstrap::new(candidates, settle_window)));
+    }
+
+    /// Enables bootstrap mode with candidates listed from Kubernetes
+    /// pods and refreshed periodically. See Swarm::set_bootstrap.
+    #[cfg(feature = "k8s")]
+    pub fn set_kubernetes_seeds(&mut self,
+            seeds: crate::k8s::KubernetesSeeds) {
+        self.set_bootstrap(Vec::new(), seeds.get_settle_window());
+        self.kubernetes_seeds = Some(seeds);
+    }
+
+    /// Journals the latest `capacity` membership deltas and serves them
+    /// to external consumers subscribing on the gossip port. See
+    /// Subscription.
+    pub fn set_change_journal(&mut self, capacity: usize) {
+        self.change_journal = Some(Arc::new(ChangeJournal::new(capacity)));
+    }
+
+    /// Returns true once bootstrap has settled, or immediately when
+    /// bootstrap mode is disabled or the topology is static.
+    pub fn is_ready(&self) -> bool {
+        match self.bootstrap {
+            Some(ref bootstrap) if !self.topology.is_static() =>
+                bootstrap.is_ready(),
+            _ => true,
+        }
+    }
+
+    /// Blocks until the membership map holds at least `count` nodes,
+    /// including this one, failing once `timeout` elapses first on the
+    /// swarm's clock.
+    pub fn wait_for_members(&self, count: usize, timeout: Duration)
+            -> Result<(), TimeoutError> {
+        match clock::wait_until(self.clock.as_ref(), timeout,
+                || self.nodes.len() >= count) {
+            true => Ok(()),
+            false => Err(TimeoutError { count, members: self.nodes.len() }),
+        }
+    }
+
+    /// Returns true while no gossip round has succeeded for the
+    /// partition threshold. See Swarm::set_partition_threshold.
+    pub fn is_partitioned(&self) -> bool {
+        self.partition.is_partitioned()
+    }
+
+    /// Treats this node as partitioned from the swarm after `rounds`
+    /// consecutive failed gossip rounds, re-contacting the seed or
+    /// bootstrap candidates until a round succeeds. Defaults to 10.
+    pub fn set_partition_threshold(&mut self, rounds: u32) {
+        self.partition.set_threshold(rounds);
+    }
+
+    /// Returns the cluster epoch, which may have advanced past the
+    /// configured one by rejoining a newer cluster.
+    pub fn get_cluster_epoch(&self) -> u64 {
+        self.nodes.get_cluster_epoch()
+    }
+
+    /// Sets the cluster epoch, to be raised whenever the cluster is
+    /// wiped and redeployed. Every exchange between members carries the
+    /// epoch alongside the cluster name: exchanges of members with an
+    /// older epoch are rejected, and gossiping ones are told to forget
+    /// their membership and rejoin, so members of a previous deployment
+    /// cannot resurrect it. Epochs persist in the state store and never
+    /// decrease. Defaults to 0.
+    pub fn set_cluster_epoch(&mut self, cluster_epoch: u64) {
+        self.nodes.set_cluster_epoch(cluster_epoch);
+    }
+
+    pub fn get_cluster_name(&self) -> String {
+        self.nodes.get_cluster_name()
+    }
+
+    /// Only gossips membership with peers sharing `cluster_name`, so
+    /// unrelated clusters on adjacent ports cannot merge by accident.
+    /// Every exchange between members, including one-off queries and
+    /// service messages, names the cluster before any state, and
+    /// mismatched exchanges are dropped. Names are at most 255 bytes.
+    /// Defaults to the empty name.
+    pub fn set_cluster_name(&mut self, cluster_name: &str) {
+        self.nodes.set_cluster_name(cluster_name);
+    }
+
+    /// Only accepts gossip connections from addresses within
+    /// `allowed_cidrs`. Other connections are closed as they are
+    /// accepted, before any of their bytes are read. Defaults to
+    /// accepting every address.
+    pub fn set_allowed_cidrs(&mut self, allowed_cidrs: Vec<Cidr>) {
+        self.allowed_cidrs = allowed_cidrs;
+    }
+
+    /// Chooses between conflicting records of the same member, as found
+    /// when a split brain heals. Higher incarnations always win, then
+    /// higher versions; records of equal incarnation and version which
+    /// disagree go to `tie_breaker`. Every member must use the same
+    /// tie breaker. Defaults to TieBreaker::LowestAddress.
+    pub fn set_tie_breaker(&mut self, tie_breaker: TieBreaker) {
+        self.nodes.set_tie_breaker(tie_breaker);
+    }
+
+    /// Writes the membership, and topology state such as Dht tokens, to
+    /// the state store every `interval` and on stop. Starts seed the
+    /// membership from the last snapshot before the first gossip round,
+    /// so restarted nodes rejoin even while every seed is down.
+    pub fn set_membership_snapshots(&mut self, interval: Duration) {
+        self.snapshot_interval = Some(interval);
+    }
+
+    /// Calls `handler` whenever this node becomes partitioned from the
+    /// swarm or rejoins it, and when conflicting member records merge.
+    pub fn on_partition<F: 'static + Fn(PartitionEvent) + Send + Sync>(
+            &mut self, handler: F) {
+        self.partition.add_handler(Box::new(handler));
+    }
+
+    pub fn get_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Replaces the clock of the swarm. The topology keeps the clock it
+    /// was built with, see Swarm::with_clock to share one with both.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Enables failure detection: peers not heard from within
+    /// `suspect_timeout` become suspect and are dead after a further
+    /// `dead_timeout`. Gossip skips both. Disabled by default, leaving
+    /// every known peer alive.
+    pub fn set_failure_timeouts(&mut self, suspect_timeout: Duration,
+            dead_timeout: Duration) {
+        self.failure_detector = Some(Arc::new(
+            FailureDetector::new(suspect_timeout, dead_timeout,
+                self.heartbeat_timeout)
+            .with_max_local_health(self.max_local_health)));
+    }
+
+    /// Scales failure timeouts with local health, as in Lifeguard, so
+    /// an overloaded member does not suspect healthy peers it was too
+    /// slow to hear from. Rounds starting two gossip intervals late and
+    /// failed rounds whose peer was reached indirectly raise the score
+    /// up to `max_local_health`, successful rounds lower it, and
+    /// timeouts are multiplied by one more than the score. Applies once
+    /// failure timeouts are set.
+    pub fn set_local_health(&mut self, max_local_health: u32) {
+        self.max_local_health = max_local_health;
+        self.failure_detector = self.failure_detector.as_ref()
+            .map(|failure_detector| Arc::new(failure_detector
+                .with_max_local_health(max_local_health)));
+    }
+
+    /// Returns the local health score, zero when healthy or when local
+    /// health is not tracked. See Swarm::set_local_health.
+    pub fn local_health(&self) -> u32 {
+        self.failure_detector.as_ref()
+            .map(|failure_detector| failure_detector.local_health())
+            .unwrap_or(0)
+    }
+
+    /// Also suspects peers whose heartbeat counter, advanced by every
+    /// member each gossip round, stops advancing for `heartbeat_timeout`,
+    /// catching members which answer exchanges but no longer gossip.
+    /// Applies once failure timeouts are set.
+    pub fn set_heartbeat_timeout(&mut self, heartbeat_timeout: Duration) {
+        self.heartbeat_timeout = Some(heartbeat_timeout);
+        self.failure_detector = self.failure_detector.as_ref()
+            .map(|failure_detector| Arc::new(failure_detector
+                .with_heartbeat_timeout(self.heartbeat_timeout)));
+    }
+
+    /// Before a failed gossip round counts against a peer, asks `count`
+    /// other members to reach it within `timeout`, SWIM-style, so one
+    /// broken route does not get a healthy peer suspected. Applies once
+    /// failure timeouts are set.
+    pub fn set_indirect_probes(&mut self, count: usize, timeout: Duration) {
+        self.indirect_probes = Some(IndirectProbes::new(count, timeout));
+    }
+
+    /// Backs off gossip targets which refuse connections, starting at
+    /// `base_backoff` and doubling per consecutive failure up to
+    /// `max_backoff`. Targets are reported unreachable after
+    /// `max_retries` failures. Defaults to 500ms, 30s and 8 retries.
+    pub fn set_connect_backoff(&mut self, base_backoff: Duration,
+            max_backoff: Duration, max_retries: u32) {
+        self.connect_backoff.set_limits(base_backoff, max_backoff,
+            max_retries);
+    }
+
+    /// Calls `handler` with the address and failure count of gossip
+    /// targets, such as seeds, once their retry budget is exhausted.
+    /// Targets keep being retried at the maximum backoff.
+    pub fn on_unreachable<F: 'static + Fn(SocketAddr, u32) + Send + Sync>(
+            &mut self, handler: F) {
+        self.connect_backoff.set_handler(Box::new(handler));
+    }
+
+    /// Keeps gossip connections open between rounds so consecutive
+    /// rounds to a peer share one connection, closing connections idle
+    /// for `idle_timeout`. Peers serve pooled connections regardless, up
+    /// to the connection limit, see Swarm::set_connection_limit.
+    pub fn set_connection_pool(&mut self, idle_timeout: Duration) {
+        self.pool = Some(Arc::new(ConnectionPool::new(idle_timeout)));
+    }
+
+    /// Makes this node a federation gateway, exchanging swarm summaries
+    /// with remote gateways once per gossip interval. The returned
+    /// handle lists remote swarms.
+    pub fn set_federation(&mut self, federation: Federation)
+            -> Arc<Federation> {
+        let federation = Arc::new(federation);
+        self.federation = Some(federation.clone());
+        federation
+    }
+
+    /// Answers admin queries on the gossip port, listing members, ring
+    /// tokens, gossip counters and per-peer counters, and accepting
+    /// requests to mark members dead, remove them cluster-wide or start
+    /// a gossip round. See AdminClient.
+    ///
+    /// Admin queries carry no cluster name, epoch or credentials, so
+    /// anyone who can reach the gossip port can remove members. Limit
+    /// who can with set_allowed_cidrs or the network.
+    pub fn enable_admin(&mut self) {
+        self.admin = true;
+    }
+
+    /// Starts a gossip round without waiting for the gossip interval.
+    pub fn gossip_now(&self) {
+        self.trigger.trigger();
+    }
+
+    /// Serves read-only JSON cluster state over HTTP at `address`, with
+    /// `/members`, `/ring` and `/metrics` endpoints. Sensitive metadata
+    /// values are redacted.
+    #[cfg(feature = "http-status")]
+    pub fn enable_http_status(&mut self, address: SocketAddr) {
+        self.status_address = Some(address);
+    }
+
+    /// Advertises this node and discovers peers on the local network
+    /// over mDNS, so members need no seed address.
+    #[cfg(feature = "mdns")]
+    pub fn enable_mdns(&mut self) {
+        self.mdns = true;
+    }
+
+    /// Delivers membership events (join, leave, dead, ring change) to
+    /// `webhook`, checking for changes once per gossip interval.
+    pub fn add_webhook(&mut self, webhook: Webhook) {
+        self.webhooks.push(webhook);
+    }
+
+    /// Caps the exchanges (started and served) and bytes per gossip
+    /// interval. Rounds over budget run in the next interval instead.
+    pub fn set_gossip_budget(&mut self, max_exchanges: u32, max_bytes: u64) {
+        self.budget_limits = Some((max_exchanges, max_bytes));
+    }
+
+    /// Caps the gossip bytes sent per second, by rounds this node starts
+    /// and replies it serves, so membership traffic cannot starve the
+    /// application on constrained links. Exchanges over the cap are
+    /// deferred as with Swarm::set_gossip_budget.
+    pub fn set_outbound_rate(&mut self, bytes_per_second: u64) {
+        self.outbound_rate = Some(bytes_per_second);
+    }
+
+    /// Shifts the gossip phase by a random delay whenever phase locking
+    /// is detected. Disabled by default.
+    pub fn set_auto_dephase(&mut self, auto_dephase: bool) {
+        self.phase.set_auto_dephase(auto_dephase);
+    }
+
+    /// Returns gossip concurrency statistics of the last completed
+    /// gossip interval.
+    pub fn phase_stats(&self) -> PhaseSnapshot {
+        self.phase.snapshot(self.clock.now())
+    }
+
+    /// Returns gossip counters accumulated since this Swarm was created
+    /// along with the current member count.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot(self.nodes.len())
+    }
+
+    /// Returns counters of the gossip rounds this node started with each
+    /// peer, ordered by peer address.
+    pub fn peer_metrics(&self) -> Vec<PeerMetrics> {
+        self.metrics.peer_snapshot()
+    }
+
+    /// Returns the number of idle pooled gossip connections.
+    pub fn pooled_connections(&self) -> usize {
+        self.pool.as_ref().map(|pool| pool.len()).unwrap_or(0)
+    }
+
+    pub fn get_state_store(&self) -> Arc<dyn StateStore> {
+        self.state_store.clone()
+    }
+
+    pub fn set_state_store(&mut self, state_store: Arc<dyn StateStore>) {
+        self.state_store = state_store;
+    }
+
+    /// Broadcasts `payload` to every member by piggybacking it on gossip
+    /// exchanges, returning the message id. Payloads are limited to 16KiB.
+    pub fn broadcast(&self, payload: &[u8]) -> Result<u64, Box<dyn Error>> {
+        let node = self.nodes.get(self.id).unwrap();
+        self.broadcasts.enqueue(&node, payload)
+    }
+
+    /// Returns the number of broadcasts this member still retransmits.
+    pub fn pending_broadcasts(&self) -> usize {
+        self.broadcasts.pending()
+    }
+
+    /// Calls `handler` once with the origin and payload of every
+    /// broadcast received from other members.
+    pub fn on_broadcast<F: 'static + Fn(&Node, &[u8]) + Send + Sync>(
+            &mut self, handler: F) {
+        self.broadcasts.set_handler(Box::new(handler));
+    }
+
+    /// Sets the value of `key` piggybacked on gossip exchanges, such as
+    /// a load hint. Each direct gossip partner receives every value
+    /// once through Swarm::on_piggyback; values are never forwarded.
+    /// Values are limited to 1KiB and 8KiB altogether.
+    pub fn piggyback(&self, key: &str, value: &[u8])
+            -> Result<(), Box<dyn Error>> {
+        self.piggyback.set(key, value)
+    }
+
+    /// Stops piggybacking `key`, returning false if it was not set.
+    pub fn remove_piggyback(&self, key: &str) -> bool {
+        self.piggyback.remove(key)
+    }
+
+    /// Calls `handler` with the sending member id, key and value of
+    /// every piggybacked value received.
+    pub fn on_piggyback<F: 'static + Fn(u32, &str, &[u8]) + Send + Sync>(
+            &mut self, handler: F) {
+        self.piggyback.set_handler(Box::new(handler));
+    }
+
+    /// Sends `payload` directly to every other member, returning the
+    /// message id. See Swarm::set_broadcast_inbox for members which are
+    /// unreachable. Must be called after Swarm::start.
+    pub fn broadcast_control(&self, payload: &[u8]) -> u64 {
+        self.control.broadcast(&self.nodes, payload, self.clock.now())
+    }
+
+    /// Calls `handler` once for every control message received.
+    pub fn on_control<F: 'static + Fn(&ControlMessage) + Send + Sync>(
+            &mut self, handler: F) {
+        self.control.set_handler(Box::new(handler));
+    }
+
+    /// Returns the number of control messages this member retains for
+    /// unreachable member `id`.
+    pub fn retained_control(&self, id: u32) -> usize {
+        self.control.retained(id)
+    }
+
+    /// Retains up to `capacity` control messages per unreachable member
+    /// for `ttl`, delivering them once the member rejoins. Messages are
+    /// held by the member's successor, so every member should enable
+    /// the inbox.
+    pub fn set_broadcast_inbox(&mut self, capacity: usize, ttl: Duration) {
+        self.control.set_inbox(capacity, ttl);
+    }
+
+    /// Registers an application connection to member `peer`. Once the
+    /// peer leaves or is declared dead, `on_drain` is called with the
+    /// peer id and the deadline for tearing the connection down. Drop
+    /// the returned handle when the connection closes normally.
+    pub fn register_connection<F>(&self, peer: u32, on_drain: F)
+            -> ConnectionHandle
+            where F: 'static + FnOnce(u32, Instant) + Send {
+        self.drainer.register(peer, Box::new(on_drain))
+    }
+
+    /// Returns the number of registered connections to member `peer`.
+    pub fn connections(&self, peer: u32) -> usize {
+        self.drainer.connections(peer)
+    }
+
+    /// Gives drained connections `timeout` to close, 30s by default.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drainer.set_timeout(timeout);
+    }
+
+    /// Limits the threads serving long-lived inbound connections, such
+    /// as keepalive channels and pooled gossip, to `limit` per exchange
+    /// kind. Connections past the limit are closed. Defaults to 256.
+    pub fn set_connection_limit(&mut self, limit: usize) {
+        self.connection_threads.set_limit(limit);
+    }
+
+    /// Enables Plumtree broadcasts, returning the handle to broadcast
+    /// and receive them with. Members missing an announced message
+    /// repair the tree after `graft_timeout`.
+    pub fn enable_plumtree(&mut self, graft_timeout: Duration)
+            -> Arc<Plumtree> {
+        let plumtree = Arc::new(Plumtree::new(self.id,
+            self.nodes.clone(), graft_timeout));
+        self.plumtree = Some(plumtree.clone());
+        plumtree
+    }
+
+    /// Returns the leader election service.
+    pub fn election(&self) -> Arc<Election> {
+        self.election.clone()
+    }
+
+    /// Returns the advisory lock service.
+    pub fn locks(&self) -> Arc<LockService> {
+        self.locks.clone()
+    }
+
+    /// Returns the topic publish / subscribe service.
+    pub fn pubsub(&self) -> Arc<PubSub> {
+        self.pubsub.clone()
+    }
+
+    /// Serves `service` on the gossip port for its message types, one
+    /// thread per connection up to the connection limit, see
+    /// Swarm::set_connection_limit. Swarm::stop shuts down connections
+    /// still being handled and waits for their handlers to return.
+    /// Fails if another service already handles one of them.
+    pub fn register_service(&self, service: Arc<dyn SwarmService>)
+            -> Result<(), Box<dyn Error>> {
+        self.services.register(service)
+    }
+
+    /// Opens a connection to the service handling `message_type` on
+    /// member `id`.
+    pub fn connect_service(&self, id: u32, message_type: u8)
+            -> Result<TcpStream, Box<dyn Error>> {
+        let node = self.nodes.get(id)
+            .ok_or_else(|| format!("unknown node [id={}]", id))?;
+        dispatch::connect(&self.nodes, node.get_address(), message_type)
+    }
+
+    /// Checks the environment before Swarm::start: that the gossip
+    /// address binds and its advertised ip dials back, that the clock is
+    /// sane, that file descriptors suffice for the thread model and
+    /// known peers, and that seeds answer.
+    pub fn preflight(&self) -> PreflightReport {
+        let running = !self.shutdown.load(Ordering::Relaxed);
+        let (bind, listener) = preflight::bind(&self.address, running);
+        let advertise = preflight::advertise(&self.address, listener.as_ref());
+        drop(listener);
+
+        let mut seeds: Vec<SocketAddr> = self.seed_address.into_iter()
+            .chain(self.bootstrap.iter()
+                .flat_map(|bootstrap| bootstrap.candidates()))
+            .filter(|seed| *seed != self.address).collect();
+        seeds.sort();
+        seeds.dedup();
+
+        let report = PreflightReport {
+            checks: vec!(bind, advertise,
+                preflight::clock(self.clock.as_ref()),
+                preflight::file_descriptors(preflight::process_limits(),
+                    self.thread_model.0, self.nodes.len()),
+                preflight::seeds(&seeds)),
+        };
+
+        for check in report.checks.iter() {
+            debug!("preflight check [name={}, status={:?}]: {}",
+                check.name, check.status, check.detail);
+        }
+        report
+    }
+
+    /// Returns the address gossip is served on. Port 0 binds an
+    /// ephemeral port, which is only known once Swarm::start has opened
+    /// the listener.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.address.port() {
+            0 => None,
+            _ => Some(self.address),
+        }
+    }
+
+    /// Returns a handle distributing configuration through gossip. Its
+    /// keys live in the reserved "config" metadata namespace.
+    pub fn config_distribution(&self) -> ConfigDistribution {
+        ConfigDistribution::new(self.id, self.nodes.clone())
+    }
+
+    /// Returns a tower Discover stream of alive members, creating each
+    /// member's service with `make_service` from its service address.
+    #[cfg(feature = "discover")]
+    pub fn discover<S, F>(&self, make_service: F) -> Discovery<S>
+            where F: 'static + FnMut(&Node, SocketAddr) -> S + Send {
+        Discovery::new(self.nodes.clone(), Box::new(make_service))
+    }
+
+    /// Returns a handle whose keys are scoped under `namespace`. Keys
+    /// set through Swarm::set_metadata live outside every namespace.
+    pub fn metadata(&self, namespace: &str)
+            -> Result<MetadataNamespace, Box<dyn Error>> {
+        MetadataNamespace::new(self.id, namespace, self.nodes.clone())
+    }
+
+    /// Maintains a dedicated heartbeat connection to node `id`, calling
+    /// `callback` as soon as the peer becomes reachable or a heartbeat
+    /// is lost. Heartbeats are sent every `interval` until the swarm
+    /// stops. Must be called after Swarm::start.
+    pub fn monitor<F: 'static + Fn(u32, KeepaliveEvent) + Send>(
+            &mut self, id: u32, interval: Duration, callback: F)
+            -> Result<(), Box<dyn Error>> {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Err("swarm is not started".into());
+        }
+
+        let address = match self.nodes.get(id) {
+            Some(node) => node.get_address(),
+            None => return Err(format!("unknown node [id={}]", id).into()),
+        };
+
+        // start keepalive thread
+        debug!("starting keepalive monitor [id={}, address={}]", id, address);
+        let clock = self.clock.clone();
+        let local_id = self.id;
+        let shutdown = self.shutdown.clone();
+        let join_handle = thread::spawn(move || keepalive::monitor(clock,
+            id, local_id, address, interval, shutdown, callback));
+
+        self.join_handles.push(join_handle);
+        Ok(())
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) {
+        debug!("removing metadata [key={}]", key);
+        self.nodes.update(self.id, |node| {
+            node.remove_metadata(key);
+        });
+    }
+
+    /// Adds `value` to the set-valued metadata `key`, such as one of
+    /// several served shards. Peers read sets with Node::metadata_values.
+    pub fn add_metadata_value(&mut self, key: &str, value: &str) {
+        debug!("adding metadata value [key={}, value={}]",
+            key, secret::redact(key, value));
+        self.nodes.update(self.id, |node| {
+            node.add_metadata_value(key, value);
+        });
+    }
+
+    pub fn remove_metadata_value(&mut self, key: &str, value: &str) {
+        debug!("removing metadata value [key={}, value={}]",
+            key, secret::redact(key, value));
+        self.nodes.update(self.id, |node| {
+            node.remove_metadata_value(key, value);
+        });
+    }
+
+    /// Applies several metadata changes atomically so peers observe
+    /// them together under one version.
+    pub fn update_metadata<F: FnOnce(&mut MetadataBatch)>(&mut self, f: F) {
+        let mut count = 0;
+        self.nodes.update(self.id, |node| count = node.update_metadata(f));
+        debug!("updated metadata [count={}]", count);
+    }
+
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        debug!("setting metadata [key={}, value={}]",
+            key, secret::redact(key, value));
+        self.nodes.update(self.id, |node| node.set_metadata(key, value));
+    }
+
+    /// Indexes metadata `key` by value so topology lookups such as
+    /// Cluster::nodes_with_metadata avoid scanning every member.
+    pub fn index_metadata(&self, key: &str) {
+        debug!("indexing metadata [key={}]", key);
+        self.nodes.index_metadata(key);
+    }
+
+    /// Declares the roles of this node, such as "frontend" or "storage",
+    /// replacing any previous ones. Peers list nodes by role through
+    /// the topology, e.g. Cluster::nodes_with_role. Fails on more than
+    /// 255 roles or roles longer than 255 bytes.
+    pub fn set_roles(&mut self, roles: &[&str]) -> Result<(), Box<dyn Error>> {
+        debug!("setting roles [roles={:?}]", roles);
+        crate::node::check_roles(roles.iter())?;
+        self.nodes.update(self.id, |node| {
+            let _ = node.set_roles(roles);
+        });
+        Ok(())
+    }
+
+    /// Sets the arguments used by Swarm::start_configured.
+    pub fn set_thread_model(&mut self, thread_count: u8,
+            thread_sleep: Duration, gossip_interval: Duration) {
+        self.thread_model = (thread_count, thread_sleep.as_millis() as u64,
+            gossip_interval.as_millis() as u64);
+    }
+
+    /// Starts with the thread model set by SwarmBuilder or
+    /// Swarm::set_thread_model.
+    pub fn start_configured(&mut self) -> Result<(), Box<dyn Error>> {
+        let (thread_count, thread_sleep_ms, gossip_interval_ms) =
+            self.thread_model;
+        self.start(thread_count, thread_sleep_ms, gossip_interval_ms)
+    }
+
+    pub fn start(&mut self, thread_count: u8, thread_sleep_ms: u64,
+            gossip_interval_ms: u64) -> Result<(), Box<dyn Error>> {
+        info!("starting [thread_count={}, thread_sleep_ms={}, gossip_interval_ms={}]", 
+            thread_count, thread_sleep_ms, gossip_interval_ms);
+
+        // check if already started
+        if !self.shutdown.load(Ordering::Relaxed) {
+            return Err("swarm already started".into());
+        }
+
+        let cluster_name = self.nodes.get_cluster_name();
+        if cluster_name.len() > u8::MAX as usize {
+            return Err(format!("cluster name too long [length={}]",
+                cluster_name.len()).into());
+        }
+
+        // bind before changing state so failed starts may be retried
+        let listener = match thread_count {
+            0 => None,
+            _ => {
+                debug!("opening tcp listener [address={}]", self.address);
+                Some(TcpListener::bind(self.address)?)
+            },
+        };
+
+        // advertise the bound port when an ephemeral port was requested
+        if let (0, Some(listener)) = (self.address.port(), &listener) {
+            self.address = listener.local_addr()?;
+            let port = self.address.port();
+            self.nodes.update(self.id, |node| node.set_port(port));
+            debug!("bound ephemeral port [port={}]", port);
+        }
+
+        // persist node identity
+        self.state_store.put_u64(store::IDENTITY_KEY, self.id as u64)?;
+
+        // epochs never decrease -> keep any newer one rejoined earlier
+        if let Some(stored) = self.state_store.get_u64(store::EPOCH_KEY)? {
+            self.nodes.advance_cluster_epoch(stored);
+        }
+        self.state_store.put_u64(store::EPOCH_KEY, self.get_cluster_epoch())?;
+
+        // restarted members no longer drain
+        if self.draining.swap(false, Ordering::Relaxed) {
+            self.nodes.update(self.id,
+                |node| { node.remove_metadata(DRAINING_KEY); });
+        }
+
+        // bump incarnation -> wall clock covers non-persistent stores
+        let stored = self.state_store.get_u64(store::INCARNATION_KEY)?;
+        let incarnation = std::cmp::max(stored.unwrap_or(0) + 1,
+            self.clock.timestamp());
+        self.state_store.put_u64(store::INCARNATION_KEY, incarnation)?;
+        self.nodes.update(self.id,
+            |node| node.set_incarnation(incarnation));
+        debug!("starting incarnation [incarnation={}]", incarnation);
+
+        // seed membership from the last snapshot -> rejoin without seeds
+        if self.snapshot_interval.is_some() {
+            snapshot::load(self.id, &self.nodes, self.topology.as_ref(),
+                self.state_store.as_ref())?;
+        }
+
+        // track gossip phases over the configured interval
+        self.phase.reset(Duration::from_millis(gossip_interval_ms),
+            self.clock.now());
+
+        // initialize gossip budget for this interval
+        let gossip_interval = Duration::from_millis(gossip_interval_ms);
+        let now = self.clock.now();
+        self.budget = match (self.budget_limits, self.outbound_rate) {
+            (None, None) => None,
+            (limits, outbound_rate) => {
+                let (max_exchanges, max_bytes) =
+                    limits.unwrap_or((u32::MAX, u64::MAX));
+                let max_outbound = outbound_rate.map(|rate| std::cmp::max(1,
+                        rate.saturating_mul(gossip_interval_ms) / 1000))
+                    .unwrap_or(u64::MAX);
+                Some(Arc::new(GossipBudget::new(gossip_interval,
+                    max_exchanges, max_bytes, now)
+                    .max_outbound(max_outbound)))
+            },
+        };
+
+        // fresh shutdown flag -> detached threads of previous runs
+        // (keepalive responders, subscriptions) never observe a restart
+        self.shutdown = Arc::new(AtomicBool::new(false));
+
+        // start TcpListener 
+        if let Some(listener) = listener {
+            if let Err(e) = self.start_listeners(listener, thread_count) {
+                self.stop()?;
+                return Err(e);
+            }
+        }
+
+        // start kubernetes seed refresh
+        #[cfg(feature = "k8s")]
+        if let (Some(seeds), Some(bootstrap)) =
+                (self.kubernetes_seeds.clone(), self.bootstrap.clone()) {
+            let clock = self.clock.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move ||
+                crate::k8s::refresh(clock, seeds, bootstrap, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start local network discovery
+        #[cfg(feature = "mdns")]
+        if self.mdns {
+            match crate::mdns::start(self.id, self.address,
+                    self.nodes.clone(), self.shutdown.clone()) {
+                Ok(join_handle) => self.join_handles.push(join_handle),
+                Err(e) => {
+                    self.stop()?;
+                    return Err(e);
+                },
+            }
+        }
+
+        // start http status endpoint
+        #[cfg(feature = "http-status")]
+        if let Some(address) = self.status_address {
+            match crate::status::start(address, self.nodes.clone(),
+                    self.topology.clone(), self.metrics.clone(),
+                    self.shutdown.clone()) {
+                Ok(join_handle) => self.join_handles.push(join_handle),
+                Err(e) => {
+                    self.stop()?;
+                    return Err(e);
+                },
+            }
+        }
+
+        // start change journal recorder
+        if let Some(change_journal) = self.change_journal.clone() {
+            let clock = self.clock.clone();
+            let nodes = self.nodes.clone();
+            let shutdown = self.shutdown.clone();
+            let thread_sleep = Duration::from_millis(thread_sleep_ms);
+            let join_handle = thread::spawn(move || {
+                while !shutdown.load(Ordering::Relaxed) {
+                    change_journal.record(&nodes);
+                    clock.sleep(thread_sleep);
+                }
+            });
+            self.join_handles.push(join_handle);
+        }
+
+        // start control message redelivery
+        if self.control.has_inbox() {
+            let control = self.control.clone();
+            let clock = self.clock.clone();
+            let nodes = self.nodes.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || control::run(
+                control, clock, nodes, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start connection draining
+        {
+            let drainer = self.drainer.clone();
+            let clock = self.clock.clone();
+            let nodes = self.nodes.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || drain::run(
+                drainer, clock, nodes, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start leadership change detection
+        {
+            let election = self.election.clone();
+            let clock = self.clock.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || election::run(
+                election, clock, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start plumtree announcements and grafts
+        if let Some(plumtree) = self.plumtree.clone() {
+            let clock = self.clock.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || plumtree::run(
+                plumtree, clock, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start federation exchanges
+        if let Some(federation) = self.federation.clone() {
+            let clock = self.clock.clone();
+            let nodes = self.nodes.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || federation::run(
+                federation, clock, nodes, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // start webhook notifier
+        if !self.webhooks.is_empty() {
+            let clock = self.clock.clone();
+            let (id, nodes) = (self.id, self.nodes.clone());
+            let topology = self.topology.clone();
+            let webhooks = self.webhooks.clone();
+            let shutdown = self.shutdown.clone();
+            let join_handle = thread::spawn(move || webhook::notify(clock,
+                id, nodes, topology, webhooks, gossip_interval, shutdown));
+            self.join_handles.push(join_handle);
+        }
+
+        // static topologies never gossip
+        if self.topology.is_static() {
+            debug!("static topology -> gossiper disabled");
+            return Ok(());
+        }
+
+        // clone gossip request variables
+        let id = self.id;
+        let context = self.gossip_context();
+        let nodes_clone = self.nodes.clone();
+        let seed_address = self.seed_address;
+        let topology_clone = self.topology.clone();
+
+        // start gossip request thread
+        debug!("starting gossiper thread");
+        let join_handle = thread::spawn(move || {
+            if let Err(e) = gossiper(context, gossip_interval, id,
+                    nodes_clone, seed_address, topology_clone) {
+                error!("gossiper failed: {}", e);
+            }
+        });
+
+        // capture gossip request thread JoinHandle
+        self.join_handles.push(join_handle);
+
+        Ok(())
+    }
+
+    fn start_listeners(&mut self, listener: TcpListener, thread_count: u8)
+            -> Result<(), Box<dyn Error>> {
+        // start gossip worker threads sharing one connection queue
+        debug!("starting gossip workers [thread_count={}]", thread_count);
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..thread_count {
+            // clone gossip reply variables
+            let context = self.gossip_context();
+            let receiver_clone = receiver.clone();
+            let nodes_clone = self.nodes.clone();
+            let topology_clone = self.topology.clone();
+
+            let join_handle = thread::spawn(move || gossip_worker(context,
+                receiver_clone, nodes_clone, topology_clone));
+
+            // capture gossip worker thread JoinHandle
+            self.join_handles.push(join_handle);
+        }
+
+        // register listener readiness and a shutdown waker
+        listener.set_nonblocking(true)?;
+        let mut listener = MioListener::from_std(listener);
+        let poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER_TOKEN,
+            Interest::READABLE)?;
+        self.wakers.push(Waker::new(poll.registry(), WAKER_TOKEN)?);
+
+        // start gossip acceptor thread -> workers exit once it drops
+        // the connection queue
+        let allowed_cidrs = self.allowed_cidrs.clone();
+        let metrics = self.metrics.clone();
+        let shutdown = self.shutdown.clone();
+        let join_handle = thread::spawn(move || {
+            if let Err(e) = gossip_acceptor(listener, poll, sender,
+                    allowed_cidrs, metrics, shutdown) {
+                error!("gossip acceptor failed: {}", e);
+            }
+        });
+
+        // capture gossip acceptor thread JoinHandle
+        self.join_handles.push(join_handle);
+        Ok(())
+    }
+
+    fn gossip_context(&self) -> GossipContext {
+        GossipContext {
+            admin: self.admin,
+            bootstrap: self.bootstrap.clone(),
+            broadcasts: self.broadcasts.clone(),
+            budget: self.budget.clone(),
+            change_journal: self.change_journal.clone(),
+            clock: self.clock.clone(),
+            connect_backoff: self.connect_backoff.clone(),
+            connection_threads: self.connection_threads.clone(),
+            control: self.control.clone(),
+            draining: self.draining.clone(),
+            exchanges: self.exchanges.clone(),
+            failure_detector: self.failure_detector.clone(),
+            federation: self.federation.clone(),
+            id: self.id,
+            indirect_probes: self.indirect_probes,
+            locks: self.locks.clone(),
+            metrics: self.metrics.clone(),
+            partition: self.partition.clone(),
+            phase: self.phase.clone(),
+            piggyback: self.piggyback.clone(),
+            plumtree: self.plumtree.clone(),
+            pool: self.pool.clone(),
+            pubsub: self.pubsub.clone(),
+            services: self.services.clone(),
+            shutdown: self.shutdown.clone(),
+            snapshots: self.snapshot_interval
+                .map(|interval| (interval, self.state_store.clone())),
+            state_store: self.state_store.clone(),
+            trigger: self.trigger.clone(),
+            worker_sockets: self.worker_sockets.clone(),
+        }
+    }
+
+    /// Drains this member ahead of shutdown: marks it draining, which
+    /// gossips to peers so Dht::locate stops routing to it, then stops
+    /// initiating gossip rounds while still answering peers for
+    /// `grace_period`, and finally stops the swarm.
+    pub fn drain(&mut self, grace_period: Duration)
+            -> Result<(), Box<dyn Error>> {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Err("swarm not started".into());
+        }
+
+        info!("draining swarm [grace_period_ms={}]",
+            grace_period.as_millis());
+        self.nodes.update(self.id,
+            |node| node.set_metadata(DRAINING_KEY, "true"));
+        self.draining.store(true, Ordering::Relaxed);
+        self.clock.sleep(grace_period);
+        self.stop()
+    }
+
+    /// Returns true while Swarm::drain runs, and after it until the
+    /// next start.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("stopping swarm");
+
+        // check if already shutdown
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // perform shutdown -> wake the gossiper and blocked listeners
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.trigger.trigger();
+        for waker in self.wakers.iter() {
+            if let Err(e) = waker.wake() {
+                warn!("listener wake failure: {}", e);
+            }
+        }
+
+        // unblock workers serving silent connections
+        self.worker_sockets.shutdown();
+
+        // join threads -> wakers close their fds once listeners exit
+        while let Some(join_handle) = self.join_handles.pop() {
+            if let Err(e) = join_handle.join() {
+                warn!("join thread failure: {:?}", e);
+            }
+        }
+        self.wakers.clear();
+
+        // workers have exited -> no connection threads start past here
+        self.connection_threads.join();
+
+        // capture the final membership for the next start
+        if self.snapshot_interval.is_some() {
+            snapshot::save(&self.nodes, self.topology.as_ref(),
+                self.state_store.as_ref())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// State shared between the Swarm and its gossip threads.
+#[derive(Clone)]
+struct GossipContext {
+    admin: bool,
+    bootstrap: Option<Arc<Bootstrap>>,
+    broadcasts: Arc<BroadcastQueue>,
+    budget: Option<Arc<GossipBudget>>,
+    change_journal: Option<Arc<ChangeJournal>>,
+    clock: Arc<dyn Clock>,
+    connect_backoff: Arc<ConnectBackoff>,
+    connection_threads: Arc<ConnectionThreads>,
+    control: Arc<ControlChannel>,
+    draining: Arc<AtomicBool>,
+    exchanges: Arc<Exchanges>,
+    failure_detector: Option<Arc<FailureDetector>>,
+    federation: Option<Arc<Federation>>,
+    id: u32,
+    indirect_probes: Option<IndirectProbes>,
+    locks: Arc<LockService>,
+    metrics: Arc<Metrics>,
+    partition: Arc<PartitionDetector>,
+    phase: Arc<PhaseTracker>,
+    piggyback: Arc<Piggyback>,
+    plumtree: Option<Arc<Plumtree>>,
+    pool: Option<Arc<ConnectionPool>>,
+    pubsub: Arc<PubSub>,
+    services: Arc<ServiceRegistry>,
+    shutdown: Arc<AtomicBool>,
+    snapshots: Option<(Duration, Arc<dyn StateStore>)>,
+    state_store: Arc<dyn StateStore>,
+    trigger: Arc<RoundTrigger>,
+    worker_sockets: Arc<WorkerSockets>,
+}
+
+/// Accepts gossip connections and queues them for the worker pool
+/// until shutdown.
+fn gossip_acceptor(listener: MioListener, mut poll: Poll,
+        connections: Sender<TcpStream>, allowed_cidrs: Vec<Cidr>,
+        metrics: Arc<Metrics>, shutdown: Arc<AtomicBool>)
+        -> Result<(), Box<dyn Error>> {
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
+    while !shutdown.load(Ordering::Relaxed) {
+        // block until connections arrive or Swarm::stop wakes the poll
+        if let Err(e) = poll.poll(&mut events, None) {
+            match e.kind() {
+                ErrorKind::Interrupted => continue,
+                _ => return Err(e.into()),
+            }
+        }
+
+        // readiness is edge triggered -> accept until drained
+        loop {
+            let (stream, address) = match listener.accept() {
+                Ok((stream, address)) => (TcpStream::from(stream), address),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("gossip connection failure: {}", e);
+                    metrics.connection_error();
+                    break;
+                },
+            };
+
+            // drop connections from outside the allowlist unread
+            if !allowed_cidrs.is_empty() && !allowed_cidrs.iter()
+                    .any(|cidr| cidr.contains(address.ip())) {
+                debug!("rejecting gossip connection [address={}]", address);
+                metrics.connection_error();
+                continue;
+            }
+
+            // accepted streams inherit nonblocking mode
+            if let Err(e) = stream.set_nonblocking(false) {
+                warn!("gossip connection failure: {}", e);
+                metrics.connection_error();
+                continue;
+            }
+
+            if connections.send(stream).is_err() {
+                return Err("gossip workers exited".into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves connections queued by the acceptor until it exits. Idle
+/// workers take the next connection, so one slow exchange never holds
+/// up others while workers are free.
+fn gossip_worker<T: 'static + Topology + Sync + Send>(
+        context: GossipContext,
+        connections: Arc<Mutex<Receiver<TcpStream>>>, nodes: Arc<NodeMap>,
+        topology: Arc<T>) {
+    let mut buffers = ExchangeBuffers::new();
+    loop {
+        // release the queue before serving -> other workers keep taking
+        let stream = connections.lock().unwrap().recv();
+        match stream {
+            Ok(stream) => serve_connection(&context, stream, &nodes,
+                &topology, &mut buffers),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Reads the header of an accepted connection and serves its exchange.
+fn serve_connection<T: 'static + Topology + Sync + Send>(
+        context: &GossipContext, mut stream: TcpStream, nodes: &Arc<NodeMap>,
+        topology: &Arc<T>, buffers: &mut ExchangeBuffers) {
+    let GossipContext { admin, change_journal, clock, connection_threads,
+        control, federation, id, locks, metrics, plumtree, pubsub, services,
+        shutdown, state_store, trigger, worker_sockets, .. } = context;
+
+    // stop shuts down registered sockets -> check shutdown after
+    // registering so no connection escapes it
+    let _worker_socket = match worker_sockets.register(&stream) {
+        Ok(worker_socket) => worker_socket,
+        Err(e) => {
+            warn!("gossip connection failure: {}", e);
+            return;
+        },
+    };
+    if shutdown.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // digest exchanges are chatty -> disable nagle
+    if let Err(e) = stream.set_nodelay(true) {
+        warn!("gossip nodelay failure: {}", e);
+    }
+
+    // read exchange trace id and kind
+    let peer_address = stream.peer_addr().ok();
+    let start = clock.now();
+    let mut metered_stream =
+        MeteredStream::new(&mut stream, metrics.clone());
+    let header = codec::read_header(&mut metered_stream);
+    let (trace_id, kind) = match header {
+        Ok(header) => header,
+        Err(e) => {
+            warn!("gossip header failure: {}", e);
+            metrics.reply(false);
+            return;
+        },
+    };
+    let _trace_guard = trace::enter(trace_id);
+    let exchange_span = ExchangeSpan::reply(peer_address);
+
+    // reject other clusters before reading any membership state
+    if exchange::is_cluster_exchange(kind) {
+        let (peer_cluster, peer_epoch) =
+                match codec::read_cluster_header(&mut metered_stream) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("gossip header failure: {}", e);
+                metrics.reply(false);
+                return;
+            },
+        };
+
+        if peer_cluster != nodes.get_cluster_name() {
+            warn!("cluster name mismatch [trace_id={}, peer_address={:?}, cluster_name={}]",
+                trace_id, peer_address, peer_cluster);
+            metrics.reply(false);
+            return;
+        }
+
+        // reject state of older epochs -> rejoin newer ones
+        let epoch = nodes.get_cluster_epoch();
+        if peer_epoch < epoch {
+            warn!("stale cluster epoch -> rejecting peer state [trace_id={}, peer_address={:?}, peer_epoch={}, epoch={}]",
+                trace_id, peer_address, peer_epoch, epoch);
+
+            // only gossip requesters read an admission reply
+            if kind == TRACKED_EXCHANGE || kind == POOLED_EXCHANGE {
+                if let Err(e) = metered_stream.write_u8(STALE_EPOCH)
+                        .and_then(|_| metered_stream
+                            .write_u64::<BigEndian>(epoch)) {
+                    debug!("stale epoch reply failure: {}", e);
+                }
+            }
+
+            metrics.reply(false);
+            return;
+        }
+
+        rejoin_epoch(peer_epoch, *id, nodes, state_store.as_ref());
+    }
+
+    // read requesting peer -> untracked for one-off queries
+    let peer_id = match kind {
+        UNTRACKED_EXCHANGE => Ok(None),
+        KEEPALIVE_EXCHANGE => {
+            // hand keepalive channels to a dedicated thread
+            let result: Result<_, Box<dyn Error>> =
+                metered_stream.read_u32::<BigEndian>()
+                .and_then(|peer_id| stream.try_clone()
+                    .map(|stream| (peer_id, stream)))
+                .map_err(|e| e.into())
+                .and_then(|(peer_id, stream)| {
+                    let shutdown = shutdown.clone();
+                    connection_threads.spawn(kind, stream, move |stream| {
+                        if let Err(e) = keepalive::respond(
+                                stream, peer_id, shutdown) {
+                            warn!("keepalive failure [peer_id={}]: {}",
+                                peer_id, e);
+                        }
+                    })
+                });
+            if let Err(e) = result {
+                warn!("keepalive channel failure: {}", e);
+            }
+
+            return;
+        },
+        CONTROL_EXCHANGE => {
+            if let Err(e) = control.receive(&mut metered_stream,
+                    clock.now()) {
+                debug!("control exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        PLUMTREE_EXCHANGE => {
+            let result = match plumtree.as_ref() {
+                Some(plumtree) => plumtree.receive(
+                    &mut metered_stream, clock.now()),
+                None => Err("plumtree disabled".into()),
+            };
+
+            if let Err(e) = result {
+                debug!("plumtree exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        PUBSUB_EXCHANGE => {
+            if let Err(e) = pubsub.receive(&mut metered_stream) {
+                debug!("pubsub exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        ADMIN_EXCHANGE => {
+            let result = match *admin {
+                true => admin::serve(&mut metered_stream, *id, nodes,
+                    topology.as_ref(), metrics, trigger),
+                false => Err("admin disabled".into()),
+            };
+
+            if let Err(e) = result {
+                debug!("admin exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        PROBE_EXCHANGE => {
+            // relayed probes wait on their target -> dedicated thread
+            let result: Result<_, Box<dyn Error>> = stream.try_clone()
+                .map_err(|e| e.into())
+                .and_then(|stream| connection_threads.spawn(kind, stream,
+                    |mut stream| {
+                        if let Err(e) = probe::serve(&mut stream) {
+                            debug!("probe exchange failure: {}", e);
+                        }
+                    }));
+            if let Err(e) = result {
+                warn!("probe connection failure: {}", e);
+            }
+
+            return;
+        },
+        SERVICE_EXCHANGE => {
+            // hand service connections to a dedicated thread
+            let result: Result<_, Box<dyn Error>> =
+                metered_stream.read_u8().map_err(|e| e.into())
+                .and_then(|message_type| services.get(message_type)
+                    .map(|service| (message_type, service))
+                    .ok_or_else(|| format!("unknown service message type [message_type={}]",
+                        message_type).into()))
+                .and_then(|(message_type, service)| stream.try_clone()
+                    .map(|stream| (message_type, service, stream))
+                    .map_err(|e| e.into()))
+                .and_then(|(message_type, service, stream)|
+                    connection_threads.spawn(kind, stream, move |stream| {
+                        if let Err(e) = service.handle(message_type,
+                                peer_address, stream) {
+                            debug!("service failure [message_type={}]: {}",
+                                message_type, e);
+                        }
+                    }));
+            if let Err(e) = result {
+                debug!("service exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        LOCK_EXCHANGE => {
+            if let Err(e) = locks.receive(&mut metered_stream) {
+                debug!("lock exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        FEDERATION_EXCHANGE => {
+            // answer remote gateways -> members ignore them
+            let result = match federation.as_ref() {
+                Some(federation) => federation.reply(
+                    &mut metered_stream, nodes, clock.now()),
+                None => Err("federation disabled".into()),
+            };
+
+            if let Err(e) = result {
+                debug!("federation exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+            }
+
+            return;
+        },
+        POOLED_EXCHANGE => {
+            // hand pooled connections to a dedicated thread
+            let result: Result<_, Box<dyn Error>> =
+                metered_stream.read_u32::<BigEndian>()
+                .and_then(|peer_id| metered_stream
+                    .read_u64::<BigEndian>()
+                    .map(|idle_ms| (peer_id, idle_ms)))
+                .and_then(|header| stream.try_clone()
+                    .map(|stream| (header, stream)))
+                .map_err(|e| e.into())
+                .and_then(|((peer_id, idle_ms), stream)| {
+                    let context = context.clone();
+                    let (nodes, topology) =
+                        (nodes.clone(), topology.clone());
+                    connection_threads.spawn(kind, stream, move |stream|
+                        serve_pooled(context, stream, peer_id,
+                            Duration::from_millis(idle_ms), nodes, topology))
+                });
+            if let Err(e) = result {
+                warn!("pooled connection failure: {}", e);
+            }
+
+            return;
+        },
+        SUBSCRIBE_EXCHANGE => {
+            // hand delta streams to a dedicated thread
+            let result = metered_stream.read_u64::<BigEndian>()
+                .and_then(|seq| stream.try_clone()
+                    .map(|stream| (seq, stream)));
+            match (result, change_journal.clone()) {
+                (Ok((seq, stream)), Some(change_journal)) => {
+                    let nodes = nodes.clone();
+                    let shutdown = shutdown.clone();
+                    let result = connection_threads.spawn(kind, stream,
+                        move |stream| {
+                            if let Err(e) = journal::serve(stream,
+                                    seq, change_journal, nodes,
+                                    shutdown) {
+                                debug!("closed subscription: {}", e);
+                            }
+                        });
+                    if let Err(e) = result {
+                        warn!("subscription failure: {}", e);
+                    }
+                },
+                (Ok(_), None) =>
+                    warn!("subscription rejected -> change journal disabled"),
+                (Err(e), _) => warn!("subscription failure: {}", e),
+            }
+
+            return;
+        },
+        _ => metered_stream.read_u32::<BigEndian>().map(Some),
+    };
+
+    let peer_id = match peer_id {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            warn!("gossip exchange failure [trace_id={}]: {}",
+                trace::current(), e);
+            metrics.reply(false);
+            return;
+        },
+    };
+
+    reply_exchange(context, nodes, topology.as_ref(), buffers,
+        &mut metered_stream, peer_id, exchange_span, start);
+
+    // shutdown gossip connection
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        warn!("gossip shutdown failure: {}", e);
+    }
+}
+
+/// Answers an exchange once its header is read: admits tracked peers
+/// unless already exchanging or out of budget, then replies to the
+/// topology gossip. Returns false if the connection failed.
+#[allow(clippy::too_many_arguments)]
+fn reply_exchange<T: Topology, S: Read + Write>(context: &GossipContext,
+        nodes: &Arc<NodeMap>, topology: &T, buffers: &mut ExchangeBuffers,
+        metered_stream: &mut MeteredStream<S>, peer_id: Option<u32>,
+        exchange_span: ExchangeSpan, start: Instant) -> bool {
+    let GossipContext { broadcasts, budget, clock, exchanges,
+        failure_detector, id, metrics, phase, piggyback, .. } = context;
+
+    let _exchange_guard = match peer_id {
+        Some(peer_id) => {
+            // reject if already exchanging with this peer
+            // or out of budget for this interval
+            exchange_span.record_peer_id(peer_id);
+            let guard = exchanges.inbound(peer_id)
+                .filter(|_| budget.as_ref().map(|budget|
+                    budget.acquire(clock.now())).unwrap_or(true));
+            let accepted = guard.is_some();
+            if let Err(e) = metered_stream.write_u8(accepted as u8) {
+                warn!("gossip exchange failure [trace_id={}]: {}",
+                    trace::current(), e);
+                metrics.reply(false);
+                return false;
+            }
+
+            if !accepted {
+                debug!("duplicate exchange rejected [trace_id={}, peer_id={}]",
+                    trace::current(), peer_id);
+                return true;
+            }
+
+            guard
+        },
+        None => None,
+    };
+    let _phase_guard = peer_id.map(|_| phase.receive(clock.now()));
+
+    // handle topology gossip reply
+    let result = exchange_span.in_scope(|| {
+        let mut buffered_stream = BufferedStream::new(
+            metered_stream, buffers);
+        // tracked peers piggyback broadcasts -> queries do not
+        topology.reply(&mut buffered_stream)
+            .and_then(|_| match peer_id {
+                Some(_) => broadcasts.read(nodes,
+                    &mut buffered_stream).and_then(|_| broadcasts
+                        .write(nodes.len(), &mut buffered_stream))
+                    .and_then(|_| piggyback.read(&mut buffered_stream))
+                    .and_then(|_| piggyback.write(*id, peer_id,
+                        &mut buffered_stream)),
+                None => Ok(()),
+            })
+            .and_then(|_| buffered_stream.flush()
+                .map_err(|e| e.into()))
+    });
+    if let Err(ref e) = result {
+        warn!("topology gossip reply failure [trace_id={}]: {}",
+            trace::current(), e);
+    }
+    metrics.reply(result.is_ok());
+    if let (Some(_), Some(budget)) = (peer_id, budget) {
+        budget.consume(metered_stream.get_bytes_sent(),
+            metered_stream.get_bytes_received());
+    }
+
+    if let (Ok(_), Some(peer_id), Some(failure_detector)) =
+            (&result, peer_id, failure_detector) {
+        failure_detector.heard(peer_id, nodes, clock.now());
+    }
+
+    exchange_span.finish(metered_stream.get_bytes_sent(),
+        metered_stream.get_bytes_received(), clock.now() - start);
+    result.is_ok()
+}
+
+/// Serves consecutive exchanges from `peer_id` over a pooled
+/// connection until it closes, fails, idles past the requester's
+/// `idle_timeout`, or shutdown.
+fn serve_pooled<T: Topology>(context: GossipContext, mut stream: TcpStream,
+        peer_id: u32, idle_timeout: Duration, nodes: Arc<NodeMap>,
+        topology: Arc<T>) {
+    let (clock, metrics, shutdown) = (context.clock.clone(),
+        context.metrics.clone(), context.shutdown.clone());
+    let peer_address = stream.peer_addr().ok();
+    if let Err(e) = stream.set_read_timeout(
+            Some(Duration::from_millis(POOLED_POLL_MS))) {
+        warn!("pooled connection failure [peer_id={}]: {}", peer_id, e);
+        return;
+    }
+
+    let mut buffers = ExchangeBuffers::new();
+    let mut last_exchange = clock.now();
+    while !shutdown.load(Ordering::Relaxed) {
+        // read the trace id framing the next exchange
+        let start = clock.now();
+        let mut metered_stream =
+            MeteredStream::new(&mut stream, metrics.clone());
+        let trace_id = match metered_stream.read_u64::<BigEndian>() {
+            Ok(trace_id) => trace_id,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => {
+                // requesters close connections idle past their timeout
+                if start - last_exchange >= idle_timeout * 2 {
+                    break;
+                }
+
+                continue;
+            },
+            Err(e) => {
+                debug!("closed pooled connection [peer_id={}]: {}",
+                    peer_id, e);
+                break;
+            },
+        };
+
+        let _trace_guard = trace::enter(trace_id);
+        let exchange_span = ExchangeSpan::reply(peer_address);
+        if !reply_exchange(&context, &nodes, topology.as_ref(), &mut buffers,
+                &mut metered_stream, Some(peer_id), exchange_span, start) {
+            break;
+        }
+
+        last_exchange = clock.now();
+    }
+
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        debug!("pooled connection shutdown failure: {}", e);
+    }
+}
+
+fn gossiper<T: 'static + Topology + Sync + Send>(
+        context: GossipContext, gossip_interval: Duration, id: u32,
+        nodes: Arc<NodeMap>, seed_address: Option<SocketAddr>,
+        topology: Arc<T>) -> Result<(), Box<dyn Error>> {
+    let GossipContext { bootstrap, broadcasts, budget, clock,
+        connect_backoff, draining, exchanges, failure_detector,
+        indirect_probes, metrics, partition, phase, piggyback, pool,
+        shutdown, snapshots, state_store, trigger, .. } = context;
+    let mut buffers = ExchangeBuffers::new();
+    let mut instant = clock.now();
+    let mut snapshot_instant = instant;
+    let mut first_round = true;
+
+    loop {
+        // check if shutdown
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+
+        // rounds carried over from an exhausted budget run immediately
+        let carried = budget.as_ref()
+            .map(|budget| budget.take_deferred(clock.now()))
+            .unwrap_or(false);
+
+        let previous_instant = instant;
+        if !carried {
+            // sleep -> the first round starts immediately
+            let elapsed = clock.now() - instant;
+            if elapsed < gossip_interval && !first_round {
+                trigger.wait(gossip_interval - elapsed);
+            }
+
+            // shift the gossip phase away from lockstepped peers
+            if let Some(delay) = phase.take_dephase(clock.now()) {
+                info!("dephasing gossip rounds [delay_ms={}]",
+                    delay.as_millis());
+                clock.sleep(delay);
+            }
+
+            // reset instance
+            first_round = false;
+            instant = clock.now();
+        }
+
+        // update peer states before selecting a gossip peer -> rounds
+        // starting two intervals late mean this member is overloaded
+        if let Some(ref failure_detector) = failure_detector {
+            if instant.saturating_duration_since(previous_instant)
+                    >= gossip_interval * 2 {
+                failure_detector.degrade_local_health();
+            }
+
+            failure_detector.tick(id, &nodes, instant);
+        }
+
+        // advance the local heartbeat once per round
+        nodes.beat(id);
+        topology.tick();
+
+        // persist membership for faster restarts
+        if let Some((interval, ref state_store)) = snapshots {
+            if instant.saturating_duration_since(snapshot_instant) >= interval {
+                if let Err(e) = snapshot::save(&nodes, topology.as_ref(),
+                        state_store.as_ref()) {
+                    warn!("membership snapshot failure: {}", e);
+                }
+
+                snapshot_instant = instant;
+            }
+        }
+
+        // draining members answer peers but initiate no rounds
+        if draining.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        // retrieve gossip address -> bootstrap candidates until ready
+        let bootstrap_addr = match bootstrap {
+            Some(ref bootstrap) => {
+                let address = nodes.get(id).unwrap().get_address();
+                match bootstrap.observe(&address, &nodes, instant) {
+                    true => None,
+                    false => bootstrap.next_addr(&address),
+                }
+            },
+            None => None,
+        };
+
+        // partitioned nodes re-contact seeds rather than stale peers
+        let rejoin_addr = match partition.is_partitioned() {
+            true => {
+                let address = nodes.get(id).unwrap().get_address();
+                bootstrap.as_ref()
+                    .and_then(|bootstrap| bootstrap.next_addr(&address))
+                    .or(seed_address)
+                    .filter(|seed_address| *seed_address != address)
+            },
+            false => None,
+        };
+
+        let socket_addr = match bootstrap_addr.or(rejoin_addr)
+                .or_else(|| topology.gossip_addr(id, &seed_address)) {
+            Some(socket_addr) => socket_addr,
+            None => continue,
+        };
+
+        // skip targets backing off after failed connections
+        if let Some(remaining) = connect_backoff.remaining(&socket_addr,
+                clock.now()) {
+            debug!("gossip target backing off -> skipping round [address={}, remaining_ms={}]",
+                socket_addr, remaining.as_millis());
+            partition.round(false);
+            continue;
+        }
+
+        // start trace for this round
+        let trace_id = rand::random::<u64>();
+        let _trace_guard = trace::enter(trace_id);
+        debug!("starting gossip round [trace_id={}, address={}]",
+            trace::current(), socket_addr);
+        let exchange_span = ExchangeSpan::request(socket_addr);
+
+        // skip peers already exchanging with us -> unknown seeds proceed
+        let peer_id = nodes.find_id(&socket_addr);
+        if let Some(peer_id) = peer_id {
+            exchange_span.record_peer_id(peer_id);
+        }
+
+        let _exchange_guard = match peer_id {
+            Some(peer_id) => match exchanges.outbound(peer_id) {
+                Some(guard) => Some(guard),
+                None => {
+                    debug!("exchange in progress -> skipping round [trace_id={}, peer_id={}]",
+                        trace::current(), peer_id);
+                    continue;
+                },
+            },
+            None => None,
+        };
+
+        // defer the round if this interval's budget is spent
+        if let Some(ref budget) = budget {
+            if !budget.acquire(clock.now()) {
+                debug!("gossip budget exhausted -> deferring round [trace_id={}]",
+                    trace::current());
+                budget.defer();
+                metrics.round_deferred();
+                continue;
+            }
+        }
+
+        metrics.round_attempted();
+        let _phase_guard = phase.send(clock.now());
+
+        // reuse a pooled connection or connect to SocketAddr
+        let pooled = pool.as_ref()
+            .and_then(|pool| pool.checkout(&socket_addr, clock.now()));
+        let is_pooled = pooled.is_some();
+        let mut stream = match pooled.map(Ok)
+                .unwrap_or_else(|| TcpStream::connect(socket_addr)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                // warn once per outage -> retries are backed off
+                match connect_backoff.failure(socket_addr, clock.now()) {
+                    1 => warn!("gossip connection failure [trace_id={}]: {}",
+                        trace::current(), e),
+                    failures => debug!("gossip connection failure [trace_id={}, failures={}]: {}",
+                        trace::current(), failures, e),
+                }
+                metrics.connection_error();
+                metrics.round_completed(false);
+                metrics.peer_round(socket_addr, false, 0, 0,
+                    clock.now() - instant);
+                partition.round(false);
+                probe_indirectly(id, peer_id, &nodes, &indirect_probes,
+                    &failure_detector, clock.as_ref());
+                continue;
+            },
+        };
+
+        connect_backoff.success(&socket_addr);
+
+        // digest exchanges are chatty -> disable nagle
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("gossip nodelay failure: {}", e);
+        }
+
+        // send trace id, cluster name, epoch and local id -> the
+        // admission reply completes one round trip
+        let mut metered_stream =
+            MeteredStream::new(&mut stream, metrics.clone());
+        let (sent, mut rtt) = (clock.now(), None);
+        let (cluster_name, epoch) =
+            (nodes.get_cluster_name(), nodes.get_cluster_epoch());
+        let result = exchange_span.in_scope(|| write_request_header(
+                &mut metered_stream, trace_id, &cluster_name, epoch, id,
+                pool.as_deref(), is_pooled)
+            .and_then(|_| metered_stream.read_u8().map_err(|e| e.into()))
+            .inspect(|_| rtt = Some(clock.now() - sent))
+            .and_then(|accepted| match accepted {
+                // peer is already exchanging with us -> nothing to do
+                0 => {
+                    debug!("duplicate exchange rejected [trace_id={}]",
+                        trace::current());
+                    Ok(())
+                },
+                // peer runs a newer epoch -> forget stale membership
+                STALE_EPOCH => {
+                    let peer_epoch = metered_stream.read_u64::<BigEndian>()?;
+                    rejoin_epoch(peer_epoch, id, &nodes,
+                        state_store.as_ref());
+                    Err(format!("stale cluster epoch [epoch={}, peer_epoch={}]",
+                        epoch, peer_epoch).into())
+                },
+                // perform topology gossip request
+                _ => {
+                    let mut buffered_stream = BufferedStream::new(
+                        &mut metered_stream, &mut buffers);
+                    topology.request(id, &mut buffered_stream)
+                        .and_then(|_| broadcasts.write(nodes.len(),
+                            &mut buffered_stream))
+                        .and_then(|_| broadcasts.read(&nodes,
+                            &mut buffered_stream))
+                        .and_then(|_| piggyback.write(id, peer_id,
+                            &mut buffered_stream))
+                        .and_then(|_| piggyback.read(&mut buffered_stream))
+                        .and_then(|_| buffered_stream.flush()
+                            .map_err(|e| e.into()))
+                },
+            }));
+        exchange_span.finish(metered_stream.get_bytes_sent(),
+            metered_stream.get_bytes_received(), clock.now() - instant);
+
+        if let Err(ref e) = result {
+            warn!("gossip request failure [trace_id={}]: {}",
+                trace::current(), e);
+        }
+        metrics.round_completed(result.is_ok());
+        metrics.peer_round(socket_addr, result.is_ok(),
+            metered_stream.get_bytes_sent(),
+            metered_stream.get_bytes_received(), clock.now() - instant);
+        partition.round(result.is_ok());
+        partition.observe_conflicts(nodes.conflicts());
+        if let Some(ref budget) = budget {
+            budget.consume(metered_stream.get_bytes_sent(),
+                metered_stream.get_bytes_received());
+        }
+
+        match (&result, peer_id, &failure_detector) {
+            (Ok(_), Some(peer_id), Some(failure_detector)) => {
+                failure_detector.heard(peer_id, &nodes, clock.now());
+                failure_detector.restore_local_health();
+            },
+            (Err(_), _, _) => probe_indirectly(id, peer_id, &nodes,
+                &indirect_probes, &failure_detector, clock.as_ref()),
+            _ => {},
+        }
+
+        if let (Some(peer_id), Some(rtt)) = (peer_id, rtt) {
+            nodes.record_rtt(peer_id, rtt);
+        }
+
+        // pool connection for the next round or shutdown
+        match (pool.as_ref(), result.is_ok()) {
+            (Some(pool), true) => pool.checkin(socket_addr, stream, clock.now()),
+            _ => if let Err(e) = stream.shutdown(Shutdown::Both) {
+                warn!("gossip shutdown failure: {}", e);
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks other members to reach a peer whose round failed, counting the
+/// peer as heard if any of them did.
+fn probe_indirectly(id: u32, peer_id: Option<u32>, nodes: &NodeMap,
+        indirect_probes: &Option<IndirectProbes>,
+        failure_detector: &Option<Arc<FailureDetector>>, clock: &dyn Clock) {
+    if let (Some(peer_id), Some(indirect_probes), Some(failure_detector)) =
+            (peer_id, indirect_probes, failure_detector) {
+        // peers others reach -> the failure was likely local
+        if indirect_probes.probe(id, peer_id, nodes) {
+            debug!("peer reached indirectly [trace_id={}, peer_id={}]",
+                trace::current(), peer_id);
+            failure_detector.heard(peer_id, nodes, clock.now());
+            failure_detector.degrade_local_health();
+        }
+    }
+}
+
+/// Adopts `epoch` if it is newer than the local cluster epoch,
+/// forgetting every member but the local one so the node rejoins the
+/// newer cluster fresh.
+fn rejoin_epoch(epoch: u64, id: u32, nodes: &NodeMap,
+        state_store: &dyn StateStore) {
+    let previous = nodes.advance_cluster_epoch(epoch);
+    if previous >= epoch {
+        return;
+    }
+
+    let forgotten = nodes.reset(id);
+    info!("rejoining newer cluster epoch [epoch={}, previous_epoch={}, forgotten={}]",
+        epoch, previous, forgotten);
+    if let Err(e) = state_store.put_u64(store::EPOCH_KEY, epoch) {
+        warn!("cluster epoch persist failure: {}", e);
+    }
+}
+
+/// Writes the header of a tracked exchange. Pooled connections carry
+/// their own header once, then frame each exchange by its trace id.
+/// Either header names the cluster and its epoch ahead of the local id.
+#[allow(clippy::too_many_arguments)]
+fn write_request_header(writer: &mut impl Write, trace_id: u64,
+        cluster_name: &str, cluster_epoch: u64, id: u32,
+        pool: Option<&ConnectionPool>, is_pooled: bool)
+        -> Result<(), Box<dyn Error>> {
+    match (pool, is_pooled) {
+        (Some(pool), false) => {
+            codec::write_cluster_header(writer, rand::random::<u64>(),
+                POOLED_EXCHANGE, cluster_name, cluster_epoch)?;
+            writer.write_u32::<BigEndian>(id)?;
+            writer.write_u64::<BigEndian>(
+                pool.get_idle_timeout().as_millis() as u64)?;
+        },
+        (None, _) => {
+            codec::write_cluster_header(writer, trace_id, TRACKED_EXCHANGE,
+                cluster_name, cluster_epoch)?;
+            writer.write_u32::<BigEndian>(id)?;
+            return Ok(());
+        },
+        _ => {},
+    }
+
+    writer.write_u64::<BigEndian>(trace_id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, ManualClock, SystemClock};
+    use crate::codec;
+    use crate::exchange::UNTRACKED_EXCHANGE;
+    use crate::prelude::{Cluster, ClusterBuilder, KeepaliveEvent,
+        MembershipDelta, Node, Subscription, Swarm, TimeoutError, Topology};
+
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cycle_swarm() {
+	// initialize topology builder
+        let cluster_builder = ClusterBuilder::new();
+
+	// initialize swarm
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, _cluster) =
+            Swarm::new(0, ip_address, 12000, None, cluster_builder);
+
+	// start swarm
+	swarm.start(2, 50, 2000).expect("swarm start");
+
+        // stop swarm
+        swarm.stop().expect("swarm stop")
+    }
+
+    #[test]
+    fn preflight_duplicate_seeds() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let listeners: Vec<std::net::TcpListener> = (0..2)
+            .map(|_| std::net::TcpListener::bind((ip_address, 0))
+                .expect("bind listener")).collect();
+        let addresses: Vec<SocketAddr> = listeners.iter()
+            .map(|listener| listener.local_addr().expect("local addr"))
+            .collect();
+
+        // the seed repeats among candidates, but not adjacently
+        let (mut swarm, _cluster) = Swarm::new(0, ip_address, 0,
+            Some(addresses[0]), ClusterBuilder::new());
+        swarm.set_bootstrap(vec!(addresses[1], addresses[0], addresses[1]),
+            Duration::from_secs(1));
+        let report = swarm.preflight();
+        assert_eq!(report.get("seeds").expect("seeds check").detail,
+            "2 reachable");
+    }
+
+    #[test]
+    fn wait_for_members_timeout() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let clock = Arc::new(ManualClock::new(0));
+        let (swarm, _cluster) = Swarm::with_clock(0, ip_address, 0, None,
+            clock.clone(), ClusterBuilder::new());
+
+        // waits run on the swarm's clock rather than real time
+        let timeout = Duration::from_secs(60);
+        assert_eq!(swarm.wait_for_members(2, timeout),
+            Err(TimeoutError { count: 2, members: 1 }));
+        assert!(clock.elapsed() >= timeout);
+        assert_eq!(swarm.wait_for_members(1, timeout), Ok(()));
+    }
+
+    #[test]
+    fn ephemeral_port() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        assert!(swarm.local_addr().is_none());
+        let report = swarm.preflight();
+        assert!(report.is_ok(), "{}", report);
+        swarm.start(1, 20, 50).expect("swarm start");
+
+        // peers seed from the bound address
+        let address = swarm.local_addr().expect("local addr");
+        assert_ne!(address.port(), 0);
+        let (mut peer, peer_cluster) = Swarm::new(1, ip_address, 0,
+            Some(address), ClusterBuilder::new());
+        peer.start(1, 20, 50).expect("peer start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+        peer.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        let peer_address = peer.local_addr().expect("local addr");
+        assert!(cluster.nodes().iter().any(|node|
+            node.get_address() == peer_address));
+
+        // rounds with known peers measure their round trip
+        assert!(wait_until(&SystemClock, Duration::from_secs(2),
+            || cluster.peer_stats().contains_key(&1)), "peer stats");
+        assert!(peer_cluster.nodes().iter().any(|node|
+            node.get_address() == address));
+
+        peer.stop().expect("peer stop");
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn piggybacked_broadcast() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        for i in 0..3 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            let received = received.clone();
+            swarm.on_broadcast(move |node, payload| received.lock().unwrap()
+                .push((i, node.get_id(), payload.to_vec())));
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        for swarm in swarms.iter() {
+            swarm.wait_for_members(3, Duration::from_secs(5))
+                .expect("wait for members");
+        }
+        swarms[2].broadcast(b"hello").expect("broadcast");
+        assert_eq!(swarms[2].pending_broadcasts(), 1);
+
+        // retired broadcasts are no longer retransmitted
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            received.lock().unwrap().len() >= 2 && swarms.iter()
+                .all(|swarm| swarm.pending_broadcasts() == 0)),
+            "broadcast delivery");
+
+        // every other member receives the broadcast exactly once
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(received, vec!((0, 2, b"hello".to_vec()),
+            (1, 2, b"hello".to_vec())));
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn piggybacked_entries() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        for i in 0..2 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            let received = received.clone();
+            swarm.on_piggyback(move |sender, key, value| received.lock()
+                .unwrap().push((i, sender, key.to_string(), value.to_vec())));
+            swarm.piggyback("load", &[i as u8]).expect("piggyback");
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+        }
+
+        // values flow in both directions of an exchange
+        let expected = vec!((0, 1, "load".to_string(), vec!(1)),
+            (1, 0, "load".to_string(), vec!(0)));
+        let entries = || {
+            let mut received = received.lock().unwrap().clone();
+            received.sort();
+            received.dedup();
+            received
+        };
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || entries() == expected), "piggybacked entries");
+        assert_eq!(entries(), expected);
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn control_redelivery() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let members: Vec<Node> = (0..3)
+            .map(|id| Node::new(id, ip_address, 16200 + id as u16)).collect();
+
+        let mut swarms = Vec::new();
+        for id in 0..3 {
+            let (mut swarm, cluster) = Swarm::new(id, ip_address,
+                16200 + id as u16, None,
+                ClusterBuilder::new().static_membership());
+            cluster.register_nodes(members.clone());
+            swarm.set_broadcast_inbox(8, Duration::from_secs(10));
+            swarms.push(swarm);
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        swarms[2].on_control(move |message|
+            received_clone.lock().unwrap().push(message.sender));
+        swarms[0].start(1, 20, 50).expect("swarm start");
+        swarms[1].start(1, 20, 50).expect("swarm start");
+
+        // member 2 is down -> its successor retains the message
+        swarms[1].broadcast_control(b"rotate");
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(swarms[0].retained_control(2), 1);
+
+        swarms[2].start(1, 20, 50).expect("swarm start");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || !received.lock().unwrap().is_empty()), "redelivery");
+        assert_eq!(*received.lock().unwrap(), vec!(1));
+
+        for swarm in swarms.iter_mut() {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn restart_swarm() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let seed_address = SocketAddr::new(ip_address, 15950);
+        let (mut swarm, cluster) = Swarm::new(0, ip_address, 15950,
+            None, ClusterBuilder::new());
+
+        // repeated cycles rebind the same port
+        for i in 0..3 {
+            swarm.start(1, 20, 50).expect("swarm start");
+            assert!(swarm.start(1, 20, 50).is_err());
+
+            let members = 2 + i as usize;
+            assert!(swarm.wait_for_members(members,
+                Duration::from_millis(50)).is_err());
+
+            let (mut peer, _) = Swarm::new(1 + i, ip_address,
+                15951, Some(seed_address), ClusterBuilder::new());
+            peer.start(1, 20, 50).expect("peer start");
+            swarm.wait_for_members(members, Duration::from_secs(2))
+                .expect("wait for members");
+            assert!(cluster.nodes().iter().any(|node| node.get_id() == 1 + i));
+
+            peer.stop().expect("peer stop");
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn bounded_staleness() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        let mut clusters = Vec::new();
+        for i in 0..3 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, cluster) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, ClusterBuilder::new());
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            clusters.push(cluster);
+        }
+
+        let bound = Duration::from_millis(400);
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            clusters[1].nodes_max_stale(bound).len() == 3), "confirmations");
+
+        // stopped members age out while their records remain
+        swarms[2].stop().expect("swarm stop");
+        let ids = || clusters[0].nodes_max_stale(bound).iter()
+            .map(|node| node.get_id()).collect::<Vec<u32>>();
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || ids() == vec!(0, 1)), "stale member");
+        assert_eq!(clusters[0].nodes().len(), 3);
+
+        swarms[0].stop().expect("swarm stop");
+        swarms[1].stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn node_gossip() {
+        let swarm_count = 4;
+
+        // start multiple swarm instances
+        let mut swarms: Vec<Swarm<Cluster>> = Vec::new();
+        for i in 0..swarm_count {
+            // initialize topology builder
+            let cluster_builder = ClusterBuilder::new();
+
+            // initialize swarm seeded by the first
+            let ip_address = "127.0.0.1".parse()
+                .expect("parse ip addr");
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, _cluster) = Swarm::new(i, ip_address, 0,
+                seed_address, cluster_builder);
+
+            // start swarm
+            swarm.start(2, 50, 75).expect("swarm start");
+
+            // add swarm to vector
+            swarms.push(swarm);
+        }
+
+        // wait for every swarm to learn the membership
+        for swarm in swarms.iter() {
+            swarm.wait_for_members(swarm_count as usize,
+                Duration::from_secs(5)).expect("wait for members");
+        }
+
+        // stop swarms
+        for i in 0..swarm_count {
+            swarms[i as usize].stop().expect("swarm stop")
+        }
+    }
+
+    #[test]
+    fn metadata_removal() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, _) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        let (mut peer, peer_cluster) = Swarm::new(1, ip_address, 0,
+            Some(address), ClusterBuilder::new());
+        peer.start(1, 20, 50).expect("peer start");
+
+        let zone = || peer_cluster.nodes().into_iter()
+            .find(|node| node.get_id() == 0)
+            .and_then(|node| node.get_metadata("zone").cloned());
+        let wait_for = |expected: Option<&str>| {
+            assert!(wait_until(&SystemClock, Duration::from_secs(2),
+                || zone().as_deref() == expected),
+                "metadata did not converge [expected={:?}]", expected);
+        };
+
+        // removals gossip like any other update
+        swarm.set_metadata("zone", "a");
+        wait_for(Some("a"));
+        swarm.remove_metadata("zone");
+        wait_for(None);
+
+        peer.stop().expect("peer stop");
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn bootstrap_cold_start() {
+        let port = 15300;
+        let swarm_count = 3;
+
+        // every member bootstraps from the full candidate list
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let candidates: Vec<SocketAddr> = (0..swarm_count)
+            .map(|i| SocketAddr::new(ip_address, port + i)).collect();
+
+        let mut swarms = Vec::new();
+        let mut clusters = Vec::new();
+        for i in 0..swarm_count {
+            let (mut swarm, cluster) = Swarm::new(i as u32, ip_address,
+                port + i, None, ClusterBuilder::new());
+            swarm.set_bootstrap(candidates.clone(),
+                Duration::from_millis(200));
+            assert!(!swarm.is_ready());
+
+            swarm.start(2, 50, 50).expect("swarm start");
+            swarms.push(swarm);
+            clusters.push(cluster);
+        }
+
+        // wait for bootstrap to settle
+        let settled = || swarms.iter().zip(clusters.iter())
+            .all(|(swarm, cluster)| swarm.is_ready()
+                && cluster.nodes().len() == swarm_count as usize);
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), settled),
+            "bootstrap");
+        for (swarm, cluster) in swarms.iter().zip(clusters.iter()) {
+            assert!(swarm.is_ready());
+            assert_eq!(cluster.nodes().len(), swarm_count as usize);
+        }
+
+        for swarm in swarms.iter_mut() {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn keepalive_monitor() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) = Swarm::new(0, ip_address, 15400,
+            None, ClusterBuilder::new().static_membership());
+        let (mut peer, _) = Swarm::new(1, ip_address, 15401,
+            None, ClusterBuilder::new().static_membership());
+        cluster.register_nodes(vec!(Node::new(1, ip_address, 15401)));
+
+        swarm.start(1, 50, 50).expect("swarm start");
+        peer.start(1, 50, 50).expect("swarm start");
+
+        // record keepalive events
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        swarm.monitor(1, Duration::from_millis(50), move |id, event| {
+            events_clone.lock().unwrap().push((id, event));
+        }).expect("monitor");
+        assert!(swarm.monitor(2, Duration::from_millis(50), |_, _| {})
+            .is_err());
+
+        let event_count = |count: usize| wait_until(&SystemClock,
+            Duration::from_secs(5), || events.lock().unwrap().len() >= count);
+        assert!(event_count(1), "keepalive connected");
+        assert_eq!(*events.lock().unwrap(),
+            vec!((1, KeepaliveEvent::Connected)));
+
+        // stopping the peer closes the channel
+        peer.stop().expect("swarm stop");
+        assert!(event_count(2), "keepalive lost");
+        assert_eq!(*events.lock().unwrap(),
+            vec!((1, KeepaliveEvent::Connected), (1, KeepaliveEvent::Lost)));
+
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn change_subscription() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, _) = Swarm::new(0, ip_address, 15800,
+            None, ClusterBuilder::new().static_membership());
+        swarm.set_change_journal(16);
+        swarm.start(1, 20, 50).expect("swarm start");
+
+        // new subscribers receive every journaled delta
+        let address = SocketAddr::new(ip_address, 15800);
+        let timeout = Duration::from_millis(1000);
+        let mut subscription = Subscription::connect(&address, 0, timeout)
+            .expect("subscribe");
+        let (seq, delta) = subscription.next_delta().expect("next delta");
+        assert_eq!(seq, 1);
+        assert!(matches!(delta, MembershipDelta::Upsert(ref node)
+            if node.get_id() == 0));
+
+        swarm.set_metadata("k", "v");
+        let (seq, _) = subscription.next_delta().expect("next delta");
+        assert_eq!(seq, 2);
+
+        // resuming past the journal resets the consumer
+        let mut subscription = Subscription::connect(&address, 9, timeout)
+            .expect("subscribe");
+        let (seq, delta) = subscription.next_delta().expect("next delta");
+        assert!(matches!(delta, MembershipDelta::Reset));
+        assert_eq!(seq, 2);
+        assert_eq!(subscription.get_seq(), 2);
+
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn cluster_name_mismatch() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.set_cluster_name(&"a".repeat(256));
+        assert!(swarm.start(1, 20, 50).is_err());
+        swarm.set_cluster_name("orders");
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // peers of another cluster are dropped before any state is read
+        let (mut stranger, stranger_cluster) = Swarm::new(1, ip_address, 0,
+            Some(address), ClusterBuilder::new());
+        stranger.set_cluster_name("billing");
+        stranger.start(1, 20, 50).expect("stranger start");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || swarm.metrics().replies_failed > 0), "rejected exchange");
+        assert_eq!(cluster.nodes().len(), 1);
+        assert_eq!(stranger_cluster.nodes().len(), 1);
+
+        let (mut peer, _) = Swarm::new(2, ip_address, 0,
+            Some(address), ClusterBuilder::new());
+        peer.set_cluster_name("orders");
+        assert_eq!(peer.get_cluster_name(), "orders");
+        peer.start(1, 20, 50).expect("peer start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+        assert!(cluster.nodes().iter().all(|node| node.get_id() != 1));
+
+        peer.stop().expect("peer stop");
+        stranger.stop().expect("stranger stop");
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn untracked_cluster_name() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.set_cluster_name("orders");
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // one-off queries name the cluster too -> strangers cannot
+        // register members through them
+        let (_stranger, stranger_cluster) =
+            Swarm::new(1, ip_address, 1, None, ClusterBuilder::new());
+        let exchange = |cluster_name: &str| {
+            let mut stream = TcpStream::connect(address).expect("connect");
+            codec::write_cluster_header(&mut stream, 1, UNTRACKED_EXCHANGE,
+                cluster_name, 0).expect("write header");
+            stranger_cluster.request(1, &mut stream)
+        };
+
+        assert!(exchange("billing").is_err());
+        assert_eq!(cluster.nodes().len(), 1);
+        exchange("orders").expect("exchange");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn cluster_epoch() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut old, old_cluster) =
+            Swarm::new(1, ip_address, 0, None, ClusterBuilder::new());
+        old.set_cluster_epoch(1);
+        old.start(1, 20, 50).expect("old start");
+        let old_address = old.local_addr().expect("local addr");
+
+        // members of the previous deployment know each other
+        let (mut stale, _) = Swarm::new(2, ip_address, 0, Some(old_address),
+            ClusterBuilder::new());
+        stale.set_cluster_epoch(1);
+        stale.start(1, 20, 50).expect("stale start");
+        old.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+        stale.stop().expect("stale stop");
+
+        // newer epochs make members forget the previous deployment
+        let (mut swarm, cluster) = Swarm::new(0, ip_address, 0,
+            Some(old_address), ClusterBuilder::new());
+        swarm.set_cluster_epoch(2);
+        swarm.start(1, 20, 50).expect("swarm start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+        assert_eq!(old.get_cluster_epoch(), 2);
+        assert!(old_cluster.nodes().iter().all(|node| node.get_id() != 2));
+        assert!(cluster.nodes().iter().all(|node| node.get_id() != 2));
+
+        // returning members of older epochs are told to rejoin fresh
+        stale.start(1, 20, 50).expect("stale start");
+        swarm.wait_for_members(3, Duration::from_secs(2))
+            .expect("wait for members");
+        assert_eq!(stale.get_cluster_epoch(), 2);
+
+        stale.stop().expect("stale stop");
+        swarm.stop().expect("swarm stop");
+        old.stop().expect("old stop");
+    }
+
+    #[test]
+    fn untracked_cluster_epoch() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.set_cluster_epoch(2);
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // stale members cannot bring back old membership through
+        // one-off queries either
+        let (_stale, stale_cluster) =
+            Swarm::new(1, ip_address, 1, None, ClusterBuilder::new());
+        let exchange = |cluster_epoch: u64| {
+            let mut stream = TcpStream::connect(address).expect("connect");
+            codec::write_cluster_header(&mut stream, 1, UNTRACKED_EXCHANGE,
+                "", cluster_epoch).expect("write header");
+            stale_cluster.request(1, &mut stream)
+        };
+
+        assert!(exchange(1).is_err());
+        assert_eq!(cluster.nodes().len(), 1);
+        exchange(2).expect("exchange");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn allowed_cidrs() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.set_allowed_cidrs(vec!("10.0.0.0/8".parse().expect("cidr")));
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // connections from outside the allowlist are closed unread
+        let (mut peer, _) = Swarm::new(1, ip_address, 0, Some(address),
+            ClusterBuilder::new());
+        peer.start(1, 20, 50).expect("peer start");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || swarm.metrics().connection_errors > 0), "rejected connection");
+        assert_eq!(cluster.nodes().len(), 1);
+
+        swarm.stop().expect("swarm stop");
+        swarm.set_allowed_cidrs(vec!("10.0.0.0/8".parse().expect("cidr"),
+            "127.0.0.0/8".parse().expect("cidr")));
+        swarm.start(1, 20, 50).expect("swarm start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        peer.stop().expect("peer stop");
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn drain_swarm() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, cluster) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        assert!(swarm.drain(Duration::from_millis(10)).is_err());
+        swarm.start(1, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        let (mut peer, _) = Swarm::new(1, ip_address, 0, Some(address),
+            ClusterBuilder::new());
+        peer.start(1, 20, 50).expect("peer start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+
+        // peers pull the draining flag while the member still answers
+        peer.drain(Duration::from_millis(300)).expect("peer drain");
+        assert!(peer.is_draining());
+        let draining = |cluster: &Cluster| cluster.nodes().iter()
+            .any(|node| node.get_id() == 1 && node.is_draining());
+        assert!(draining(&cluster));
+
+        // restarts clear the flag
+        peer.start(1, 20, 50).expect("peer restart");
+        assert!(!peer.is_draining());
+        assert!(wait_until(&SystemClock, Duration::from_secs(5),
+            || !draining(&cluster)), "draining flag");
+
+        peer.stop().expect("peer stop");
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn stalled_connection() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let (mut swarm, _) =
+            Swarm::new(0, ip_address, 0, None, ClusterBuilder::new());
+        swarm.start(2, 20, 50).expect("swarm start");
+        let address = swarm.local_addr().expect("local addr");
+
+        // a silent connection occupies one worker -> others keep serving
+        let stalled = std::net::TcpStream::connect(address)
+            .expect("connect");
+        let (mut peer, _) = Swarm::new(1, ip_address, 0, Some(address),
+            ClusterBuilder::new());
+        peer.start(1, 20, 50).expect("peer start");
+        swarm.wait_for_members(2, Duration::from_secs(2))
+            .expect("wait for members");
+        peer.stop().expect("peer stop");
+
+        // stop unblocks the worker while the client is still connected
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            swarm.stop().expect("swarm stop");
+            let _ = sender.send(());
+        });
+        assert!(receiver.recv_timeout(Duration::from_secs(10)).is_ok(),
+            "stop blocked on stalled connection");
+        drop(stalled);
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+const DEFAULT_LIMIT: usize = 256;
+
+/// Dedicated threads serving long-lived inbound connections, such as
+/// keepalive channels and pooled gossip, bounded per exchange kind.
+/// Swarm::stop shuts their sockets down, unblocking any read, and joins
+/// them.
+pub struct ConnectionThreads {
+    limit: AtomicUsize,
+    threads: Mutex<Vec<(u8, TcpStream, JoinHandle<()>)>>,
+}
+
+impl ConnectionThreads {
+    pub fn new() -> ConnectionThreads {
+        ConnectionThreads {
+            limit: AtomicUsize::new(DEFAULT_LIMIT),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Serves `stream`, a connection of exchange `kind`, with `serve` on
+    /// a new thread. Fails, closing the connection, once the limit of
+    /// threads serving `kind` is running.
+    pub fn spawn<F>(&self, kind: u8, stream: TcpStream, serve: F)
+            -> Result<(), Box<dyn Error>>
+            where F: 'static + FnOnce(TcpStream) + Send {
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|(_, _, handle)| !handle.is_finished());
+        let limit = self.limit.load(Ordering::Relaxed);
+        if threads.iter().filter(|(thread_kind, _, _)| *thread_kind == kind)
+                .count() >= limit {
+            return Err(format!(
+                "connection thread limit reached [kind={}, limit={}]",
+                kind, limit).into());
+        }
+
+        let socket = stream.try_clone()?;
+        let handle = thread::spawn(move || serve(stream));
+        threads.push((kind, socket, handle));
+        Ok(())
+    }
+
+    /// Shuts down the sockets of running threads and joins them.
+    pub fn join(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for (_, socket, handle) in threads {
+            // threads which closed their connection already fail here
+            let _ = socket.shutdown(Shutdown::Both);
+            if let Err(e) = handle.join() {
+                warn!("join connection thread failure: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Sockets of connections gossip workers are serving. Swarm::stop shuts
+/// them down so no worker stays blocked reading from a silent peer.
+pub struct WorkerSockets {
+    next_id: AtomicU64,
+    sockets: Mutex<HashMap<u64, TcpStream>>,
+}
+
+impl WorkerSockets {
+    pub fn new() -> WorkerSockets {
+        WorkerSockets {
+            next_id: AtomicU64::new(0),
+            sockets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Tracks the socket of `stream` until the returned guard drops.
+    pub fn register(&self, stream: &TcpStream)
+            -> Result<WorkerSocket<'_>, Box<dyn Error>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sockets.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(WorkerSocket { id, sockets: self })
+    }
+
+    /// Shuts down the sockets of every tracked connection.
+    pub fn shutdown(&self) {
+        for socket in self.sockets.lock().unwrap().values() {
+            // connections closed by their peer already fail here
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Stops tracking a socket of WorkerSockets when dropped.
+pub struct WorkerSocket<'a> {
+    id: u64,
+    sockets: &'a WorkerSockets,
+}
+
+impl Drop for WorkerSocket<'_> {
+    fn drop(&mut self) {
+        self.sockets.sockets.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionThreads, WorkerSockets};
+
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn connection_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = listener.local_addr().expect("local addr");
+        let connect = || {
+            let client = TcpStream::connect(address).expect("connect");
+            let (server, _) = listener.accept().expect("accept");
+            (client, server)
+        };
+
+        // threads block reading until their socket shuts down
+        let serve = |mut stream: TcpStream| {
+            let _ = stream.read(&mut [0; 1]);
+        };
+
+        let threads = ConnectionThreads::new();
+        threads.set_limit(1);
+        let (_first, server) = connect();
+        threads.spawn(0, server, serve).expect("spawn");
+        let (_second, server) = connect();
+        assert!(threads.spawn(0, server, serve).is_err());
+        let (_third, server) = connect();
+        threads.spawn(1, server, serve).expect("spawn");
+
+        // joined threads free their slots
+        threads.join();
+        let (_fourth, server) = connect();
+        threads.spawn(0, server, serve).expect("spawn");
+        threads.join();
+    }
+
+    #[test]
+    fn worker_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = listener.local_addr().expect("local addr");
+        let _client = TcpStream::connect(address).expect("connect");
+        let (mut server, _) = listener.accept().expect("accept");
+
+        // shutdown unblocks reads of tracked sockets
+        let sockets = WorkerSockets::new();
+        let socket = sockets.register(&server).expect("register");
+        sockets.shutdown();
+        assert_eq!(server.read(&mut [0; 1]).expect("read"), 0);
+
+        // dropped guards stop tracking their socket
+        drop(socket);
+        assert!(sockets.sockets.lock().unwrap().is_empty());
+    }
+}
@@ -1,114 +1,511 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-use crate::node::Node;
-use crate::topology::{Topology, TopologyBuilder};
+use crate::node::{Node, NodeMap, PeerStats};
+use crate::topology::{FullSync, Topology, TopologyBuilder};
+use crate::clock::Clock;
+use crate::topology::policy::MembershipPolicy;
+use crate::topology::quarantine::Quarantine;
+use crate::topology::selector::{PeerSelector, RandomSelector};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct ClusterBuilder {
+    flap_damping: Option<(Duration, Duration)>,
+    full_sync_interval: Option<Duration>,
+    is_static: bool,
+    observer: bool,
+    policy: MembershipPolicy,
+    selector: Arc<dyn PeerSelector>,
 }
 
 impl Default for ClusterBuilder {
-    fn default() -> Self {
-        ClusterBuilder {}
+    fn default() -> ClusterBuilder {
+        ClusterBuilder {
+            flap_damping: None,
+            full_sync_interval: None,
+            is_static: false,
+            observer: false,
+            policy: MembershipPolicy::new(),
+            selector: Arc::new(RandomSelector),
+        }
+    }
+}
+
+impl ClusterBuilder {
+    pub fn new() -> ClusterBuilder {
+        ClusterBuilder::default()
+    }
+
+    /// Quarantines nodes which rejoin with a new incarnation, starting
+    /// at `base_backoff` and doubling per flap up to `max_backoff`.
+    pub fn flap_damping(mut self, base_backoff: Duration,
+            max_backoff: Duration) -> ClusterBuilder {
+        self.flap_damping = Some((base_backoff, max_backoff));
+        self
+    }
+
+    /// Pulls the full membership of a peer once per `interval` rather
+    /// than relying on membership hashes alone, bounding how long any
+    /// divergence the hashes miss can last.
+    pub fn full_sync_interval(mut self, interval: Duration)
+            -> ClusterBuilder {
+        self.full_sync_interval = Some(interval);
+        self
+    }
+
+    /// Learns the membership through gossip without joining it: members
+    /// never register the local node. See OBSERVER_KEY.
+    pub fn observer(mut self) -> ClusterBuilder {
+        self.observer = true;
+        self
+    }
+
+    /// Applies `policy` at admission and eviction decisions.
+    pub fn policy(mut self, policy: MembershipPolicy) -> ClusterBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Replaces the default random gossip peer selection.
+    pub fn peer_selector(mut self, selector: impl PeerSelector + 'static)
+            -> ClusterBuilder {
+        self.selector = Arc::new(selector);
+        self
+    }
+
+    /// Fixes membership to the locally registered nodes and disables
+    /// gossip entirely.
+    pub fn static_membership(mut self) -> ClusterBuilder {
+        self.is_static = true;
+        self
     }
 }
 
 impl TopologyBuilder<Cluster> for ClusterBuilder {
-    fn build(&self, _id: u32,
-            nodes: Arc<RwLock<HashMap<u32, Node>>>) -> Cluster {
-        Cluster { nodes }
+    fn build(&self, id: u32, nodes: Arc<NodeMap>, clock: Arc<dyn Clock>)
+            -> Cluster {
+        if self.observer {
+            crate::topology::mark_observer(id, &nodes);
+        }
+
+        Cluster {
+            clock: clock.clone(),
+            full_sync: self.full_sync_interval.map(|interval|
+                FullSync::new(clock.clone(), interval)),
+            id,
+            is_static: self.is_static,
+            nodes,
+            policy: self.policy.clone(),
+            quarantine: self.flap_damping.map(|(base, max)|
+                Quarantine::new(clock.clone(), base, max)),
+            selector: self.selector.clone(),
+        }
     }
 }
 
 pub struct Cluster {
-    nodes: Arc<RwLock<HashMap<u32, Node>>>,
+    clock: Arc<dyn Clock>,
+    full_sync: Option<FullSync>,
+    id: u32,
+    is_static: bool,
+    nodes: Arc<NodeMap>,
+    policy: MembershipPolicy,
+    quarantine: Option<Quarantine>,
+    selector: Arc<dyn PeerSelector>,
+}
+
+impl Cluster {
+    pub fn nodes(&self) -> Vec<Node> {
+        self.nodes.nodes()
+    }
+
+    /// Returns the nodes matching `predicate` ordered by id, copying
+    /// only those.
+    pub fn nodes_where<F: Fn(&Node) -> bool>(&self, predicate: F)
+            -> Vec<Node> {
+        self.nodes.nodes_where(predicate)
+    }
+
+    /// Returns the nodes whose metadata `key` is `value`, see
+    /// Swarm::index_metadata.
+    pub fn nodes_with_metadata(&self, key: &str, value: &str) -> Vec<Node> {
+        self.nodes.nodes_with_metadata(key, value)
+    }
+
+    /// Returns the nodes which declared `role`, see Swarm::set_roles.
+    pub fn nodes_with_role(&self, role: &str) -> Vec<Node> {
+        crate::topology::nodes_with_role(&self.nodes, role)
+    }
+
+    /// Returns members confirmed alive, directly or through peers,
+    /// within `max_staleness`. Confirmations are wall clock based, so
+    /// bounds should exceed the clock skew between members.
+    pub fn nodes_max_stale(&self, max_staleness: Duration) -> Vec<Node> {
+        let now = self.clock.timestamp();
+        self.nodes.confirm(self.id, now);
+        let max_staleness = max_staleness.as_millis() as u64;
+        self.nodes.nodes().into_iter().filter(|node|
+            now.saturating_sub(node.get_confirmed()) <= max_staleness)
+            .collect()
+    }
+
+    /// Returns the remaining quarantine of a flapping node, if any.
+    pub fn quarantined(&self, id: u32) -> Option<Duration> {
+        self.quarantine.as_ref().and_then(|quarantine| quarantine.remaining(id))
+    }
+
+    /// Registers a statically known membership list in one locked
+    /// operation. Entries for the local node are ignored.
+    pub fn register_nodes(&self, nodes: impl IntoIterator<Item=Node>) {
+        let id = self.id;
+        let count = self.nodes.insert_all(nodes.into_iter()
+            .filter(|node| node.get_id() != id));
+        debug!("registered nodes [count={}]", count);
+    }
+
+    pub fn snapshot(&self) -> ClusterSnapshot {
+        let nodes = self.nodes.nodes().into_iter()
+            .map(|node| (node.get_id(), node)).collect();
+        ClusterSnapshot { nodes }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct ClusterSnapshot {
+    pub nodes: HashMap<u32, Node>,
 }
 
 impl Topology for Cluster {
     fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
             -> Option<SocketAddr> {
-        let nodes = self.nodes.read().unwrap();
-        if nodes.len() > 1 {
-            // if more than local node is registered -> choose random
-            let mut index = rand::random::<usize>() % (nodes.len() - 1);
-            for (node_id, node) in nodes.iter() {
-                match (node_id, index) {
-                    (x, _) if x == &id => {},
-                    (_, 0) => return Some(node.get_address()),
-                    _ => index -= 1,
-                }
-            }
-        } else if let Some(seed_address) = seed_address {
-            // if no other registered nodes -> return seed node
-            return Some(*seed_address);
-        }
+        crate::topology::select_gossip_addr(id, &self.nodes,
+            self.selector.as_ref(), seed_address)
+    }
+
+    fn request<S: Read + Write>(&self, id: u32, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        // write local node and node hash
+        let node = self.nodes.get(id).unwrap();
+        let full_sync = crate::topology::is_full_sync(id,
+            self.full_sync.as_ref());
+        crate::topology::write_request_node(&node,
+            crate::topology::member_hash(&self.nodes, full_sync), stream)?;
+
+        // process node updates
+        crate::topology::read_node_updates(&self.nodes, self.clock.as_ref(),
+            &self.policy, self.quarantine.as_ref(), stream)?;
 
-        None
+        // exchange confirmation timestamps
+        crate::topology::read_confirmations(&self.nodes, self.is_static, stream)?;
+        crate::topology::write_confirmations(id, &self.nodes,
+            self.clock.as_ref(), stream)?;
+
+        // exchange tombstones of removed nodes
+        crate::topology::read_tombstones(id, &self.nodes, self.is_static, stream)?;
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
+
+        Ok(())
     }
 
-    fn request(&self, id: u32, stream: &mut TcpStream)
+    fn reply<S: Read + Write>(&self, stream: &mut S)
             -> Result<(), Box<dyn Error>> {
-        {
-            let nodes = self.nodes.read().unwrap();
+        // read request node and node hash
+        let (node, node_hash) = crate::topology::read_request_node(stream)?;
 
-            // write local node
-            let node = nodes.get(&id).unwrap();
-            node.write(stream)?;
+        // write node updates
+        crate::topology::write_node_updates(&self.nodes,
+            node_hash, stream)?;
 
-            // write node and token hashes
-            stream.write_u64::<BigEndian>(
-                crate::node::hash_nodes(nodes.values()))?;
-        }
+        // exchange confirmation timestamps
+        crate::topology::write_confirmations(self.id, &self.nodes,
+            self.clock.as_ref(), stream)?;
+        crate::topology::read_confirmations(&self.nodes, self.is_static, stream)?;
 
-        // process node updates
-        let node_updates = stream.read_u16::<BigEndian>()?;
-        for _ in 0..node_updates {
-            let node = Node::read(stream)?;
-
-            let mut nodes = self.nodes.write().unwrap();
-            if !nodes.contains_key(&node.get_id()) {
-                debug!("registering node [id={}, address={}]",
-                    node.get_id(), node.get_address());
-            }
-            nodes.insert(node.get_id(), node);
+        // exchange tombstones of removed nodes
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
+        crate::topology::read_tombstones(self.id, &self.nodes, self.is_static, stream)?;
+
+        // add gossiping node to nodes if does not exist
+        if !self.is_static {
+            crate::topology::register_node(&self.nodes, self.clock.as_ref(),
+                &self.policy, self.quarantine.as_ref(), node);
         }
 
         Ok(())
     }
 
-    fn reply(&self, stream: &mut TcpStream)
+    fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    fn authorize_join(&self, node: &Node) -> bool {
+        self.policy.authorizes(node)
+    }
+
+    fn member_counts(&self) -> (usize, usize) {
+        crate::topology::count_members(&self.nodes)
+    }
+
+    fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        self.nodes.peer_stats()
+    }
+
+    fn remove_node(&self, id: u32, ttl: Duration)
             -> Result<(), Box<dyn Error>> {
-        // read request node and node and token hashes
-        let node = Node::read(stream)?;
-        let node_hash = stream.read_u64::<BigEndian>()?;
-
-        {
-            // write node updates
-            let nodes = self.nodes.read().unwrap();
-            if node_hash != crate::node::hash_nodes(nodes.values()) {
-                stream.write_u16::<BigEndian>(nodes.len() as u16)?;
-                for node in nodes.values() {
-                    node.write(stream)?;
-                }
-            } else {
-                stream.write_u16::<BigEndian>(0)?;
-            }
+        crate::topology::remove_node(self.id, &self.nodes,
+            self.clock.as_ref(), id, ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ReadBytesExt};
+
+    use crate::clock::{Clock, ManualClock, SystemClock};
+    use crate::node::{Node, NodeMap};
+    use crate::prelude::{MembershipPolicy, RoundRobinSelector};
+    use crate::sim::Simulation;
+    use crate::topology::{Topology, TopologyBuilder, FULL_SYNC_HASH};
+    use crate::transport::MemoryStream;
+    use super::{Cluster, ClusterBuilder};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn cluster_full_sync() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..3 {
+            nodes.insert(Node::new(id, ip_address, 15920 + id as u16));
+        }
+
+        // repliers send every member for the full sync hash
+        let updates = |node_hash| {
+            let mut buf = Vec::new();
+            crate::topology::write_node_updates(&nodes, node_hash, &mut buf)
+                .expect("write node updates");
+            let message = crate::topology::read_message(&mut buf.as_slice())
+                .expect("read message");
+            message.as_slice().read_u32::<BigEndian>().expect("read count")
+        };
+        assert_eq!(updates(crate::topology::member_hash(&nodes, false)), 0);
+        assert_eq!(updates(FULL_SYNC_HASH), 3);
+
+        // requesters send it once per interval
+        let node_hash = |builder: ClusterBuilder| {
+            let cluster =
+                builder.build(0, nodes.clone(), Arc::new(SystemClock));
+            let (mut client, mut server) = MemoryStream::pair();
+            client.set_read_timeout(Some(Duration::from_millis(10)));
+            assert!(cluster.request(0, &mut client).is_err());
+            crate::topology::read_request_node(&mut server)
+                .expect("read request node").1
+        };
+        assert_eq!(node_hash(ClusterBuilder::new()
+            .full_sync_interval(Duration::from_secs(0))), FULL_SYNC_HASH);
+        assert_ne!(node_hash(ClusterBuilder::new()
+            .full_sync_interval(Duration::from_secs(3600))), FULL_SYNC_HASH);
+    }
+
+    #[test]
+    fn cluster_clock() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..3 {
+            nodes.insert(Node::new(id, ip_address, 15950 + id as u16));
+        }
+
+        let clock = Arc::new(ManualClock::new(1000));
+        let cluster = ClusterBuilder::new()
+            .build(0, nodes.clone(), clock.clone());
+        let ids = |nodes: Vec<Node>| nodes.iter()
+            .map(|node| node.get_id()).collect::<Vec<_>>();
+
+        // confirmation ages follow the injected clock
+        nodes.confirm(1, 1000);
+        clock.advance(Duration::from_secs(10));
+        nodes.confirm(2, 11000);
+        assert_eq!(ids(cluster.nodes_max_stale(Duration::from_secs(5))),
+            vec!(0, 2));
+
+        // so do tombstone expiries
+        cluster.remove_node(2, Duration::from_secs(60)).expect("remove");
+        let register = || crate::topology::register_node(&nodes,
+            clock.as_ref(), &MembershipPolicy::new(), None,
+            Node::new(2, ip_address, 15952));
+        register();
+        assert!(!nodes.contains(2));
+        clock.advance(Duration::from_secs(61));
+        register();
+        assert!(nodes.contains(2));
+
+        // ttls past the range of timestamps never expire
+        cluster.remove_node(2, Duration::from_millis(u64::MAX))
+            .expect("remove");
+        clock.advance(Duration::from_secs(3600));
+        register();
+        assert!(!nodes.contains(2));
+        assert_eq!(nodes.tombstones(clock.timestamp()).get(&2),
+            Some(&u64::MAX));
+    }
+
+    #[test]
+    fn cluster_corrupt_node_updates() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = NodeMap::new();
+        for id in 0..3 {
+            nodes.insert(Node::new(id, ip_address, 15930 + id as u16));
         }
- 
-        {
-            // add gossiping node to nodes if does not exist
-            let mut nodes = self.nodes.write().unwrap();
-            if !nodes.contains_key(&node.get_id()) {
-                debug!("registering node [id={}, address={}]",
-                    node.get_id(), node.get_address());
+
+        let mut buf = Vec::new();
+        crate::topology::write_node_updates(&nodes, FULL_SYNC_HASH, &mut buf)
+            .expect("write node updates");
+        let merge = |buf: &[u8]| {
+            let nodes = NodeMap::new();
+            let result = crate::topology::read_node_updates(&nodes,
+                &SystemClock, &MembershipPolicy::new(), None, &mut &*buf);
+            (result.is_ok(), nodes.len())
+        };
+        assert_eq!(merge(&buf), (true, 3));
+
+        // one flipped byte rejects the whole message
+        let mut corrupt = buf.clone();
+        corrupt[12] ^= 0x01;
+        assert_eq!(merge(&corrupt), (false, 0));
+    }
+
+    #[test]
+    fn cluster_large_node_exchange() {
+        // more members and metadata than one message holds
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let replier_nodes = Arc::new(NodeMap::new());
+        for id in 0..5000 {
+            let mut node = Node::new(id, ip_address, 20000 + id as u16);
+            for key in ["zone", "rack", "version", "owner"] {
+                node.set_metadata(key, &format!("{}-{:032}", key, id));
             }
-            nodes.insert(node.get_id(), node);
+
+            replier_nodes.insert(node);
         }
 
-        Ok(())
+        let requester_nodes = Arc::new(NodeMap::new());
+        requester_nodes.insert(Node::new(5000, ip_address, 25000));
+        let replier = ClusterBuilder::new()
+            .build(0, replier_nodes.clone(), Arc::new(SystemClock));
+        let requester = ClusterBuilder::new()
+            .build(5000, requester_nodes.clone(), Arc::new(SystemClock));
+
+        let mut buf = Vec::new();
+        crate::topology::write_node_updates(&replier_nodes, FULL_SYNC_HASH,
+            &mut buf).expect("write node updates");
+        assert!(buf.len() > crate::codec::MAX_FRAME_BYTES);
+
+        let (mut client, mut server) = MemoryStream::pair();
+        std::thread::scope(|scope| {
+            scope.spawn(|| replier.reply(&mut server).expect("reply"));
+            requester.request(5000, &mut client).expect("request");
+        });
+        assert_eq!(requester_nodes.len(), 5001);
+        assert_eq!(replier_nodes.len(), 5001);
+        assert_eq!(requester_nodes.get(4999).expect("get node")
+            .get_metadata("owner").map(|value| value.as_str()),
+            Some("owner-00000000000000000000000000004999"));
+    }
+
+    #[test]
+    fn cluster_remove_node() {
+        let mut sim: Simulation<Cluster> =
+            Simulation::new(Duration::from_millis(100));
+        for id in 0..4 {
+            let builder = ClusterBuilder::new()
+                .peer_selector(RoundRobinSelector::default());
+            sim.join(id, Some(0).filter(|_| id != 0), builder)
+                .expect("join");
+        }
+        sim.run_until(50, |sim| sim.is_converged()).expect("converge");
+
+        // removals spread from any member and stick while the node gossips
+        let topology = sim.topology(1).expect("topology");
+        assert!(topology.remove_node(1, Duration::from_secs(60)).is_err());
+        topology.remove_node(3, Duration::from_secs(60)).expect("remove");
+        let knows = |sim: &Simulation<Cluster>, id: u32| sim.nodes(id).iter()
+            .any(|node| node.get_id() == 3);
+        sim.run_until(50, |sim| (0..3).all(|id| !knows(sim, id)))
+            .expect("remove");
+        sim.run_until(20, |_| false);
+        assert!((0..3).all(|id| !knows(&sim, id)));
+        assert_eq!(sim.nodes(3).len(), 4);
+    }
+
+    #[test]
+    fn cluster_authorize_join() {
+        let mut sim: Simulation<Cluster> =
+            Simulation::new(Duration::from_millis(100));
+        for id in 0..3 {
+            let policy = MembershipPolicy::new()
+                .authorize_join(move |node| id != 0 || node.get_id() != 2);
+            let builder = ClusterBuilder::new().policy(policy)
+                .peer_selector(RoundRobinSelector::default());
+            sim.join(id, Some(0).filter(|_| id != 0), builder)
+                .expect("join");
+        }
+
+        // unauthorized nodes are refused directly and through peers
+        let knows = |sim: &Simulation<Cluster>, id: u32, peer_id: u32|
+            sim.nodes(id).iter().any(|node| node.get_id() == peer_id);
+        sim.run_until(50, |sim| knows(sim, 0, 1) && knows(sim, 1, 2))
+            .expect("converge");
+        sim.run_until(20, |_| false);
+        assert!(!knows(&sim, 0, 2));
+
+        let topology = sim.topology(0).expect("topology");
+        assert!(!topology.authorize_join(&sim.nodes(2)[2]));
+
+        // authorized ids are checked again when they move or restart
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let policy = MembershipPolicy::new()
+            .authorize_join(|node| node.get_address().port() == 15940);
+        let nodes = NodeMap::new();
+        let register = |port, incarnation| {
+            let mut node = Node::new(1, ip_address, port);
+            node.set_incarnation(incarnation);
+            crate::topology::register_node(&nodes, &SystemClock, &policy,
+                None, node);
+            nodes.get(1).map(|node|
+                (node.get_address().port(), node.get_incarnation()))
+        };
+        assert_eq!(register(15940, 1), Some((15940, 1)));
+        assert_eq!(register(15941, 2), Some((15940, 1)));
+        assert_eq!(register(15940, 3), Some((15940, 3)));
+    }
+
+    #[test]
+    fn cluster_roles() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for (id, roles) in [(0, vec!("frontend")), (1, vec!("storage")),
+                (2, vec!("frontend", "storage")), (3, vec!())] {
+            let mut node = Node::new(id, ip_address, 15900 + id as u16);
+            node.set_roles(roles).expect("set roles");
+            nodes.insert(node);
+        }
+
+        let cluster = ClusterBuilder::new()
+            .build(0, nodes, Arc::new(SystemClock));
+        let ids = |role| cluster.nodes_with_role(role).iter()
+            .map(|node| node.get_id()).collect::<Vec<_>>();
+        assert_eq!(ids("storage"), vec!(1, 2));
+        assert_eq!(ids("frontend"), vec!(0, 2));
+        assert!(ids("coordinator").is_empty());
     }
 }
@@ -1,203 +1,1051 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::node::Node;
-use crate::topology::{Topology, TopologyBuilder};
+use crate::codec;
+use crate::merkle::{self, MerkleTree};
+use crate::node::{Node, NodeMap, NodeState, PeerStats};
+use crate::ring::{DhtSnapshot, RangeMovement, RingHasher, RingOperation,
+    RingPlan, TokenChange, XxHasher};
+use crate::store::{StateStore, LOCAL_TOKENS_KEY, TOKENS_KEY};
+use crate::topology::{FullSync, Topology, TopologyBuilder};
+use crate::clock::Clock;
+use crate::topology::policy::MembershipPolicy;
+use crate::topology::quarantine::Quarantine;
+use crate::topology::selector::{PeerSelector, RandomSelector};
 
-use std::collections::{BTreeMap, HashMap};
-use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
-use std::hash::Hasher;
+use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const GOSSIP_MSG: u8 = 0;
+const EPOCH_MSG: u8 = 1;
+// token updates per message -> large rings stay within the frame limit
+const TOKEN_CHUNK: usize = 32 * 1024;
+
+type OwnershipHook = Box<dyn Fn(&[TokenChange]) + Send + Sync>;
+type RangeHook = Box<dyn Fn(&[RangeMovement]) + Send + Sync>;
+// tokens and their owners gossiped by a replier
+type TokenUpdates = Vec<(u64, u32)>;
 
 pub struct DhtBuilder {
+    flap_damping: Option<(Duration, Duration)>,
+    full_sync_interval: Option<Duration>,
+    hasher: Arc<dyn RingHasher>,
+    is_static: bool,
+    observer: bool,
+    policy: MembershipPolicy,
+    preload_nodes: Vec<Node>,
+    preload_tokens: BTreeMap<u64, u32>,
+    selector: Arc<dyn PeerSelector>,
+    token_store: Option<Arc<dyn StateStore>>,
     tokens: Vec<u64>,
+    vnodes: u32,
 }
 
 impl DhtBuilder {
     pub fn new(tokens: Vec<u64>) -> DhtBuilder {
-        DhtBuilder { tokens }
+        DhtBuilder {
+            flap_damping: None,
+            full_sync_interval: None,
+            hasher: Arc::new(XxHasher),
+            is_static: false,
+            observer: false,
+            policy: MembershipPolicy::new(),
+            preload_nodes: Vec::new(),
+            preload_tokens: BTreeMap::new(),
+            selector: Arc::new(RandomSelector),
+            token_store: None,
+            tokens,
+            vnodes: 0,
+        }
+    }
+
+    /// Registers a statically known membership list (and the tokens
+    /// they own) before the first gossip round.
+    pub fn preload_nodes(mut self, nodes: Vec<Node>,
+            tokens: BTreeMap<u64, u32>) -> DhtBuilder {
+        self.preload_nodes.extend(nodes);
+        self.preload_tokens.extend(tokens);
+        self
+    }
+
+    /// Quarantines nodes which rejoin with a new incarnation, starting
+    /// at `base_backoff` and doubling per flap up to `max_backoff`.
+    pub fn flap_damping(mut self, base_backoff: Duration,
+            max_backoff: Duration) -> DhtBuilder {
+        self.flap_damping = Some((base_backoff, max_backoff));
+        self
+    }
+
+    /// Pulls the full membership and ring of a peer once per
+    /// `interval`, see ClusterBuilder::full_sync_interval.
+    pub fn full_sync_interval(mut self, interval: Duration) -> DhtBuilder {
+        self.full_sync_interval = Some(interval);
+        self
+    }
+
+    /// Replaces the default xxHash used to hash keys and derive vnode
+    /// tokens, for matching an existing partitioning scheme.
+    pub fn hasher(mut self, hasher: impl RingHasher + 'static)
+            -> DhtBuilder {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    /// Learns the membership and ring through gossip without joining
+    /// them: members never register the local node and it owns no
+    /// tokens, whether configured, vnode or durable. See OBSERVER_KEY.
+    pub fn observer(mut self) -> DhtBuilder {
+        self.observer = true;
+        self
+    }
+
+    /// Applies `policy` at admission, eviction, and token placement
+    /// decisions.
+    pub fn policy(mut self, policy: MembershipPolicy) -> DhtBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Replaces the default random gossip peer selection.
+    pub fn peer_selector(mut self, selector: impl PeerSelector + 'static)
+            -> DhtBuilder {
+        self.selector = Arc::new(selector);
+        self
+    }
+
+    /// Persists the local token set to `store` when first assigned and
+    /// reuses it on later builds in place of the configured and vnode
+    /// tokens, so restarted nodes keep their ring position.
+    pub fn token_store(mut self, store: Arc<dyn StateStore>) -> DhtBuilder {
+        self.token_store = Some(store);
+        self
+    }
+
+    /// Fixes the ring to the local and preloaded nodes and tokens and
+    /// disables gossip entirely. Listeners still answer queries.
+    pub fn static_ring(mut self) -> DhtBuilder {
+        self.is_static = true;
+        self
+    }
+
+    /// Derives `count` tokens for the local node, one within each of
+    /// `count` equal segments of the ring at a position hashed from the
+    /// node id, in addition to any explicit tokens.
+    pub fn vnodes(mut self, count: u32) -> DhtBuilder {
+        self.vnodes = count;
+        self
     }
 }
 
 impl TopologyBuilder<Dht> for DhtBuilder {
-    fn build(&self, id: u32,
-            nodes: Arc<RwLock<HashMap<u32, Node>>>) -> Dht {
-        // initialize tokens
-        let mut tokens = BTreeMap::new();
-        for token in self.tokens.iter() {
+    fn build(&self, id: u32, nodes: Arc<NodeMap>, clock: Arc<dyn Clock>)
+            -> Dht {
+        // register preloaded nodes and tokens
+        let count = nodes.insert_all(self.preload_nodes.iter()
+            .filter(|node| node.get_id() != id).cloned());
+        debug!("preloaded nodes [count={}, tokens={}]",
+            count, self.preload_tokens.len());
+
+        // initialize tokens -> durable tokens replace configured ones
+        let mut local_tokens = self.tokens.clone();
+        local_tokens.extend(
+            vnode_tokens(self.hasher.as_ref(), id, self.vnodes));
+        if self.observer {
+            crate::topology::mark_observer(id, &nodes);
+            local_tokens.clear();
+        } else if let Some(ref store) = self.token_store {
+            match durable_tokens(store.as_ref(), &local_tokens) {
+                Ok(stored) => local_tokens = stored,
+                Err(e) => warn!("durable token failure [id={}]: {}", id, e),
+            }
+        }
+
+        let mut tokens = self.preload_tokens.clone();
+        for token in local_tokens.iter() {
             debug!("registering token [token={}, id={}]", token, id);
             tokens.insert(*token, id);
         }
 
         // initialize dht
-        Dht { tokens: Arc::new(RwLock::new(tokens)), nodes }
+        Dht {
+            acquired_hooks: RwLock::new(Vec::new()),
+            clock: clock.clone(),
+            epoch: AtomicU64::new(1),
+            full_sync: self.full_sync_interval.map(|interval|
+                FullSync::new(clock.clone(), interval)),
+            hasher: self.hasher.clone(),
+            id,
+            is_static: self.is_static,
+            nodes,
+            ownership_hooks: RwLock::new(Vec::new()),
+            policy: self.policy.clone(),
+            quarantine: self.flap_damping.map(|(base, max)|
+                Quarantine::new(clock.clone(), base, max)),
+            released_hooks: RwLock::new(Vec::new()),
+            selector: self.selector.clone(),
+            tokens: Arc::new(RwLock::new(tokens)),
+            tombstones: Mutex::new(BTreeMap::new()),
+        }
     }
 }
 
 pub struct Dht {
+    acquired_hooks: RwLock<Vec<RangeHook>>,
+    clock: Arc<dyn Clock>,
+    epoch: AtomicU64,
+    full_sync: Option<FullSync>,
+    hasher: Arc<dyn RingHasher>,
+    id: u32,
+    is_static: bool,
+    nodes: Arc<NodeMap>,
+    ownership_hooks: RwLock<Vec<OwnershipHook>>,
+    policy: MembershipPolicy,
+    quarantine: Option<Quarantine>,
+    released_hooks: RwLock<Vec<RangeHook>>,
+    selector: Arc<dyn PeerSelector>,
     tokens: Arc<RwLock<BTreeMap<u64, u32>>>,
-    nodes: Arc<RwLock<HashMap<u32, Node>>>,
+    tombstones: Mutex<BTreeMap<u64, u32>>,
 }
 
 impl Dht {
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Returns the owner of `token`, skipping owners which are draining
+    /// or fail the placement policy.
     pub fn locate(&self, token: u64) -> Option<Node> {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
         let tokens = self.tokens.read().unwrap();
+        tokens.range((Excluded(token), Unbounded))
+            .chain(tokens.range((Unbounded, Included(token))))
+            .filter_map(|(_, id)| self.nodes.get(*id))
+            .find(|node| !node.is_draining() && self.policy.places(node))
+    }
 
-        // find smallest token that is larger than search token
-        for (key, value) in tokens.iter() {
-            if token < *key {
-                let nodes = self.nodes.read().unwrap();
-                return Some(nodes.get(value).unwrap().clone());
+    /// Returns the owner of `key`, hashed with the ring's hasher.
+    pub fn locate_key(&self, key: &[u8]) -> Option<Node> {
+        self.locate(self.token(key))
+    }
+
+    /// Returns up to `count` distinct nodes responsible for `key`,
+    /// hashed with the ring's hasher. See Dht::locate_replicas.
+    pub fn locate_key_replicas(&self, key: &[u8], count: usize)
+            -> Vec<Node> {
+        self.locate_replicas(self.token(key), count)
+    }
+
+    /// Returns up to `count` distinct nodes responsible for `token`,
+    /// walking the ring from its owner and skipping further tokens of
+    /// nodes already chosen, draining, or failing the placement policy.
+    pub fn locate_replicas(&self, token: u64, count: usize) -> Vec<Node> {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
+        let tokens = self.tokens.read().unwrap();
+        let mut replicas: Vec<Node> = Vec::new();
+        for (_, id) in tokens.range((Excluded(token), Unbounded))
+                .chain(tokens.range((Unbounded, Included(token)))) {
+            if replicas.len() == count {
+                break;
+            } else if replicas.iter().any(|node| node.get_id() == *id) {
+                continue;
             }
-        }
 
-        // if there are tokens -> return lowest token
-        if !tokens.is_empty() {
-            let id = tokens.values().next().unwrap();
-            
-            let nodes = self.nodes.read().unwrap();
-            return Some(nodes.get(&id).unwrap().clone());
+            if let Some(node) = self.nodes.get(*id)
+                    .filter(|node| !node.is_draining()
+                        && self.policy.places(node)) {
+                replicas.push(node);
+            }
         }
 
-        None
+        replicas
+    }
+
+    /// Returns the distinct nodes owning the tokens adjacent to those
+    /// of node `id`, ordered by id.
+    pub fn neighbors(&self, id: u32) -> Vec<Node> {
+        let mut ids: Vec<u32> = self.tokens_of(id).into_iter()
+            .flat_map(|token| vec!(self.predecessor(token),
+                self.successor(token)))
+            .filter_map(|neighbor| neighbor.map(|(_, node)| node.get_id()))
+            .filter(|neighbor| *neighbor != id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter().filter_map(|id| self.nodes.get(id)).collect()
     }
 
     pub fn nodes(&self) -> Vec<Node> {
-        let nodes = self.nodes.read().unwrap();
-        nodes.values().cloned().collect()
+        self.nodes.nodes()
     }
-}
 
-impl Topology for Dht {
-    fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
-            -> Option<SocketAddr> {
-        let nodes = self.nodes.read().unwrap();
-        if nodes.len() > 1 {
-            // if more than local node is registered -> choose random
-            let mut index = rand::random::<usize>() % (nodes.len() - 1);
-            for (node_id, node) in nodes.iter() {
-                match (node_id, index) {
-                    (x, _) if x == &id => {},
-                    (_, 0) => return Some(node.get_address()),
-                    _ => index -= 1,
+    /// Returns the nodes matching `predicate` ordered by id, copying
+    /// only those.
+    pub fn nodes_where<F: Fn(&Node) -> bool>(&self, predicate: F)
+            -> Vec<Node> {
+        self.nodes.nodes_where(predicate)
+    }
+
+    /// Returns the nodes whose metadata `key` is `value`, see
+    /// Swarm::index_metadata.
+    pub fn nodes_with_metadata(&self, key: &str, value: &str) -> Vec<Node> {
+        self.nodes.nodes_with_metadata(key, value)
+    }
+
+    /// Returns the nodes which declared `role`, see Swarm::set_roles.
+    pub fn nodes_with_role(&self, role: &str) -> Vec<Node> {
+        crate::topology::nodes_with_role(&self.nodes, role)
+    }
+
+    /// Returns the closest token before `token`, wrapping around to the
+    /// highest token, and its owner.
+    pub fn predecessor(&self, token: u64) -> Option<(u64, Node)> {
+        let tokens = self.tokens.read().unwrap();
+        tokens.range(..token).next_back()
+            .or_else(|| tokens.iter().next_back())
+            .and_then(|(token, id)|
+                self.nodes.get(*id).map(|node| (*token, node)))
+    }
+
+    /// Returns the closest token after `token`, wrapping around to the
+    /// lowest token, and its owner.
+    pub fn successor(&self, token: u64) -> Option<(u64, Node)> {
+        use std::ops::Bound::{Excluded, Unbounded};
+        let tokens = self.tokens.read().unwrap();
+        tokens.range((Excluded(token), Unbounded)).next()
+            .or_else(|| tokens.iter().next())
+            .and_then(|(token, id)|
+                self.nodes.get(*id).map(|node| (*token, node)))
+    }
+
+    /// Returns the token of `key` under the ring's hasher.
+    pub fn token(&self, key: &[u8]) -> u64 {
+        self.hasher.hash(key)
+    }
+
+    /// Returns the number of tokens owned by each node.
+    pub fn token_counts(&self) -> BTreeMap<u32, usize> {
+        let mut counts = BTreeMap::new();
+        for id in self.tokens.read().unwrap().values() {
+            *counts.entry(*id).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    /// Returns the tokens owned by node `id` in ring order.
+    pub fn tokens_of(&self, id: u32) -> Vec<u64> {
+        self.tokens.read().unwrap().iter()
+            .filter(|(_, owner)| **owner == id)
+            .map(|(token, _)| *token).collect()
+    }
+
+    /// Registers `hook`, called with the previous and new owner of
+    /// every token added to, moved within, or removed from the ring by a
+    /// change. Hooks see tokens added (`from: None`), moved to the owner
+    /// of a newer ring, or dropped with their departed owner
+    /// (`to: None`).
+    pub fn on_ownership_change<F>(&self, hook: F)
+            where F: 'static + Fn(&[TokenChange]) + Send + Sync {
+        self.ownership_hooks.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Registers `hook`, called with the token ranges the local node
+    /// takes over whenever a ring change moves ranges to it.
+    pub fn on_range_acquired<F>(&self, hook: F)
+            where F: 'static + Fn(&[RangeMovement]) + Send + Sync {
+        self.acquired_hooks.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Registers `hook`, called with the token ranges the local node
+    /// hands over whenever a ring change moves ranges away from it.
+    pub fn on_range_released<F>(&self, hook: F)
+            where F: 'static + Fn(&[RangeMovement]) + Send + Sync {
+        self.released_hooks.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Returns the remaining quarantine of a flapping node, if any.
+    pub fn quarantined(&self, id: u32) -> Option<Duration> {
+        self.quarantine.as_ref().and_then(|quarantine| quarantine.remaining(id))
+    }
+
+    pub fn snapshot(&self) -> DhtSnapshot {
+        let nodes = self.nodes.nodes().into_iter()
+            .map(|node| (node.get_id(), node)).collect();
+        let tokens = self.tokens.read().unwrap();
+
+        DhtSnapshot {
+            epoch: self.epoch(),
+            nodes,
+            tokens: tokens.clone(),
+        }
+    }
+
+    /// Dry run of `operation` against the current ring. See
+    /// DhtSnapshot::plan.
+    pub fn plan(&self, operation: &RingOperation)
+            -> Result<RingPlan, Box<dyn Error>> {
+        self.snapshot().plan(operation)
+    }
+
+    /// Confirms with a quorum of the alive replicas of `token`, see
+    /// Dht::locate_replicas, that none has observed a newer ring epoch
+    /// than the local one, returning the confirmed epoch. Replicas are
+    /// queried in parallel and the barrier fails once `timeout` elapses
+    /// without a quorum. Callers use this before ownership decisions
+    /// which must not act on a stale ring.
+    pub fn read_barrier(&self, token: u64, replica_count: usize,
+            timeout: Duration) -> Result<u64, Box<dyn Error>> {
+        let epoch = self.epoch();
+        let replicas: Vec<Node> = self.locate_replicas(token, replica_count)
+            .into_iter().filter(|node| node.state() == NodeState::Alive)
+            .collect();
+        if replicas.is_empty() {
+            return Err(format!("no alive replicas [token={}]", token).into());
+        }
+
+        let quorum = replicas.len() / 2 + 1;
+        let mut confirmations = 0;
+        let (sender, receiver) = mpsc::channel();
+        for node in replicas {
+            // local node always confirms its own epoch
+            if node.get_id() == self.id {
+                confirmations += 1;
+                continue;
+            }
+
+            // queries end within their own timeouts -> late answers
+            // are dropped along with the receiver
+            let (nodes, sender) = (self.nodes.clone(), sender.clone());
+            std::thread::spawn(move || {
+                let address = node.get_address();
+                let result = query_epoch(&nodes, &address, timeout)
+                    .map_err(|e| e.to_string());
+                let _ = sender.send((address, result));
+            });
+        }
+        drop(sender);
+
+        let deadline = self.clock.now() + timeout;
+        while confirmations < quorum {
+            let remaining = deadline
+                .saturating_duration_since(self.clock.now());
+            let (address, result) = match receiver.recv_timeout(remaining) {
+                Ok(answer) => answer,
+                // deadline passed or every replica answered
+                Err(_) => break,
+            };
+
+            match result {
+                Ok(remote_epoch) if remote_epoch > epoch =>
+                    return Err(format!("stale ring epoch [local={}, remote={}]",
+                        epoch, remote_epoch).into()),
+                Ok(_) => confirmations += 1,
+                Err(e) => warn!("epoch query failure [address={}]: {}",
+                    address, e),
+            }
+        }
+
+        match confirmations >= quorum {
+            true => Ok(epoch),
+            false => Err(format!("epoch quorum unavailable [confirmations={}, quorum={}]",
+                confirmations, quorum).into()),
+        }
+    }
+
+    /// Drops the tokens of owners which were evicted, removed, or are
+    /// dead, advancing the epoch. Tombstones keep peers which have not
+    /// yet observed the departure from gossiping the tokens back until
+    /// the owner rejoins alive, and travel with token updates so peers
+    /// drop the tokens as well.
+    fn drop_departed_tokens(&self) {
+        // look up each owner once, outside the tokens lock
+        let owners: BTreeSet<u32> = self.tokens.read().unwrap().values()
+            .chain(self.tombstones.lock().unwrap().values())
+            .copied().collect();
+        let departed: BTreeSet<u32> = owners.into_iter()
+            .filter(|id| *id != self.id && self.nodes.get(*id)
+                .map(|node| node.state() == NodeState::Dead).unwrap_or(true))
+            .collect();
+
+        let previous = {
+            let mut tokens = self.tokens.write().unwrap();
+            let mut tombstones = self.tombstones.lock().unwrap();
+            tombstones.retain(|_, id| departed.contains(id));
+            if !tokens.values().any(|id| departed.contains(id)) {
+                return;
+            }
+
+            let previous = tokens.clone();
+            tokens.retain(|token, id| match departed.contains(id) {
+                true => {
+                    debug!("dropping departed token [token={}, id={}]",
+                        token, id);
+                    tombstones.insert(*token, *id);
+                    false
+                },
+                false => true,
+            });
+            previous
+        };
+
+        self.merge_epoch(self.epoch(), true);
+        self.notify_ring_change(&previous);
+    }
+
+    /// Merges the token updates and removals gossiped by a peer at ring
+    /// epoch `remote_epoch`. Updates fill in tokens missing from the
+    /// ring unless tombstoned, and move tokens to the peer's owner when
+    /// the peer's ring is newer, or as new at an equal epoch and the
+    /// peer's owner has the higher id. Removals drop tokens still held
+    /// by the removed owner, tombstoning them in turn.
+    fn merge_tokens(&self, updates: TokenUpdates, removals: TokenUpdates,
+            remote_epoch: u64) {
+        let epoch = self.epoch();
+        let previous = {
+            let mut tokens = self.tokens.write().unwrap();
+            let mut tombstones = self.tombstones.lock().unwrap();
+
+            // retain the ring before its first change for range hooks
+            let mut previous = None;
+            for (token, id) in removals {
+                // the local node never gives up its own tokens
+                if id == self.id || tokens.get(&token) != Some(&id) {
+                    continue;
+                }
+
+                previous.get_or_insert_with(|| tokens.clone());
+                debug!("dropping removed token [token={}, id={}, trace_id={}]",
+                    token, id, crate::trace::current());
+                tokens.remove(&token);
+                tombstones.insert(token, id);
+            }
+
+            for (token, id) in updates {
+                if tombstones.get(&token) == Some(&id) {
+                    continue;
+                }
+
+                match tokens.get(&token) {
+                    None => debug!("registering token [token={}, id={}, trace_id={}]",
+                        token, id, crate::trace::current()),
+                    // newer rings win, ties go to the higher owner id
+                    Some(owner) if (remote_epoch, id) > (epoch, *owner) =>
+                        debug!("moving token [token={}, from={}, to={}, trace_id={}]",
+                            token, owner, id, crate::trace::current()),
+                    Some(_) => continue,
                 }
+
+                previous.get_or_insert_with(|| tokens.clone());
+                tokens.insert(token, id);
+            }
+
+            previous
+        };
+
+        // merge ring epoch
+        self.merge_epoch(remote_epoch, previous.is_some());
+        if let Some(previous) = previous {
+            self.notify_ring_change(&previous);
+        }
+    }
+
+    /// Invokes ownership hooks with the token changes, and range hooks
+    /// with the local node's ranges moved, between the `previous` and
+    /// current rings.
+    fn notify_ring_change(&self, previous: &BTreeMap<u64, u32>) {
+        let tokens = self.tokens.read().unwrap().clone();
+        let plan = RingPlan::new(previous, tokens);
+        if !plan.changes.is_empty() {
+            for hook in self.ownership_hooks.read().unwrap().iter() {
+                hook(&plan.changes);
             }
-        } else if let Some(seed_address) = seed_address {
-            // if no other registered nodes -> return seed node
-            return Some(*seed_address);
         }
 
-        None
+        let (acquired, released): (Vec<RangeMovement>, Vec<RangeMovement>) =
+            plan.movements.into_iter()
+                .filter(|movement| movement.to == self.id
+                    || movement.from == self.id)
+                .partition(|movement| movement.to == self.id);
+
+        for (movements, hooks) in [(acquired, &self.acquired_hooks),
+                (released, &self.released_hooks)].iter() {
+            if movements.is_empty() {
+                continue;
+            }
+
+            debug!("ring ranges moved [id={}, count={}]",
+                self.id, movements.len());
+            for hook in hooks.read().unwrap().iter() {
+                hook(movements);
+            }
+        }
     }
 
-    fn request(&self, id: u32, stream: &mut TcpStream)
+    fn merge_epoch(&self, remote_epoch: u64, ring_changed: bool) {
+        let mut epoch = self.epoch();
+        loop {
+            // ring changes advance past both epochs like a lamport clock
+            let mut merged = std::cmp::max(epoch, remote_epoch);
+            if ring_changed {
+                merged += 1;
+            }
+
+            if merged == epoch {
+                return;
+            }
+
+            match self.epoch.compare_exchange(epoch, merged,
+                    Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    debug!("updated ring epoch [epoch={}]", merged);
+                    return;
+                },
+                Err(current) => epoch = current,
+            }
+        }
+    }
+}
+
+impl Topology for Dht {
+    fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
+            -> Option<SocketAddr> {
+        crate::topology::select_gossip_addr(id, &self.nodes,
+            self.selector.as_ref(), seed_address)
+    }
+
+    fn request<S: Read + Write>(&self, id: u32, stream: &mut S)
             -> Result<(), Box<dyn Error>> {
-        {
-            let nodes = self.nodes.read().unwrap();
+        // write local node, node hash and token digest
+        stream.write_u8(GOSSIP_MSG)?;
+        let node = self.nodes.get(id).unwrap();
+        let tree = {
             let tokens = self.tokens.read().unwrap();
+            MerkleTree::new(&tokens)
+        };
+
+        let full_sync = crate::topology::is_full_sync(id,
+            self.full_sync.as_ref());
+        crate::topology::write_message(stream, |buf| {
+            node.write(buf)?;
+            buf.write_u64::<BigEndian>(
+                crate::topology::member_hash(&self.nodes, full_sync))?;
+            buf.write_u64::<BigEndian>(match full_sync {
+                true => crate::topology::FULL_SYNC_HASH,
+                false => tree.root(),
+            })?;
+            buf.write_u64::<BigEndian>(self.epoch())?;
+            Ok(())
+        })?;
+
+        // process node updates
+        let evicted = crate::topology::read_node_updates(&self.nodes,
+            self.clock.as_ref(), &self.policy, self.quarantine.as_ref(),
+            stream)?;
+
+        // exchange confirmation timestamps
+        crate::topology::read_confirmations(&self.nodes, self.is_static, stream)?;
+        crate::topology::write_confirmations(id, &self.nodes,
+            self.clock.as_ref(), stream)?;
 
-            // write local node
-            let node = nodes.get(&id).unwrap();
-            node.write(stream)?;
+        // exchange tombstones of removed nodes
+        let removed = crate::topology::read_tombstones(id, &self.nodes,
+            self.is_static, stream)?;
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
 
-            // write node and token hashes
-            stream.write_u64::<BigEndian>(
-                crate::node::hash_nodes(nodes.values()))?;
+        // descend token digest and process token updates and removals
+        request_token_diff(&tree, full_sync, stream)?;
 
-            stream.write_u64::<BigEndian>(hash_tokens(&tokens))?;
+        let updates = read_token_updates(stream)?;
+        let removals = read_token_updates(stream)?;
+        let message = crate::topology::read_message(stream)?;
+        let remote_epoch = message.as_slice().read_u64::<BigEndian>()?;
+        self.merge_tokens(updates, removals, remote_epoch);
+
+        // members which just left the membership lose their tokens
+        if evicted + removed > 0 {
+            self.drop_departed_tokens();
         }
 
-        // process node updates
-        let node_updates = stream.read_u16::<BigEndian>()?;
-        for _ in 0..node_updates {
-            let node = Node::read(stream)?;
+        Ok(())
+    }
 
-            let mut nodes = self.nodes.write().unwrap();
-            if !nodes.contains_key(&node.get_id()) {
-                debug!("registering node [id={}, address={}]",
-                    node.get_id(), node.get_address());
-            }
-            nodes.insert(node.get_id(), node);
+    fn reply<S: Read + Write>(&self, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        match stream.read_u8()? {
+            GOSSIP_MSG => {},
+            EPOCH_MSG => return crate::topology::write_message(stream,
+                |buf| Ok(buf.write_u64::<BigEndian>(self.epoch())?)),
+            message_type => return Err(format!(
+                "unknown message type [type={}]", message_type).into()),
         }
 
-        // process token updates
-        let token_updates = stream.read_u16::<BigEndian>()?;
-        for _ in 0..token_updates {
-            let token = stream.read_u64::<BigEndian>()?;
-            let id = stream.read_u32::<BigEndian>()?;
+        // read request node, node hash, and token digest
+        let message = crate::topology::read_message(stream)?;
+        let reader = &mut message.as_slice();
+        let node = Node::read(reader)?;
+        let node_hash = reader.read_u64::<BigEndian>()?;
+        let token_root = reader.read_u64::<BigEndian>()?;
+        let remote_epoch = reader.read_u64::<BigEndian>()?;
 
-            let mut tokens = self.tokens.write().unwrap();
-            if !tokens.contains_key(&token) {
-                debug!("registering token [token={}, id={}]", token, id);
-                tokens.insert(token, id);
+        // the requester weighs token owners by the epoch of this ring
+        // -> sent as it was before merging the requester's
+        let epoch = self.epoch();
+        if !self.is_static {
+            self.merge_epoch(remote_epoch, false);
+        }
+
+        // write node updates
+        crate::topology::write_node_updates(&self.nodes,
+            node_hash, stream)?;
+
+        // exchange confirmation timestamps
+        crate::topology::write_confirmations(self.id, &self.nodes,
+            self.clock.as_ref(), stream)?;
+        crate::topology::read_confirmations(&self.nodes, self.is_static, stream)?;
+
+        // exchange tombstones of removed nodes
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
+        let removed = crate::topology::read_tombstones(self.id, &self.nodes,
+            self.is_static, stream)?;
+
+        // descend token digest to find differing segments
+        let tokens = self.tokens.read().unwrap().clone();
+        let tombstones = self.tombstones.lock().unwrap().clone();
+        let tree = MerkleTree::new(&tokens);
+        let leaves = reply_token_diff(&tree, token_root, stream)?;
+
+        // write token updates and removals for differing segments
+        let (mut updates, mut removals) = (Vec::new(), Vec::new());
+        for leaf in leaves {
+            let (start, end) = merkle::leaf_range(leaf);
+            updates.extend(tokens.range(start..=end));
+            removals.extend(tombstones.range(start..=end));
+        }
+
+        write_token_updates(&updates, stream)?;
+        write_token_updates(&removals, stream)?;
+        crate::topology::write_message(stream,
+            |buf| Ok(buf.write_u64::<BigEndian>(epoch)?))?;
+
+        // add gossiping node to nodes if does not exist
+        if !self.is_static {
+            let evicted = crate::topology::register_node(&self.nodes,
+                self.clock.as_ref(), &self.policy, self.quarantine.as_ref(),
+                node);
+
+            // members which just left the membership lose their tokens
+            if evicted || removed > 0 {
+                self.drop_departed_tokens();
             }
         }
 
         Ok(())
     }
 
-    fn reply(&self, stream: &mut TcpStream)
+    fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    fn ring_epoch(&self) -> Option<u64> {
+        Some(self.epoch())
+    }
+
+    fn ring_tokens(&self) -> Option<BTreeMap<u64, u32>> {
+        Some(self.tokens.read().unwrap().clone())
+    }
+
+    fn tick(&self) {
+        self.drop_departed_tokens();
+    }
+
+    fn authorize_join(&self, node: &Node) -> bool {
+        self.policy.authorizes(node)
+    }
+
+    fn member_counts(&self) -> (usize, usize) {
+        crate::topology::count_members(&self.nodes)
+    }
+
+    fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        self.nodes.peer_stats()
+    }
+
+    fn remove_node(&self, id: u32, ttl: Duration)
             -> Result<(), Box<dyn Error>> {
-        // read request node and node and token hashes
-        let node = Node::read(stream)?;
-        let node_hash = stream.read_u64::<BigEndian>()?;
-        let token_hash = stream.read_u64::<BigEndian>()?;
+        crate::topology::remove_node(self.id, &self.nodes,
+            self.clock.as_ref(), id, ttl)?;
+        self.drop_departed_tokens();
+        Ok(())
+    }
+
+    fn save(&self, store: &dyn StateStore) -> Result<(), Box<dyn Error>> {
+        let snapshot = DhtSnapshot {
+            epoch: self.epoch(),
+            nodes: HashMap::new(),
+            tokens: self.tokens.read().unwrap().clone(),
+        };
+
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf)?;
+        store.put(TOKENS_KEY, &buf)
+    }
 
+    fn load(&self, store: &dyn StateStore) -> Result<(), Box<dyn Error>> {
+        let snapshot = match store.get(TOKENS_KEY)? {
+            Some(buf) => DhtSnapshot::read(&mut buf.as_slice())?,
+            None => return Ok(()),
+        };
+
+        // configured tokens of the local node take precedence
+        let mut count = 0;
         {
-            // write node updates
-            let nodes = self.nodes.read().unwrap();
-            if node_hash != crate::node::hash_nodes(nodes.values()) {
-                stream.write_u16::<BigEndian>(nodes.len() as u16)?;
-                for node in nodes.values() {
-                    node.write(stream)?;
+            let mut tokens = self.tokens.write().unwrap();
+            for (token, id) in snapshot.tokens {
+                if id != self.id && !tokens.contains_key(&token) {
+                    tokens.insert(token, id);
+                    count += 1;
                 }
-            } else {
-                stream.write_u16::<BigEndian>(0)?;
             }
         }
 
-        {
-            // write token updates
-            let tokens = self.tokens.read().unwrap();
-            if token_hash != hash_tokens(&tokens) {
-                stream.write_u16::<BigEndian>(tokens.len() as u16)?;
-                for (token, id) in tokens.iter() {
-                    stream.write_u64::<BigEndian>(*token)?;
-                    stream.write_u32::<BigEndian>(*id)?;
-                }
-            } else {
-                stream.write_u16::<BigEndian>(0)?;
+        debug!("loaded tokens [count={}, epoch={}]", count, snapshot.epoch);
+        self.merge_epoch(snapshot.epoch, false);
+        Ok(())
+    }
+}
+
+/// Returns the local tokens stored in `store`, storing `tokens` if
+/// none were stored yet.
+fn durable_tokens(store: &dyn StateStore, tokens: &[u64])
+        -> Result<Vec<u64>, Box<dyn Error>> {
+    if let Some(buf) = store.get(LOCAL_TOKENS_KEY)? {
+        let mut reader = buf.as_slice();
+        let count = reader.read_u32::<BigEndian>()?;
+        return (0..count).map(|_| reader.read_u64::<BigEndian>()
+            .map_err(|e| e.into())).collect();
+    }
+
+    let mut buf = Vec::new();
+    buf.write_u32::<BigEndian>(tokens.len() as u32)?;
+    for token in tokens.iter() {
+        buf.write_u64::<BigEndian>(*token)?;
+    }
+
+    store.put(LOCAL_TOKENS_KEY, &buf)?;
+    Ok(tokens.to_vec())
+}
+
+/// Returns one token within each of `count` equal segments, hashed
+/// from `id` so restarted nodes derive the same tokens.
+fn vnode_tokens(hasher: &dyn RingHasher, id: u32, count: u32) -> Vec<u64> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let step = u64::MAX / count as u64;
+    (0..count as u64)
+        .map(|i| i * step + hasher.hash(format!("{}-{}", id, i).as_bytes())
+            % step)
+        .collect()
+}
+
+fn query_epoch(nodes: &NodeMap, address: &SocketAddr, timeout: Duration)
+        -> Result<u64, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // epoch queries are not tracked as peer exchanges
+    codec::write_cluster_header(&mut stream, rand::random::<u64>(),
+        crate::exchange::UNTRACKED_EXCHANGE, &nodes.get_cluster_name(),
+        nodes.get_cluster_epoch())?;
+    stream.write_u8(EPOCH_MSG)?;
+    let message = crate::topology::read_message(&mut stream)?;
+    Ok(message.as_slice().read_u64::<BigEndian>()?)
+}
+
+/// Reads token updates written by write_token_updates.
+fn read_token_updates(reader: &mut impl Read)
+        -> Result<TokenUpdates, Box<dyn Error>> {
+    let mut updates = Vec::new();
+    loop {
+        let message = crate::topology::read_message(reader)?;
+        let reader = &mut message.as_slice();
+        let count = reader.read_u32::<BigEndian>()? as usize;
+        if count > TOKEN_CHUNK {
+            return Err(format!("token update chunk too large [count={}]",
+                count).into());
+        }
+
+        for _ in 0..count {
+            updates.push((reader.read_u64::<BigEndian>()?,
+                reader.read_u32::<BigEndian>()?));
+        }
+
+        // a partial chunk is the last one
+        if count < TOKEN_CHUNK {
+            return Ok(updates);
+        }
+    }
+}
+
+/// Writes `updates` in messages of TOKEN_CHUNK tokens each, ending
+/// with a partial, possibly empty, message.
+fn write_token_updates(updates: &[(&u64, &u32)], writer: &mut impl Write)
+        -> Result<(), Box<dyn Error>> {
+    let mut chunks = updates.chunks(TOKEN_CHUNK);
+    loop {
+        let chunk = chunks.next().unwrap_or(&[]);
+        crate::topology::write_message(writer, |buf| {
+            buf.write_u32::<BigEndian>(chunk.len() as u32)?;
+            for (token, id) in chunk {
+                buf.write_u64::<BigEndian>(**token)?;
+                buf.write_u32::<BigEndian>(**id)?;
             }
+
+            Ok(())
+        })?;
+
+        if chunk.len() < TOKEN_CHUNK {
+            return Ok(());
         }
- 
-        {
-            // add gossiping node to nodes if does not exist
-            let mut nodes = self.nodes.write().unwrap();
-            if !nodes.contains_key(&node.get_id()) {
-                debug!("registering node [id={}, address={}]",
-                    node.get_id(), node.get_address());
+    }
+}
+
+/// Requester side of the digest descent: for each level compare the
+/// replier's child hashes against the local tree and answer with the
+/// indices which differ, or every index in full sync rounds.
+fn request_token_diff<S: Read + Write>(tree: &MerkleTree, full_sync: bool,
+        stream: &mut S) -> Result<(), Box<dyn Error>> {
+    for depth in 0..merkle::DEPTH {
+        let message = crate::topology::read_message(stream)?;
+        let reader = &mut message.as_slice();
+        let count = read_node_count(reader, depth)?;
+        if count == 0 {
+            break;
+        }
+
+        let mut differing = Vec::new();
+        for _ in 0..count {
+            let index = read_node_index(reader, depth)?;
+            let (left, right) = merkle::children(index);
+            // full syncs descend into every segment
+            if reader.read_u64::<BigEndian>()? != tree.hash(left)
+                    || full_sync {
+                differing.push(left);
+            }
+
+            if reader.read_u64::<BigEndian>()? != tree.hash(right)
+                    || full_sync {
+                differing.push(right);
             }
-            nodes.insert(node.get_id(), node);
         }
 
-        Ok(())
+        crate::topology::write_message(stream, |buf| {
+            buf.write_u16::<BigEndian>(differing.len() as u16)?;
+            for index in differing {
+                buf.write_u16::<BigEndian>(index as u16)?;
+            }
+
+            Ok(())
+        })?;
     }
+
+    Ok(())
 }
 
-fn hash_tokens(tokens: &BTreeMap<u64, u32>) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    for (token, id) in tokens.iter() {
-        hasher.write_u64(*token);
-        hasher.write_u32(*id);
+/// Replier side of the digest descent, returning the leaf indices
+/// whose token segments differ from the requester.
+fn reply_token_diff<S: Read + Write>(tree: &MerkleTree, root: u64,
+        stream: &mut S) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut pending = match root == crate::topology::FULL_SYNC_HASH
+            || root != tree.root() {
+        true => vec!(0),
+        false => Vec::new(),
+    };
+
+    for depth in 0..merkle::DEPTH {
+        // write child hashes of each differing node
+        crate::topology::write_message(stream, |buf| {
+            buf.write_u16::<BigEndian>(pending.len() as u16)?;
+            for index in pending.iter() {
+                let (left, right) = merkle::children(*index);
+                buf.write_u16::<BigEndian>(*index as u16)?;
+                buf.write_u64::<BigEndian>(tree.hash(left))?;
+                buf.write_u64::<BigEndian>(tree.hash(right))?;
+            }
+
+            Ok(())
+        })?;
+
+        if pending.is_empty() {
+            break;
+        }
+
+        // read differing children
+        let message = crate::topology::read_message(stream)?;
+        let reader = &mut message.as_slice();
+        let count = read_node_count(reader, depth + 1)?;
+        pending.clear();
+        for _ in 0..count {
+            pending.push(read_node_index(reader, depth + 1)?);
+        }
     }
 
-    hasher.finish()
+    Ok(pending)
+}
+
+/// Reads the number of digest nodes sent for the level `depth` below
+/// the root, rejecting counts above the level width.
+fn read_node_count(reader: &mut &[u8], depth: usize)
+        -> Result<usize, Box<dyn Error>> {
+    let count = reader.read_u16::<BigEndian>()? as usize;
+    if count > merkle::level(depth).len() {
+        return Err(format!(
+            "digest node count exceeds level width [count={}, depth={}]",
+            count, depth).into());
+    }
+
+    Ok(count)
+}
+
+/// Reads the index of a digest node at the level `depth` below the
+/// root, rejecting indices of any other level.
+fn read_node_index(reader: &mut &[u8], depth: usize)
+        -> Result<usize, Box<dyn Error>> {
+    let index = reader.read_u16::<BigEndian>()? as usize;
+    if !merkle::level(depth).contains(&index) {
+        return Err(format!(
+            "digest node index outside level [index={}, depth={}]",
+            index, depth).into());
+    }
+
+    Ok(index)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::prelude::{DhtBuilder, Swarm};
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use crate::clock::{wait_until, SystemClock};
+    use crate::merkle::{self, MerkleTree};
+    use crate::node::{NodeMap, NodeState, DRAINING_KEY};
+    use crate::prelude::{DhtBuilder, MemoryStore, Node, RangeMovement,
+        RingHasher, StateStore, Swarm, TokenChange, Topology};
+    use crate::topology::TopologyBuilder;
+    use crate::transport::MemoryStream;
+    use super::{reply_token_diff, request_token_diff, Dht};
+
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[test]
     fn dht_get() {
@@ -213,5 +1061,617 @@ mod tests {
         let result = dht.locate(15605);
         assert!(result.is_some());
         assert_eq!(result.unwrap().get_id(), 0);
+
+        let snapshot = dht.snapshot();
+        assert_eq!(snapshot.nodes.len(), 1);
+        assert_eq!(snapshot.tokens.len(), 3);
+    }
+
+    // builds the ring of node `id` from preloaded `tokens` and owners
+    fn ring(id: u32, tokens: &[(u64, u32)]) -> Dht {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let node = |id| Node::new(id, ip_address, 15600 + id as u16);
+        let nodes = Arc::new(NodeMap::new());
+        nodes.insert(node(id));
+        DhtBuilder::new(Vec::new())
+            .preload_nodes(tokens.iter().map(|(_, id)| node(*id)).collect(),
+                tokens.iter().copied().collect())
+            .build(id, nodes, Arc::new(SystemClock))
+    }
+
+    // gossips one round from `requester` to `replier`
+    fn exchange(requester: &Dht, replier: &Dht) {
+        let (mut client, mut server) = MemoryStream::pair();
+        std::thread::scope(|scope| {
+            scope.spawn(|| replier.reply(&mut server).expect("reply"));
+            requester.request(requester.id, &mut client).expect("request");
+        });
+    }
+
+    #[test]
+    fn dht_locate_key() {
+        let dht = ring(0, &[(1 << 62, 0), (1 << 63, 1), (3 << 62, 2)]);
+        let locate = |key: &[u8]| {
+            let (token, owner) = dht.successor(dht.token(key))
+                .expect("successor");
+            let node = dht.locate_key(key).expect("locate key");
+            assert_eq!(node.get_id(), owner.get_id());
+            (dht.token(key), token, node.get_id())
+        };
+
+        // keys belong to the first token past their hash
+        assert_eq!(locate(b"user:5"), (116517794710607256, 1 << 62, 0));
+        assert_eq!(locate(b"user:6"), (6562785817488704643, 1 << 63, 1));
+        assert_eq!(locate(b"user:3"), (11651512469413158329, 3 << 62, 2));
+
+        // hashes past the last token wrap around to the first
+        assert_eq!(locate(b"user:42"), (15861654238046376386, 1 << 62, 0));
+        assert!(ring(0, &[]).locate_key(b"user:42").is_none());
+    }
+
+    #[test]
+    fn dht_ownership_hooks() {
+        let dhts: Vec<Dht> = (0..3).map(|id| ring(id, &[(id as u64, id)]))
+            .collect();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let hook_changes = changes.clone();
+        dhts[0].on_ownership_change(move |token_changes|
+            hook_changes.lock().unwrap().extend_from_slice(token_changes));
+        let take = || std::mem::take(&mut *changes.lock().unwrap());
+
+        // gossiped tokens are added
+        exchange(&dhts[0], &dhts[1]);
+        exchange(&dhts[0], &dhts[2]);
+        assert_eq!(take(), vec!(
+            TokenChange { from: None, to: Some(1), token: 1 },
+            TokenChange { from: None, to: Some(2), token: 2 }));
+
+        // claims of older rings on known tokens never change their owner
+        let rival = ring(3, &[(1, 3), (3, 3)]);
+        exchange(&dhts[0], &rival);
+        assert_eq!(take(), vec!(
+            TokenChange { from: None, to: Some(3), token: 3 }));
+        assert_eq!(dhts[0].tokens_of(1), vec!(1));
+
+        // while newer rings move tokens to their owner
+        let newer = ring(4, &[(1, 4)]);
+        newer.merge_epoch(dhts[0].epoch() + 1, false);
+        exchange(&dhts[0], &newer);
+        assert_eq!(take(), vec!(
+            TokenChange { from: Some(1), to: Some(4), token: 1 }));
+        assert!(dhts[0].tokens_of(1).is_empty());
+        assert_eq!(dhts[0].tokens_of(4), vec!(1));
+
+        // dead and departed owners lose their tokens
+        dhts[0].nodes.update(4, |node| node.set_state(NodeState::Dead));
+        dhts[0].nodes.remove(2);
+        dhts[0].tick();
+        assert_eq!(take(), vec!(
+            TokenChange { from: Some(4), to: None, token: 1 },
+            TokenChange { from: Some(2), to: None, token: 2 }));
+        assert_eq!(dhts[0].tokens_of(3), vec!(3));
+    }
+
+    #[test]
+    fn dht_token_removals() {
+        let local = ring(0, &[(0, 0), (1, 1), (2, 2)]);
+        let peer = ring(3, &[(1, 1), (2, 2), (3, 3)]);
+
+        // removed members lose their tokens without waiting for a tick
+        local.remove_node(2, Duration::from_secs(60)).expect("remove node");
+        assert!(local.tokens_of(2).is_empty());
+        local.nodes.remove(1);
+        local.tick();
+        assert!(local.tokens_of(1).is_empty());
+
+        // removals travel with token updates to peers still holding them
+        let epoch = peer.epoch();
+        exchange(&peer, &local);
+        assert!(peer.tokens_of(1).is_empty());
+        assert!(peer.tokens_of(2).is_empty());
+        assert_eq!(peer.tokens_of(0), vec!(0));
+        assert_eq!(peer.tokens_of(3), vec!(3));
+        assert!(peer.epoch() > epoch);
+
+        // and never take the tokens of the requester itself
+        let owner = ring(1, &[(1, 1)]);
+        exchange(&owner, &local);
+        assert_eq!(owner.tokens_of(1), vec!(1));
+    }
+
+    #[test]
+    fn dht_large_token_exchange() {
+        // more token updates than a u16 count or one message holds
+        let count = u16::MAX as u64 + 5000;
+        let step = u64::MAX / count;
+        let tokens: Vec<(u64, u32)> = (0..count).map(|i| (i * step, 1))
+            .collect();
+        let replier = ring(1, &tokens);
+        let requester = ring(0, &[]);
+
+        exchange(&requester, &replier);
+        assert_eq!(requester.tokens_of(1).len(), count as usize);
+        // the epoch following every chunk is read intact
+        assert_eq!(requester.epoch(), replier.epoch() + 1);
+    }
+
+    #[test]
+    fn dht_malformed_token_diff() {
+        let mut tokens = BTreeMap::new();
+        tokens.insert(1 << 62, 0);
+        let tree = MerkleTree::new(&tokens);
+
+        // descent messages, the requester's carrying child hashes
+        let message = |indices: &[usize], hashes: bool| {
+            let mut buf = Vec::new();
+            buf.write_u16::<BigEndian>(indices.len() as u16).unwrap();
+            for index in indices {
+                buf.write_u16::<BigEndian>(*index as u16).unwrap();
+                if hashes {
+                    buf.write_u64::<BigEndian>(0).unwrap();
+                    buf.write_u64::<BigEndian>(0).unwrap();
+                }
+            }
+
+            buf
+        };
+
+        // drives one side with `messages`, returning its result
+        let diff = |messages: Vec<Vec<u8>>, request: bool| {
+            let (mut client, mut server) = MemoryStream::pair();
+            server.set_read_timeout(Some(Duration::from_millis(100)));
+            for message in messages {
+                crate::topology::write_message(&mut client, |buf| {
+                    buf.extend(message);
+                    Ok(())
+                }).expect("write");
+            }
+
+            match request {
+                true => request_token_diff(&tree, false, &mut server)
+                    .map(|_| Vec::new()),
+                false => reply_token_diff(&tree,
+                    crate::topology::FULL_SYNC_HASH, &mut server),
+            }
+        };
+
+        let rejects = |messages: Vec<Vec<u8>>, request: bool| {
+            match diff(messages, request) {
+                Ok(_) => false,
+                Err(e) => e.to_string().starts_with("digest node"),
+            }
+        };
+
+        // the leftmost path down to `depth`, then `indices`
+        let path = |depth: usize, indices: &[usize], request: bool| {
+            let first = match request {
+                true => 0,
+                false => 1,
+            };
+
+            let mut messages: Vec<Vec<u8>> = (first..depth)
+                .map(|d| message(&[merkle::level(d).start], request))
+                .collect();
+            messages.push(message(indices, request));
+            messages
+        };
+
+        // well-formed descents reach the leaves
+        let leaf = merkle::level(merkle::DEPTH).start;
+        assert!(diff(path(merkle::DEPTH - 1,
+            &[merkle::level(merkle::DEPTH - 1).start], true), true).is_ok());
+        assert_eq!(diff(path(merkle::DEPTH, &[leaf], false), false)
+            .expect("reply"), vec!(leaf));
+
+        for request in [true, false] {
+            let first = match request {
+                true => 0,
+                false => 1,
+            };
+
+            for depth in first..first + merkle::DEPTH {
+                // indices outside the level are rejected
+                let level = merkle::level(depth);
+                let mut indices = vec!(level.end, 2 * leaf + 1,
+                    u16::MAX as usize);
+                if level.start > 0 {
+                    indices.push(level.start - 1);
+                }
+
+                for index in indices {
+                    assert!(rejects(path(depth, &[index], request), request),
+                        "index {} at depth {}", index, depth);
+                }
+
+                // counts above the level width are rejected
+                let indices = vec!(level.start; level.len() + 1);
+                assert!(rejects(path(depth, &indices, request), request));
+            }
+        }
+    }
+
+    #[test]
+    fn dht_ring_navigation() {
+        let adjacent = |neighbor: Option<(u64, Node)>| neighbor
+            .map(|(token, node)| (token, node.get_id()));
+        let neighbors = |dht: &Dht, id| dht.neighbors(id).iter()
+            .map(|node| node.get_id()).collect::<Vec<u32>>();
+
+        // both ends of the ring wrap around
+        let dht = ring(0, &[(100, 0), (200, 1), (300, 2)]);
+        assert_eq!(adjacent(dht.successor(150)), Some((200, 1)));
+        assert_eq!(adjacent(dht.successor(300)), Some((100, 0)));
+        assert_eq!(adjacent(dht.successor(u64::MAX)), Some((100, 0)));
+        assert_eq!(adjacent(dht.successor(0)), Some((100, 0)));
+        assert_eq!(adjacent(dht.predecessor(250)), Some((200, 1)));
+        assert_eq!(adjacent(dht.predecessor(100)), Some((300, 2)));
+        assert_eq!(adjacent(dht.predecessor(0)), Some((300, 2)));
+        assert_eq!(adjacent(dht.predecessor(u64::MAX)), Some((300, 2)));
+        assert_eq!(neighbors(&dht, 0), vec!(1, 2));
+        assert_eq!(neighbors(&dht, 2), vec!(0, 1));
+
+        // a single token is its own successor and predecessor
+        let dht = ring(0, &[(100, 0)]);
+        for token in [0, 100, u64::MAX] {
+            assert_eq!(adjacent(dht.successor(token)), Some((100, 0)));
+            assert_eq!(adjacent(dht.predecessor(token)), Some((100, 0)));
+        }
+        assert!(neighbors(&dht, 0).is_empty());
+
+        let dht = ring(0, &[]);
+        assert!(dht.successor(100).is_none() && dht.predecessor(100).is_none());
+        assert!(neighbors(&dht, 0).is_empty());
+    }
+
+    #[test]
+    fn dht_preload_nodes() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut tokens = BTreeMap::new();
+        tokens.insert(100, 1);
+        tokens.insert(200, 2);
+
+        let dht_builder = DhtBuilder::new(vec!(0))
+            .preload_nodes(vec!(Node::new(1, ip_address, 15101),
+                Node::new(2, ip_address, 15102)), tokens);
+        let (_swarm, dht) =
+            Swarm::new(0, ip_address, 15100, None, dht_builder);
+
+        assert_eq!(dht.nodes().len(), 3);
+        assert_eq!(dht.locate(150).expect("locate").get_id(), 2);
+        assert_eq!(dht.locate(250).expect("locate").get_id(), 0);
+    }
+
+    #[test]
+    fn dht_durable_tokens() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStore::new());
+        let dht = DhtBuilder::new(vec!(5, 9)).token_store(store.clone())
+            .build(0, Arc::new(NodeMap::new()), Arc::new(SystemClock));
+        assert_eq!(dht.tokens_of(0), vec!(5, 9));
+
+        // rebuilt rings keep the first assignment
+        let dht = DhtBuilder::new(vec!(7)).vnodes(4).token_store(store)
+            .build(0, Arc::new(NodeMap::new()), Arc::new(SystemClock));
+        assert_eq!(dht.tokens_of(0), vec!(5, 9));
+    }
+
+    #[test]
+    fn dht_observer() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Dht>> = Vec::new();
+        let mut dhts = Vec::new();
+        for i in 0..3 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let dht_builder = match i {
+                2 => DhtBuilder::new(vec!(2)).observer(),
+                i => DhtBuilder::new(vec!(i as u64)),
+            };
+
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, dht_builder);
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        // observers learn the ring -> members never learn the observer
+        let ring: BTreeMap<u64, u32> = vec!((0, 0), (1, 1)).into_iter().collect();
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            dhts[2].nodes().len() == 3 && dhts.iter().all(|dht|
+                dht.ring_tokens() == Some(ring.clone())
+                    && dht.member_counts() == (2, 2))),
+            "ring convergence");
+        for dht in dhts.iter() {
+            assert_eq!(dht.ring_tokens(), Some(ring.clone()));
+            assert_eq!(dht.member_counts(), (2, 2));
+        }
+
+        assert_eq!(dhts[0].nodes().len(), 2);
+        assert_eq!(dhts[1].nodes().len(), 2);
+        assert_eq!(dhts[2].nodes().len(), 3);
+        assert_eq!(dhts[2].locate(2).expect("locate").get_id(), 0);
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn dht_quorum() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..4 {
+            nodes.insert(Node::new(id, ip_address, 15800 + id as u16));
+        }
+
+        let dht = DhtBuilder::new(vec!(0))
+            .build(0, nodes.clone(), Arc::new(SystemClock));
+        assert_eq!(dht.member_counts(), (4, 4));
+        assert_eq!(dht.quorum(), 3);
+        assert!(dht.has_quorum());
+
+        // suspect and dead members count against quorum
+        nodes.update(1, |node| node.set_state(NodeState::Suspect));
+        assert!(dht.has_quorum());
+        nodes.update(2, |node| node.set_state(NodeState::Dead));
+        assert_eq!(dht.member_counts(), (4, 2));
+        assert!(!dht.has_quorum());
+    }
+
+    #[test]
+    fn dht_read_barrier_replicas() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        nodes.insert(Node::new(0, ip_address, 15810));
+        let mut tokens = BTreeMap::new();
+        for id in 1..3 {
+            nodes.insert(Node::new(id, ip_address, 15810 + id as u16));
+            tokens.insert(id as u64 * 100, id);
+        }
+
+        let dht = DhtBuilder::new(vec!(0))
+            .preload_nodes(Vec::new(), tokens)
+            .build(0, nodes.clone(), Arc::new(SystemClock));
+
+        // unreachable alive replicas withhold the quorum
+        let timeout = Duration::from_millis(200);
+        assert!(dht.read_barrier(50, 3, timeout).is_err());
+
+        // dead replicas leave the quorum to the local node
+        nodes.update(1, |node| node.set_state(NodeState::Dead));
+        nodes.update(2, |node| node.set_state(NodeState::Dead));
+        assert_eq!(dht.read_barrier(50, 3, timeout).expect("read barrier"),
+            dht.epoch());
+        assert!(dht.read_barrier(50, 0, timeout).is_err());
+    }
+
+    #[test]
+    fn dht_replicas() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut tokens = BTreeMap::new();
+        tokens.insert(100, 1);
+        tokens.insert(150, 1);
+        tokens.insert(200, 2);
+
+        let dht_builder = DhtBuilder::new(vec!(0, 300))
+            .preload_nodes(vec!(Node::new(1, ip_address, 15901),
+                Node::new(2, ip_address, 15902)), tokens);
+        let (mut swarm, dht) =
+            Swarm::new(0, ip_address, 15900, None, dht_builder);
+
+        // duplicate vnode owners are skipped
+        let ids = |token, count| dht.locate_replicas(token, count).iter()
+            .map(|node| node.get_id()).collect::<Vec<u32>>();
+        assert_eq!(ids(50, 2), vec!(1, 2));
+        assert_eq!(ids(250, 3), vec!(0, 1, 2));
+        assert_eq!(ids(250, 5), vec!(0, 1, 2));
+        assert_eq!(ids(250, 0), Vec::<u32>::new());
+
+        // ring neighbors wrap around
+        let adjacent = |neighbor: Option<(u64, Node)>| neighbor
+            .map(|(token, node)| (token, node.get_id()));
+        assert_eq!(adjacent(dht.successor(50)), Some((100, 1)));
+        assert_eq!(adjacent(dht.successor(300)), Some((0, 0)));
+        assert_eq!(adjacent(dht.predecessor(100)), Some((0, 0)));
+        assert_eq!(adjacent(dht.predecessor(0)), Some((300, 0)));
+        let neighbors = |id| dht.neighbors(id).iter()
+            .map(|node| node.get_id()).collect::<Vec<u32>>();
+        assert_eq!(neighbors(1), vec!(0, 2));
+        assert_eq!(neighbors(2), vec!(0, 1));
+
+        // keys route by their hashed token
+        let token = dht.token(b"user:42");
+        assert_eq!(dht.locate_key(b"user:42").map(|node| node.get_id()),
+            dht.locate(token).map(|node| node.get_id()));
+        assert_eq!(dht.locate_key_replicas(b"user:42", 3).len(), 3);
+
+        // draining owners are skipped
+        swarm.set_metadata(DRAINING_KEY, "true");
+        assert_eq!(ids(250, 3), vec!(1, 2));
+        assert_eq!(dht.locate(250).map(|node| node.get_id()), Some(1));
+    }
+
+    #[test]
+    fn dht_vnodes() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut tokens = BTreeMap::new();
+        tokens.insert(100, 1);
+
+        let dht_builder = DhtBuilder::new(vec!(7)).vnodes(256)
+            .preload_nodes(vec!(Node::new(1, ip_address, 15701)), tokens);
+        let (_swarm, dht) =
+            Swarm::new(0, ip_address, 15700, None, dht_builder);
+
+        // one derived token per segment plus the explicit token
+        let local_tokens = dht.tokens_of(0);
+        assert_eq!(local_tokens.len(), 257);
+        let step = u64::MAX / 256;
+        let derived = local_tokens.iter().filter(|token| **token != 7);
+        for (i, token) in derived.enumerate() {
+            assert_eq!(token / step, i as u64);
+        }
+
+        assert_eq!(dht.tokens_of(1), vec!(100));
+        assert_eq!(dht.token_counts().get(&0), Some(&257));
+
+        // derived tokens follow the configured hasher
+        let (_swarm, hashed) = Swarm::new(0, ip_address, 15700, None,
+            DhtBuilder::new(Vec::new()).vnodes(4).hasher(FixedHasher));
+        assert_eq!(hashed.tokens_of(0), (0..4).map(|i| i * (u64::MAX / 4) + 9)
+            .collect::<Vec<u64>>());
+        assert_eq!(hashed.token(b"key"), 9);
+        assert_ne!(dht.token(b"key"), 9);
+        assert_eq!(hashed.locate_key(b"key").expect("locate").get_id(), 0);
+    }
+
+    struct FixedHasher;
+
+    impl RingHasher for FixedHasher {
+        fn hash(&self, _key: &[u8]) -> u64 {
+            9
+        }
+    }
+
+    #[test]
+    fn dht_range_hooks() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Dht>> = Vec::new();
+        let mut dhts = Vec::new();
+        let moved = Arc::new(Mutex::new(Vec::new()));
+        for (i, token) in [0, 1 << 63].iter().enumerate() {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, DhtBuilder::new(vec!(*token)));
+            for released in [false, true] {
+                let moved = moved.clone();
+                let hook = move |movements: &[RangeMovement]| moved.lock()
+                    .unwrap().extend(movements.iter()
+                        .map(|movement| (i, released, movement.clone())));
+                match released {
+                    true => dht.on_range_released(hook),
+                    false => dht.on_range_acquired(hook),
+                }
+            }
+
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            moved.lock().unwrap().len() == 2), "range movements");
+        assert_eq!(dhts[0].snapshot().tokens.len(), 2);
+
+        // each member hands the range below the other's token over
+        let mut moved = moved.lock().unwrap().clone();
+        moved.sort_by_key(|(i, released, _)| (*i, *released));
+        assert_eq!(moved, vec!(
+            (0, true, RangeMovement { end: 1 << 63, from: 0, start: 0, to: 1 }),
+            (1, true, RangeMovement { end: 0, from: 1, start: 1 << 63, to: 0 })));
+
+        for swarm in swarms.iter_mut() {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+
+    #[test]
+    fn dht_departed_tokens() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<Dht>> = Vec::new();
+        let mut dhts = Vec::new();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        for (i, token) in [0, 1 << 63].iter().enumerate() {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, DhtBuilder::new(vec!(*token)));
+            if i == 0 {
+                let changes = changes.clone();
+                dht.on_ownership_change(move |token_changes|
+                    changes.lock().unwrap().extend_from_slice(token_changes));
+            }
+
+            swarm.set_failure_timeouts(Duration::from_millis(100),
+                Duration::from_millis(100));
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            dhts[0].tokens_of(1) == vec!(1 << 63)), "token gossip");
+        let epoch = dhts[0].epoch();
+
+        // dead owners lose their ranges to the survivors
+        swarms[1].stop().expect("swarm stop");
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            dhts[0].tokens_of(1).is_empty()), "token removal");
+        assert_eq!(dhts[0].locate(5).expect("locate").get_id(), 0);
+        assert!(dhts[0].epoch() > epoch);
+        assert_eq!(*changes.lock().unwrap(), vec!(
+            TokenChange { from: None, to: Some(1), token: 1 << 63 },
+            TokenChange { from: Some(1), to: None, token: 1 << 63 }));
+
+        swarms[0].stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn dht_static_ring() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut tokens = BTreeMap::new();
+        tokens.insert(100, 1);
+
+        let dht_builder = DhtBuilder::new(vec!(0))
+            .preload_nodes(vec!(Node::new(1, ip_address, 15201)), tokens)
+            .static_ring();
+        let (mut swarm, dht) =
+            Swarm::new(0, ip_address, 15200, None, dht_builder);
+
+        // start listeners only -> gossiper is disabled
+        swarm.start(1, 50, 75).expect("swarm start");
+        let timeout = Duration::from_millis(500);
+        assert!(dht.read_barrier(50, 1, timeout).is_err());
+        assert_eq!(dht.locate(50).expect("locate").get_id(), 1);
+
+        swarm.stop().expect("swarm stop");
+    }
+
+    #[test]
+    fn dht_read_barrier() {
+        let swarm_count = 3;
+
+        // start multiple swarm instances
+        let mut swarms: Vec<Swarm<Dht>> = Vec::new();
+        let mut dhts = Vec::new();
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        for i in 0..swarm_count {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let dht_builder = DhtBuilder::new(vec!(i as u64 * 1000));
+            let (mut swarm, dht) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, dht_builder);
+
+            swarm.start(2, 50, 75).expect("swarm start");
+            swarms.push(swarm);
+            dhts.push(dht);
+        }
+
+        // wait for ring convergence
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            dhts.iter().all(|dht| dht.snapshot().tokens.len() == swarm_count
+                && dht.epoch() == dhts[0].epoch())), "ring convergence");
+
+        let timeout = Duration::from_millis(500);
+        for dht in dhts.iter() {
+            let epoch = dht.read_barrier(0, swarm_count, timeout)
+                .expect("read barrier");
+            assert_eq!(epoch, dhts[0].epoch());
+        }
+
+        // stop swarms
+        for swarm in swarms.iter_mut() {
+            swarm.stop().expect("swarm stop");
+        }
     }
 }
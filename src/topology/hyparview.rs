@@ -0,0 +1,445 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::clock::Clock;
+use crate::node::{Node, NodeMap, NodeState, PeerStats};
+use crate::topology::{Topology, TopologyBuilder};
+use crate::topology::policy::MembershipPolicy;
+use crate::topology::quarantine::Quarantine;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_ACTIVE_SIZE: usize = 5;
+const DEFAULT_PASSIVE_SIZE: usize = 30;
+const DEFAULT_SHUFFLE_LENGTH: usize = 8;
+
+// exchange kinds -> decide whether the replier admits the requester
+const JOIN: u8 = 0;
+const PROMOTE: u8 = 1;
+const SHUFFLE: u8 = 2;
+
+pub struct HyParViewBuilder {
+    active_size: usize,
+    flap_damping: Option<(Duration, Duration)>,
+    observer: bool,
+    passive_size: usize,
+    policy: MembershipPolicy,
+    shuffle_length: usize,
+}
+
+impl Default for HyParViewBuilder {
+    fn default() -> HyParViewBuilder {
+        HyParViewBuilder {
+            active_size: DEFAULT_ACTIVE_SIZE,
+            flap_damping: None,
+            observer: false,
+            passive_size: DEFAULT_PASSIVE_SIZE,
+            policy: MembershipPolicy::new(),
+            shuffle_length: DEFAULT_SHUFFLE_LENGTH,
+        }
+    }
+}
+
+impl HyParViewBuilder {
+    pub fn new() -> HyParViewBuilder {
+        HyParViewBuilder::default()
+    }
+
+    /// Number of members gossiped with directly, 5 by default.
+    pub fn active_size(mut self, active_size: usize) -> HyParViewBuilder {
+        self.active_size = std::cmp::max(active_size, 1);
+        self
+    }
+
+    /// Quarantines nodes which rejoin with a new incarnation, starting
+    /// at `base_backoff` and doubling per flap up to `max_backoff`.
+    pub fn flap_damping(mut self, base_backoff: Duration,
+            max_backoff: Duration) -> HyParViewBuilder {
+        self.flap_damping = Some((base_backoff, max_backoff));
+        self
+    }
+
+    /// Learns members through gossip without joining: peers never
+    /// register the local node or admit it to their views, so it only
+    /// keeps a passive view. See OBSERVER_KEY.
+    pub fn observer(mut self) -> HyParViewBuilder {
+        self.observer = true;
+        self
+    }
+
+    /// Number of backup members known for replacing failed active
+    /// members, 30 by default.
+    pub fn passive_size(mut self, passive_size: usize) -> HyParViewBuilder {
+        self.passive_size = passive_size;
+        self
+    }
+
+    /// Applies `policy` at admission and eviction decisions.
+    pub fn policy(mut self, policy: MembershipPolicy) -> HyParViewBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Number of members sampled into each exchange, 8 by default.
+    pub fn shuffle_length(mut self, shuffle_length: usize)
+            -> HyParViewBuilder {
+        self.shuffle_length = shuffle_length;
+        self
+    }
+}
+
+impl TopologyBuilder<HyParView> for HyParViewBuilder {
+    fn build(&self, id: u32, nodes: Arc<NodeMap>, clock: Arc<dyn Clock>)
+            -> HyParView {
+        if self.observer {
+            crate::topology::mark_observer(id, &nodes);
+        }
+
+        HyParView {
+            active_size: self.active_size,
+            clock: clock.clone(),
+            id,
+            nodes,
+            passive_size: self.passive_size,
+            policy: self.policy.clone(),
+            quarantine: self.flap_damping.map(|(base, max)|
+                Quarantine::new(clock.clone(), base, max)),
+            shuffle_length: self.shuffle_length,
+            views: Mutex::new(Views {
+                active: Vec::new(),
+                passive: Vec::new(),
+                target: None,
+            }),
+        }
+    }
+}
+
+struct Views {
+    active: Vec<u32>,
+    passive: Vec<u32>,
+    // peer chosen by the last gossip_addr call
+    target: Option<u32>,
+}
+
+impl Views {
+    fn contains(&self, id: u32) -> bool {
+        self.active.contains(&id) || self.passive.contains(&id)
+    }
+}
+
+/// Partial view membership after HyParView. Each member knows a small
+/// symmetric active view it gossips with and a larger passive view of
+/// backups, so memory and gossip cost stay bounded as the swarm grows.
+/// Members join through the seed, promote passive members while their
+/// active view has room, and otherwise shuffle random samples of their
+/// views with active members. Only local, active and passive members
+/// are kept in the node map.
+pub struct HyParView {
+    active_size: usize,
+    clock: Arc<dyn Clock>,
+    id: u32,
+    nodes: Arc<NodeMap>,
+    passive_size: usize,
+    policy: MembershipPolicy,
+    quarantine: Option<Quarantine>,
+    shuffle_length: usize,
+    views: Mutex<Views>,
+}
+
+impl HyParView {
+    pub fn active_view(&self) -> Vec<Node> {
+        let views = self.views.lock().unwrap();
+        views.active.iter().filter_map(|id| self.nodes.get(*id)).collect()
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.nodes.nodes()
+    }
+
+    /// Returns the nodes matching `predicate` ordered by id, copying
+    /// only those.
+    pub fn nodes_where<F: Fn(&Node) -> bool>(&self, predicate: F)
+            -> Vec<Node> {
+        self.nodes.nodes_where(predicate)
+    }
+
+    /// Returns the nodes whose metadata `key` is `value`, see
+    /// Swarm::index_metadata.
+    pub fn nodes_with_metadata(&self, key: &str, value: &str) -> Vec<Node> {
+        self.nodes.nodes_with_metadata(key, value)
+    }
+
+    /// Returns the nodes which declared `role`, see Swarm::set_roles.
+    pub fn nodes_with_role(&self, role: &str) -> Vec<Node> {
+        crate::topology::nodes_with_role(&self.nodes, role)
+    }
+
+    pub fn passive_view(&self) -> Vec<Node> {
+        let views = self.views.lock().unwrap();
+        views.passive.iter().filter_map(|id| self.nodes.get(*id)).collect()
+    }
+
+    /// Adds `id` to the active view, demoting a random active member
+    /// to the passive view when full.
+    fn activate(&self, views: &mut Views, id: u32) {
+        if id == self.id || views.active.contains(&id) {
+            return;
+        }
+
+        views.passive.retain(|passive| *passive != id);
+        if views.active.len() >= self.active_size {
+            let index = rand::random::<usize>() % views.active.len();
+            let demoted = views.active.remove(index);
+            debug!("demoting active member [id={}]", demoted);
+            views.passive.push(demoted);
+        }
+
+        debug!("promoting active member [id={}]", id);
+        views.active.push(id);
+        self.trim(views);
+    }
+
+    /// Moves `id` from the active to the passive view.
+    fn deactivate(&self, views: &mut Views, id: u32) {
+        if let Some(index) = views.active.iter().position(|active| *active == id) {
+            views.active.remove(index);
+            views.passive.push(id);
+            self.trim(views);
+        }
+    }
+
+    /// Registers sampled members, adding unknown ones to the passive view.
+    fn integrate(&self, views: &mut Views, sample: Vec<Node>) {
+        for node in sample {
+            let id = node.get_id();
+            if id == self.id {
+                continue;
+            }
+
+            crate::topology::register_node(&self.nodes, self.clock.as_ref(),
+                &self.policy, self.quarantine.as_ref(), node);
+            if self.nodes.contains(id) && !views.contains(id) {
+                views.passive.push(id);
+            }
+        }
+
+        self.trim(views);
+    }
+
+    /// Returns up to shuffle_length random alive members of both views.
+    fn sample(&self, views: &Views) -> Vec<Node> {
+        let mut candidates: Vec<Node> = views.active.iter()
+            .chain(views.passive.iter())
+            .filter_map(|id| self.nodes.get(*id))
+            .filter(|node| node.state() == NodeState::Alive).collect();
+        while candidates.len() > self.shuffle_length {
+            candidates.swap_remove(rand::random::<usize>() % candidates.len());
+        }
+
+        candidates
+    }
+
+    /// Drops the oldest passive members beyond passive_size, removing
+    /// them from the node map as well.
+    fn trim(&self, views: &mut Views) {
+        while views.passive.len() > self.passive_size {
+            let id = views.passive.remove(0);
+            self.nodes.remove(id);
+        }
+    }
+}
+
+impl Topology for HyParView {
+    fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
+            -> Option<SocketAddr> {
+        let mut views = self.views.lock().unwrap();
+
+        // replace failed active members from the passive view
+        let failed: Vec<u32> = views.active.iter().copied()
+            .filter(|id| self.nodes.get(*id).map(|node|
+                node.state() != NodeState::Alive).unwrap_or(true))
+            .collect();
+        for id in failed {
+            self.deactivate(&mut views, id);
+        }
+
+        let alive = |ids: &[u32]| -> Vec<Node> {
+            ids.iter().filter_map(|id| self.nodes.get(*id))
+                .filter(|node| node.state() == NodeState::Alive).collect()
+        };
+        let passive = alive(&views.passive);
+        let candidates = match views.active.len() < self.active_size
+                && !passive.is_empty() {
+            true => passive,
+            false => alive(&views.active),
+        };
+
+        let peer = candidates.get(rand::random::<usize>()
+            % std::cmp::max(candidates.len(), 1))
+            .filter(|node| node.get_id() != id);
+        views.target = peer.map(|node| node.get_id());
+        match peer {
+            Some(node) => Some(node.get_address()),
+            None => *seed_address,
+        }
+    }
+
+    fn request<S: Read + Write>(&self, id: u32, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        // write local node, exchange kind and sample
+        let node = self.nodes.get(id).unwrap();
+        let (kind, sample) = {
+            let views = self.views.lock().unwrap();
+            let kind = match views.target {
+                _ if views.active.is_empty() => JOIN,
+                Some(target) if views.active.contains(&target) => SHUFFLE,
+                _ => PROMOTE,
+            };
+
+            (kind, self.sample(&views))
+        };
+
+        crate::topology::write_message(stream, |buf| {
+            node.write(buf)?;
+            buf.write_u8(kind)?;
+            write_sample(&sample, buf)
+        })?;
+
+        // read admission, replying node and sample
+        let message = crate::topology::read_message(stream)?;
+        let reader = &mut message.as_slice();
+        let accepted = reader.read_u8()? != 0;
+        let peer = Node::read(reader)?;
+        let sample = read_sample(reader)?;
+
+        let mut views = self.views.lock().unwrap();
+        let peer_id = peer.get_id();
+        self.integrate(&mut views, vec!(peer));
+        if accepted && self.nodes.contains(peer_id) {
+            self.activate(&mut views, peer_id);
+        } else {
+            // peers which dropped us are kept as backups only
+            self.deactivate(&mut views, peer_id);
+        }
+        self.integrate(&mut views, sample);
+
+        Ok(())
+    }
+
+    fn reply<S: Read + Write>(&self, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        // read request node, exchange kind and sample
+        let message = crate::topology::read_message(stream)?;
+        let reader = &mut message.as_slice();
+        let node = Node::read(reader)?;
+        let kind = reader.read_u8()?;
+        let sample = read_sample(reader)?;
+
+        let mut views = self.views.lock().unwrap();
+        let node_id = node.get_id();
+        self.integrate(&mut views, vec!(node));
+
+        // joining members are always admitted, promotions if room
+        let admissible = self.nodes.contains(node_id) && node_id != self.id;
+        let accepted = admissible && match kind {
+            JOIN => true,
+            PROMOTE => views.active.contains(&node_id)
+                || views.active.len() < self.active_size,
+            SHUFFLE => views.active.contains(&node_id),
+            _ => return Err(format!("unknown exchange kind [kind={}]",
+                kind).into()),
+        };
+        if accepted {
+            self.activate(&mut views, node_id);
+        }
+
+        // write admission, local node and sample
+        let reply_sample = self.sample(&views);
+        self.integrate(&mut views, sample);
+        drop(views);
+
+        let local = self.nodes.get(self.id).unwrap();
+        crate::topology::write_message(stream, |buf| {
+            buf.write_u8(accepted as u8)?;
+            local.write(buf)?;
+            write_sample(&reply_sample, buf)
+        })
+    }
+
+    fn authorize_join(&self, node: &Node) -> bool {
+        self.policy.authorizes(node)
+    }
+
+    fn member_counts(&self) -> (usize, usize) {
+        crate::topology::count_members(&self.nodes)
+    }
+
+    fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        self.nodes.peer_stats()
+    }
+}
+
+fn read_sample(reader: &mut impl Read) -> Result<Vec<Node>, Box<dyn Error>> {
+    let count = reader.read_u16::<BigEndian>()?;
+    let mut sample = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        sample.push(Node::read(reader)?);
+    }
+
+    Ok(sample)
+}
+
+fn write_sample(sample: &[Node], writer: &mut impl Write)
+        -> Result<(), Box<dyn Error>> {
+    writer.write_u16::<BigEndian>(sample.len() as u16)?;
+    for node in sample.iter() {
+        node.write(writer)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{wait_until, SystemClock};
+    use crate::prelude::{HyParView, HyParViewBuilder, Swarm};
+
+    use std::time::Duration;
+
+    #[test]
+    fn partial_views() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut swarms: Vec<Swarm<HyParView>> = Vec::new();
+        let mut views = Vec::new();
+        for i in 0..8 {
+            let seed_address = swarms.first()
+                .map(|swarm| swarm.local_addr().expect("local addr"));
+            let builder = HyParViewBuilder::new().active_size(2)
+                .passive_size(3).shuffle_length(2);
+            let (mut swarm, view) = Swarm::new(i as u32, ip_address, 0,
+                seed_address, builder);
+            swarm.start(1, 20, 50).expect("swarm start");
+            swarms.push(swarm);
+            views.push(view);
+        }
+
+        // memory stays bounded while every member stays connected
+        assert!(wait_until(&SystemClock, Duration::from_secs(5), ||
+            views.iter().all(|view| !view.active_view().is_empty())),
+            "active views");
+        for view in views.iter() {
+            let active = view.active_view();
+            assert!(!active.is_empty() && active.len() <= 2);
+            assert!(view.passive_view().len() <= 3);
+            assert!(view.nodes().len() <= 6);
+        }
+
+        for mut swarm in swarms {
+            swarm.stop().expect("swarm stop");
+        }
+    }
+}
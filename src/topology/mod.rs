@@ -1,23 +1,495 @@
-use crate::node::Node;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-pub mod cluster;
-pub mod dht;
+use crate::clock::Clock;
+use crate::codec;
+use crate::node::{MergeStatus, Node, NodeMap, NodeState, PeerStats};
+use crate::store::StateStore;
+use crate::topology::policy::MembershipPolicy;
+use crate::topology::quarantine::Quarantine;
+use crate::topology::selector::PeerSelector;
 
-use std::collections::HashMap;
+pub(crate) mod cluster;
+pub(crate) mod dht;
+pub(crate) mod hyparview;
+pub(crate) mod policy;
+mod quarantine;
+pub(crate) mod ring;
+pub(crate) mod selector;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Metadata key marking an observer with "true". Observers learn the
+/// membership and ring through gossip, but members never register them
+/// and they own no tokens. See the `observer` topology builder methods.
+pub const OBSERVER_KEY: &str = "observer";
+
+/// Member hash sent by requesters in full sync rounds, which repliers
+/// and token roots never treat as matching their own.
+const FULL_SYNC_HASH: u64 = 0;
+
+/// Encoded bytes of node updates past which a message is closed, so
+/// large memberships span several frames.
+const NODE_CHUNK_BYTES: usize = 256 * 1024;
 
 pub trait TopologyBuilder<T: 'static + Topology + Sync + Send> {
-    fn build(&self, id: u32,
-        nodes: Arc<RwLock<HashMap<u32, Node>>>) -> T;
+    /// Builds the topology of the local node `id`. Tombstone expiry,
+    /// confirmation ages, full sync rounds and flap damping read
+    /// `clock`, the clock of the owning swarm.
+    fn build(&self, id: u32, nodes: Arc<NodeMap>, clock: Arc<dyn Clock>)
+        -> T;
 }
 
 pub trait Topology {
     fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
         -> Option<SocketAddr>;
-    fn request(&self, id: u32, stream: &mut TcpStream)
+    fn request<S: Read + Write>(&self, id: u32, stream: &mut S)
         -> Result<(), Box<dyn Error>>;
-    fn reply(&self, stream: &mut TcpStream)
+    fn reply<S: Read + Write>(&self, stream: &mut S)
         -> Result<(), Box<dyn Error>>;
+
+    /// Static topologies keep the membership they were configured with:
+    /// the gossiper is disabled and replies never modify local state.
+    fn is_static(&self) -> bool {
+        false
+    }
+
+    /// Epoch of the token ring, for topologies which maintain one.
+    fn ring_epoch(&self) -> Option<u64> {
+        None
+    }
+
+    /// Tokens of the ring and their owners, for topologies which
+    /// maintain one.
+    fn ring_tokens(&self) -> Option<BTreeMap<u64, u32>> {
+        None
+    }
+
+    /// Maintenance run every gossip round once peer states are updated.
+    fn tick(&self) {}
+
+    /// Persists topology state, such as ring tokens, alongside
+    /// membership snapshots.
+    fn save(&self, _store: &dyn StateStore) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Restores state persisted by `save` before the first gossip round.
+    fn load(&self, _store: &dyn StateStore) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Numbers of known and alive members, including the local node.
+    /// Topologies without a membership view report none.
+    fn member_counts(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    /// Round-trip times of the peers this node gossiped with, by id,
+    /// for spotting slow links. Topologies without a membership view
+    /// report none.
+    fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        BTreeMap::new()
+    }
+
+    /// Removes member `id` cluster-wide: the removal gossips as a
+    /// tombstone, and members refuse the member's records, even new
+    /// incarnations, until it expires after `ttl`. Expiries are wall
+    /// clock based, so `ttl` should exceed the clock skew between
+    /// members. Topologies without a membership view refuse removals.
+    fn remove_node(&self, _id: u32, _ttl: Duration)
+            -> Result<(), Box<dyn Error>> {
+        Err("topology does not support node removal".into())
+    }
+
+    /// Returns true if `node` may join, as decided before registering
+    /// unknown nodes. See MembershipPolicy::authorize_join.
+    fn authorize_join(&self, _node: &Node) -> bool {
+        true
+    }
+
+    /// Number of alive members forming a majority of known members.
+    fn quorum(&self) -> usize {
+        self.member_counts().0 / 2 + 1
+    }
+
+    /// Returns true while a majority of known members are alive, for
+    /// gating writes or leadership on majority availability.
+    fn has_quorum(&self) -> bool {
+        self.member_counts().1 >= self.quorum()
+    }
+}
+
+/// Schedules full sync rounds, in which a requester pulls the whole
+/// state of its peer rather than only what differing hashes reveal. A
+/// backstop against divergence that hashes miss, such as collisions.
+struct FullSync {
+    clock: Arc<dyn Clock>,
+    interval: Duration,
+    last_sync: Mutex<Instant>,
+}
+
+impl FullSync {
+    fn new(clock: Arc<dyn Clock>, interval: Duration) -> FullSync {
+        let now = clock.now();
+        FullSync {
+            clock,
+            interval,
+            last_sync: Mutex::new(now),
+        }
+    }
+
+    /// Returns true if a full sync round is due, starting its interval
+    /// over when it is.
+    fn is_due(&self) -> bool {
+        let now = self.clock.now();
+        let mut last_sync = self.last_sync.lock().unwrap();
+        if now.saturating_duration_since(*last_sync) < self.interval {
+            return false;
+        }
+
+        *last_sync = now;
+        true
+    }
+}
+
+/// Returns true if `full_sync` schedules a full sync round now.
+fn is_full_sync(id: u32, full_sync: Option<&FullSync>) -> bool {
+    let is_due = full_sync.map(FullSync::is_due).unwrap_or(false);
+    if is_due {
+        debug!("requesting full sync [id={}, trace_id={}]",
+            id, crate::trace::current());
+    }
+
+    is_due
+}
+
+/// Writes one message of a topology exchange, as written by `write`,
+/// in a checksummed frame.
+fn write_message<W: Write, F>(writer: &mut W, write: F)
+        -> Result<(), Box<dyn Error>>
+        where F: FnOnce(&mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let mut buf = Vec::new();
+    write(&mut buf)?;
+    codec::write_frame(writer, &buf)
+}
+
+/// Reads one framed message of a topology exchange. A checksum mismatch
+/// rejects the whole exchange rather than merging corrupted state.
+fn read_message(reader: &mut impl Read) -> Result<Vec<u8>, Box<dyn Error>> {
+    codec::read_frame(reader)?
+        .ok_or_else(|| "topology message checksum mismatch".into())
+}
+
+/// Tombstones member `id` of `nodes` for `ttl`, refusing to remove
+/// the local node `local_id`.
+fn remove_node(local_id: u32, nodes: &NodeMap, clock: &dyn Clock, id: u32,
+        ttl: Duration) -> Result<(), Box<dyn Error>> {
+    if id == local_id {
+        return Err("cannot remove the local node".into());
+    }
+
+    // ttls past the range of timestamps never expire
+    let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    let expires = clock.timestamp().saturating_add(ttl_ms);
+    nodes.tombstone(id, expires);
+    info!("removing node [id={}, ttl_ms={}]", id, ttl.as_millis());
+    Ok(())
+}
+
+/// Returns true if `node` is an observer rather than a member.
+fn is_observer(node: &Node) -> bool {
+    node.get_metadata(OBSERVER_KEY).map(|value| value == "true")
+        .unwrap_or(false)
+}
+
+/// Returns the nodes of `nodes` which declared `role`.
+fn nodes_with_role(nodes: &NodeMap, role: &str) -> Vec<Node> {
+    nodes.nodes_where(|node| node.has_role(role))
+}
+
+/// Marks the local node `id` as an observer.
+fn mark_observer(id: u32, nodes: &NodeMap) {
+    nodes.update(id, |node| node.set_metadata(OBSERVER_KEY, "true"));
+    debug!("observing swarm [id={}]", id);
+}
+
+/// Counts the known and alive members of `nodes`. Suspect members are
+/// known but not alive, observers are neither.
+fn count_members(nodes: &NodeMap) -> (usize, usize) {
+    let nodes: Vec<Node> = nodes.nodes().into_iter()
+        .filter(|node| !is_observer(node)).collect();
+    let alive = nodes.iter()
+        .filter(|node| node.state() == NodeState::Alive).count();
+    (nodes.len(), alive)
+}
+
+fn select_gossip_addr(id: u32, nodes: &NodeMap,
+        selector: &dyn PeerSelector, seed_address: &Option<SocketAddr>)
+        -> Option<SocketAddr> {
+    let (local, peers): (Vec<Node>, Vec<Node>) = nodes.nodes().into_iter()
+        .filter(|node| node.state() == NodeState::Alive)
+        .partition(|node| node.get_id() == id);
+
+    if let (Some(local), false) = (local.first(), peers.is_empty()) {
+        // if other alive nodes are registered -> delegate to selector
+        return selector.select_measured(local, &peers, &nodes.peer_stats())
+            .map(|node| node.get_address());
+    } else if let Some(seed_address) = seed_address {
+        // if no other alive nodes -> return seed node
+        return Some(*seed_address);
+    }
+
+    None
+}
+
+/// Registers or updates `node`, returning true if the update left it
+/// evicted by policy.
+fn register_node(nodes: &NodeMap, clock: &dyn Clock,
+        policy: &MembershipPolicy, quarantine: Option<&Quarantine>,
+        node: Node) -> bool {
+    let (id, address, version) =
+        (node.get_id(), node.get_address(), node.get_version());
+
+    // removed nodes stay removed until their tombstones expire
+    if nodes.is_tombstoned(id, clock.timestamp()) {
+        debug!("ignoring removed node [id={}, trace_id={}]",
+            id, crate::trace::current());
+        return false;
+    }
+
+    // observers pull state only -> never registered
+    if is_observer(&node) {
+        debug!("ignoring observer node [id={}, trace_id={}]",
+            id, crate::trace::current());
+        return false;
+    }
+
+    // unknown nodes must pass admission and not be evicted outright
+    if !nodes.contains(id) && (!policy.admits(&node) || policy.evicts(&node)) {
+        debug!("rejecting node by policy [id={}, trace_id={}]",
+            id, crate::trace::current());
+        return false;
+    }
+
+    // then pass join authorization, again whenever a known node moves
+    // or restarts -> checked last as it may be costly
+    let rejoins = match nodes.get(id) {
+        Some(current) => current.get_address() != address
+            || current.get_incarnation() != node.get_incarnation(),
+        None => true,
+    };
+    if rejoins && !policy.authorizes(&node) {
+        info!("rejecting unauthorized node [id={}, address={}, trace_id={}]",
+            id, address, crate::trace::current());
+        return false;
+    }
+
+    // new incarnations of known nodes are flaps -> may be deferred
+    if let (Some(quarantine), Some(current)) = (quarantine, nodes.get(id)) {
+        if node.get_incarnation() > current.get_incarnation()
+                && !quarantine.admit(id) {
+            debug!("deferring quarantined node [id={}, trace_id={}]",
+                id, crate::trace::current());
+            return false;
+        }
+    }
+
+    match nodes.merge(node) {
+        MergeStatus::Inserted => debug!(
+            "registering node [id={}, address={}, trace_id={}]",
+            id, address, crate::trace::current()),
+        MergeStatus::Updated => debug!(
+            "updating node [id={}, version={}, trace_id={}]",
+            id, version, crate::trace::current()),
+        MergeStatus::Stale => return false,
+    }
+
+    // updated nodes may now match eviction
+    if nodes.get(id).map(|node| policy.evicts(&node)).unwrap_or(false) {
+        info!("evicting node by policy [id={}, trace_id={}]",
+            id, crate::trace::current());
+        nodes.remove(id);
+        return true;
+    }
+
+    false
+}
+
+/// Merges the confirmation timestamps and heartbeats written by a
+/// peer, applying them unless `is_static`.
+fn read_confirmations(nodes: &NodeMap, is_static: bool,
+        reader: &mut impl Read) -> Result<(), Box<dyn Error>> {
+    let message = read_message(reader)?;
+    let reader = &mut message.as_slice();
+    let count = reader.read_u32::<BigEndian>()?;
+    for _ in 0..count {
+        let id = reader.read_u32::<BigEndian>()?;
+        let timestamp = reader.read_u64::<BigEndian>()?;
+        let incarnation = reader.read_u64::<BigEndian>()?;
+        let heartbeat = reader.read_u64::<BigEndian>()?;
+        if !is_static {
+            nodes.confirm(id, timestamp);
+            nodes.heartbeat(id, incarnation, heartbeat);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the confirmation timestamp and heartbeat of every node,
+/// confirming the local node `id` first.
+fn write_confirmations(id: u32, nodes: &NodeMap, clock: &dyn Clock,
+        writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    nodes.confirm(id, clock.timestamp());
+    let nodes = nodes.nodes();
+    write_message(writer, |buf| {
+        buf.write_u32::<BigEndian>(nodes.len() as u32)?;
+        for node in nodes.iter() {
+            buf.write_u32::<BigEndian>(node.get_id())?;
+            buf.write_u64::<BigEndian>(node.get_confirmed())?;
+            buf.write_u64::<BigEndian>(node.get_incarnation())?;
+            buf.write_u64::<BigEndian>(node.get_heartbeat())?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Merges the tombstones written by a peer, applying them unless
+/// `is_static`, and returns the number of nodes removed. Tombstones of
+/// the local node `id` are ignored.
+fn read_tombstones(id: u32, nodes: &NodeMap, is_static: bool,
+        reader: &mut impl Read) -> Result<usize, Box<dyn Error>> {
+    let message = read_message(reader)?;
+    let reader = &mut message.as_slice();
+    let count = reader.read_u32::<BigEndian>()?;
+    let mut removed = 0;
+    for _ in 0..count {
+        let tombstone_id = reader.read_u32::<BigEndian>()?;
+        let expires = reader.read_u64::<BigEndian>()?;
+        if !is_static && tombstone_id != id
+                && nodes.tombstone(tombstone_id, expires) {
+            debug!("removing node by tombstone [id={}, expires={}, trace_id={}]",
+                tombstone_id, expires, crate::trace::current());
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Writes every unexpired tombstone with its expiry.
+fn write_tombstones(nodes: &NodeMap, clock: &dyn Clock,
+        writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    let tombstones = nodes.tombstones(clock.timestamp());
+    write_message(writer, |buf| {
+        buf.write_u32::<BigEndian>(tombstones.len() as u32)?;
+        for (id, expires) in tombstones.iter() {
+            buf.write_u32::<BigEndian>(*id)?;
+            buf.write_u64::<BigEndian>(*expires)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Merges the node updates written by a peer, returning the number of
+/// nodes evicted by policy. Every update is parsed before any is
+/// registered, so a malformed message merges nothing.
+fn read_node_updates(nodes: &NodeMap, clock: &dyn Clock,
+        policy: &MembershipPolicy, quarantine: Option<&Quarantine>,
+        reader: &mut impl Read) -> Result<usize, Box<dyn Error>> {
+    let mut updates = Vec::new();
+    loop {
+        let message = read_message(reader)?;
+        let reader = &mut message.as_slice();
+        let count = reader.read_u32::<BigEndian>()?;
+        // an empty message ends the updates
+        if count == 0 {
+            break;
+        }
+
+        for _ in 0..count {
+            updates.push(Node::read(reader)?);
+        }
+    }
+
+    let mut evicted = 0;
+    for node in updates {
+        if register_node(nodes, clock, policy, quarantine, node) {
+            evicted += 1;
+        }
+    }
+
+    Ok(evicted)
+}
+
+/// Hashes the members of `nodes`, leaving out observers so that an
+/// observer's view matches those of the members it gossips with. Full
+/// sync rounds send FULL_SYNC_HASH instead.
+fn member_hash(nodes: &NodeMap, full_sync: bool) -> u64 {
+    match full_sync {
+        true => FULL_SYNC_HASH,
+        false => crate::node::hash_nodes(nodes.nodes().iter()
+            .filter(|node| !is_observer(node))),
+    }
+}
+
+/// Writes every member unless `node_hash` matches them, in messages
+/// closed past NODE_CHUNK_BYTES and ended with an empty message.
+fn write_node_updates(nodes: &NodeMap, node_hash: u64,
+        writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    let nodes: Vec<Node> = nodes.nodes().into_iter()
+        .filter(|node| !is_observer(node)).collect();
+    let write_chunk = |writer: &mut _, count: u32, chunk: &[u8]|
+        write_message(writer, |buf| {
+            buf.write_u32::<BigEndian>(count)?;
+            buf.extend_from_slice(chunk);
+            Ok(())
+        });
+
+    if node_hash == FULL_SYNC_HASH
+            || node_hash != crate::node::hash_nodes(nodes.iter()) {
+        let (mut count, mut chunk) = (0, Vec::new());
+        for node in nodes.iter() {
+            node.write(&mut chunk)?;
+            count += 1;
+            if chunk.len() >= NODE_CHUNK_BYTES {
+                write_chunk(writer, count, &chunk)?;
+                count = 0;
+                chunk.clear();
+            }
+        }
+
+        if count > 0 {
+            write_chunk(writer, count, &chunk)?;
+        }
+    }
+
+    write_chunk(writer, 0, &[])
+}
+
+/// Writes the requesting node and its member hash.
+fn write_request_node(node: &Node, node_hash: u64, writer: &mut impl Write)
+        -> Result<(), Box<dyn Error>> {
+    write_message(writer, |buf| {
+        node.write(buf)?;
+        buf.write_u64::<BigEndian>(node_hash)?;
+        Ok(())
+    })
+}
+
+/// Reads the requesting node and its member hash.
+fn read_request_node(reader: &mut impl Read)
+        -> Result<(Node, u64), Box<dyn Error>> {
+    let message = read_message(reader)?;
+    let reader = &mut message.as_slice();
+    let node = Node::read(reader)?;
+    Ok((node, reader.read_u64::<BigEndian>()?))
 }
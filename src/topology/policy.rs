@@ -0,0 +1,431 @@
+use crate::node::Node;
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+type Authorizer = Arc<dyn Fn(&Node) -> bool + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    And,
+    Comma,
+    Ident(String),
+    In,
+    Int(i64),
+    LeftBracket,
+    LeftParen,
+    Not,
+    Op(&'static str),
+    Or,
+    RightBracket,
+    RightParen,
+    Str(String),
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Compare(&'static str, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Vec<Expr>),
+    Literal(Value),
+    Not(Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Variable(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Missing,
+    Str(String),
+}
+
+/// A boolean expression over a node, for example
+/// `zone in ["us-east-1a", "us-east-1b"] && version >= "1.4.0"`.
+///
+/// Variables are `id`, `zone` and `version` (shorthand for the
+/// metadata keys of the same name) and `metadata.<key>`. Values are
+/// compared numerically when both sides are integers or dotted version
+/// strings, and as strings otherwise. Comparisons with missing metadata
+/// are false, except `!=` which is true. Operators are `==`, `!=`,
+/// `<`, `<=`, `>`, `>=`, `in [..]`, `&&`, `||`, `!` and parentheses.
+#[derive(Debug)]
+pub struct Expression {
+    root: Expr,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, Box<dyn Error>> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { position: 0, tokens };
+        let root = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("unexpected token {:?} in '{}'",
+                token, source).into());
+        }
+
+        Ok(Expression { root })
+    }
+
+    pub fn evaluate(&self, node: &Node) -> bool {
+        truthy(&evaluate(&self.root, node))
+    }
+}
+
+/// Operator supplied expressions evaluated at membership decision
+/// points, so policy changes need no recompilation:
+///
+///  - admission: unknown nodes failing it are never registered
+///  - join authorization: like admission, but an arbitrary callback for
+///    allowlists or token checks beyond what expressions express
+///  - eviction: registered nodes matching it are removed, and are not
+///    readmitted while they still match
+///  - placement: Dht::locate skips owners failing it, routing their
+///    tokens to the next owner on the ring
+///
+/// Unset expressions admit, keep, and place every node.
+#[derive(Clone, Default)]
+pub struct MembershipPolicy {
+    admission: Option<Arc<Expression>>,
+    authorizer: Option<Authorizer>,
+    eviction: Option<Arc<Expression>>,
+    placement: Option<Arc<Expression>>,
+}
+
+impl Debug for MembershipPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("MembershipPolicy")
+            .field("admission", &self.admission)
+            .field("authorizer", &self.authorizer.as_ref().map(|_| ".."))
+            .field("eviction", &self.eviction)
+            .field("placement", &self.placement)
+            .finish()
+    }
+}
+
+impl MembershipPolicy {
+    pub fn new() -> MembershipPolicy {
+        MembershipPolicy::default()
+    }
+
+    pub fn admission(mut self, source: &str)
+            -> Result<MembershipPolicy, Box<dyn Error>> {
+        self.admission = Some(Arc::new(Expression::parse(source)?));
+        Ok(self)
+    }
+
+    /// Invokes `authorizer` before an unknown node is registered, from
+    /// its own gossip or a peer's, registering it only if it returns
+    /// true. Runs on gossip threads, so it should not block.
+    pub fn authorize_join<F>(mut self, authorizer: F) -> MembershipPolicy
+            where F: Fn(&Node) -> bool + Send + Sync + 'static {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    pub fn eviction(mut self, source: &str)
+            -> Result<MembershipPolicy, Box<dyn Error>> {
+        self.eviction = Some(Arc::new(Expression::parse(source)?));
+        Ok(self)
+    }
+
+    pub fn placement(mut self, source: &str)
+            -> Result<MembershipPolicy, Box<dyn Error>> {
+        self.placement = Some(Arc::new(Expression::parse(source)?));
+        Ok(self)
+    }
+
+    pub fn admits(&self, node: &Node) -> bool {
+        self.admission.as_ref()
+            .map(|expression| expression.evaluate(node)).unwrap_or(true)
+    }
+
+    pub fn authorizes(&self, node: &Node) -> bool {
+        self.authorizer.as_ref()
+            .map(|authorizer| authorizer(node)).unwrap_or(true)
+    }
+
+    pub fn evicts(&self, node: &Node) -> bool {
+        self.eviction.as_ref()
+            .map(|expression| expression.evaluate(node)).unwrap_or(false)
+    }
+
+    pub fn places(&self, node: &Node) -> bool {
+        self.placement.as_ref()
+            .map(|expression| expression.evaluate(node)).unwrap_or(true)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => { i += 1; continue; },
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op("=="), 2),
+            ('!', Some('=')) => (Token::Op("!="), 2),
+            ('<', Some('=')) => (Token::Op("<="), 2),
+            ('>', Some('=')) => (Token::Op(">="), 2),
+            ('<', _) => (Token::Op("<"), 1),
+            ('>', _) => (Token::Op(">"), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LeftParen, 1),
+            (')', _) => (Token::RightParen, 1),
+            ('[', _) => (Token::LeftBracket, 1),
+            (']', _) => (Token::RightBracket, 1),
+            (',', _) => (Token::Comma, 1),
+            ('"', _) | ('\'', _) => {
+                let end = chars[i+1..].iter().position(|x| *x == c)
+                    .ok_or_else(|| format!("unterminated string in '{}'",
+                        source))?;
+                let value: String = chars[i+1..i+1+end].iter().collect();
+                (Token::Str(value), end + 2)
+            },
+            (c, _) if c.is_ascii_digit() || c == '-' => {
+                let len = chars[i+1..].iter()
+                    .take_while(|x| x.is_ascii_digit()).count() + 1;
+                let value: String = chars[i..i+len].iter().collect();
+                (Token::Int(value.parse()?), len)
+            },
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().take_while(|x| x.is_alphanumeric()
+                    || **x == '_' || **x == '.' || **x == '-').count();
+                let value: String = chars[i..i+len].iter().collect();
+                match value.as_str() {
+                    "in" => (Token::In, len),
+                    _ => (Token::Ident(value), len),
+                }
+            },
+            (c, _) => return Err(format!("unexpected character '{}' in '{}'",
+                c, source).into()),
+        };
+
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    position: usize,
+    tokens: Vec<Token>,
+}
+
+impl Parser {
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Box<dyn Error>> {
+        match self.advance() {
+            Some(ref token) if *token == expected => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}",
+                expected, token).into()),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn or(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, Box<dyn Error>> {
+        if self.peek() == Some(&Token::Not) {
+            self.position += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let left = self.primary()?;
+        match self.peek().cloned() {
+            Some(Token::Op(op)) => {
+                self.position += 1;
+                Ok(Expr::Compare(op, Box::new(left),
+                    Box::new(self.primary()?)))
+            },
+            Some(Token::In) => {
+                self.position += 1;
+                self.expect(Token::LeftBracket)?;
+                let mut items = Vec::new();
+                while self.peek() != Some(&Token::RightBracket) {
+                    items.push(self.primary()?);
+                    if self.peek() == Some(&Token::Comma) {
+                        self.position += 1;
+                    }
+                }
+                self.expect(Token::RightBracket)?;
+                Ok(Expr::In(Box::new(left), items))
+            },
+            _ => Ok(left),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        match self.advance() {
+            Some(Token::LeftParen) => {
+                let expr = self.or()?;
+                self.expect(Token::RightParen)?;
+                Ok(expr)
+            },
+            Some(Token::Int(value)) => Ok(Expr::Literal(Value::Int(value))),
+            Some(Token::Str(value)) => Ok(Expr::Literal(Value::Str(value))),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "id" | "zone" | "version" => Ok(Expr::Variable(name)),
+                _ => match name.strip_prefix("metadata.") {
+                    Some(key) if !key.is_empty() =>
+                        Ok(Expr::Variable(name)),
+                    _ => Err(format!("unknown variable '{}'", name).into()),
+                },
+            },
+            token => Err(format!("unexpected token {:?}", token).into()),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, node: &Node) -> Value {
+    match expr {
+        Expr::And(left, right) => Value::Bool(truthy(&evaluate(left, node))
+            && truthy(&evaluate(right, node))),
+        Expr::Compare(op, left, right) => {
+            let ordering = compare(&evaluate(left, node),
+                &evaluate(right, node));
+            Value::Bool(match (*op, ordering) {
+                ("!=", None) => true,
+                (_, None) => false,
+                ("==", Some(ordering)) => ordering == Ordering::Equal,
+                ("!=", Some(ordering)) => ordering != Ordering::Equal,
+                ("<", Some(ordering)) => ordering == Ordering::Less,
+                ("<=", Some(ordering)) => ordering != Ordering::Greater,
+                (">", Some(ordering)) => ordering == Ordering::Greater,
+                (_, Some(ordering)) => ordering != Ordering::Less,
+            })
+        },
+        Expr::In(value, items) => {
+            let value = evaluate(value, node);
+            Value::Bool(items.iter().any(|item| compare(&value,
+                &evaluate(item, node)) == Some(Ordering::Equal)))
+        },
+        Expr::Literal(value) => value.clone(),
+        Expr::Not(expr) => Value::Bool(!truthy(&evaluate(expr, node))),
+        Expr::Or(left, right) => Value::Bool(truthy(&evaluate(left, node))
+            || truthy(&evaluate(right, node))),
+        Expr::Variable(name) => {
+            let key = match name.as_str() {
+                "id" => return Value::Int(node.get_id() as i64),
+                key => key.strip_prefix("metadata.").unwrap_or(key),
+            };
+
+            match node.get_metadata(key) {
+                Some(value) => Value::Str(value.clone()),
+                None => Value::Missing,
+            }
+        },
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Missing, _) | (_, Value::Missing) => None,
+        (Value::Bool(left), Value::Bool(right)) => Some(left.cmp(right)),
+        (left, right) => {
+            let (left, right) = (to_string(left), to_string(right));
+            match (version_parts(&left), version_parts(&right)) {
+                (Some(left), Some(right)) => Some(left.cmp(&right)),
+                _ => Some(left.cmp(&right)),
+            }
+        },
+    }
+}
+
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::Bool(value) => value.to_string(),
+        Value::Int(value) => value.to_string(),
+        Value::Missing => String::new(),
+        Value::Str(value) => value.clone(),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        Value::Missing => false,
+        _ => true,
+    }
+}
+
+// integers and dotted versions compare numerically per component
+fn version_parts(value: &str) -> Option<Vec<i64>> {
+    value.trim_start_matches('v').split('.')
+        .map(|part| part.parse::<i64>().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+    use super::{Expression, MembershipPolicy};
+
+    #[test]
+    fn policy_expressions() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let mut node = Node::new(3, ip_address, 12000);
+        node.set_metadata("zone", "us-east-1a");
+        node.set_metadata("version", "1.10.2");
+        node.set_metadata("role", "storage");
+
+        let evaluate = |source: &str| Expression::parse(source)
+            .expect("parse expression").evaluate(&node);
+        assert!(evaluate("zone in ['us-east-1a', 'us-east-1b']"));
+        assert!(evaluate("version >= \"1.9\" && id < 10"));
+        assert!(evaluate("!(metadata.role == 'compute') || id == 0"));
+        assert!(evaluate("metadata.missing != 'x'"));
+        assert!(!evaluate("metadata.missing == 'x' || metadata.missing"));
+        assert!(Expression::parse("zone ==").is_err());
+        assert!(Expression::parse("hostname == 'a'").is_err());
+
+        let policy = MembershipPolicy::new()
+            .admission("zone != 'eu-west-1a'").expect("admission")
+            .eviction("version < '1.0'").expect("eviction");
+        assert!(policy.admits(&node) && !policy.evicts(&node));
+        assert!(policy.places(&node) && policy.authorizes(&node));
+
+        let policy = policy.authorize_join(|node| node.get_id() < 3);
+        assert!(!policy.authorizes(&node));
+        assert!(format!("{:?}", policy).contains("authorizer"));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::clock::Clock;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Flaps {
+    count: u32,
+    last_flap: Instant,
+    until: Instant,
+}
+
+/// Flap damping for unstable nodes. Every new incarnation of a known
+/// node counts as a flap (it failed or restarted and rejoined). Each
+/// flap quarantines the node for an exponentially growing backoff,
+/// during which further incarnations are not admitted. Flap counts
+/// reset once a node has been stable for twice the maximum backoff.
+pub struct Quarantine {
+    base_backoff: Duration,
+    clock: Arc<dyn Clock>,
+    flaps: Mutex<HashMap<u32, Flaps>>,
+    max_backoff: Duration,
+}
+
+impl Quarantine {
+    pub fn new(clock: Arc<dyn Clock>, base_backoff: Duration,
+            max_backoff: Duration) -> Quarantine {
+        Quarantine {
+            base_backoff,
+            clock,
+            flaps: Mutex::new(HashMap::new()),
+            max_backoff,
+        }
+    }
+
+    /// Returns true if a new incarnation of `id` may be admitted,
+    /// recording the flap when it is.
+    pub fn admit(&self, id: u32) -> bool {
+        let now = self.clock.now();
+        let mut flaps = self.flaps.lock().unwrap();
+        let flaps = flaps.entry(id).or_insert(Flaps {
+            count: 0,
+            last_flap: now,
+            until: now,
+        });
+
+        if now < flaps.until {
+            return false;
+        }
+
+        // stable nodes start over
+        if now.saturating_duration_since(flaps.last_flap)
+                >= self.max_backoff * 2 {
+            flaps.count = 0;
+        }
+
+        let exponent = std::cmp::min(flaps.count, 16);
+        let backoff = std::cmp::min(self.base_backoff * 2u32.pow(exponent),
+            self.max_backoff);
+        flaps.count += 1;
+        flaps.last_flap = now;
+        flaps.until = now + backoff;
+        debug!("node flapped [id={}, count={}, backoff_ms={}]",
+            id, flaps.count, backoff.as_millis());
+
+        true
+    }
+
+    /// Returns the remaining quarantine of `id`, if any.
+    pub fn remaining(&self, id: u32) -> Option<Duration> {
+        let now = self.clock.now();
+        let flaps = self.flaps.lock().unwrap();
+        flaps.get(&id).map(|flaps| flaps.until.saturating_duration_since(now))
+            .filter(|remaining| *remaining > Duration::from_millis(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use super::Quarantine;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn quarantine_backoff() {
+        let clock = Arc::new(ManualClock::new(0));
+        let quarantine = Quarantine::new(clock.clone(),
+            Duration::from_millis(100), Duration::from_millis(300));
+
+        // first flap is admitted and starts the base backoff
+        assert!(quarantine.admit(1));
+        assert_eq!(quarantine.remaining(1), Some(Duration::from_millis(100)));
+        assert!(!quarantine.admit(1));
+
+        // backoff doubles per flap up to the maximum
+        clock.advance(Duration::from_millis(100));
+        assert!(quarantine.admit(1));
+        assert_eq!(quarantine.remaining(1), Some(Duration::from_millis(200)));
+        clock.advance(Duration::from_millis(200));
+        assert!(quarantine.admit(1));
+        assert_eq!(quarantine.remaining(1), Some(Duration::from_millis(300)));
+
+        // stable nodes reset
+        clock.advance(Duration::from_millis(600));
+        assert!(quarantine.remaining(1).is_none());
+        assert!(quarantine.admit(1));
+        assert_eq!(quarantine.remaining(1), Some(Duration::from_millis(100)));
+    }
+}
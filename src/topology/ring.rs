@@ -0,0 +1,363 @@
+
+use crate::clock::Clock;
+use crate::node::{Node, NodeMap, NodeState, PeerStats};
+use crate::topology::{FullSync, Topology, TopologyBuilder};
+use crate::topology::policy::MembershipPolicy;
+use crate::topology::quarantine::Quarantine;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_NEIGHBOR_RATIO: f64 = 0.75;
+
+pub struct ChordBuilder {
+    flap_damping: Option<(Duration, Duration)>,
+    full_sync_interval: Option<Duration>,
+    neighbor_ratio: f64,
+    observer: bool,
+    policy: MembershipPolicy,
+}
+
+impl Default for ChordBuilder {
+    fn default() -> ChordBuilder {
+        ChordBuilder {
+            flap_damping: None,
+            full_sync_interval: None,
+            neighbor_ratio: DEFAULT_NEIGHBOR_RATIO,
+            observer: false,
+            policy: MembershipPolicy::new(),
+        }
+    }
+}
+
+impl ChordBuilder {
+    pub fn new() -> ChordBuilder {
+        ChordBuilder::default()
+    }
+
+    /// Quarantines nodes which rejoin with a new incarnation, starting
+    /// at `base_backoff` and doubling per flap up to `max_backoff`.
+    pub fn flap_damping(mut self, base_backoff: Duration,
+            max_backoff: Duration) -> ChordBuilder {
+        self.flap_damping = Some((base_backoff, max_backoff));
+        self
+    }
+
+    /// Pulls the full membership of a peer once per `interval`, see
+    /// ClusterBuilder::full_sync_interval.
+    pub fn full_sync_interval(mut self, interval: Duration) -> ChordBuilder {
+        self.full_sync_interval = Some(interval);
+        self
+    }
+
+    /// Fraction of rounds gossiping with the successor or predecessor
+    /// rather than a finger, 0.75 by default.
+    pub fn neighbor_ratio(mut self, neighbor_ratio: f64) -> ChordBuilder {
+        self.neighbor_ratio = neighbor_ratio;
+        self
+    }
+
+    /// Learns the membership through gossip without joining the ring:
+    /// members never register the local node and it owns no position.
+    /// See OBSERVER_KEY.
+    pub fn observer(mut self) -> ChordBuilder {
+        self.observer = true;
+        self
+    }
+
+    /// Applies `policy` at admission and eviction decisions.
+    pub fn policy(mut self, policy: MembershipPolicy) -> ChordBuilder {
+        self.policy = policy;
+        self
+    }
+}
+
+impl TopologyBuilder<Chord> for ChordBuilder {
+    fn build(&self, id: u32, nodes: Arc<NodeMap>, clock: Arc<dyn Clock>)
+            -> Chord {
+        if self.observer {
+            crate::topology::mark_observer(id, &nodes);
+        }
+
+        Chord {
+            clock: clock.clone(),
+            full_sync: self.full_sync_interval.map(|interval|
+                FullSync::new(clock.clone(), interval)),
+            id,
+            neighbor_ratio: self.neighbor_ratio,
+            nodes,
+            policy: self.policy.clone(),
+            quarantine: self.flap_damping.map(|(base, max)|
+                Quarantine::new(clock.clone(), base, max)),
+        }
+    }
+}
+
+/// Structured ring placing every member at a hash of its id. Members
+/// gossip mostly with their successor and predecessor, and otherwise
+/// with their fingers: the successors of their position plus 2^i, of
+/// which O(log n) are distinct. Lookups routed through fingers take
+/// O(log n) hops, unlike the flat Cluster map.
+pub struct Chord {
+    clock: Arc<dyn Clock>,
+    full_sync: Option<FullSync>,
+    id: u32,
+    neighbor_ratio: f64,
+    nodes: Arc<NodeMap>,
+    policy: MembershipPolicy,
+    quarantine: Option<Quarantine>,
+}
+
+impl Chord {
+    /// Returns the ring position of member `id`.
+    pub fn position(id: u32) -> u64 {
+        // splitmix64 -> identical on every member and platform
+        let mut z = (id as u64).wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns the distinct fingers of the local node.
+    pub fn fingers(&self) -> Vec<Node> {
+        fingers(&self.ring(), self.id).into_iter().cloned().collect()
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.nodes.nodes()
+    }
+
+    /// Returns the nodes matching `predicate` ordered by id, copying
+    /// only those.
+    pub fn nodes_where<F: Fn(&Node) -> bool>(&self, predicate: F)
+            -> Vec<Node> {
+        self.nodes.nodes_where(predicate)
+    }
+
+    /// Returns the nodes whose metadata `key` is `value`, see
+    /// Swarm::index_metadata.
+    pub fn nodes_with_metadata(&self, key: &str, value: &str) -> Vec<Node> {
+        self.nodes.nodes_with_metadata(key, value)
+    }
+
+    /// Returns the nodes which declared `role`, see Swarm::set_roles.
+    pub fn nodes_with_role(&self, role: &str) -> Vec<Node> {
+        crate::topology::nodes_with_role(&self.nodes, role)
+    }
+
+    /// Returns the alive member preceding `key` on the ring.
+    pub fn predecessor(&self, key: u64) -> Option<Node> {
+        let ring = self.ring();
+        ring.iter().rev().find(|(position, _)| *position < key)
+            .or_else(|| ring.last()).map(|(_, node)| node.clone())
+    }
+
+    /// Returns the ids visited routing `key` from the local node to its
+    /// owner through fingers, starting with the local id.
+    pub fn route(&self, key: u64) -> Vec<u32> {
+        let ring = self.ring();
+        let owner = match successor(&ring, key) {
+            Some(owner) => owner.get_id(),
+            None => return Vec::new(),
+        };
+
+        let mut path = vec!(self.id);
+        let mut current = self.id;
+        while current != owner && path.len() <= ring.len() {
+            // forward to the finger closest before the key
+            let position = Chord::position(current);
+            current = fingers(&ring, current).into_iter()
+                .map(|node| node.get_id())
+                .filter(|id| {
+                    let distance = Chord::position(*id)
+                        .wrapping_sub(position);
+                    distance != 0 && distance < key.wrapping_sub(position)
+                })
+                .max_by_key(|id| Chord::position(*id).wrapping_sub(position))
+                .unwrap_or(owner);
+            path.push(current);
+        }
+
+        path
+    }
+
+    /// Returns the alive member owning `key`: the first at or after it.
+    pub fn successor(&self, key: u64) -> Option<Node> {
+        successor(&self.ring(), key).cloned()
+    }
+
+    /// Returns alive members ordered by ring position. Observers hold
+    /// no position.
+    fn ring(&self) -> Vec<(u64, Node)> {
+        let mut ring: Vec<(u64, Node)> = self.nodes.nodes().into_iter()
+            .filter(|node| node.state() == NodeState::Alive
+                && !crate::topology::is_observer(node))
+            .map(|node| (Chord::position(node.get_id()), node)).collect();
+        ring.sort_by_key(|(position, node)| (*position, node.get_id()));
+        ring
+    }
+}
+
+fn fingers(ring: &[(u64, Node)], id: u32) -> Vec<&Node> {
+    let position = Chord::position(id);
+    let mut fingers: Vec<&Node> = Vec::new();
+    for i in 0..64 {
+        if let Some(node) = successor(ring, position.wrapping_add(1 << i)) {
+            if node.get_id() != id && fingers.iter()
+                    .all(|finger| finger.get_id() != node.get_id()) {
+                fingers.push(node);
+            }
+        }
+    }
+
+    fingers
+}
+
+fn successor(ring: &[(u64, Node)], key: u64) -> Option<&Node> {
+    ring.iter().find(|(position, _)| *position >= key)
+        .or_else(|| ring.first()).map(|(_, node)| node)
+}
+
+impl Topology for Chord {
+    fn gossip_addr(&self, id: u32, seed_address: &Option<SocketAddr>)
+            -> Option<SocketAddr> {
+        let ring = self.ring();
+        if ring.iter().all(|(_, node)| node.get_id() == id) {
+            return *seed_address;
+        }
+
+        // neighbors most rounds, otherwise a random finger
+        let position = Chord::position(id);
+        let peer = match rand::random::<f64>() < self.neighbor_ratio {
+            true if rand::random::<bool>() => successor(&ring,
+                position.wrapping_add(1)),
+            true => ring.iter().rev()
+                .find(|(other, _)| *other < position)
+                .or_else(|| ring.last()).map(|(_, node)| node),
+            false => {
+                let fingers = fingers(&ring, id);
+                fingers.get(rand::random::<usize>() % fingers.len()).copied()
+            },
+        };
+
+        peer.filter(|node| node.get_id() != id)
+            .map(|node| node.get_address())
+    }
+
+    fn request<S: Read + Write>(&self, id: u32, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        // write local node and node hash
+        let node = self.nodes.get(id).unwrap();
+        let full_sync = crate::topology::is_full_sync(id,
+            self.full_sync.as_ref());
+        crate::topology::write_request_node(&node,
+            crate::topology::member_hash(&self.nodes, full_sync), stream)?;
+
+        // process node updates
+        crate::topology::read_node_updates(&self.nodes, self.clock.as_ref(),
+            &self.policy, self.quarantine.as_ref(), stream)?;
+
+        // exchange confirmation timestamps
+        crate::topology::read_confirmations(&self.nodes, false, stream)?;
+        crate::topology::write_confirmations(id, &self.nodes,
+            self.clock.as_ref(), stream)?;
+
+        // exchange tombstones of removed nodes
+        crate::topology::read_tombstones(id, &self.nodes, false, stream)?;
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
+
+        Ok(())
+    }
+
+    fn reply<S: Read + Write>(&self, stream: &mut S)
+            -> Result<(), Box<dyn Error>> {
+        // read request node and node hash
+        let (node, node_hash) = crate::topology::read_request_node(stream)?;
+
+        // write node updates
+        crate::topology::write_node_updates(&self.nodes,
+            node_hash, stream)?;
+
+        // exchange confirmation timestamps
+        crate::topology::write_confirmations(self.id, &self.nodes,
+            self.clock.as_ref(), stream)?;
+        crate::topology::read_confirmations(&self.nodes, false, stream)?;
+
+        // exchange tombstones of removed nodes
+        crate::topology::write_tombstones(&self.nodes, self.clock.as_ref(),
+            stream)?;
+        crate::topology::read_tombstones(self.id, &self.nodes, false, stream)?;
+
+        // add gossiping node to nodes if does not exist
+        crate::topology::register_node(&self.nodes, self.clock.as_ref(),
+            &self.policy, self.quarantine.as_ref(), node);
+
+        Ok(())
+    }
+
+    fn authorize_join(&self, node: &Node) -> bool {
+        self.policy.authorizes(node)
+    }
+
+    fn member_counts(&self) -> (usize, usize) {
+        crate::topology::count_members(&self.nodes)
+    }
+
+    fn peer_stats(&self) -> BTreeMap<u32, PeerStats> {
+        self.nodes.peer_stats()
+    }
+
+    fn remove_node(&self, id: u32, ttl: Duration)
+            -> Result<(), Box<dyn Error>> {
+        crate::topology::remove_node(self.id, &self.nodes,
+            self.clock.as_ref(), id, ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::SystemClock;
+    use crate::node::{Node, NodeMap};
+    use crate::topology::TopologyBuilder;
+    use super::{Chord, ChordBuilder};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn chord_routing() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let nodes = Arc::new(NodeMap::new());
+        for id in 0..64 {
+            nodes.insert(Node::new(id, ip_address, 12000 + id as u16));
+        }
+
+        let chord = ChordBuilder::new()
+            .build(0, nodes.clone(), Arc::new(SystemClock));
+        let fingers = chord.fingers();
+        assert!(fingers.len() >= 6 && fingers.len() < 16);
+
+        // neighbors surround the local position
+        let position = Chord::position(0);
+        let successor = chord.successor(position.wrapping_add(1))
+            .expect("successor");
+        let predecessor = chord.predecessor(position).expect("predecessor");
+        assert_eq!(chord.predecessor(Chord::position(successor.get_id()))
+            .expect("predecessor").get_id(), 0);
+        assert_eq!(chord.successor(Chord::position(predecessor.get_id())
+            .wrapping_add(1)).expect("successor").get_id(), 0);
+
+        // lookups reach the owner in O(log n) hops
+        for i in 0..256u64 {
+            let key = i.wrapping_mul(0x9e3779b97f4a7c15);
+            let path = chord.route(key);
+            let owner = chord.successor(key).expect("owner").get_id();
+            assert_eq!(path.last(), Some(&owner));
+            assert!(path.len() - 1 <= 7, "path {:?}", path);
+        }
+    }
+}
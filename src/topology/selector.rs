@@ -0,0 +1,293 @@
+use crate::node::{Node, PeerStats};
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Metadata key holding the datacenter of a member for TieredSelector.
+pub const DATACENTER_KEY: &str = "dc";
+/// Metadata key marking a member as a TieredSelector bridge with "true".
+pub const BRIDGE_KEY: &str = "bridge";
+
+/// Chooses the peer for each gossip round. `peers` holds every alive
+/// node other than `local`, ordered by id, and is never empty.
+pub trait PeerSelector: Send + Sync {
+    fn select<'a>(&self, local: &Node, peers: &'a [Node]) -> Option<&'a Node>;
+
+    /// Variant of select given the measured round trips of peers, keyed
+    /// by id. Peers never gossiped with have no entry. Defaults to select.
+    fn select_measured<'a>(&self, local: &Node, peers: &'a [Node],
+            _stats: &BTreeMap<u32, PeerStats>) -> Option<&'a Node> {
+        self.select(local, peers)
+    }
+}
+
+/// Picks a uniformly random peer, the default.
+#[derive(Default)]
+pub struct RandomSelector;
+
+impl PeerSelector for RandomSelector {
+    fn select<'a>(&self, _: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        peers.get(rand::random::<usize>() % peers.len())
+    }
+}
+
+/// Cycles through peers in id order.
+#[derive(Default)]
+pub struct RoundRobinSelector {
+    next: AtomicUsize,
+}
+
+impl PeerSelector for RoundRobinSelector {
+    fn select<'a>(&self, _: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        peers.get(index % peers.len())
+    }
+}
+
+/// Picks the peer selected least recently, preferring peers never
+/// selected, so information reaches every member within a bounded
+/// number of rounds.
+#[derive(Default)]
+pub struct StalenessSelector {
+    rounds: Mutex<(u64, HashMap<u32, u64>)>,
+}
+
+impl PeerSelector for StalenessSelector {
+    fn select<'a>(&self, _: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        let mut rounds = self.rounds.lock().unwrap();
+        let (round, selected) = &mut *rounds;
+        let node = peers.iter().min_by_key(|node|
+            selected.get(&node.get_id()).cloned().unwrap_or(0))?;
+
+        *round += 1;
+        selected.insert(node.get_id(), *round);
+        Some(node)
+    }
+}
+
+/// Prefers peers whose ip address shares the longest prefix with the
+/// local node (same host, then subnet), gossiping with a random peer
+/// for `remote_ratio` of rounds so distant members still converge.
+pub struct ProximitySelector {
+    remote_ratio: f64,
+}
+
+impl ProximitySelector {
+    pub fn new(remote_ratio: f64) -> ProximitySelector {
+        ProximitySelector { remote_ratio }
+    }
+}
+
+impl PeerSelector for ProximitySelector {
+    fn select<'a>(&self, local: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        if rand::random::<f64>() < self.remote_ratio {
+            return RandomSelector.select(local, peers);
+        }
+
+        let prefix = |node: &Node|
+            common_prefix(local.get_ip_address(), node.get_ip_address());
+        let longest = peers.iter().map(prefix).max()?;
+        let nearest: Vec<&Node> = peers.iter()
+            .filter(|node| prefix(node) == longest).collect();
+
+        Some(nearest[rand::random::<usize>() % nearest.len()])
+    }
+}
+
+/// Prefers the peer with the lowest smoothed round-trip time, gossiping
+/// with a random peer for `remote_ratio` of rounds so distant members
+/// still converge. Peers without measurements are selected first, so
+/// every peer is measured before latency decides.
+pub struct LatencySelector {
+    remote_ratio: f64,
+}
+
+impl LatencySelector {
+    pub fn new(remote_ratio: f64) -> LatencySelector {
+        LatencySelector { remote_ratio }
+    }
+}
+
+impl PeerSelector for LatencySelector {
+    fn select<'a>(&self, local: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        self.select_measured(local, peers, &BTreeMap::new())
+    }
+
+    fn select_measured<'a>(&self, local: &Node, peers: &'a [Node],
+            stats: &BTreeMap<u32, PeerStats>) -> Option<&'a Node> {
+        if rand::random::<f64>() < self.remote_ratio {
+            return RandomSelector.select(local, peers);
+        }
+
+        // unmeasured peers sort before every measured one
+        peers.iter().min_by_key(|node| stats.get(&node.get_id())
+            .map(|stats| stats.smoothed_rtt))
+    }
+}
+
+/// Two-tier gossip for multi-datacenter deployments, where members carry
+/// their datacenter in the "dc" metadata key. Members gossip within
+/// their datacenter every round; members with "bridge" set to "true"
+/// instead gossip with a remote datacenter every `wan_rounds` rounds,
+/// preferring remote bridges. Membership crosses datacenters through
+/// bridges only, so WAN links see a fraction of the gossip traffic.
+pub struct TieredSelector {
+    round: AtomicU64,
+    wan_rounds: u64,
+}
+
+impl TieredSelector {
+    pub fn new(wan_rounds: u32) -> TieredSelector {
+        TieredSelector {
+            round: AtomicU64::new(0),
+            wan_rounds: std::cmp::max(wan_rounds, 1) as u64,
+        }
+    }
+}
+
+impl PeerSelector for TieredSelector {
+    fn select<'a>(&self, local: &Node, peers: &'a [Node]) -> Option<&'a Node> {
+        let round = self.round.fetch_add(1, Ordering::Relaxed) + 1;
+        let datacenter = local.get_metadata(DATACENTER_KEY);
+        let (lan, wan): (Vec<&Node>, Vec<&Node>) = peers.iter()
+            .partition(|node| node.get_metadata(DATACENTER_KEY) == datacenter);
+
+        if is_bridge(local) && round.is_multiple_of(self.wan_rounds)
+                && !wan.is_empty() {
+            let bridges: Vec<&Node> = wan.iter()
+                .filter(|node| is_bridge(node)).copied().collect();
+            let candidates = match bridges.is_empty() {
+                true => &wan,
+                false => &bridges,
+            };
+
+            return Some(candidates[rand::random::<usize>() % candidates.len()]);
+        }
+
+        // members alone in their datacenter fall back to any peer
+        match lan.is_empty() {
+            true => RandomSelector.select(local, peers),
+            false => Some(lan[rand::random::<usize>() % lan.len()]),
+        }
+    }
+}
+
+fn is_bridge(node: &Node) -> bool {
+    node.get_metadata(BRIDGE_KEY).map(|value| value == "true")
+        .unwrap_or(false)
+}
+
+fn common_prefix(a: &IpAddr, b: &IpAddr) -> u32 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) =>
+            (u32::from(*a) ^ u32::from(*b)).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) =>
+            (u128::from(*a) ^ u128::from(*b)).leading_zeros(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+    use crate::node::PeerStats;
+    use super::{LatencySelector, PeerSelector, ProximitySelector,
+        RoundRobinSelector, StalenessSelector, TieredSelector};
+
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn peer_selectors() {
+        let local = Node::new(0, "10.0.1.1".parse().expect("parse ip addr"),
+            12000);
+        let peers = vec!(
+            Node::new(1, "10.0.2.1".parse().expect("parse ip addr"), 12000),
+            Node::new(2, "10.0.1.2".parse().expect("parse ip addr"), 12000),
+            Node::new(3, "10.1.1.1".parse().expect("parse ip addr"), 12000));
+        let select = |selector: &dyn PeerSelector|
+            selector.select(&local, &peers).expect("select").get_id();
+
+        let selector = RoundRobinSelector::default();
+        let ids: Vec<u32> = (0..4).map(|_| select(&selector)).collect();
+        assert_eq!(ids, vec!(1, 2, 3, 1));
+
+        // stale peers are selected first
+        let selector = StalenessSelector::default();
+        let ids: Vec<u32> = (0..3).map(|_| select(&selector)).collect();
+        assert_eq!(ids, vec!(1, 2, 3));
+        assert_eq!(select(&selector), 1);
+
+        let selector = ProximitySelector::new(0.0);
+        assert_eq!(select(&selector), 2);
+    }
+
+    #[test]
+    fn latency_selector() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let local = Node::new(0, ip_address, 12000);
+        let peers: Vec<Node> = (1..4)
+            .map(|id| Node::new(id, ip_address, 12000 + id as u16)).collect();
+        let rtt = |millis: u64| PeerStats {
+            last_rtt: Duration::from_millis(millis),
+            min_rtt: Duration::from_millis(millis),
+            samples: 1,
+            smoothed_rtt: Duration::from_millis(millis),
+        };
+
+        // unmeasured peers are sampled before the fastest peer
+        let selector = LatencySelector::new(0.0);
+        let mut stats = BTreeMap::new();
+        stats.insert(1, rtt(40));
+        stats.insert(3, rtt(5));
+        let select = |stats: &BTreeMap<u32, PeerStats>| selector
+            .select_measured(&local, &peers, stats).expect("select").get_id();
+        assert_eq!(select(&stats), 2);
+
+        stats.insert(2, rtt(20));
+        assert_eq!(select(&stats), 3);
+
+        // remote rounds pick any peer
+        let selector = LatencySelector::new(1.0);
+        assert!(selector.select_measured(&local, &peers, &stats).is_some());
+    }
+
+    #[test]
+    fn tiered_selector() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let member = |id: u32, dc: &str, bridge: bool| {
+            let mut node = Node::new(id, ip_address, 12000 + id as u16);
+            node.set_metadata("dc", dc);
+            if bridge {
+                node.set_metadata("bridge", "true");
+            }
+            node
+        };
+
+        let peers = vec!(member(1, "east", false), member(2, "west", false),
+            member(3, "west", true));
+        let datacenter = |node: &Node| node.get_metadata("dc").cloned();
+
+        // regular members stay within their datacenter
+        let local = member(0, "east", false);
+        let selector = TieredSelector::new(2);
+        for _ in 0..4 {
+            assert_eq!(selector.select(&local, &peers)
+                .expect("select").get_id(), 1);
+        }
+
+        // bridges cross to remote bridges every wan_rounds rounds
+        let local = member(0, "east", true);
+        let selector = TieredSelector::new(2);
+        let ids: Vec<u32> = (0..4).map(|_| selector.select(&local, &peers)
+            .expect("select").get_id()).collect();
+        assert_eq!(ids, vec!(1, 3, 1, 3));
+
+        // isolated members gossip with any peer
+        let local = member(0, "south", false);
+        let node = selector.select(&local, &peers).expect("select");
+        assert_ne!(datacenter(node), Some("south".to_string()));
+    }
+}
@@ -0,0 +1,102 @@
+use std::cell::Cell;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+thread_local! {
+    static TRACE_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Identifier shared by both sides of a gossip exchange so log lines
+/// on different nodes can be correlated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceId(pub Option<u64>);
+
+impl Display for TraceId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(trace_id) => write!(f, "{:016x}", trace_id),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// Restores the previous trace id when dropped.
+pub struct TraceGuard {
+    previous: Option<u64>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        TRACE_ID.with(|trace_id| trace_id.set(self.previous));
+    }
+}
+
+/// Returns the trace id of the exchange running on this thread.
+pub fn current() -> TraceId {
+    TraceId(TRACE_ID.with(|trace_id| trace_id.get()))
+}
+
+/// Sets the trace id for the current thread until the guard drops.
+pub fn enter(trace_id: u64) -> TraceGuard {
+    let previous = TRACE_ID.with(|current| current.replace(Some(trace_id)));
+    TraceGuard { previous }
+}
+
+/// Span around one side of a gossip exchange, recording the peer,
+/// bytes exchanged, and duration. A no-op without the tracing feature.
+pub struct ExchangeSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+impl ExchangeSpan {
+    pub fn request(peer_address: SocketAddr) -> ExchangeSpan {
+        ExchangeSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("request",
+                trace_id = %current(),
+                peer_id = tracing::field::Empty,
+                peer_address = %peer_address,
+                bytes_sent = tracing::field::Empty,
+                bytes_received = tracing::field::Empty,
+                duration_ms = tracing::field::Empty),
+        }
+    }
+
+    pub fn reply(peer_address: Option<SocketAddr>) -> ExchangeSpan {
+        ExchangeSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("reply",
+                trace_id = %current(),
+                peer_id = tracing::field::Empty,
+                peer_address = tracing::field::debug(peer_address),
+                bytes_sent = tracing::field::Empty,
+                bytes_received = tracing::field::Empty,
+                duration_ms = tracing::field::Empty),
+        }
+    }
+
+    pub fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+        #[cfg(feature = "tracing")]
+        return self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+
+    pub fn record_peer_id(&self, peer_id: u32) {
+        #[cfg(feature = "tracing")]
+        self.span.record("peer_id", peer_id);
+    }
+
+    pub fn finish(self, bytes_sent: u64, bytes_received: u64,
+            duration: Duration) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("bytes_sent", bytes_sent);
+            self.span.record("bytes_received", bytes_received);
+            self.span.record("duration_ms", duration.as_millis() as u64);
+        }
+    }
+}
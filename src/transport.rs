@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Opens byte streams to gossip addresses. Topology exchanges are
+/// generic over Read + Write, so any transport's streams can carry
+/// them: Swarm gossips over TCP, while MemoryTransport runs the same
+/// exchanges between in-process topologies in tests.
+pub trait Transport {
+    type Stream: Read + Write + Send;
+
+    fn connect(&self, address: SocketAddr)
+        -> Result<Self::Stream, Box<dyn Error>>;
+}
+
+/// Transport over TCP connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+
+    fn connect(&self, address: SocketAddr)
+            -> Result<TcpStream, Box<dyn Error>> {
+        Ok(TcpStream::connect(address)?)
+    }
+}
+
+/// In-process transport backed by channels, so multi-node gossip
+/// scenarios run without sockets. Clones share the same address space.
+/// Connecting to an address without a bound MemoryListener is refused,
+/// like a closed TCP port.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Sender<MemoryStream>>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Listens at `address` until the returned listener is dropped.
+    pub fn bind(&self, address: SocketAddr)
+            -> Result<MemoryListener, Box<dyn Error>> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.contains_key(&address) {
+            return Err(format!("memory address in use [address={}]",
+                address).into());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        listeners.insert(address, sender);
+        debug!("bound memory listener [address={}]", address);
+        Ok(MemoryListener {
+            address,
+            listeners: self.listeners.clone(),
+            receiver,
+        })
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Stream = MemoryStream;
+
+    fn connect(&self, address: SocketAddr)
+            -> Result<MemoryStream, Box<dyn Error>> {
+        let listeners = self.listeners.lock().unwrap();
+        let listener = listeners.get(&address).ok_or_else(||
+            io::Error::new(ErrorKind::ConnectionRefused,
+                format!("no memory listener [address={}]", address)))?;
+
+        let (client, server) = MemoryStream::pair();
+        listener.send(server).map_err(|_|
+            io::Error::new(ErrorKind::ConnectionRefused,
+                format!("memory listener closed [address={}]", address)))?;
+        Ok(client)
+    }
+}
+
+/// Accepts streams connected to an address of a MemoryTransport.
+pub struct MemoryListener {
+    address: SocketAddr,
+    listeners: Arc<Mutex<HashMap<SocketAddr, Sender<MemoryStream>>>>,
+    receiver: Receiver<MemoryStream>,
+}
+
+impl MemoryListener {
+    /// Blocks until a stream connects.
+    pub fn accept(&self) -> Result<MemoryStream, Box<dyn Error>> {
+        Ok(self.receiver.recv()?)
+    }
+
+    /// Returns a pending stream without blocking.
+    pub fn try_accept(&self) -> Option<MemoryStream> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.listeners.lock().unwrap().remove(&self.address);
+    }
+}
+
+/// One end of an in-process duplex byte stream. Reads return end of
+/// stream once the other end is dropped and its writes are consumed.
+pub struct MemoryStream {
+    buffer: Vec<u8>,
+    position: usize,
+    read_timeout: Option<Duration>,
+    receiver: Receiver<Vec<u8>>,
+    sender: Sender<Vec<u8>>,
+}
+
+impl MemoryStream {
+    /// Returns two connected ends.
+    pub fn pair() -> (MemoryStream, MemoryStream) {
+        let (left_sender, right_receiver) = mpsc::channel();
+        let (right_sender, left_receiver) = mpsc::channel();
+        (MemoryStream::new(left_sender, left_receiver),
+            MemoryStream::new(right_sender, right_receiver))
+    }
+
+    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>)
+            -> MemoryStream {
+        MemoryStream {
+            buffer: Vec::new(),
+            position: 0,
+            read_timeout: None,
+            receiver,
+            sender,
+        }
+    }
+
+    /// Bounds blocking reads like TcpStream::set_read_timeout.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Returns true if the other end has written bytes not yet read.
+    pub fn has_pending(&mut self) -> bool {
+        if self.position < self.buffer.len() {
+            return true;
+        }
+
+        match self.receiver.try_recv() {
+            Ok(chunk) => {
+                self.buffer = chunk;
+                self.position = 0;
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // refill from the next chunk written by the other end
+        while self.position == self.buffer.len() {
+            let chunk = match self.read_timeout {
+                Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => return Err(
+                        io::Error::new(ErrorKind::WouldBlock, "read timed out")),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                },
+                None => match self.receiver.recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(0),
+                },
+            };
+
+            self.buffer = chunk;
+            self.position = 0;
+        }
+
+        let len = std::cmp::min(buf.len(), self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(
+            &self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.sender.send(buf.to_vec()).map_err(|_|
+            io::Error::new(ErrorKind::BrokenPipe, "memory stream closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::SystemClock;
+    use crate::node::{Node, NodeMap};
+    use crate::prelude::{ClusterBuilder, Topology};
+    use crate::topology::TopologyBuilder;
+    use super::{MemoryTransport, Transport};
+
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn memory_transport() {
+        let transport = MemoryTransport::new();
+        let address: SocketAddr = "127.0.0.1:15901".parse().expect("parse");
+        assert!(transport.connect(address).is_err());
+
+        let listener = transport.bind(address).expect("bind");
+        assert!(transport.bind(address).is_err());
+        let mut client = transport.connect(address).expect("connect");
+        let mut server = listener.try_accept().expect("accept");
+        assert!(listener.try_accept().is_none());
+
+        // duplex streams preserve bytes across chunk boundaries
+        client.write_all(b"hel").expect("write");
+        client.write_all(b"lo").expect("write");
+        assert!(server.has_pending());
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf, b"hello");
+        assert!(!server.has_pending());
+
+        server.set_read_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(server.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock);
+        server.write_all(b"ok").expect("write");
+        drop(server);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).expect("read");
+        assert_eq!(reply, b"ok");
+        assert!(client.write_all(b"closed").is_err());
+
+        // dropped listeners release their address
+        drop(listener);
+        assert!(transport.connect(address).is_err());
+        assert!(transport.bind(address).is_ok());
+    }
+
+    #[test]
+    fn memory_topology_exchange() {
+        let ip_address = "127.0.0.1".parse().expect("parse ip addr");
+        let transport = MemoryTransport::new();
+        let clusters: Vec<_> = (0..2).map(|id| {
+            let nodes = Arc::new(NodeMap::new());
+            nodes.insert(Node::new(id, ip_address, 15910 + id as u16));
+            ClusterBuilder::new().build(id, nodes, Arc::new(SystemClock))
+        }).collect();
+
+        // gossip one round from node 1 to node 0 without sockets
+        let address = SocketAddr::new(ip_address, 15910);
+        let listener = transport.bind(address).expect("bind");
+        let mut client = transport.connect(address).expect("connect");
+        let mut server = listener.accept().expect("accept");
+        std::thread::scope(|scope| {
+            scope.spawn(|| clusters[0].reply(&mut server).expect("reply"));
+            clusters[1].request(1, &mut client).expect("request");
+        });
+
+        for cluster in clusters.iter() {
+            assert_eq!(cluster.nodes().len(), 2);
+        }
+    }
+}
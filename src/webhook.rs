@@ -0,0 +1,285 @@
+use crate::clock::Clock;
+use crate::hmac;
+use crate::http::{self, HttpUrl};
+use crate::node::{Node, NodeMap, NodeState};
+use crate::secret::Secret;
+use crate::topology::Topology;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+const RETRY_BACKOFF_MS: u64 = 100;
+
+/// Membership change delivered to webhooks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ClusterEvent {
+    Join { id: u32, address: SocketAddr },
+    Leave { id: u32, address: SocketAddr },
+    Dead { id: u32, address: SocketAddr },
+    RingChange { epoch: u64 },
+}
+
+impl ClusterEvent {
+    /// Encodes the event as the JSON body POSTed to webhooks.
+    pub fn to_json(&self, reporter_id: u32, timestamp: u64) -> String {
+        let fields = match self {
+            ClusterEvent::Join { id, address } => format!(
+                "\"event\":\"join\",\"node_id\":{},\"address\":\"{}\"",
+                id, address),
+            ClusterEvent::Leave { id, address } => format!(
+                "\"event\":\"leave\",\"node_id\":{},\"address\":\"{}\"",
+                id, address),
+            ClusterEvent::Dead { id, address } => format!(
+                "\"event\":\"dead\",\"node_id\":{},\"address\":\"{}\"",
+                id, address),
+            ClusterEvent::RingChange { epoch } => format!(
+                "\"event\":\"ring_change\",\"epoch\":{}", epoch),
+        };
+
+        format!("{{{},\"reporter_id\":{},\"timestamp\":{}}}",
+            fields, reporter_id, timestamp)
+    }
+}
+
+/// An http endpoint receiving cluster events as JSON POSTs. Signed
+/// webhooks carry an `X-Swarm-Signature: sha256=<hex>` header holding
+/// the HMAC-SHA256 of the body, so receivers can authenticate events.
+/// Only plain http urls are supported; terminate TLS in a local proxy.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    retries: u32,
+    secret: Option<Secret>,
+    timeout: Duration,
+    url: HttpUrl,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Webhook, Box<dyn Error>> {
+        Ok(Webhook {
+            retries: DEFAULT_RETRIES,
+            secret: None,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            url: HttpUrl::parse(url)?,
+        })
+    }
+
+    /// Retries failed deliveries up to `retries` times with doubling
+    /// backoff before dropping the event.
+    pub fn retries(mut self, retries: u32) -> Webhook {
+        self.retries = retries;
+        self
+    }
+
+    /// Signs every body with HMAC-SHA256 under `secret`.
+    pub fn secret(mut self, secret: Secret) -> Webhook {
+        self.secret = Some(secret);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Webhook {
+        self.timeout = timeout;
+        self
+    }
+
+    fn deliver(&self, clock: &dyn Clock, body: &str) -> bool {
+        let mut backoff = Duration::from_millis(RETRY_BACKOFF_MS);
+        for attempt in 0..=self.retries {
+            match self.post(body) {
+                Ok(()) => return true,
+                Err(e) => debug!("webhook delivery failure [host={}, path={}, attempt={}]: {}",
+                    self.url.get_host(), self.url.get_path(), attempt, e),
+            }
+
+            if attempt < self.retries {
+                clock.sleep(backoff);
+                backoff *= 2;
+            }
+        }
+
+        false
+    }
+
+    fn post(&self, body: &str) -> Result<(), Box<dyn Error>> {
+        let signature = self.secret.as_ref().map(|secret| format!("sha256={}",
+            hmac::to_hex(&hmac::hmac_sha256(
+                secret.expose().as_bytes(), body.as_bytes()))));
+
+        let mut headers = vec!(("Content-Type", "application/json"));
+        if let Some(signature) = signature.as_ref() {
+            headers.push(("X-Swarm-Signature", signature));
+        }
+
+        match http::request(&self.url, "POST", "", &headers,
+                body, self.timeout)? {
+            (200..=299, _) => Ok(()),
+            (status, _) => Err(format!("http status {}", status).into()),
+        }
+    }
+}
+
+/// Tracks the last observed membership and reports the changes
+/// between observations.
+#[derive(Default)]
+pub struct MembershipWatcher {
+    epoch: Option<u64>,
+    members: HashMap<u32, (SocketAddr, NodeState)>,
+}
+
+impl MembershipWatcher {
+    pub fn new(nodes: &[Node], epoch: Option<u64>) -> MembershipWatcher {
+        // the first observation is the baseline
+        let mut watcher = MembershipWatcher::default();
+        watcher.observe(nodes, epoch);
+        watcher
+    }
+
+    pub fn observe(&mut self, nodes: &[Node], epoch: Option<u64>)
+            -> Vec<ClusterEvent> {
+        let mut events = Vec::new();
+        let mut members = HashMap::new();
+        for node in nodes {
+            let (id, address, state) =
+                (node.get_id(), node.get_address(), node.state());
+            match self.members.get(&id) {
+                None => events.push(ClusterEvent::Join { id, address }),
+                Some((_, NodeState::Dead)) if state != NodeState::Dead =>
+                    events.push(ClusterEvent::Join { id, address }),
+                Some((_, previous)) if *previous != NodeState::Dead
+                        && state == NodeState::Dead =>
+                    events.push(ClusterEvent::Dead { id, address }),
+                Some(_) => {},
+            }
+
+            members.insert(id, (address, state));
+        }
+
+        // members no longer registered have left
+        let mut left: Vec<_> = self.members.iter()
+            .filter(|(id, _)| !members.contains_key(id))
+            .map(|(id, (address, _))|
+                ClusterEvent::Leave { id: *id, address: *address })
+            .collect();
+        left.sort_by_key(|event| match event {
+            ClusterEvent::Leave { id, .. } => *id,
+            _ => 0,
+        });
+        events.extend(left);
+
+        if let (Some(previous), Some(current)) = (self.epoch, epoch) {
+            if current != previous {
+                events.push(ClusterEvent::RingChange { epoch: current });
+            }
+        }
+
+        self.epoch = epoch;
+        self.members = members;
+        events
+    }
+}
+
+/// Polls membership every `interval` and delivers each change to every
+/// webhook until shutdown.
+pub fn notify<T: Topology>(clock: Arc<dyn Clock>, local_id: u32,
+        nodes: Arc<NodeMap>, topology: Arc<T>, webhooks: Vec<Webhook>,
+        interval: Duration, shutdown: Arc<AtomicBool>) {
+    let mut watcher = MembershipWatcher::new(&sorted_nodes(&nodes),
+        topology.ring_epoch());
+
+    while !shutdown.load(Ordering::Relaxed) {
+        clock.sleep(interval);
+
+        let events = watcher.observe(&sorted_nodes(&nodes),
+            topology.ring_epoch());
+        for event in events {
+            let body = event.to_json(local_id, clock.timestamp());
+            for webhook in webhooks.iter() {
+                if !webhook.deliver(clock.as_ref(), &body) {
+                    warn!("dropped webhook event [host={}, event={:?}]",
+                        webhook.url.get_host(), event);
+                }
+            }
+        }
+    }
+}
+
+fn sorted_nodes(nodes: &NodeMap) -> Vec<Node> {
+    let mut nodes = nodes.nodes();
+    nodes.sort_by_key(|node| node.get_id());
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::node::{Node, NodeState};
+    use crate::secret::Secret;
+    use super::{ClusterEvent, MembershipWatcher, Webhook};
+
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn membership_events() {
+        let ip_address: IpAddr = "127.0.0.1".parse().unwrap();
+        let (a, b) = (Node::new(1, ip_address, 1), Node::new(2, ip_address, 2));
+        let mut watcher = MembershipWatcher::new(std::slice::from_ref(&a), Some(1));
+
+        assert_eq!(watcher.observe(&[a.clone(), b.clone()], Some(2)), vec!(
+            ClusterEvent::Join { id: 2, address: b.get_address() },
+            ClusterEvent::RingChange { epoch: 2 }));
+        assert!(watcher.observe(&[a.clone(), b.clone()], Some(2)).is_empty());
+
+        let mut dead = b.clone();
+        dead.set_state(NodeState::Dead);
+        assert_eq!(watcher.observe(&[a.clone(), dead], Some(2)),
+            vec!(ClusterEvent::Dead { id: 2, address: b.get_address() }));
+        assert_eq!(watcher.observe(&[a], Some(2)),
+            vec!(ClusterEvent::Leave { id: 2, address: b.get_address() }));
+    }
+
+    #[test]
+    fn webhook_delivery() {
+        let listener = TcpListener::bind("127.0.0.1:15500").expect("bind");
+        let server = thread::spawn(move || {
+            // fail the first attempt to exercise retry
+            let mut requests = Vec::new();
+            for status in &["500 Internal Server Error", "204 No Content"] {
+                let (mut stream, _) = listener.accept().expect("accept");
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).ends_with('}') {
+                    let len = stream.read(&mut buf).expect("read");
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                stream.write_all(format!("HTTP/1.1 {}\r\n\r\n", status)
+                    .as_bytes()).expect("write");
+                requests.push(String::from_utf8(request).expect("utf8"));
+            }
+
+            requests
+        });
+
+        let webhook = Webhook::new("http://127.0.0.1:15500/events")
+            .expect("parse url").secret(Secret::new("key")).retries(1);
+        let body = ClusterEvent::RingChange { epoch: 3 }.to_json(1, 7);
+        assert_eq!(body,
+            "{\"event\":\"ring_change\",\"epoch\":3,\"reporter_id\":1,\"timestamp\":7}");
+        assert!(webhook.deliver(&ManualClock::new(0), &body));
+
+        let requests = server.join().expect("server");
+        assert!(requests[1].starts_with("POST /events HTTP/1.0\r\n"));
+        assert!(requests[1].contains("X-Swarm-Signature: sha256="));
+        assert!(requests[1].ends_with(&body));
+
+        assert!(Webhook::new("https://example.com/").is_err());
+    }
+}